@@ -9,45 +9,55 @@
 
 extern crate async = "rust-async";
 
+use std::io::IoResult;
+
 use async::pipeline::{Filter, PipelineBuilder, PipelineDown, PipelineUp};
 
 struct U64ToU32Filter;
 
 impl Filter<u64, u32, u32, u64> for U64ToU32Filter {
-    fn down<U: PipelineUp<u64>, D: PipelineDown<u32>>(&self, data: u64, _: &U, down: &D) {
+    fn down<U: PipelineUp<u64>, D: PipelineDown<u32>>(
+            &self, data: u64, _: &U, down: &D) -> IoResult<()> {
         println!("hi64");
-        down.down(data as u32);
+        down.down(data as u32)
     }
 
-    fn up<U: PipelineUp<u64>, D: PipelineDown<u32>>(&self, data: u32, up: &U, _: &D) {
-        up.up(data as u64);
+    fn up<U: PipelineUp<u64>, D: PipelineDown<u32>>(
+            &self, data: u32, up: &U, _: &D) -> IoResult<()> {
+        try!(up.up(data as u64));
         println!("bai!");
+        Ok(())
     }
 }
 
 struct U32ToU16Filter;
 
 impl Filter<u32, u16, u16, u32> for U32ToU16Filter {
-    fn down<U: PipelineUp<u32>, D: PipelineDown<u16>>(&self, data: u32, _: &U, down: &D) {
+    fn down<U: PipelineUp<u32>, D: PipelineDown<u16>>(
+            &self, data: u32, _: &U, down: &D) -> IoResult<()> {
         println!("hi32");
-        down.down(data as u16);
+        down.down(data as u16)
     }
 
-    fn up<U: PipelineUp<u32>, D: PipelineDown<u16>>(&self, data: u16, up: &U, _: &D) {
+    fn up<U: PipelineUp<u32>, D: PipelineDown<u16>>(
+            &self, data: u16, up: &U, _: &D) -> IoResult<()> {
         println!("Stuck in the middle");
-        up.up(data as u32);
+        up.up(data as u32)
     }
 }
 
 struct PrintU16Filter;
 
 impl Filter<u16, (), (), u16> for PrintU16Filter {
-    fn down<U: PipelineUp<u16>, D: PipelineDown<()>>(&self, data: u16, up: &U, _: &D) {
+    fn down<U: PipelineUp<u16>, D: PipelineDown<()>>(
+            &self, data: u16, up: &U, _: &D) -> IoResult<()> {
         println!("Value!: {}", data);
-        up.up(data);
+        up.up(data)
     }
 
-    fn up<U: PipelineUp<u16>, D: PipelineDown<()>>(&self, _: (), _: &U, _: &D) {
+    fn up<U: PipelineUp<u16>, D: PipelineDown<()>>(
+            &self, _: (), _: &U, _: &D) -> IoResult<()> {
+        Ok(())
     }
 }
 
@@ -56,5 +66,5 @@ fn main() {
         .filter(U32ToU16Filter)
         .filter(U64ToU32Filter)
         .build();
-    pipeline.down(65);
+    pipeline.down(65).unwrap();
 }
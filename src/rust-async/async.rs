@@ -17,15 +17,339 @@ use std::io::{Reader, Writer, Listener, Acceptor};
 use std::io::IoResult;
 use std::rt::rtio::{IoFactory, LocalIo, RtioSocket, RtioTcpListener};
 use std::rt::rtio::{RtioTcpAcceptor, RtioTcpStream};
+use std::io;
+use std::io::IoError;
+use std::libc;
+use std::libc::{c_void, size_t, ssize_t};
+use std::ptr;
+
+use native;
+
+use fcntl;
+use socket;
+use socket::msghdr;
+use uio;
+use uio::iovec;
 
 /// An asynchronous reader.
 pub trait AsyncReader {
+    /// Read whatever data is available without blocking. An error of kind
+    /// `EndOfFile` means that the peer will send no more data; `Ok(0)` is only
+    /// returned if `output` is empty. If no data is available yet, an error
+    /// for which `would_block` returns true is returned.
     fn async_read(&mut self, output: &mut [u8]) -> IoResult<uint>;
+
+    /// Read into several buffers, filling each one before moving on to the
+    /// next. The default implementation performs one `async_read` per buffer
+    /// and stops at the first short read.
+    fn async_read_vectored(&mut self, outputs: &mut [&mut [u8]]) -> IoResult<uint> {
+        let mut total = 0;
+        for i in range(0, outputs.len()) {
+            let len = outputs[i].len();
+            if len == 0 {
+                continue;
+            }
+            match self.async_read(outputs[i]) {
+                Ok(n) => {
+                    total += n;
+                    if n < len {
+                        break;
+                    }
+                }
+                // Data has already been placed into earlier buffers, so
+                // report that instead of losing it.
+                Err(ref e) if total > 0 && (would_block(e) || is_eof(e)) => break,
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(total)
+    }
+
+    /// The descriptor that splice(2) can read from directly, if any. Readers
+    /// that buffer data in userspace must return `None`, which is the
+    /// default.
+    fn splice_fd(&self) -> Option<c_int> {
+        None
+    }
+}
+
+/// Which direction(s) of a connection to shut down.
+#[deriving(Clone, Eq)]
+pub enum Shutdown {
+    ShutdownRead,
+    ShutdownWrite,
+    ShutdownBoth
 }
 
 /// An asynchronous writer.
 pub trait AsyncWriter {
     fn async_write(&mut self, input: &[u8]) -> IoResult<uint>;
+
+    /// Shut down one or both directions of the underlying connection. After
+    /// `ShutdownWrite` the peer reads end of stream, while data it sends can
+    /// still be read. The default implementation reports that shutting down
+    /// is not supported.
+    fn async_shutdown(&mut self, how: Shutdown) -> IoResult<()> {
+        let _ = how;
+        Err(io::standard_error(io::IoUnavailable))
+    }
+
+    /// Write several buffers in order, as if they had been concatenated. The
+    /// default implementation performs one `async_write` per buffer and stops
+    /// at the first short write.
+    fn async_write_vectored(&mut self, inputs: &[&[u8]]) -> IoResult<uint> {
+        let mut total = 0;
+        for input in inputs.iter() {
+            if input.len() == 0 {
+                continue;
+            }
+            match self.async_write(*input) {
+                Ok(n) => {
+                    total += n;
+                    if n < input.len() {
+                        break;
+                    }
+                }
+                Err(ref e) if total > 0 && would_block(e) => break,
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(total)
+    }
+
+    /// The descriptor that splice(2) can write to directly, if any. Writers
+    /// that buffer data in userspace must return `None`, which is the
+    /// default.
+    fn splice_fd(&self) -> Option<c_int> {
+        None
+    }
+}
+
+/// An asynchronous datagram socket.
+pub trait AsyncDatagram {
+    /// Send a single datagram to `dst`.
+    fn async_send_to(&mut self, buf: &[u8], dst: SocketAddr) -> IoResult<uint>;
+
+    /// Receive a single datagram, returning its length and source. A
+    /// datagram longer than `buf` is truncated.
+    fn async_recv_from(&mut self, buf: &mut [u8]) -> IoResult<(uint, SocketAddr)>;
+
+    /// Send several datagrams, returning how many were sent. The default
+    /// implementation calls `async_send_to` for each one, stopping when the
+    /// socket would block.
+    fn async_send_many(&mut self, msgs: &[(&[u8], SocketAddr)]) -> IoResult<uint> {
+        let mut sent = 0;
+        for &(buf, dst) in msgs.iter() {
+            match self.async_send_to(buf, dst) {
+                Ok(_) => sent += 1,
+                Err(ref e) if sent > 0 && would_block(e) => break,
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Receive up to one datagram into each buffer, returning the length and
+    /// source of each datagram received. The default implementation calls
+    /// `async_recv_from` for each buffer, stopping when the socket would
+    /// block.
+    fn async_recv_many(&mut self, bufs: &mut [&mut [u8]]) -> IoResult<~[(uint, SocketAddr)]> {
+        let mut results = ~[];
+        for i in range(0, bufs.len()) {
+            match self.async_recv_from(bufs[i]) {
+                Ok(r) => results.push(r),
+                Err(ref e) if results.len() > 0 && would_block(e) => break,
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(results)
+    }
+}
+
+/// Returns true if the error indicates that the operation would have blocked
+/// and should be retried once the selector reports readiness.
+pub fn would_block(err: &IoError) -> bool {
+    err.kind == io::ResourceUnavailable
+}
+
+/// Returns true if the error indicates that the peer will send no more data.
+pub fn is_eof(err: &IoError) -> bool {
+    err.kind == io::EndOfFile
+}
+
+/// Put a file descriptor into (or take it out of) non-blocking mode. Pipes
+/// must be non-blocking before they are used as an `AsyncReader` or
+/// `AsyncWriter`.
+pub fn set_nonblocking(fd: c_int, nonblocking: bool) -> IoResult<()> {
+    unsafe {
+        let flags = fcntl::fcntl(fd, fcntl::F_GETFL, 0);
+        if flags < 0 {
+            return Err(IoError::last_error());
+        }
+        let flags = if nonblocking {
+            flags | fcntl::O_NONBLOCK
+        } else {
+            flags & !fcntl::O_NONBLOCK
+        };
+        if fcntl::fcntl(fd, fcntl::F_SETFL, flags) < 0 {
+            return Err(IoError::last_error());
+        }
+    }
+    Ok(())
+}
+
+/// Convert the return value of a read or write system call.
+pub fn io_result(res: ssize_t) -> IoResult<uint> {
+    if res < 0 {
+        Err(IoError::last_error())
+    } else {
+        Ok(res as uint)
+    }
+}
+
+/// Like `io_result`, but a zero length read into a non-empty buffer is
+/// reported as end of stream.
+pub fn read_result(res: ssize_t, len: uint) -> IoResult<uint> {
+    match io_result(res) {
+        Ok(0) if len > 0 => Err(io::standard_error(io::EndOfFile)),
+        r => r
+    }
+}
+
+pub fn total_len(outputs: &[&mut [u8]]) -> uint {
+    outputs.iter().fold(0, |acc, output| acc + output.len())
+}
+
+pub fn shutdown_socket(fd: c_int, how: Shutdown) -> IoResult<()> {
+    let how = match how {
+        ShutdownRead => socket::SHUT_RD,
+        ShutdownWrite => socket::SHUT_WR,
+        ShutdownBoth => socket::SHUT_RDWR
+    };
+    if unsafe { libc::shutdown(fd, how) } < 0 {
+        Err(IoError::last_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Describe `outputs` for readv(2) or recvmsg(2). The buffers must outlive
+/// the result.
+pub fn read_iovecs(outputs: &mut [&mut [u8]]) -> ~[iovec] {
+    outputs.mut_iter().map(|output| {
+        iovec { iov_base: output.as_mut_ptr() as *mut c_void, iov_len: output.len() as size_t }
+    }).collect()
+}
+
+/// Describe `inputs` for writev(2) or sendmsg(2). The buffers must outlive
+/// the result.
+pub fn write_iovecs(inputs: &[&[u8]]) -> ~[iovec] {
+    inputs.iter().map(|input| {
+        iovec { iov_base: input.as_ptr() as *mut c_void, iov_len: input.len() as size_t }
+    }).collect()
+}
+
+/// A message header with no address and no control data.
+pub fn empty_msghdr(iovs: &mut [iovec]) -> msghdr {
+    msghdr {
+        msg_name: ptr::mut_null(),
+        msg_namelen: 0,
+        msg_iov: iovs.as_mut_ptr(),
+        msg_iovlen: iovs.len() as size_t,
+        msg_control: ptr::mut_null(),
+        msg_controllen: 0,
+        msg_flags: 0
+    }
+}
+
+impl AsyncReader for native::io::net::TcpStream {
+    fn async_read(&mut self, output: &mut [u8]) -> IoResult<uint> {
+        read_result(unsafe {
+            libc::recv(
+                self.fd(),
+                output.as_mut_ptr() as *mut c_void,
+                output.len() as size_t,
+                socket::MSG_DONTWAIT)
+        }, output.len())
+    }
+
+    fn async_read_vectored(&mut self, outputs: &mut [&mut [u8]]) -> IoResult<uint> {
+        let len = total_len(outputs);
+        let mut iovs = read_iovecs(outputs);
+        let mut msg = empty_msghdr(iovs.as_mut_slice());
+        read_result(unsafe { socket::recvmsg(self.fd(), &mut msg, socket::MSG_DONTWAIT) }, len)
+    }
+
+    fn splice_fd(&self) -> Option<c_int> {
+        Some(self.fd())
+    }
+}
+
+impl AsyncWriter for native::io::net::TcpStream {
+    fn async_write(&mut self, input: &[u8]) -> IoResult<uint> {
+        io_result(unsafe {
+            libc::send(
+                self.fd(),
+                input.as_ptr() as *mut c_void,
+                input.len() as size_t,
+                socket::MSG_DONTWAIT | socket::MSG_NOSIGNAL)
+        })
+    }
+
+    fn async_write_vectored(&mut self, inputs: &[&[u8]]) -> IoResult<uint> {
+        let mut iovs = write_iovecs(inputs);
+        let msg = empty_msghdr(iovs.as_mut_slice());
+        io_result(unsafe {
+            socket::sendmsg(self.fd(), &msg, socket::MSG_DONTWAIT | socket::MSG_NOSIGNAL)
+        })
+    }
+
+    fn async_shutdown(&mut self, how: Shutdown) -> IoResult<()> {
+        shutdown_socket(self.fd(), how)
+    }
+
+    fn splice_fd(&self) -> Option<c_int> {
+        Some(self.fd())
+    }
+}
+
+// The following implementations are intended for pipes. The descriptor must
+// have been put into non-blocking mode with `set_nonblocking`, otherwise these
+// calls will block.
+
+impl AsyncReader for native::io::file::FileDesc {
+    fn async_read(&mut self, output: &mut [u8]) -> IoResult<uint> {
+        read_result(unsafe {
+            libc::read(self.fd(), output.as_mut_ptr() as *mut c_void, output.len() as size_t)
+        }, output.len())
+    }
+
+    fn async_read_vectored(&mut self, outputs: &mut [&mut [u8]]) -> IoResult<uint> {
+        let len = total_len(outputs);
+        let iovs = read_iovecs(outputs);
+        read_result(unsafe { uio::readv(self.fd(), iovs.as_ptr(), iovs.len() as c_int) }, len)
+    }
+
+    fn splice_fd(&self) -> Option<c_int> {
+        Some(self.fd())
+    }
+}
+
+impl AsyncWriter for native::io::file::FileDesc {
+    fn async_write(&mut self, input: &[u8]) -> IoResult<uint> {
+        io_result(unsafe {
+            libc::write(self.fd(), input.as_ptr() as *c_void, input.len() as size_t)
+        })
+    }
+
+    fn async_write_vectored(&mut self, inputs: &[&[u8]]) -> IoResult<uint> {
+        let iovs = write_iovecs(inputs);
+        io_result(unsafe { uio::writev(self.fd(), iovs.as_ptr(), iovs.len() as c_int) })
+    }
+
+    fn splice_fd(&self) -> Option<c_int> {
+        Some(self.fd())
+    }
 }
 
 pub trait AsyncListener<T, A: Acceptor<T>> {
@@ -35,3 +359,158 @@ pub trait AsyncListener<T, A: Acceptor<T>> {
 pub trait AsyncAcceptor<T> {
     fn accept(&mut self) -> IoResult<T>;
 }
+
+impl AsyncAcceptor<native::io::net::TcpStream> for native::io::net::TcpAcceptor {
+    /// The acceptor must be in non-blocking mode (see `set_nonblocking`);
+    /// `EAGAIN` is then reported as a would-block error.
+    fn accept(&mut self) -> IoResult<native::io::net::TcpStream> {
+        match self.native_accept() {
+            Ok(stream) => Ok(stream),
+            Err(ref err) if would_block(err) => Err(io::standard_error(io::ResourceUnavailable)),
+            Err(err) => Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AsyncReader, AsyncWriter, is_eof, would_block};
+
+    use std::cmp;
+    use std::io;
+    use std::io::IoResult;
+    use std::vec::bytes;
+
+    /// Returns each chunk in turn from `async_read`, splitting chunks that
+    /// don't fit. An empty chunk is reported as would-block, and running out
+    /// of chunks as end of stream. Only the default vectored read is used.
+    struct Script {
+        chunks: ~[~[u8]]
+    }
+
+    fn script(chunks: &[&[u8]]) -> Script {
+        Script { chunks: chunks.iter().map(|c| c.to_owned()).collect() }
+    }
+
+    impl AsyncReader for Script {
+        fn async_read(&mut self, output: &mut [u8]) -> IoResult<uint> {
+            if self.chunks.len() == 0 {
+                return Err(io::standard_error(io::EndOfFile));
+            }
+            if self.chunks[0].len() == 0 {
+                self.chunks.shift();
+                return Err(io::standard_error(io::ResourceUnavailable));
+            }
+            let n = cmp::min(output.len(), self.chunks[0].len());
+            bytes::copy_memory(output, self.chunks[0].slice_to(n));
+            let rest = self.chunks[0].slice_from(n).to_owned();
+            if rest.len() == 0 {
+                self.chunks.shift();
+            } else {
+                self.chunks[0] = rest;
+            }
+            Ok(n)
+        }
+    }
+
+    /// Accepts up to the next of `budgets` bytes per call, reporting
+    /// would-block once the budgets run out. Only the default vectored write
+    /// is used.
+    struct Budget {
+        budgets: ~[uint],
+        written: ~[u8]
+    }
+
+    impl AsyncWriter for Budget {
+        fn async_write(&mut self, input: &[u8]) -> IoResult<uint> {
+            match self.budgets.shift() {
+                Some(budget) => {
+                    let n = cmp::min(budget, input.len());
+                    self.written.push_all(input.slice_to(n));
+                    Ok(n)
+                }
+                None => Err(io::standard_error(io::ResourceUnavailable))
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_vectored_fills_in_order() {
+        let mut reader = script([bytes!("abc"), bytes!("defg")]);
+        let mut first = [0u8, ..3];
+        let mut empty: [u8, ..0] = [];
+        let mut second = [0u8, ..4];
+        let n = reader.async_read_vectored([first.as_mut_slice(), empty.as_mut_slice(),
+                                            second.as_mut_slice()]).unwrap();
+        assert_eq!(n, 7);
+        assert_eq!(first.as_slice(), bytes!("abc"));
+        assert_eq!(second.as_slice(), bytes!("defg"));
+    }
+
+    #[test]
+    fn test_read_vectored_stops_at_short_read() {
+        let mut reader = script([bytes!("ab"), bytes!("cd")]);
+        let mut first = [0u8, ..3];
+        let mut second = [0u8, ..3];
+        let n = reader.async_read_vectored([first.as_mut_slice(),
+                                            second.as_mut_slice()]).unwrap();
+        assert_eq!(n, 2);
+        assert!(second.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_read_vectored_keeps_data_before_would_block_or_eof() {
+        let mut reader = script([bytes!("abc"), &[]]);
+        let mut first = [0u8, ..3];
+        let mut second = [0u8, ..3];
+        let n = reader.async_read_vectored([first.as_mut_slice(),
+                                            second.as_mut_slice()]).unwrap();
+        assert_eq!(n, 3);
+
+        // With nothing read yet, the would-block itself is reported.
+        let mut reader = script([&[], bytes!("abc")]);
+        match reader.async_read_vectored([first.as_mut_slice()]) {
+            Err(ref err) => assert!(would_block(err)),
+            Ok(_) => fail!("expected would-block")
+        }
+
+        let mut reader = script([bytes!("abc")]);
+        let n = reader.async_read_vectored([first.as_mut_slice(),
+                                            second.as_mut_slice()]).unwrap();
+        assert_eq!(n, 3);
+        match reader.async_read_vectored([first.as_mut_slice()]) {
+            Err(ref err) => assert!(is_eof(err)),
+            Ok(_) => fail!("expected end of stream")
+        }
+    }
+
+    #[test]
+    fn test_write_vectored_writes_in_order() {
+        let mut writer = Budget { budgets: ~[10, 10, 10], written: ~[] };
+        let n = writer.async_write_vectored([bytes!("ab"), &[], bytes!("cde")]).unwrap();
+        assert_eq!(n, 5);
+        assert_eq!(writer.written, bytes!("abcde").to_owned());
+        // The empty buffer didn't use up a call.
+        assert_eq!(writer.budgets, ~[10]);
+    }
+
+    #[test]
+    fn test_write_vectored_stops_at_short_write() {
+        let mut writer = Budget { budgets: ~[3, 10], written: ~[] };
+        let n = writer.async_write_vectored([bytes!("abcd"), bytes!("ef")]).unwrap();
+        assert_eq!(n, 3);
+        assert_eq!(writer.written, bytes!("abc").to_owned());
+    }
+
+    #[test]
+    fn test_write_vectored_keeps_count_before_would_block() {
+        let mut writer = Budget { budgets: ~[2], written: ~[] };
+        let n = writer.async_write_vectored([bytes!("ab"), bytes!("cd")]).unwrap();
+        assert_eq!(n, 2);
+
+        match writer.async_write_vectored([bytes!("cd")]) {
+            Err(ref err) => assert!(would_block(err)),
+            Ok(_) => fail!("expected would-block")
+        }
+    }
+}
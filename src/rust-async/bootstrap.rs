@@ -0,0 +1,756 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Ready-made TCP servers and client connection pools.
+//!
+//! A `ServerBootstrap` binds a listening socket and hands it to a `Reactor`,
+//! which creates a pipeline for every accepted connection using the supplied
+//! `PipelineFactory`:
+//!
+//! ```ignore
+//! let mut server = try!(ServerBootstrap::new(EchoFactory).bind(addr));
+//! let handle = server.shutdown_handle();
+//! // Call handle.shutdown() from another task to stop the server.
+//! try!(server.run());
+//! ```
+//!
+//! Shutting down is graceful: the server stops accepting, then keeps serving
+//! the connections that are still open until they close or the drain timeout
+//! passes, at which point any left are closed.
+//!
+//! A `ClientBootstrap` builds a `ConnectionPool` of connections to a single
+//! upstream, each with a pipeline from the supplied factory. Callers queue
+//! for a connection with `checkout`, which hands out tickets in order, and
+//! collect a `Lease` with `claim` once `poll` has found or opened a connection
+//! for them:
+//!
+//! ```ignore
+//! let mut pool = try!(ClientBootstrap::new(addr, ClientFactory).max_size(4).build());
+//! let ticket = pool.checkout();
+//! let mut claim = pool.claim(&ticket);
+//! while claim.is_none() {
+//!     try!(pool.poll(-1));
+//!     claim = pool.claim(&ticket);
+//! }
+//! let lease = try!(claim.unwrap());
+//! try!(pool.endpoint(&lease).unwrap().send_event(UserEvent(~request)));
+//! ...
+//! pool.release(lease);
+//! ```
+
+use std::hashmap::HashMap;
+use std::io::{IoResult, IoError};
+use std::io::net::ip::SocketAddr;
+use std::libc;
+use std::libc::{c_void, size_t};
+
+use extra::time;
+use sync::MutexArc;
+
+use async::{AsyncListener, would_block};
+use endpoint::Endpoint;
+use epoll_selector::{EpollSelector, EpollSelectionHandle};
+use net::{Socket, TcpAcceptor, TcpListener, TcpStream};
+use pipeline::Watermarks;
+use reactor::{PipelineFactory, Reactor};
+use select;
+use select::{NullNotifier, SelectEvent, Selector, SelectorHandle};
+use socket;
+
+static DEFAULT_DRAIN_TIMEOUT_MS: u64 = 30 * 1000;
+static DEFAULT_POOL_SIZE: uint = 8;
+static DEFAULT_IDLE_TIMEOUT_MS: u64 = 60 * 1000;
+static DEFAULT_LOW_WATERMARK: uint = 32 * 1024;
+static DEFAULT_HIGH_WATERMARK: uint = 64 * 1024;
+static MAX_EVENTS: uint = 64;
+/// How often a running server checks whether it has been asked to shut down.
+static TICK_MS: int = 100;
+
+/// Asks a running server to shut down. May be cloned and sent to other
+/// tasks.
+#[deriving(Clone)]
+pub struct ShutdownHandle {
+    priv requested: MutexArc<bool>
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.requested.access(|requested| *requested = true);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.requested.access(|requested| *requested)
+    }
+}
+
+pub struct ServerBootstrap<F> {
+    priv factory: F,
+    priv low_watermark: Option<uint>,
+    priv high_watermark: Option<uint>,
+    priv drain_timeout_ms: u64
+}
+
+impl <F: PipelineFactory> ServerBootstrap<F> {
+    pub fn new(factory: F) -> ServerBootstrap<F> {
+        ServerBootstrap {
+            factory: factory,
+            low_watermark: None,
+            high_watermark: None,
+            drain_timeout_ms: DEFAULT_DRAIN_TIMEOUT_MS
+        }
+    }
+
+    /// See `Reactor::set_watermarks`.
+    pub fn watermarks(mut self, low: uint, high: uint) -> ServerBootstrap<F> {
+        assert!(low <= high);
+        self.low_watermark = Some(low);
+        self.high_watermark = Some(high);
+        self
+    }
+
+    /// How long to keep serving open connections after shutdown has been
+    /// requested.
+    pub fn drain_timeout(mut self, ms: u64) -> ServerBootstrap<F> {
+        self.drain_timeout_ms = ms;
+        self
+    }
+
+    /// Bind to `addr` and start listening.
+    pub fn bind(self, addr: SocketAddr) -> IoResult<Server<F>> {
+        self.bind_with(addr, |_| Ok(()))
+    }
+
+    /// Bind to `addr` and start listening, calling `configure` on the socket
+    /// before binding. See `TcpListener::bind_with`.
+    pub fn bind_with(
+            self,
+            addr: SocketAddr,
+            configure: |&Socket| -> IoResult<()>) -> IoResult<Server<F>> {
+        let ServerBootstrap { factory, low_watermark, high_watermark, drain_timeout_ms } = self;
+        let listener = try!(TcpListener::bind_with(addr, configure));
+        let acceptor = try!(listener.listen());
+        let local_addr = try!(acceptor.socket_name());
+
+        let mut reactor = try!(Reactor::new(factory));
+        match (low_watermark, high_watermark) {
+            (Some(low), Some(high)) => reactor.set_watermarks(low, high),
+            _ => {}
+        }
+        try!(reactor.listen(acceptor));
+        Ok(Server {
+            reactor: reactor,
+            local_addr: local_addr,
+            shutdown: ShutdownHandle { requested: MutexArc::new(false) },
+            drain_timeout_ms: drain_timeout_ms
+        })
+    }
+}
+
+pub struct Server<F> {
+    priv reactor: Reactor<TcpAcceptor, TcpStream, F>,
+    priv local_addr: SocketAddr,
+    priv shutdown: ShutdownHandle,
+    priv drain_timeout_ms: u64
+}
+
+impl <F: PipelineFactory> Server<F> {
+    /// The address the server is listening on, useful after binding to port
+    /// 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// The number of open connections.
+    pub fn connection_count(&self) -> uint {
+        self.reactor.connection_count()
+    }
+
+    /// Serve connections until shutdown is requested, then drain.
+    pub fn run(&mut self) -> IoResult<()> {
+        while !self.shutdown.is_shutdown() {
+            try!(self.reactor.run_once(TICK_MS));
+        }
+        self.drain()
+    }
+
+    /// Wait up to `timeout_ms` milliseconds for events and process them.
+    /// Does not drain if shutdown has been requested.
+    pub fn run_once(&mut self, timeout_ms: int) -> IoResult<()> {
+        self.reactor.run_once(timeout_ms)
+    }
+
+    /// Stop accepting connections, serve the open ones until they have all
+    /// closed or the drain timeout has passed, then close any that are left.
+    pub fn drain(&mut self) -> IoResult<()> {
+        self.reactor.stop_listening();
+        let deadline = time::precise_time_ns() + self.drain_timeout_ms * 1000000;
+        while self.reactor.connection_count() > 0 && time::precise_time_ns() < deadline {
+            match self.reactor.run_once(TICK_MS) {
+                Ok(()) => {}
+                Err(e) => {
+                    self.reactor.close_all();
+                    return Err(e);
+                }
+            }
+        }
+        self.reactor.close_all();
+        Ok(())
+    }
+}
+
+pub struct ClientBootstrap<F> {
+    priv addr: SocketAddr,
+    priv factory: F,
+    priv max_size: uint,
+    priv idle_timeout_ms: u64,
+    priv low_watermark: uint,
+    priv high_watermark: uint
+}
+
+impl <F: PipelineFactory> ClientBootstrap<F> {
+    /// Connections will be made to `addr`, each with a pipeline from
+    /// `factory`.
+    pub fn new(addr: SocketAddr, factory: F) -> ClientBootstrap<F> {
+        ClientBootstrap {
+            addr: addr,
+            factory: factory,
+            max_size: DEFAULT_POOL_SIZE,
+            idle_timeout_ms: DEFAULT_IDLE_TIMEOUT_MS,
+            low_watermark: DEFAULT_LOW_WATERMARK,
+            high_watermark: DEFAULT_HIGH_WATERMARK
+        }
+    }
+
+    /// The most connections, including those still connecting, the pool will
+    /// hold at once.
+    pub fn max_size(mut self, max_size: uint) -> ClientBootstrap<F> {
+        assert!(max_size > 0);
+        self.max_size = max_size;
+        self
+    }
+
+    /// How long a connection may sit unused in the pool before it is closed.
+    pub fn idle_timeout(mut self, ms: u64) -> ClientBootstrap<F> {
+        self.idle_timeout_ms = ms;
+        self
+    }
+
+    /// See `Reactor::set_watermarks`.
+    pub fn watermarks(mut self, low: uint, high: uint) -> ClientBootstrap<F> {
+        assert!(low <= high);
+        self.low_watermark = low;
+        self.high_watermark = high;
+        self
+    }
+
+    /// Create an empty pool. Connections are opened as they are needed.
+    pub fn build(self) -> IoResult<ConnectionPool<F>> {
+        let selector = try!(EpollSelector::new(NullNotifier));
+        Ok(ConnectionPool {
+            config: self,
+            selector: selector,
+            slots: HashMap::new(),
+            waiters: ~[],
+            claims: HashMap::new(),
+            next_token: 0,
+            next_ticket: 0
+        })
+    }
+}
+
+/// A place in the queue for a connection, handed out by `checkout`.
+pub struct Ticket {
+    priv id: u64
+}
+
+/// The right to use a pooled connection until it is given back with
+/// `release` or `discard`.
+pub struct Lease {
+    priv token: u64
+}
+
+enum Slot {
+    Connecting(EpollSelectionHandle<NullNotifier, TcpStream>),
+    /// An idle connection and when it last became idle.
+    Idle(Endpoint<NullNotifier, TcpStream>, u64),
+    Leased(Endpoint<NullNotifier, TcpStream>)
+}
+
+pub struct ConnectionPool<F> {
+    priv config: ClientBootstrap<F>,
+    priv selector: EpollSelector<NullNotifier>,
+    priv slots: HashMap<u64, Slot>,
+    /// Tickets waiting for a connection, oldest first.
+    priv waiters: ~[u64],
+    /// The outcome for tickets that are no longer waiting.
+    priv claims: HashMap<u64, IoResult<u64>>,
+    priv next_token: u64,
+    priv next_ticket: u64
+}
+
+impl <F: PipelineFactory> ConnectionPool<F> {
+    /// Join the queue for a connection. Tickets are served in the order they
+    /// were handed out.
+    pub fn checkout(&mut self) -> Ticket {
+        let id = self.next_ticket;
+        self.next_ticket += 1;
+        self.waiters.push(id);
+        self.assign();
+        Ticket { id: id }
+    }
+
+    /// Collect the connection for `ticket`, or the error from opening one,
+    /// once it is available. Returns `None` while the ticket is still queued.
+    pub fn claim(&mut self, ticket: &Ticket) -> Option<IoResult<Lease>> {
+        self.claims.pop(&ticket.id).map(|claim| claim.map(|token| Lease { token: token }))
+    }
+
+    /// Leave the queue, releasing the connection if one has already been
+    /// assigned to `ticket`.
+    pub fn cancel(&mut self, ticket: Ticket) {
+        match self.waiters.iter().position(|id| *id == ticket.id) {
+            Some(i) => {
+                self.waiters.remove(i);
+            }
+            None => {}
+        }
+        match self.claims.pop(&ticket.id) {
+            Some(Ok(token)) => self.release(Lease { token: token }),
+            _ => {}
+        }
+    }
+
+    /// The connection held by `lease`, or `None` if it has been closed since.
+    pub fn endpoint<'a>(
+            &'a mut self,
+            lease: &Lease) -> Option<&'a mut Endpoint<NullNotifier, TcpStream>> {
+        match self.slots.find_mut(&lease.token) {
+            Some(&Leased(ref mut endpoint)) => Some(endpoint),
+            _ => None
+        }
+    }
+
+    /// Give a connection back to the pool for reuse.
+    pub fn release(&mut self, lease: Lease) {
+        match self.slots.pop(&lease.token) {
+            Some(Leased(endpoint)) => {
+                self.slots.insert(lease.token, Idle(endpoint, time::precise_time_ns()));
+            }
+            Some(slot) => {
+                self.slots.insert(lease.token, slot);
+            }
+            None => {}
+        }
+        self.assign();
+    }
+
+    /// Close a connection instead of returning it to the pool, for example
+    /// after a protocol error.
+    pub fn discard(&mut self, lease: Lease) {
+        self.close(lease.token);
+        self.assign();
+    }
+
+    /// The number of connections, including those still connecting.
+    pub fn size(&self) -> uint {
+        self.slots.len()
+    }
+
+    /// The number of tickets still waiting for a connection.
+    pub fn queued(&self) -> uint {
+        self.waiters.len()
+    }
+
+    /// Wait up to `timeout_ms` milliseconds for events on the pool's
+    /// connections and process them, then close connections that have been
+    /// idle too long and hand connections out to waiting tickets.
+    pub fn poll(&mut self, timeout_ms: int) -> IoResult<()> {
+        let mut events =
+            [SelectEvent { mode: select::ReadyRead, data: 0, read_closed: false }, ..MAX_EVENTS];
+        let n = try!(self.selector.select(events, timeout_ms));
+        for event in events.slice_to(n).iter() {
+            match self.slots.pop(&event.data) {
+                Some(Connecting(handle)) => self.connected(event.data, handle),
+                Some(Idle(mut endpoint, since)) => {
+                    match endpoint.ready(event.mode) {
+                        Ok(()) => {
+                            self.slots.insert(event.data, Idle(endpoint, since));
+                        }
+                        Err(_) => {
+                            endpoint.close();
+                        }
+                    }
+                }
+                Some(Leased(mut endpoint)) => {
+                    match endpoint.ready(event.mode) {
+                        Ok(()) => {
+                            self.slots.insert(event.data, Leased(endpoint));
+                        }
+                        Err(_) => {
+                            endpoint.close();
+                        }
+                    }
+                }
+                None => {}
+            }
+        }
+        self.expire();
+        self.assign();
+        Ok(())
+    }
+
+    /// Finish a connection attempt that has become ready.
+    fn connected(&mut self, token: u64, mut handle: EpollSelectionHandle<NullNotifier, TcpStream>) {
+        let res = match handle.get_ref().finish_connect() {
+            Ok(()) => handle.modify(select::SelectRead, true),
+            Err(err) => Err(err)
+        };
+        let res = match res {
+            Ok(()) => {
+                let config = &self.config;
+                let watermarks = Watermarks::new(config.low_watermark, config.high_watermark);
+                Endpoint::new(handle, watermarks, |sink| config.factory.new_pipeline(sink))
+            }
+            Err(err) => Err(err)
+        };
+        match res {
+            Ok(endpoint) => {
+                self.slots.insert(token, Idle(endpoint, time::precise_time_ns()));
+            }
+            // The upstream is failing: tell the oldest waiter rather than
+            // retrying for ever.
+            Err(err) => self.fail_waiter(err)
+        }
+    }
+
+    fn fail_waiter(&mut self, err: IoError) {
+        if self.waiters.len() > 0 {
+            let id = self.waiters.shift().unwrap();
+            self.claims.insert(id, Err(err));
+        }
+    }
+
+    fn close(&mut self, token: u64) {
+        match self.slots.pop(&token) {
+            Some(Idle(endpoint, _)) | Some(Leased(endpoint)) => {
+                endpoint.close();
+            }
+            _ => {}
+        }
+    }
+
+    /// Close connections that have been idle for longer than the idle
+    /// timeout.
+    fn expire(&mut self) {
+        let now = time::precise_time_ns();
+        let timeout_ns = self.config.idle_timeout_ms * 1000000;
+        let mut expired = ~[];
+        for (token, slot) in self.slots.iter() {
+            match *slot {
+                Idle(_, since) if now - since >= timeout_ns => expired.push(*token),
+                _ => {}
+            }
+        }
+        for token in expired.iter() {
+            self.close(*token);
+        }
+    }
+
+    /// Find a healthy idle connection, closing any unhealthy ones found on
+    /// the way.
+    fn take_idle(&mut self) -> Option<u64> {
+        loop {
+            let mut idle = None;
+            for (token, slot) in self.slots.iter() {
+                match *slot {
+                    Idle(..) => {
+                        idle = Some(*token);
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            let token = match idle {
+                Some(token) => token,
+                None => return None
+            };
+            match self.slots.pop(&token) {
+                Some(Idle(endpoint, _)) => {
+                    if healthy(&endpoint) {
+                        self.slots.insert(token, Leased(endpoint));
+                        return Some(token);
+                    }
+                    endpoint.close();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Hand idle connections to waiting tickets in order, then start enough
+    /// new connections, up to the pool's size limit, for the tickets still
+    /// waiting.
+    fn assign(&mut self) {
+        while self.waiters.len() > 0 {
+            match self.take_idle() {
+                Some(token) => {
+                    let id = self.waiters.shift().unwrap();
+                    self.claims.insert(id, Ok(token));
+                }
+                None => break
+            }
+        }
+
+        let mut connecting = 0;
+        for (_, slot) in self.slots.iter() {
+            match *slot {
+                Connecting(..) => connecting += 1,
+                _ => {}
+            }
+        }
+        let mut wanted = if self.waiters.len() > connecting {
+            self.waiters.len() - connecting
+        } else {
+            0
+        };
+        while wanted > 0 && self.slots.len() < self.config.max_size {
+            match self.start_connect() {
+                Ok(()) => {}
+                Err(err) => self.fail_waiter(err)
+            }
+            wanted -= 1;
+        }
+    }
+
+    fn start_connect(&mut self) -> IoResult<()> {
+        let token = self.next_token;
+        self.next_token += 1;
+        let stream = try!(TcpStream::connect(self.config.addr));
+        let handle = try!(self.selector.register(stream, token, select::SelectWrite, true));
+        self.slots.insert(token, Connecting(handle));
+        Ok(())
+    }
+}
+
+/// Check that an idle connection is still usable before handing it out. A
+/// peer that has closed or half-closed the connection may not have been
+/// noticed yet, so peek at the socket: only would-block means it is idle as
+/// expected. End of stream or an error means the connection is unusable, and
+/// data that arrived while nobody was using it means it is out of step with
+/// the peer.
+fn healthy(endpoint: &Endpoint<NullNotifier, TcpStream>) -> bool {
+    let mut buf = [0u8];
+    let res = unsafe {
+        libc::recv(
+            endpoint.get_ref().socket().fd(),
+            buf.as_mut_ptr() as *mut c_void,
+            buf.len() as size_t,
+            socket::MSG_PEEK | socket::MSG_DONTWAIT)
+    };
+    res < 0 && would_block(&IoError::last_error())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ClientBootstrap, ConnectionPool, Lease, ServerBootstrap, Ticket};
+
+    use std::from_str::from_str;
+    use std::io::IoResult;
+    use std::io::net::ip::SocketAddr;
+
+    use async::{AsyncAcceptor, AsyncReader, AsyncWriter, ShutdownWrite, is_eof, would_block};
+    use net::{TcpAcceptor, TcpListener, TcpStream};
+    use pipeline::{PipelineDown, PipelineUp};
+    use reactor::PipelineFactory;
+
+    fn localhost() -> SocketAddr {
+        from_str("127.0.0.1:0").unwrap()
+    }
+
+    /// Writes everything it is sent back to the connection.
+    struct Echo {
+        output: ~PipelineUp<~[u8]>
+    }
+
+    impl PipelineDown<~[u8]> for Echo {
+        fn down(&self, data: ~[u8]) -> IoResult<()> {
+            self.output.up(data)
+        }
+    }
+
+    struct EchoFactory;
+
+    impl PipelineFactory for EchoFactory {
+        fn new_pipeline(&self, output: ~PipelineUp<~[u8]>) -> ~PipelineDown<~[u8]> {
+            ~Echo { output: output } as ~PipelineDown<~[u8]>
+        }
+    }
+
+    /// Retry `op` until it stops reporting would-block.
+    fn retry<T>(op: || -> IoResult<T>) -> IoResult<T> {
+        loop {
+            match op() {
+                Err(ref e) if would_block(e) => {}
+                res => return res
+            }
+        }
+    }
+
+    /// A listener for a pool to connect to.
+    fn upstream() -> (TcpAcceptor, SocketAddr) {
+        let listener = TcpListener::bind(localhost()).unwrap();
+        let addr = listener.socket_name().unwrap();
+        (listener.listen().unwrap(), addr)
+    }
+
+    /// Poll `pool` until `ticket` has been given a connection.
+    fn wait_for(pool: &mut ConnectionPool<EchoFactory>, ticket: &Ticket) -> Lease {
+        loop {
+            match pool.claim(ticket) {
+                Some(Ok(lease)) => return lease,
+                Some(Err(e)) => fail!("{}", e),
+                None => pool.poll(10).unwrap()
+            }
+        }
+    }
+
+    /// Read from `stream` until the peer closes it.
+    fn read_to_end(stream: &mut TcpStream) -> ~[u8] {
+        let mut received = ~[];
+        let mut buf = [0u8, ..64];
+        loop {
+            match stream.async_read(buf) {
+                Ok(n) => received.push_all(buf.slice_to(n)),
+                Err(ref e) if would_block(e) => {}
+                Err(ref e) if is_eof(e) => return received,
+                Err(e) => fail!("{}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn test_drain_serves_open_connections() {
+        let mut server = ServerBootstrap::new(EchoFactory).bind(localhost()).unwrap();
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        while server.connection_count() == 0 {
+            server.run_once(10).unwrap();
+        }
+        client.finish_connect().unwrap();
+        client.async_write(bytes!("hello")).unwrap();
+        client.async_shutdown(ShutdownWrite).unwrap();
+
+        // The request arrived before shutdown, so it is still answered, and
+        // the server stops as soon as the connection has closed.
+        server.shutdown_handle().shutdown();
+        server.run().unwrap();
+        assert_eq!(server.connection_count(), 0);
+        assert_eq!(read_to_end(&mut client).as_slice(), bytes!("hello"));
+    }
+
+    #[test]
+    fn test_drain_timeout_closes_idle_connections() {
+        let bootstrap = ServerBootstrap::new(EchoFactory).drain_timeout(20);
+        let mut server = bootstrap.bind(localhost()).unwrap();
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        while server.connection_count() == 0 {
+            server.run_once(10).unwrap();
+        }
+        client.finish_connect().unwrap();
+
+        server.drain().unwrap();
+        assert_eq!(server.connection_count(), 0);
+        assert_eq!(read_to_end(&mut client).len(), 0);
+    }
+
+    #[test]
+    fn test_pool_respects_max_size() {
+        let (_acceptor, addr) = upstream();
+        let mut pool = ClientBootstrap::new(addr, EchoFactory).max_size(2).build().unwrap();
+        let first = pool.checkout();
+        let second = pool.checkout();
+        let third = pool.checkout();
+        assert_eq!(pool.size(), 2);
+        assert_eq!(pool.queued(), 3);
+
+        let first = wait_for(&mut pool, &first);
+        let second = wait_for(&mut pool, &second);
+        pool.poll(10).unwrap();
+        assert!(pool.claim(&third).is_none());
+        assert_eq!(pool.size(), 2);
+        assert_eq!(pool.queued(), 1);
+
+        // A released connection is reused rather than a third one opened.
+        pool.release(first);
+        let third = wait_for(&mut pool, &third);
+        assert_eq!(pool.size(), 2);
+        pool.release(second);
+        pool.release(third);
+        assert_eq!(pool.size(), 2);
+        assert_eq!(pool.queued(), 0);
+    }
+
+    #[test]
+    fn test_waiters_are_served_in_order() {
+        let (_acceptor, addr) = upstream();
+        let mut pool = ClientBootstrap::new(addr, EchoFactory).max_size(1).build().unwrap();
+        let tickets = ~[pool.checkout(), pool.checkout(), pool.checkout()];
+        let mut lease = wait_for(&mut pool, &tickets[0]);
+        for i in range(1, tickets.len()) {
+            for later in tickets.slice_from(i).iter() {
+                assert!(pool.claim(later).is_none());
+            }
+            pool.release(lease);
+            lease = wait_for(&mut pool, &tickets[i]);
+        }
+        pool.release(lease);
+        assert_eq!(pool.size(), 1);
+    }
+
+    #[test]
+    fn test_idle_connections_expire() {
+        let (_acceptor, addr) = upstream();
+        let mut pool = ClientBootstrap::new(addr, EchoFactory).idle_timeout(20).build().unwrap();
+        let ticket = pool.checkout();
+        let lease = wait_for(&mut pool, &ticket);
+        pool.release(lease);
+        assert_eq!(pool.size(), 1);
+        while pool.size() > 0 {
+            pool.poll(10).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_unhealthy_connections_are_replaced() {
+        let (mut acceptor, addr) = upstream();
+        let mut pool = ClientBootstrap::new(addr, EchoFactory).max_size(1).build().unwrap();
+        let ticket = pool.checkout();
+        let lease = wait_for(&mut pool, &ticket);
+        pool.release(lease);
+        let mut peer = retry(|| acceptor.accept()).unwrap();
+
+        // Data arriving on an idle connection means it is out of step with
+        // the peer, so it is closed instead of handed out.
+        peer.async_write(bytes!("stray")).unwrap();
+        let ticket = pool.checkout();
+        assert!(pool.claim(&ticket).is_none());
+        let lease = wait_for(&mut pool, &ticket);
+        pool.release(lease);
+        assert_eq!(pool.size(), 1);
+
+        // So is a connection the peer has closed.
+        let peer = retry(|| acceptor.accept()).unwrap();
+        drop(peer);
+        let ticket = pool.checkout();
+        assert!(pool.claim(&ticket).is_none());
+        let lease = wait_for(&mut pool, &ticket);
+        pool.release(lease);
+        assert_eq!(pool.size(), 1);
+    }
+}
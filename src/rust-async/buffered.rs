@@ -0,0 +1,371 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Buffering wrappers for asynchronous readers and writers.
+
+use std::cmp;
+use std::io;
+use std::io::IoResult;
+use std::vec;
+use std::vec::bytes;
+
+use async::{AsyncReader, AsyncWriter, Shutdown, would_block};
+
+static DEFAULT_CAPACITY: uint = 64 * 1024;
+
+/// Wraps an `AsyncReader` and reads from it in large chunks.
+///
+/// Data is made available through `fill_buf` and released with `consume`. A
+/// would-block error from the underlying reader is returned unchanged from
+/// `fill_buf` when no buffered data remains; the caller should wait for read
+/// readiness and try again.
+pub struct AsyncBufReader<R> {
+    priv inner: R,
+    priv buf: ~[u8],
+    priv pos: uint,
+    priv cap: uint
+}
+
+impl <R: AsyncReader> AsyncBufReader<R> {
+    pub fn new(inner: R) -> AsyncBufReader<R> {
+        AsyncBufReader::with_capacity(DEFAULT_CAPACITY, inner)
+    }
+
+    /// A reader with a buffer of `capacity` bytes, or one byte if
+    /// `capacity` is zero, since an empty buffer can't hold any data.
+    pub fn with_capacity(capacity: uint, inner: R) -> AsyncBufReader<R> {
+        AsyncBufReader {
+            inner: inner,
+            buf: vec::from_elem(cmp::max(capacity, 1), 0u8),
+            pos: 0,
+            cap: 0
+        }
+    }
+
+    /// Returns the buffered data, reading more from the underlying reader
+    /// only if the buffer is empty.
+    pub fn fill_buf<'a>(&'a mut self) -> IoResult<&'a [u8]> {
+        if self.pos == self.cap {
+            self.cap = try!(self.inner.async_read(self.buf));
+            self.pos = 0;
+        }
+        Ok(self.buf.slice(self.pos, self.cap))
+    }
+
+    /// Mark `amt` bytes returned from `fill_buf` as used.
+    pub fn consume(&mut self, amt: uint) {
+        self.pos = cmp::min(self.pos + amt, self.cap);
+    }
+
+    /// The data that has been read but not yet consumed.
+    pub fn buffer<'a>(&'a self) -> &'a [u8] {
+        self.buf.slice(self.pos, self.cap)
+    }
+
+    pub fn get_ref<'a>(&'a self) -> &'a R {
+        &self.inner
+    }
+
+    pub fn get_mut_ref<'a>(&'a mut self) -> &'a mut R {
+        &mut self.inner
+    }
+
+    /// Returns the underlying reader. Any buffered data is lost.
+    pub fn unwrap(self) -> R {
+        self.inner
+    }
+}
+
+impl <R: AsyncReader> AsyncReader for AsyncBufReader<R> {
+    fn async_read(&mut self, output: &mut [u8]) -> IoResult<uint> {
+        // Skip the buffer entirely for large reads when it is empty.
+        if self.pos == self.cap && output.len() >= self.buf.len() {
+            return self.inner.async_read(output);
+        }
+        let n = {
+            let available = try!(self.fill_buf());
+            let n = cmp::min(available.len(), output.len());
+            bytes::copy_memory(output, available.slice_to(n));
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+/// Wraps an `AsyncWriter` and collects small writes into larger ones.
+///
+/// Unlike a blocking buffered writer, `flush` never waits for the underlying
+/// writer. It writes as much as it can and returns the number of bytes that
+/// are still pending, so the caller only needs to ask for write readiness
+/// while that number is non-zero.
+pub struct AsyncBufWriter<W> {
+    priv inner: W,
+    priv buf: ~[u8],
+    priv start: uint,
+    priv capacity: uint
+}
+
+impl <W: AsyncWriter> AsyncBufWriter<W> {
+    pub fn new(inner: W) -> AsyncBufWriter<W> {
+        AsyncBufWriter::with_capacity(DEFAULT_CAPACITY, inner)
+    }
+
+    /// A writer that buffers up to `capacity` bytes, or one byte if
+    /// `capacity` is zero.
+    pub fn with_capacity(capacity: uint, inner: W) -> AsyncBufWriter<W> {
+        let capacity = cmp::max(capacity, 1);
+        AsyncBufWriter {
+            inner: inner,
+            buf: vec::with_capacity(capacity),
+            start: 0,
+            capacity: capacity
+        }
+    }
+
+    /// The number of bytes accepted by `async_write` that have not yet been
+    /// written to the underlying writer.
+    pub fn pending(&self) -> uint {
+        self.buf.len() - self.start
+    }
+
+    /// Write as much buffered data as the underlying writer will take without
+    /// blocking. Returns the number of bytes still pending.
+    pub fn flush(&mut self) -> IoResult<uint> {
+        while self.start < self.buf.len() {
+            match self.inner.async_write(self.buf.slice_from(self.start)) {
+                Ok(0) => break,
+                Ok(n) => self.start += n,
+                Err(ref e) if would_block(e) => break,
+                Err(e) => return Err(e)
+            }
+        }
+        if self.start == self.buf.len() {
+            self.buf.truncate(0);
+            self.start = 0;
+        }
+        Ok(self.pending())
+    }
+
+    pub fn get_ref<'a>(&'a self) -> &'a W {
+        &self.inner
+    }
+
+    pub fn get_mut_ref<'a>(&'a mut self) -> &'a mut W {
+        &mut self.inner
+    }
+
+    /// Returns the underlying writer. Any pending data is lost.
+    pub fn unwrap(self) -> W {
+        self.inner
+    }
+
+    fn compact(&mut self) {
+        if self.start > 0 {
+            self.buf = self.buf.slice_from(self.start).to_owned();
+            self.start = 0;
+        }
+    }
+}
+
+impl <W: AsyncWriter> AsyncWriter for AsyncBufWriter<W> {
+    /// Accepts as much of `input` as fits in the buffer. A would-block error
+    /// is only returned if the buffer is full and could not be flushed.
+    fn async_write(&mut self, input: &[u8]) -> IoResult<uint> {
+        if self.pending() + input.len() > self.capacity {
+            try!(self.flush());
+        }
+        if self.pending() == 0 && input.len() >= self.capacity {
+            return self.inner.async_write(input);
+        }
+        let space = self.capacity - self.pending();
+        if space == 0 {
+            return Err(io::standard_error(io::ResourceUnavailable));
+        }
+        if self.start + self.pending() + input.len() > self.capacity {
+            self.compact();
+        }
+        let n = cmp::min(space, input.len());
+        self.buf.push_all(input.slice_to(n));
+        Ok(n)
+    }
+
+    /// Shuts down the underlying writer. Pending data is not flushed first;
+    /// call `flush` until it returns 0 before shutting down the write side.
+    fn async_shutdown(&mut self, how: Shutdown) -> IoResult<()> {
+        self.inner.async_shutdown(how)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AsyncBufReader, AsyncBufWriter};
+
+    use std::cmp;
+    use std::io;
+    use std::io::IoResult;
+
+    use async::{AsyncReader, AsyncWriter, Shutdown, is_eof, would_block};
+    use unix::UnixStream;
+
+    fn assert_would_block<T>(res: IoResult<T>) {
+        match res {
+            Err(ref e) if would_block(e) => {}
+            _ => fail!("expected a would-block error")
+        }
+    }
+
+    /// Accepts at most `budget` bytes, then reports would-block until the
+    /// budget is raised again.
+    struct Budget {
+        budget: uint,
+        max_write: uint,
+        written: ~[u8]
+    }
+
+    impl AsyncWriter for Budget {
+        fn async_write(&mut self, input: &[u8]) -> IoResult<uint> {
+            if self.budget == 0 {
+                return Err(io::standard_error(io::ResourceUnavailable));
+            }
+            let n = cmp::min(cmp::min(self.budget, self.max_write), input.len());
+            self.written.push_all(input.slice_to(n));
+            self.budget -= n;
+            Ok(n)
+        }
+
+        fn async_shutdown(&mut self, _: Shutdown) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_fill_buf_resumes_after_would_block() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let mut reader = AsyncBufReader::with_capacity(8, a);
+        assert_would_block(reader.fill_buf());
+
+        b.async_write(bytes!("hello world")).unwrap();
+        assert_eq!(reader.fill_buf().unwrap(), bytes!("hello wo"));
+        reader.consume(6);
+        assert_eq!(reader.buffer(), bytes!("wo"));
+
+        // Reads drain the buffer before touching the socket again.
+        let mut out = [0u8, ..16];
+        assert_eq!(reader.async_read(out).unwrap(), 2);
+        assert_eq!(out.slice_to(2), bytes!("wo"));
+        assert_eq!(reader.fill_buf().unwrap(), bytes!("rld"));
+        reader.consume(3);
+        assert_would_block(reader.fill_buf());
+
+        drop(b);
+        match reader.fill_buf() {
+            Err(ref e) if is_eof(e) => {}
+            _ => fail!("expected end of file")
+        }
+    }
+
+    #[test]
+    fn test_zero_capacity_reads() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let mut reader = AsyncBufReader::with_capacity(0, a);
+        b.async_write(bytes!("ab")).unwrap();
+        assert_eq!(reader.fill_buf().unwrap(), bytes!("a"));
+        reader.consume(1);
+        assert_eq!(reader.fill_buf().unwrap(), bytes!("b"));
+    }
+
+    #[test]
+    fn test_large_read_bypasses_buffer() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let mut reader = AsyncBufReader::with_capacity(4, a);
+        b.async_write(bytes!("0123456789")).unwrap();
+        let mut out = [0u8, ..10];
+        assert_eq!(reader.async_read(out).unwrap(), 10);
+        assert_eq!(out.as_slice(), bytes!("0123456789"));
+        assert_eq!(reader.buffer().len(), 0);
+    }
+
+    #[test]
+    fn test_short_writes_flushed_in_full() {
+        let inner = Budget { budget: 100, max_write: 3, written: ~[] };
+        let mut writer = AsyncBufWriter::with_capacity(16, inner);
+        assert_eq!(writer.async_write(bytes!("abcdefgh")).unwrap(), 8);
+        assert_eq!(writer.pending(), 8);
+        assert_eq!(writer.flush().unwrap(), 0);
+        assert_eq!(writer.get_ref().written.as_slice(), bytes!("abcdefgh"));
+    }
+
+    #[test]
+    fn test_flush_resumes_after_would_block() {
+        let inner = Budget { budget: 4, max_write: 100, written: ~[] };
+        let mut writer = AsyncBufWriter::with_capacity(16, inner);
+        assert_eq!(writer.async_write(bytes!("abcdefgh")).unwrap(), 8);
+        assert_eq!(writer.flush().unwrap(), 4);
+        assert_eq!(writer.flush().unwrap(), 4);
+
+        writer.get_mut_ref().budget = 100;
+        assert_eq!(writer.flush().unwrap(), 0);
+        assert_eq!(writer.get_ref().written.as_slice(), bytes!("abcdefgh"));
+    }
+
+    #[test]
+    fn test_full_buffer_would_block() {
+        let inner = Budget { budget: 0, max_write: 100, written: ~[] };
+        let mut writer = AsyncBufWriter::with_capacity(4, inner);
+        assert_eq!(writer.async_write(bytes!("ab")).unwrap(), 2);
+        assert_eq!(writer.async_write(bytes!("cdef")).unwrap(), 2);
+        assert_would_block(writer.async_write(bytes!("x")));
+        assert_eq!(writer.pending(), 4);
+
+        writer.get_mut_ref().budget = 3;
+        assert_eq!(writer.flush().unwrap(), 1);
+        assert_eq!(writer.async_write(bytes!("xyz")).unwrap(), 3);
+        writer.get_mut_ref().budget = 100;
+        assert_eq!(writer.flush().unwrap(), 0);
+        assert_eq!(writer.get_ref().written.as_slice(), bytes!("abcdxyz"));
+    }
+
+    #[test]
+    fn test_flush_to_full_socket() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let mut writer = AsyncBufWriter::with_capacity(1024, a);
+
+        // Fill the socket so that nothing more can be written to it.
+        let chunk = [0u8, ..4096];
+        loop {
+            match writer.get_mut_ref().async_write(chunk) {
+                Ok(_) => {}
+                Err(ref e) if would_block(e) => break,
+                Err(e) => fail!("{}", e)
+            }
+        }
+
+        assert_eq!(writer.async_write(bytes!("tail")).unwrap(), 4);
+        assert_eq!(writer.flush().unwrap(), 4);
+
+        let mut out = [0u8, ..4096];
+        let mut last: ~[u8] = ~[];
+        loop {
+            match b.async_read(out) {
+                Ok(n) => {
+                    last.push_all(out.slice_to(n));
+                    if last.len() > 4 {
+                        last = last.slice_from(last.len() - 4).to_owned();
+                    }
+                }
+                Err(ref e) if would_block(e) => {
+                    if writer.pending() == 0 {
+                        break;
+                    }
+                    writer.flush().unwrap();
+                }
+                Err(e) => fail!("{}", e)
+            }
+        }
+        assert_eq!(last.as_slice(), bytes!("tail"));
+    }
+}
@@ -0,0 +1,779 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The client side of HTTP/1.1: requests are encoded and responses decoded.
+//!
+//! A request body is framed by the request's `Content-Length`, or sent
+//! chunked if the request has `Transfer-Encoding: chunked`; otherwise the
+//! request may not have a body. Requests may be pipelined: responses are
+//! matched to them in order.
+//!
+//! The body of a request with `Expect: 100-continue` is held back until the
+//! server answers `100 Continue`, or until the owner gives up waiting by
+//! sending a `ContinueTimeout` down. If the server sends its final response
+//! instead, the body is dropped, including any part of it sent up after the
+//! response, and the connection is closed after the response. No other
+//! request may be sent while a body is held back. Other informational
+//! responses are not sent down. After a `101 Switching Protocols` response
+//! to a request with an `Upgrade` header, the codec stops interpreting the
+//! connection, as `HttpServerCodec` does.
+//!
+//! Once a response that ends the connection is complete, `Closed` is sent
+//! up.
+
+use std::any::AnyRefExt;
+use std::cell::RefCell;
+use std::from_str::from_str;
+use std::io;
+use std::io::{IoResult, IoError};
+use std::util;
+
+use codec::http::{BodyState, Headers, Request, Response};
+use codec::http::{RequestPart, RequestHead, RequestBody, RequestEnd};
+use codec::http::{ResponsePart, ResponseHead, ResponseBody, ResponseEnd};
+use codec::http::{Remaining, UntilClose};
+use codec::http::{DEFAULT_MAX_HEADER_SIZE, body_framing, encode_chunk, encode_head};
+use codec::http::{content_length, keep_alive, malformed, parse_head, parse_version};
+use pipeline::{Closed, Filter, PipelineDown, PipelineEvent, PipelineUp, ReadEof, UserEvent};
+
+/// Sent down in a `UserEvent`, stops waiting for `100 Continue` and sends the
+/// request body held back so far. Servers that don't support the expectation
+/// never answer, so owners send this once their own timeout expires.
+pub struct ContinueTimeout;
+
+/// What the codec expects to read next.
+enum ReadState {
+    ReadHead,
+    /// The body of a response, and whether the connection stays open after
+    /// it.
+    ReadBody(BodyState, bool),
+    /// The protocol has been switched; input is passed down as it is.
+    Upgraded,
+    /// The connection is closing; input is ignored.
+    Discard
+}
+
+/// How the body of the request being written is framed.
+enum Framing {
+    NoBody,
+    /// This many bytes of body remain to be sent.
+    Fixed(u64),
+    Chunked
+}
+
+/// What the codec expects to be sent up next.
+enum WriteState {
+    WriteHead,
+    WriteBody(Framing),
+    WriteUpgraded
+}
+
+/// A request that has been sent but not yet fully responded to.
+struct Sent {
+    keep_alive: bool,
+    head: bool,
+    upgrade: bool
+}
+
+struct ClientState {
+    buf: ~[u8],
+    read: ReadState,
+    write: WriteState,
+    sent: ~[Sent],
+    /// Whether output is being held back until the server answers
+    /// `100 Continue`.
+    awaiting_continue: bool,
+    held: ~[u8],
+    /// Whether the server answered before the body was sent, so the rest of
+    /// it is dropped.
+    body_refused: bool
+}
+
+/// What decoding produced.
+struct Decoded {
+    parts: ~[ResponsePart],
+    /// Output released by `100 Continue`.
+    release: ~[u8],
+    close: bool
+}
+
+/// Encodes the `RequestPart`s sent up into bytes and decodes the bytes sent
+/// down into `ResponsePart`s.
+pub struct HttpClientCodec {
+    priv max_header_size: uint,
+    priv state: RefCell<ClientState>
+}
+
+impl HttpClientCodec {
+    /// Accept response heads of up to 64KiB.
+    pub fn new() -> HttpClientCodec {
+        HttpClientCodec::with_max_header_size(DEFAULT_MAX_HEADER_SIZE)
+    }
+
+    /// Accept response heads, including the status line, of up to
+    /// `max_header_size` bytes.
+    pub fn with_max_header_size(max_header_size: uint) -> HttpClientCodec {
+        HttpClientCodec {
+            max_header_size: max_header_size,
+            state: RefCell::new(ClientState {
+                buf: ~[],
+                read: ReadHead,
+                write: WriteHead,
+                sent: ~[],
+                awaiting_continue: false,
+                held: ~[],
+                body_refused: false
+            })
+        }
+    }
+
+    /// Add `data` to the buffer and decode as much as possible. On error the
+    /// rest of the input is discarded.
+    fn decode(&self, data: &[u8]) -> IoResult<Decoded> {
+        let mut state = self.state.borrow_mut();
+        let state = state.get();
+        state.buf.push_all(data);
+        let mut decoded = Decoded { parts: ~[], release: ~[], close: false };
+        match state.decode(self.max_header_size, &mut decoded) {
+            Ok(()) => Ok(decoded),
+            Err(err) => {
+                state.buf.clear();
+                state.read = Discard;
+                Err(err)
+            }
+        }
+    }
+
+    fn encode(&self, part: RequestPart) -> IoResult<~[u8]> {
+        self.state.borrow_mut().get().encode(part)
+    }
+
+    /// Stop waiting for `100 Continue`, returning the output held back.
+    fn continue_timeout(&self) -> ~[u8] {
+        let mut state = self.state.borrow_mut();
+        let state = state.get();
+        state.awaiting_continue = false;
+        util::replace(&mut state.held, ~[])
+    }
+
+    /// Handle end of stream, returning the part that ends a close-delimited
+    /// response.
+    fn read_eof(&self) -> IoResult<Option<ResponsePart>> {
+        let mut state = self.state.borrow_mut();
+        let state = state.get();
+        let read = state.read;
+        match read {
+            ReadBody(UntilClose, _) => {
+                state.sent.shift();
+                state.read = Discard;
+                Ok(Some(ResponseEnd))
+            }
+            ReadBody(..) => Err(incomplete()),
+            ReadHead if state.sent.len() > 0 => Err(incomplete()),
+            _ => Ok(None)
+        }
+    }
+}
+
+impl ClientState {
+    fn decode(&mut self, max_header_size: uint, decoded: &mut Decoded) -> IoResult<()> {
+        loop {
+            let read = self.read;
+            match read {
+                ReadHead => {
+                    let (line, headers, len) = match try!(parse_head(self.buf, max_header_size)) {
+                        Some(head) => head,
+                        None => return Ok(())
+                    };
+                    self.buf = self.buf.slice_from(len).to_owned();
+                    let response = try!(parse_response(line, headers));
+                    if self.sent.len() == 0 {
+                        return Err(malformed("A response was received without a request."));
+                    }
+                    let request = self.sent[0];
+                    let status = response.status;
+
+                    if status == 101 && request.upgrade {
+                        self.sent.shift();
+                        self.release(decoded);
+                        self.read = Upgraded;
+                        self.write = WriteUpgraded;
+                        decoded.parts.push(ResponseHead(response));
+                        continue;
+                    }
+                    if status >= 100 && status < 200 {
+                        if status == 100 {
+                            self.release(decoded);
+                        }
+                        continue;
+                    }
+
+                    // A final response to a request whose body was held back:
+                    // the server doesn't want the body, and the connection
+                    // can't be reused without sending it.
+                    let mut keep_alive = request.keep_alive &&
+                        keep_alive(response.version, &response.headers);
+                    if self.awaiting_continue {
+                        self.awaiting_continue = false;
+                        self.held.clear();
+                        self.body_refused = match self.write {
+                            WriteBody(..) => true,
+                            _ => false
+                        };
+                        keep_alive = false;
+                    }
+
+                    let body = if request.head || status == 204 || status == 304 {
+                        Remaining(0)
+                    } else {
+                        try!(body_framing(&response.headers, UntilClose))
+                    };
+                    decoded.parts.push(ResponseHead(response));
+                    self.read = ReadBody(body, keep_alive);
+                }
+                ReadBody(body, keep_alive) => {
+                    let mut body = body;
+                    let mut chunks = ~[];
+                    let (used, done) = try!(body.decode(self.buf, &mut chunks));
+                    self.buf = self.buf.slice_from(used).to_owned();
+                    for chunk in chunks.move_iter() {
+                        decoded.parts.push(ResponseBody(chunk));
+                    }
+                    if !done {
+                        self.read = ReadBody(body, keep_alive);
+                        return Ok(());
+                    }
+                    decoded.parts.push(ResponseEnd);
+                    self.sent.shift();
+                    if keep_alive {
+                        self.read = ReadHead;
+                    } else {
+                        self.read = Discard;
+                        decoded.close = true;
+                    }
+                }
+                Upgraded => {
+                    if self.buf.len() > 0 {
+                        decoded.parts.push(ResponseBody(util::replace(&mut self.buf, ~[])));
+                    }
+                    return Ok(());
+                }
+                Discard => {
+                    self.buf.clear();
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Stop holding output back.
+    fn release(&mut self, decoded: &mut Decoded) {
+        if self.awaiting_continue {
+            self.awaiting_continue = false;
+            decoded.release.push_all(util::replace(&mut self.held, ~[]));
+        }
+    }
+
+    /// Returns `bytes`, or holds them back if the server hasn't answered
+    /// `100 Continue` yet.
+    fn output(&mut self, bytes: ~[u8]) -> ~[u8] {
+        if self.awaiting_continue {
+            self.held.push_all(bytes);
+            ~[]
+        } else {
+            bytes
+        }
+    }
+
+    fn encode(&mut self, part: RequestPart) -> IoResult<~[u8]> {
+        if self.body_refused {
+            match part {
+                RequestBody(_) => return Ok(~[]),
+                RequestEnd => {
+                    self.body_refused = false;
+                    self.write = WriteHead;
+                    return Ok(~[]);
+                }
+                RequestHead(_) => return Err(out_of_order())
+            }
+        }
+        match part {
+            RequestHead(request) => self.encode_head(request),
+            RequestBody(data) => {
+                let write = self.write;
+                let data = match write {
+                    WriteBody(NoBody) if data.len() > 0 => {
+                        return Err(malformed("A request without Content-Length or chunked \
+                            Transfer-Encoding may not have a body."));
+                    }
+                    WriteBody(Fixed(remaining)) => {
+                        if data.len() as u64 > remaining {
+                            return Err(malformed("A request body is longer than its \
+                                Content-Length."));
+                        }
+                        self.write = WriteBody(Fixed(remaining - data.len() as u64));
+                        data
+                    }
+                    WriteBody(Chunked) if data.len() > 0 => encode_chunk(data),
+                    WriteBody(..) | WriteUpgraded => data,
+                    WriteHead => return Err(out_of_order())
+                };
+                Ok(self.output(data))
+            }
+            RequestEnd => {
+                let write = self.write;
+                let data = match write {
+                    WriteBody(framing) => {
+                        let data = match framing {
+                            Chunked => bytes!("0\r\n\r\n").to_owned(),
+                            Fixed(remaining) if remaining > 0 => {
+                                return Err(malformed("A request body is shorter than its \
+                                    Content-Length."));
+                            }
+                            _ => ~[]
+                        };
+                        self.write = WriteHead;
+                        data
+                    }
+                    WriteUpgraded => ~[],
+                    WriteHead => return Err(out_of_order())
+                };
+                Ok(self.output(data))
+            }
+        }
+    }
+
+    fn encode_head(&mut self, request: Request) -> IoResult<~[u8]> {
+        match self.write {
+            WriteHead => {}
+            _ => return Err(out_of_order())
+        }
+        // A request behind a held body would be lost along with the body if
+        // the server refused it.
+        if self.awaiting_continue {
+            return Err(malformed("A request can't be sent while an earlier one waits for \
+                100 Continue."));
+        }
+        let framing = match try!(content_length(&request.headers)) {
+            Some(length) => Fixed(length),
+            None if request.headers.has_token("Transfer-Encoding", "chunked") => Chunked,
+            None => NoBody
+        };
+        self.sent.push(Sent {
+            keep_alive: keep_alive(request.version, &request.headers),
+            head: request.method.as_slice() == "HEAD",
+            upgrade: request.headers.contains("Upgrade") &&
+                request.headers.has_token("Connection", "upgrade")
+        });
+        self.write = WriteBody(framing);
+
+        let line = format!("{} {} {}", request.method, request.uri, request.version.as_str());
+        let head = self.output(encode_head(line, &request.headers));
+        let has_body = match framing {
+            NoBody | Fixed(0) => false,
+            _ => true
+        };
+        if has_body && request.headers.has_token("Expect", "100-continue") {
+            self.awaiting_continue = true;
+        }
+        Ok(head)
+    }
+}
+
+fn parse_response(line: ~str, headers: Headers) -> IoResult<Response> {
+    let (version, rest) = match line.find(' ') {
+        Some(i) => (line.slice_to(i), line.slice_from(i + 1)),
+        None => return Err(malformed("A status line is malformed."))
+    };
+    let (code, reason) = match rest.find(' ') {
+        Some(i) => (rest.slice_to(i), rest.slice_from(i + 1)),
+        None => (rest, "")
+    };
+    let status = match from_str::<u16>(code) {
+        Some(status) if code.len() == 3 && status >= 100 => status,
+        _ => return Err(malformed("A status code is malformed."))
+    };
+    Ok(Response {
+        version: try!(parse_version(version)),
+        status: status,
+        reason: reason.to_owned(),
+        headers: headers
+    })
+}
+
+fn out_of_order() -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "A request part was sent out of order.",
+        detail: None
+    }
+}
+
+fn incomplete() -> IoError {
+    IoError {
+        kind: io::EndOfFile,
+        desc: "The connection closed before the response was complete.",
+        detail: None
+    }
+}
+
+impl Filter<~[u8], ResponsePart, RequestPart, ~[u8]> for HttpClientCodec {
+    fn down<U: PipelineUp<~[u8]>, D: PipelineDown<ResponsePart>>(
+            &self, data: ~[u8], up: &U, down: &D) -> IoResult<()> {
+        let Decoded { parts, release, close } = try!(self.decode(data));
+        if release.len() > 0 {
+            try!(up.up(release));
+        }
+        for part in parts.move_iter() {
+            try!(down.down(part));
+        }
+        if close {
+            try!(up.up_event(Closed));
+        }
+        Ok(())
+    }
+
+    fn up<U: PipelineUp<~[u8]>, D: PipelineDown<ResponsePart>>(
+            &self, data: RequestPart, up: &U, _: &D) -> IoResult<()> {
+        let bytes = try!(self.encode(data));
+        if bytes.len() > 0 {
+            try!(up.up(bytes));
+        }
+        Ok(())
+    }
+
+    fn down_event<U: PipelineUp<~[u8]>, D: PipelineDown<ResponsePart>>(
+            &self, event: PipelineEvent, up: &U, down: &D) -> IoResult<()> {
+        match event {
+            ReadEof => {
+                match try!(self.read_eof()) {
+                    Some(part) => try!(down.down(part)),
+                    None => {}
+                }
+                down.down_event(ReadEof)
+            }
+            UserEvent(data) => {
+                if !data.is::<ContinueTimeout>() {
+                    return down.down_event(UserEvent(data));
+                }
+                let release = self.continue_timeout();
+                if release.len() > 0 {
+                    try!(up.up(release));
+                }
+                Ok(())
+            }
+            event => down.down_event(event)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ContinueTimeout, HttpClientCodec};
+
+    use std::any::{Any, AnyOwnExt};
+    use std::cell::RefCell;
+    use std::from_str::from_str;
+    use std::io;
+    use std::io::IoResult;
+    use std::io::net::ip::SocketAddr;
+    use std::str;
+    use std::util;
+
+    use sync::MutexArc;
+
+    use bootstrap::{ClientBootstrap, ServerBootstrap};
+    use codec::http::{Http10, Request, Response};
+    use codec::http::{RequestPart, RequestHead, RequestBody, RequestEnd};
+    use codec::http::{ResponsePart, ResponseHead, ResponseBody, ResponseEnd};
+    use codec::http::server::HttpServerCodec;
+    use pipeline::{Filter, PipelineBuilder, PipelineDown, PipelineEvent, PipelineUp, UserEvent};
+    use reactor::PipelineFactory;
+
+    /// Answers each request with its body, or its URI if it has no body.
+    /// The response to `/stream` has no `Content-Length` and is sent in two
+    /// parts.
+    struct Respond {
+        request: RefCell<(~str, ~[u8])>
+    }
+
+    impl Filter<RequestPart, (), (), ResponsePart> for Respond {
+        fn down<U: PipelineUp<ResponsePart>, D: PipelineDown<()>>(
+                &self, data: RequestPart, up: &U, _: &D) -> IoResult<()> {
+            match data {
+                RequestHead(request) => {
+                    if request.headers.has_token("Expect", "100-continue") {
+                        try!(up.up(ResponseHead(Response::new(100, "Continue"))));
+                    }
+                    *self.request.borrow_mut().get() = (request.uri, ~[]);
+                    Ok(())
+                }
+                RequestBody(data) => {
+                    let mut request = self.request.borrow_mut();
+                    let (_, ref mut body) = *request.get();
+                    body.push_all(data);
+                    Ok(())
+                }
+                RequestEnd => {
+                    let (uri, body) = util::replace(self.request.borrow_mut().get(),
+                                                    (~"", ~[]));
+                    let body = if body.len() > 0 { body } else { uri.as_bytes().to_owned() };
+                    let mut response = Response::new(200, "OK");
+                    if uri.as_slice() == "/stream" {
+                        try!(up.up(ResponseHead(response)));
+                        try!(up.up(ResponseBody(body.slice_to(2).to_owned())));
+                        try!(up.up(ResponseBody(body.slice_from(2).to_owned())));
+                    } else {
+                        response.headers.set("Content-Length", body.len().to_str().as_slice());
+                        try!(up.up(ResponseHead(response)));
+                        try!(up.up(ResponseBody(body)));
+                    }
+                    up.up(ResponseEnd)
+                }
+            }
+        }
+
+        fn up<U: PipelineUp<ResponsePart>, D: PipelineDown<()>>(
+                &self, _: (), _: &U, _: &D) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    struct ServerFactory;
+
+    impl PipelineFactory for ServerFactory {
+        fn new_pipeline(&self, output: ~PipelineUp<~[u8]>) -> ~PipelineDown<~[u8]> {
+            PipelineBuilder::new(Respond { request: RefCell::new((~"", ~[])) })
+                .filter(HttpServerCodec::new())
+                .build_with_sink(output)
+        }
+    }
+
+    /// Sends the request parts in a `UserEvent` up and records each response's
+    /// status and body.
+    struct Collect {
+        current: RefCell<(u16, ~[u8])>,
+        responses: MutexArc<~[(u16, ~str)]>
+    }
+
+    impl Filter<ResponsePart, (), (), RequestPart> for Collect {
+        fn down<U: PipelineUp<RequestPart>, D: PipelineDown<()>>(
+                &self, data: ResponsePart, _: &U, _: &D) -> IoResult<()> {
+            match data {
+                ResponseHead(response) => {
+                    *self.current.borrow_mut().get() = (response.status, ~[]);
+                }
+                ResponseBody(data) => {
+                    let mut current = self.current.borrow_mut();
+                    let (_, ref mut body) = *current.get();
+                    body.push_all(data);
+                }
+                ResponseEnd => {
+                    let (status, body) = util::replace(self.current.borrow_mut().get(), (0, ~[]));
+                    let body = unsafe { str::raw::from_utf8_owned(body) };
+                    self.responses.access(|responses| responses.push((status, body.clone())));
+                }
+            }
+            Ok(())
+        }
+
+        fn up<U: PipelineUp<RequestPart>, D: PipelineDown<()>>(
+                &self, _: (), _: &U, _: &D) -> IoResult<()> {
+            Ok(())
+        }
+
+        fn down_event<U: PipelineUp<RequestPart>, D: PipelineDown<()>>(
+                &self, event: PipelineEvent, up: &U, _: &D) -> IoResult<()> {
+            match event {
+                UserEvent(data) => {
+                    let parts = match data.move::<~[RequestPart]>() {
+                        Ok(parts) => parts,
+                        Err(_) => return Ok(())
+                    };
+                    for part in parts.move_iter() {
+                        try!(up.up(part));
+                    }
+                    Ok(())
+                }
+                _ => Ok(())
+            }
+        }
+    }
+
+    struct ClientFactory {
+        responses: MutexArc<~[(u16, ~str)]>
+    }
+
+    impl PipelineFactory for ClientFactory {
+        fn new_pipeline(&self, output: ~PipelineUp<~[u8]>) -> ~PipelineDown<~[u8]> {
+            let collect = Collect {
+                current: RefCell::new((0, ~[])),
+                responses: self.responses.clone()
+            };
+            PipelineBuilder::new(collect)
+                .filter(HttpClientCodec::new())
+                .build_with_sink(output)
+        }
+    }
+
+    /// Send `parts` over one connection to an in-process server and wait for
+    /// `expected` responses.
+    fn exchange(parts: ~[RequestPart], expected: uint) -> ~[(u16, ~str)] {
+        let addr: SocketAddr = from_str("127.0.0.1:0").unwrap();
+        let mut server = ServerBootstrap::new(ServerFactory).bind(addr).unwrap();
+        let responses = MutexArc::new(~[]);
+        let factory = ClientFactory { responses: responses.clone() };
+        let mut pool = ClientBootstrap::new(server.local_addr(), factory).build().unwrap();
+
+        let ticket = pool.checkout();
+        let mut parts = Some(parts);
+        for _ in range(0, 500) {
+            server.run_once(10).unwrap();
+            pool.poll(10).unwrap();
+            match pool.claim(&ticket) {
+                Some(lease) => {
+                    let lease = lease.unwrap();
+                    let parts = ~parts.take_unwrap() as ~Any;
+                    pool.endpoint(&lease).unwrap().send_event(UserEvent(parts)).unwrap();
+                }
+                None => {}
+            }
+            if responses.access(|responses| responses.len()) >= expected {
+                break;
+            }
+        }
+        responses.access(|responses| responses.clone())
+    }
+
+    fn get(uri: &str) -> ~[RequestPart] {
+        ~[RequestHead(Request::new("GET", uri)), RequestEnd]
+    }
+
+    #[test]
+    fn test_chunked_response() {
+        assert_eq!(exchange(get("/stream"), 1), ~[(200, ~"/stream")]);
+    }
+
+    #[test]
+    fn test_pipelined() {
+        let mut parts = get("/a");
+        parts.push_all_move(get("/stream"));
+        parts.push_all_move(get("/b"));
+        assert_eq!(exchange(parts, 3), ~[(200, ~"/a"), (200, ~"/stream"), (200, ~"/b")]);
+    }
+
+    #[test]
+    fn test_expect_continue() {
+        let mut request = Request::new("POST", "/upload");
+        request.headers.set("Content-Length", "5");
+        request.headers.set("Expect", "100-continue");
+        let parts = ~[RequestHead(request), RequestBody(bytes!("hello").to_owned()), RequestEnd];
+        assert_eq!(exchange(parts, 1), ~[(200, ~"hello")]);
+    }
+
+    #[test]
+    fn test_close_delimited() {
+        let mut request = Request::new("GET", "/stream");
+        request.version = Http10;
+        assert_eq!(exchange(~[RequestHead(request), RequestEnd], 1), ~[(200, ~"/stream")]);
+    }
+
+    struct Output {
+        output: MutexArc<~str>
+    }
+
+    impl PipelineUp<~[u8]> for Output {
+        fn up(&self, data: ~[u8]) -> IoResult<()> {
+            let data = unsafe { str::raw::from_utf8_owned(data) };
+            self.output.access(|output| output.push_str(data));
+            Ok(())
+        }
+    }
+
+    /// A client pipeline that isn't connected to a server: its output is
+    /// recorded and responses are sent down by the test.
+    fn client_pipeline(
+            responses: &MutexArc<~[(u16, ~str)]>,
+            output: &MutexArc<~str>) -> ~PipelineDown<~[u8]> {
+        let collect = Collect { current: RefCell::new((0, ~[])), responses: responses.clone() };
+        PipelineBuilder::new(collect)
+            .filter(HttpClientCodec::new())
+            .build_with_sink(~Output { output: output.clone() } as ~PipelineUp<~[u8]>)
+    }
+
+    fn send(pipeline: &~PipelineDown<~[u8]>, parts: ~[RequestPart]) -> IoResult<()> {
+        pipeline.down_event(UserEvent(~parts as ~Any))
+    }
+
+    fn expect_upload() -> ~[RequestPart] {
+        let mut request = Request::new("POST", "/upload");
+        request.headers.set("Content-Length", "5");
+        request.headers.set("Expect", "100-continue");
+        ~[RequestHead(request), RequestBody(bytes!("hello").to_owned()), RequestEnd]
+    }
+
+    static UPLOAD_HEAD: &'static str =
+        "POST /upload HTTP/1.1\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n";
+
+    #[test]
+    fn test_continue_timeout() {
+        let responses = MutexArc::new(~[]);
+        let output = MutexArc::new(~"");
+        let pipeline = client_pipeline(&responses, &output);
+
+        // The server never answers 100 Continue, so the body stays held back
+        // until the owner gives up waiting.
+        send(&pipeline, expect_upload()).unwrap();
+        assert_eq!(output.access(|o| o.clone()), UPLOAD_HEAD.to_owned());
+        pipeline.down_event(UserEvent(~ContinueTimeout as ~Any)).unwrap();
+        assert_eq!(output.access(|o| o.clone()), UPLOAD_HEAD + "hello");
+
+        // The connection can be reused once the body has been sent.
+        pipeline.down(bytes!("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").to_owned())
+            .unwrap();
+        assert_eq!(responses.access(|r| r.clone()), ~[(200, ~"ok")]);
+        send(&pipeline, get("/b")).unwrap();
+        assert_eq!(output.access(|o| o.clone()), UPLOAD_HEAD + "hello" + "GET /b HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn test_expectation_refused() {
+        let responses = MutexArc::new(~[]);
+        let output = MutexArc::new(~"");
+        let pipeline = client_pipeline(&responses, &output);
+
+        send(&pipeline, expect_upload()).unwrap();
+        // Nothing may be pipelined behind the held body.
+        let err = send(&pipeline, get("/b")).unwrap_err();
+        assert_eq!(err.kind, io::InvalidInput);
+
+        // A final response instead of 100 Continue drops the body.
+        pipeline.down(bytes!("HTTP/1.1 417 Expectation Failed\r\n\
+                              Content-Length: 0\r\n\r\n").to_owned()).unwrap();
+        assert_eq!(responses.access(|r| r.clone()), ~[(417, ~"")]);
+        pipeline.down_event(UserEvent(~ContinueTimeout as ~Any)).unwrap();
+        assert_eq!(output.access(|o| o.clone()), UPLOAD_HEAD.to_owned());
+    }
+
+    #[test]
+    fn test_body_after_refusal_is_dropped() {
+        let responses = MutexArc::new(~[]);
+        let output = MutexArc::new(~"");
+        let pipeline = client_pipeline(&responses, &output);
+        let mut parts = expect_upload();
+        let end = parts.pop().unwrap();
+        let body = parts.pop().unwrap();
+
+        send(&pipeline, parts).unwrap();
+        pipeline.down(bytes!("HTTP/1.1 417 Expectation Failed\r\n\
+                              Content-Length: 0\r\n\r\n").to_owned()).unwrap();
+        assert_eq!(responses.access(|r| r.clone()), ~[(417, ~"")]);
+
+        // The body the server refused never reaches the connection.
+        send(&pipeline, ~[body, end]).unwrap();
+        assert_eq!(output.access(|o| o.clone()), UPLOAD_HEAD.to_owned());
+    }
+}
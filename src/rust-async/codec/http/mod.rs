@@ -0,0 +1,407 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! HTTP/1.1 messages and the framing shared by the server and client codecs.
+//!
+//! Messages travel through a pipeline in parts so that bodies are streamed
+//! rather than buffered: a head, any number of body chunks, then an end
+//! marker. The codecs take care of `Content-Length` and chunked framing in
+//! both directions; filters never see chunk sizes or trailers.
+
+use std::ascii::StrAsciiExt;
+use std::from_str::from_str;
+use std::io;
+use std::io::{IoResult, IoError};
+use std::str;
+
+use codec::find;
+
+pub mod client;
+pub mod server;
+
+static DEFAULT_MAX_HEADER_SIZE: uint = 64 * 1024;
+/// The longest chunk size line, including any chunk extensions, or trailer
+/// line that will be accepted.
+static MAX_LINE_LENGTH: uint = 4 * 1024;
+
+#[deriving(Clone, Eq)]
+pub enum Version {
+    Http10,
+    Http11
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Http10 => "HTTP/1.0",
+            Http11 => "HTTP/1.1"
+        }
+    }
+}
+
+fn parse_version(s: &str) -> IoResult<Version> {
+    match s {
+        "HTTP/1.0" => Ok(Http10),
+        "HTTP/1.1" => Ok(Http11),
+        _ => Err(malformed("Unsupported HTTP version."))
+    }
+}
+
+/// Header fields in the order they were received or added. Names are
+/// compared case-insensitively.
+#[deriving(Clone)]
+pub struct Headers {
+    priv entries: ~[(~str, ~str)]
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers { entries: ~[] }
+    }
+
+    /// Add a field, keeping any others with the same name.
+    pub fn add(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_owned(), value.to_owned()));
+    }
+
+    /// Replace all fields named `name` with a single field.
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.add(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|&(ref n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    /// The value of the first field named `name`.
+    pub fn get<'a>(&'a self, name: &str) -> Option<&'a str> {
+        for &(ref n, ref v) in self.entries.iter() {
+            if n.eq_ignore_ascii_case(name) {
+                return Some(v.as_slice());
+            }
+        }
+        None
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Whether any field named `name`, read as a comma separated list,
+    /// contains `token`, as in `Connection: keep-alive, Upgrade`.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.entries.iter().any(|&(ref n, ref v)| {
+            n.eq_ignore_ascii_case(name) &&
+                v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token))
+        })
+    }
+
+    pub fn entries<'a>(&'a self) -> &'a [(~str, ~str)] {
+        self.entries.as_slice()
+    }
+}
+
+pub struct Request {
+    method: ~str,
+    uri: ~str,
+    version: Version,
+    headers: Headers
+}
+
+impl Request {
+    /// An HTTP/1.1 request with no headers.
+    pub fn new(method: &str, uri: &str) -> Request {
+        Request {
+            method: method.to_owned(),
+            uri: uri.to_owned(),
+            version: Http11,
+            headers: Headers::new()
+        }
+    }
+}
+
+pub struct Response {
+    version: Version,
+    status: u16,
+    reason: ~str,
+    headers: Headers
+}
+
+impl Response {
+    /// An HTTP/1.1 response with no headers.
+    pub fn new(status: u16, reason: &str) -> Response {
+        Response {
+            version: Http11,
+            status: status,
+            reason: reason.to_owned(),
+            headers: Headers::new()
+        }
+    }
+}
+
+/// A request as it travels through a pipeline.
+pub enum RequestPart {
+    RequestHead(Request),
+    /// Part of the body. Bodies may be split into any number of parts.
+    RequestBody(~[u8]),
+    RequestEnd
+}
+
+/// A response as it travels through a pipeline.
+pub enum ResponsePart {
+    ResponseHead(Response),
+    /// Part of the body. Bodies may be split into any number of parts.
+    ResponseBody(~[u8]),
+    ResponseEnd
+}
+
+fn malformed(desc: &'static str) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: desc,
+        detail: None
+    }
+}
+
+/// Split a complete head off the front of `buf`, returning the start line,
+/// the header fields and the length of the head, or `None` if the head is not
+/// complete yet.
+fn parse_head(buf: &[u8], max_size: uint) -> IoResult<Option<(~str, Headers, uint)>> {
+    let end = match find(buf, bytes!("\r\n\r\n")) {
+        Some(end) => end,
+        None if buf.len() > max_size => return Err(head_too_large(max_size)),
+        None => return Ok(None)
+    };
+    if end + 4 > max_size {
+        return Err(head_too_large(max_size));
+    }
+
+    let head = buf.slice_to(end);
+    if !str::is_utf8(head) {
+        return Err(malformed("An HTTP head is not valid text."));
+    }
+    let head = unsafe { str::raw::from_utf8_owned(head.to_owned()) };
+    let mut lines = head.split_str("\r\n");
+    let start = lines.next().unwrap_or("").to_owned();
+    let mut headers = Headers::new();
+    for line in lines {
+        // Folded header lines are obsolete and a smuggling risk.
+        if line.starts_with(" ") || line.starts_with("\t") {
+            return Err(malformed("Folded header lines are not supported."));
+        }
+        // Whitespace between a field name and its colon must be rejected, as
+        // an intermediary might read the name differently.
+        match line.find(':') {
+            Some(i) if i > 0 && !line.slice_to(i).chars().any(|c| c == ' ' || c == '\t') => {
+                headers.add(line.slice_to(i), line.slice_from(i + 1).trim())
+            }
+            _ => return Err(malformed("A header line is malformed."))
+        }
+    }
+    Ok(Some((start, headers, end + 4)))
+}
+
+fn head_too_large(max_size: uint) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "An HTTP head is larger than the maximum size.",
+        detail: Some(format!("maximum size: {}", max_size))
+    }
+}
+
+fn encode_head(start_line: &str, headers: &Headers) -> ~[u8] {
+    let mut head = start_line.to_owned();
+    head.push_str("\r\n");
+    for &(ref name, ref value) in headers.entries().iter() {
+        head.push_str(*name);
+        head.push_str(": ");
+        head.push_str(*value);
+        head.push_str("\r\n");
+    }
+    head.push_str("\r\n");
+    head.into_bytes()
+}
+
+fn encode_chunk(data: &[u8]) -> ~[u8] {
+    let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+    chunk.push_all(data);
+    chunk.push_all(bytes!("\r\n"));
+    chunk
+}
+
+fn parse_length(value: &str) -> IoResult<u64> {
+    let value = value.trim();
+    if value.len() == 0 || !value.chars().all(|c| c >= '0' && c <= '9') {
+        return Err(malformed("A Content-Length is not a number."));
+    }
+    match from_str::<u64>(value) {
+        Some(length) => Ok(length),
+        None => Err(malformed("A Content-Length is out of range."))
+    }
+}
+
+/// Whether a connection should be kept open after a message with these
+/// headers.
+fn keep_alive(version: Version, headers: &Headers) -> bool {
+    match version {
+        Http10 => headers.has_token("Connection", "keep-alive"),
+        Http11 => !headers.has_token("Connection", "close")
+    }
+}
+
+/// How the body of a message with these headers is framed, or `default` if
+/// the headers don't say.
+fn body_framing(headers: &Headers, default: BodyState) -> IoResult<BodyState> {
+    match headers.get("Transfer-Encoding") {
+        Some(codings) => {
+            let chunked = match codings.split(',').last() {
+                Some(coding) => coding.trim().eq_ignore_ascii_case("chunked"),
+                None => false
+            };
+            // A Content-Length alongside a Transfer-Encoding is a smuggling
+            // attempt, and without chunked coding last the body can't be
+            // delimited.
+            if !chunked || headers.contains("Content-Length") {
+                Err(malformed("A message has conflicting or unsupported body framing."))
+            } else {
+                Ok(ChunkSize)
+            }
+        }
+        None => match try!(content_length(headers)) {
+            Some(length) => Ok(Remaining(length)),
+            None => Ok(default)
+        }
+    }
+}
+
+/// The body length given by the `Content-Length` fields, if any. Several
+/// fields, or a comma separated list, are only accepted if every value is the
+/// same.
+fn content_length(headers: &Headers) -> IoResult<Option<u64>> {
+    let mut length = None;
+    for &(ref name, ref value) in headers.entries().iter() {
+        if !name.eq_ignore_ascii_case("Content-Length") {
+            continue;
+        }
+        for value in value.split(',') {
+            let value = try!(parse_length(value));
+            match length {
+                Some(length) if length != value => {
+                    return Err(malformed("A message has conflicting Content-Lengths."));
+                }
+                _ => length = Some(value)
+            }
+        }
+    }
+    Ok(length)
+}
+
+/// Where an incoming body has got to.
+enum BodyState {
+    /// This many bytes of body remain.
+    Remaining(u64),
+    /// The next line holds the size of the next chunk.
+    ChunkSize,
+    /// This many bytes of the current chunk remain.
+    ChunkData(u64),
+    /// The CRLF after a chunk comes next.
+    ChunkEnd,
+    /// Trailer fields follow the last chunk, up to an empty line.
+    Trailers,
+    /// The body lasts until the connection closes.
+    UntilClose
+}
+
+impl BodyState {
+    /// Decode as much of the body as possible from the front of `buf`,
+    /// adding its parts to `chunks`. Returns the number of bytes used and
+    /// whether the body is complete.
+    fn decode(&mut self, buf: &[u8], chunks: &mut ~[~[u8]]) -> IoResult<(uint, bool)> {
+        let mut pos = 0;
+        loop {
+            let state = *self;
+            match state {
+                Remaining(0) => return Ok((pos, true)),
+                Remaining(n) | ChunkData(n) => {
+                    let available = (buf.len() - pos) as u64;
+                    if available == 0 {
+                        return Ok((pos, false));
+                    }
+                    let take = if n < available { n } else { available };
+                    chunks.push(buf.slice(pos, pos + take as uint).to_owned());
+                    pos += take as uint;
+                    *self = match state {
+                        ChunkData(_) if take == n => ChunkEnd,
+                        ChunkData(_) => ChunkData(n - take),
+                        _ => Remaining(n - take)
+                    };
+                }
+                ChunkSize | Trailers => {
+                    let line = match find(buf.slice_from(pos), bytes!("\r\n")) {
+                        Some(len) if len <= MAX_LINE_LENGTH => buf.slice(pos, pos + len),
+                        Some(_) => return Err(malformed("A chunk line is too long.")),
+                        None if buf.len() - pos > MAX_LINE_LENGTH => {
+                            return Err(malformed("A chunk line is too long."));
+                        }
+                        None => return Ok((pos, false))
+                    };
+                    pos += line.len() + 2;
+                    match state {
+                        ChunkSize => {
+                            let size = try!(parse_chunk_size(line));
+                            *self = if size == 0 { Trailers } else { ChunkData(size) };
+                        }
+                        // Trailer fields are dropped.
+                        _ if line.len() == 0 => return Ok((pos, true)),
+                        _ => {}
+                    }
+                }
+                ChunkEnd => {
+                    if buf.len() - pos < 2 {
+                        return Ok((pos, false));
+                    }
+                    if buf.slice(pos, pos + 2) != bytes!("\r\n") {
+                        return Err(malformed("A chunk is not followed by CRLF."));
+                    }
+                    pos += 2;
+                    *self = ChunkSize;
+                }
+                UntilClose => {
+                    if pos < buf.len() {
+                        chunks.push(buf.slice_from(pos).to_owned());
+                    }
+                    return Ok((buf.len(), false));
+                }
+            }
+        }
+    }
+}
+
+/// Parse the hexadecimal size at the start of a chunk size line, ignoring any
+/// chunk extensions.
+fn parse_chunk_size(line: &[u8]) -> IoResult<u64> {
+    let mut size = 0u64;
+    let mut digits = 0;
+    for &b in line.iter() {
+        let digit = match b as char {
+            '0'..'9' => b - '0' as u8,
+            'a'..'f' => b - 'a' as u8 + 10,
+            'A'..'F' => b - 'A' as u8 + 10,
+            ';' | ' ' | '\t' => break,
+            _ => return Err(malformed("A chunk size is not a hexadecimal number."))
+        };
+        if digits == 15 {
+            return Err(malformed("A chunk size is out of range."));
+        }
+        size = (size << 4) | digit as u64;
+        digits += 1;
+    }
+    if digits == 0 {
+        return Err(malformed("A chunk size is missing."));
+    }
+    Ok(size)
+}
@@ -0,0 +1,658 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The server side of HTTP/1.1: requests are decoded and responses encoded.
+//!
+//! Pipelined requests are decoded and sent down as soon as they arrive; the
+//! filters above must send responses up in the same order. A response
+//! without a `Content-Length` is sent chunked, or, to an HTTP/1.0 client,
+//! delimited by closing the connection. Once a response that ends the
+//! connection is complete, `Closed` is sent up so that the connection's owner
+//! closes it after writing.
+//!
+//! Informational responses other than `101` may be sent before the final
+//! response, for example `100 Continue` to a request that expects it. After
+//! a `101 Switching Protocols` response to a request with an `Upgrade`
+//! header, the codec stops interpreting the connection: bytes received are
+//! sent down as `RequestBody` parts and `ResponseBody` parts are written as
+//! they are.
+
+use std::cell::RefCell;
+use std::io;
+use std::io::{IoResult, IoError};
+use std::util;
+
+use codec::http::{BodyState, Headers, Http10, Http11, Request, Response, Version};
+use codec::http::{RequestPart, RequestHead, RequestBody, RequestEnd};
+use codec::http::{ResponsePart, ResponseHead, ResponseBody, ResponseEnd};
+use codec::http::Remaining;
+use codec::http::{DEFAULT_MAX_HEADER_SIZE, body_framing, encode_chunk, encode_head};
+use codec::http::{content_length, keep_alive, malformed, parse_head, parse_version};
+use pipeline::{Closed, Filter, PipelineDown, PipelineUp};
+
+/// What the codec expects to read next.
+enum ReadState {
+    ReadHead,
+    ReadBody(BodyState),
+    /// An upgrade has been requested; input is held until the response.
+    AwaitUpgrade,
+    /// The protocol has been switched; input is passed down as it is.
+    Upgraded,
+    /// The connection will close after the responses already requested;
+    /// input is ignored.
+    Discard
+}
+
+/// How the body of the response being written is framed.
+enum Framing {
+    NoBody,
+    /// This many bytes of body remain to be sent.
+    Fixed(u64),
+    Chunked,
+    UntilClose
+}
+
+/// What the codec expects to be sent up next.
+enum WriteState {
+    WriteHead,
+    /// The body of a response, and whether the connection stays open after
+    /// it.
+    WriteBody(Framing, bool),
+    WriteUpgraded
+}
+
+/// A request that has been decoded but not yet responded to.
+struct Pending {
+    version: Version,
+    keep_alive: bool,
+    head: bool,
+    upgrade: bool
+}
+
+struct ServerState {
+    buf: ~[u8],
+    read: ReadState,
+    write: WriteState,
+    pending: ~[Pending]
+}
+
+/// What to do once a response part has been written.
+enum After {
+    Continue,
+    /// Decode input that was held back until the response.
+    Resume,
+    Close
+}
+
+/// Decodes the bytes sent down into `RequestPart`s and encodes the
+/// `ResponsePart`s sent up into bytes.
+pub struct HttpServerCodec {
+    priv max_header_size: uint,
+    priv state: RefCell<ServerState>
+}
+
+impl HttpServerCodec {
+    /// Accept request heads of up to 64KiB.
+    pub fn new() -> HttpServerCodec {
+        HttpServerCodec::with_max_header_size(DEFAULT_MAX_HEADER_SIZE)
+    }
+
+    /// Accept request heads, including the request line, of up to
+    /// `max_header_size` bytes.
+    pub fn with_max_header_size(max_header_size: uint) -> HttpServerCodec {
+        HttpServerCodec {
+            max_header_size: max_header_size,
+            state: RefCell::new(ServerState {
+                buf: ~[],
+                read: ReadHead,
+                write: WriteHead,
+                pending: ~[]
+            })
+        }
+    }
+
+    /// Add `data` to the buffer and decode as much as possible. On error the
+    /// rest of the input is discarded, and the parts decoded before the error
+    /// are returned along with it.
+    fn decode(&self, data: &[u8]) -> (~[RequestPart], Option<IoError>) {
+        let mut state = self.state.borrow_mut();
+        let state = state.get();
+        state.buf.push_all(data);
+        let mut parts = ~[];
+        match state.decode(self.max_header_size, &mut parts) {
+            Ok(()) => (parts, None),
+            Err(err) => {
+                state.buf.clear();
+                state.read = Discard;
+                (parts, Some(err))
+            }
+        }
+    }
+
+    fn encode(&self, part: ResponsePart) -> IoResult<(~[u8], After)> {
+        self.state.borrow_mut().get().encode(part)
+    }
+
+    /// Whether the connection is closing and every request that will be
+    /// answered has been, so that any requests decoded after them are dropped.
+    fn closing(&self) -> bool {
+        let state = self.state.borrow();
+        match state.get().read {
+            Discard => state.get().pending.len() == 0,
+            _ => false
+        }
+    }
+}
+
+impl ServerState {
+    fn decode(&mut self, max_header_size: uint, parts: &mut ~[RequestPart]) -> IoResult<()> {
+        loop {
+            let read = self.read;
+            match read {
+                ReadHead => {
+                    let (line, headers, len) = match try!(parse_head(self.buf, max_header_size)) {
+                        Some(head) => head,
+                        None => return Ok(())
+                    };
+                    self.buf = self.buf.slice_from(len).to_owned();
+                    let request = try!(parse_request(line, headers));
+                    let body = try!(body_framing(&request.headers, Remaining(0)));
+                    self.pending.push(Pending {
+                        version: request.version,
+                        keep_alive: keep_alive(request.version, &request.headers),
+                        head: request.method.as_slice() == "HEAD",
+                        upgrade: request.headers.contains("Upgrade") &&
+                            request.headers.has_token("Connection", "upgrade")
+                    });
+                    parts.push(RequestHead(request));
+                    self.read = ReadBody(body);
+                }
+                ReadBody(body) => {
+                    let mut body = body;
+                    let mut chunks = ~[];
+                    let (used, done) = try!(body.decode(self.buf, &mut chunks));
+                    self.buf = self.buf.slice_from(used).to_owned();
+                    for chunk in chunks.move_iter() {
+                        parts.push(RequestBody(chunk));
+                    }
+                    if !done {
+                        self.read = ReadBody(body);
+                        return Ok(());
+                    }
+                    parts.push(RequestEnd);
+                    let request = self.pending[self.pending.len() - 1];
+                    self.read = if request.upgrade {
+                        AwaitUpgrade
+                    } else if !request.keep_alive {
+                        Discard
+                    } else {
+                        ReadHead
+                    };
+                }
+                AwaitUpgrade => return Ok(()),
+                Upgraded => {
+                    if self.buf.len() > 0 {
+                        parts.push(RequestBody(util::replace(&mut self.buf, ~[])));
+                    }
+                    return Ok(());
+                }
+                Discard => {
+                    self.buf.clear();
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn encode(&mut self, part: ResponsePart) -> IoResult<(~[u8], After)> {
+        match part {
+            ResponseHead(response) => self.encode_head(response),
+            ResponseBody(data) => {
+                let write = self.write;
+                let data = match write {
+                    WriteBody(NoBody, _) if data.len() > 0 => {
+                        return Err(malformed("This response may not have a body."));
+                    }
+                    WriteBody(Fixed(remaining), keep_alive) => {
+                        if data.len() as u64 > remaining {
+                            return Err(malformed("A response body is longer than its \
+                                Content-Length."));
+                        }
+                        self.write = WriteBody(Fixed(remaining - data.len() as u64), keep_alive);
+                        data
+                    }
+                    WriteBody(Chunked, _) if data.len() > 0 => encode_chunk(data),
+                    WriteBody(..) | WriteUpgraded => data,
+                    WriteHead => return Err(out_of_order())
+                };
+                Ok((data, Continue))
+            }
+            ResponseEnd => {
+                let write = self.write;
+                match write {
+                    WriteBody(framing, keep_alive) => {
+                        let data = match framing {
+                            Chunked => bytes!("0\r\n\r\n").to_owned(),
+                            Fixed(remaining) if remaining > 0 => {
+                                return Err(malformed("A response body is shorter than its \
+                                    Content-Length."));
+                            }
+                            _ => ~[]
+                        };
+                        self.write = WriteHead;
+                        let after = if !keep_alive {
+                            Close
+                        } else if self.buf.len() > 0 {
+                            Resume
+                        } else {
+                            Continue
+                        };
+                        Ok((data, after))
+                    }
+                    WriteUpgraded => Ok((~[], Close)),
+                    WriteHead => Err(out_of_order())
+                }
+            }
+        }
+    }
+
+    fn encode_head(&mut self, mut response: Response) -> IoResult<(~[u8], After)> {
+        match self.write {
+            WriteHead => {}
+            _ => return Err(out_of_order())
+        }
+        if self.pending.len() == 0 {
+            return Err(malformed("A response was sent without a request."));
+        }
+        let request = self.pending[0];
+        let status = response.status;
+
+        // Informational responses come before the final response to the same
+        // request.
+        if status >= 100 && status < 200 && status != 101 {
+            return Ok((encode_response_head(&response), Continue));
+        }
+        self.pending.shift();
+
+        if status == 101 {
+            if !request.upgrade {
+                return Err(malformed("Switching Protocols was sent without an upgrade \
+                    request."));
+            }
+            self.write = WriteUpgraded;
+            self.read = Upgraded;
+            return Ok((encode_response_head(&response), Resume));
+        }
+
+        let mut keep_alive = request.keep_alive &&
+            !response.headers.has_token("Connection", "close");
+        let framing = if request.head || status == 204 || status == 304 {
+            NoBody
+        } else {
+            match try!(content_length(&response.headers)) {
+                Some(length) => Fixed(length),
+                None if request.version == Http11 => {
+                    response.headers.set("Transfer-Encoding", "chunked");
+                    Chunked
+                }
+                None => {
+                    keep_alive = false;
+                    UntilClose
+                }
+            }
+        };
+        if !keep_alive {
+            response.headers.set("Connection", "close");
+        } else if request.version == Http10 {
+            response.headers.set("Connection", "keep-alive");
+        }
+        self.write = WriteBody(framing, keep_alive);
+
+        let read = self.read;
+        match read {
+            // A refused upgrade: whatever followed the request is the next
+            // request, decoded once this response is complete.
+            AwaitUpgrade if keep_alive => self.read = ReadHead,
+            _ if !keep_alive => {
+                self.read = Discard;
+                self.pending.clear();
+            }
+            _ => {}
+        }
+        Ok((encode_response_head(&response), Continue))
+    }
+}
+
+fn parse_request(line: ~str, headers: Headers) -> IoResult<Request> {
+    let words: ~[&str] = line.split(' ').collect();
+    if words.len() != 3 || words[0].len() == 0 || words[1].len() == 0 {
+        return Err(malformed("A request line is malformed."));
+    }
+    Ok(Request {
+        method: words[0].to_owned(),
+        uri: words[1].to_owned(),
+        version: try!(parse_version(words[2])),
+        headers: headers
+    })
+}
+
+fn encode_response_head(response: &Response) -> ~[u8] {
+    let line = format!("{} {} {}", response.version.as_str(), response.status, response.reason);
+    encode_head(line, &response.headers)
+}
+
+fn out_of_order() -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "A response part was sent out of order.",
+        detail: None
+    }
+}
+
+impl Filter<~[u8], RequestPart, ResponsePart, ~[u8]> for HttpServerCodec {
+    fn down<U: PipelineUp<~[u8]>, D: PipelineDown<RequestPart>>(
+            &self, data: ~[u8], _: &U, down: &D) -> IoResult<()> {
+        let (parts, err) = self.decode(data);
+        for part in parts.move_iter() {
+            if self.closing() {
+                break;
+            }
+            try!(down.down(part));
+        }
+        match err {
+            Some(err) => Err(err),
+            None => Ok(())
+        }
+    }
+
+    fn up<U: PipelineUp<~[u8]>, D: PipelineDown<RequestPart>>(
+            &self, data: ResponsePart, up: &U, down: &D) -> IoResult<()> {
+        let (bytes, after) = try!(self.encode(data));
+        if bytes.len() > 0 {
+            try!(up.up(bytes));
+        }
+        match after {
+            Continue => Ok(()),
+            Resume => self.down(~[], up, down),
+            Close => up.up_event(Closed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HttpServerCodec;
+
+    use std::cell::RefCell;
+    use std::io;
+    use std::io::IoResult;
+    use std::str;
+
+    use sync::MutexArc;
+
+    use codec::http::Response;
+    use codec::http::{RequestPart, RequestHead, RequestBody, RequestEnd};
+    use codec::http::{ResponsePart, ResponseHead, ResponseBody, ResponseEnd};
+    use pipeline::{Closed, Filter, PipelineBuilder, PipelineDown, PipelineEvent, PipelineUp};
+
+    /// Records the request parts sent down and answers each complete request
+    /// with the next of `responses`, if any.
+    struct Requests {
+        requests: MutexArc<~[~str]>,
+        responses: RefCell<~[~[ResponsePart]]>
+    }
+
+    impl Filter<RequestPart, (), (), ResponsePart> for Requests {
+        fn down<U: PipelineUp<ResponsePart>, D: PipelineDown<()>>(
+                &self, data: RequestPart, up: &U, _: &D) -> IoResult<()> {
+            let record = match data {
+                RequestHead(request) => format!("{} {}", request.method, request.uri),
+                RequestBody(data) => {
+                    format!("body {}", unsafe { str::raw::from_utf8_owned(data) })
+                }
+                RequestEnd => ~"end"
+            };
+            let end = record.as_slice() == "end";
+            self.requests.access(|requests| requests.push(record.clone()));
+            if !end {
+                return Ok(());
+            }
+            match self.responses.borrow_mut().get().shift() {
+                Some(parts) => {
+                    for part in parts.move_iter() {
+                        try!(up.up(part));
+                    }
+                    Ok(())
+                }
+                None => Ok(())
+            }
+        }
+
+        fn up<U: PipelineUp<ResponsePart>, D: PipelineDown<()>>(
+                &self, _: (), _: &U, _: &D) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    struct Output {
+        output: MutexArc<~str>,
+        closed: MutexArc<bool>
+    }
+
+    impl PipelineUp<~[u8]> for Output {
+        fn up(&self, data: ~[u8]) -> IoResult<()> {
+            let data = unsafe { str::raw::from_utf8_owned(data) };
+            self.output.access(|output| output.push_str(data));
+            Ok(())
+        }
+
+        fn up_event(&self, event: PipelineEvent) -> IoResult<()> {
+            match event {
+                Closed => self.closed.access(|closed| *closed = true),
+                _ => {}
+            }
+            Ok(())
+        }
+    }
+
+    struct Server {
+        pipeline: ~PipelineDown<~[u8]>,
+        requests: MutexArc<~[~str]>,
+        output: MutexArc<~str>,
+        closed: MutexArc<bool>
+    }
+
+    impl Server {
+        fn requests(&self) -> ~[~str] {
+            self.requests.access(|requests| requests.clone())
+        }
+
+        fn output(&self) -> ~str {
+            self.output.access(|output| output.clone())
+        }
+
+        fn closed(&self) -> bool {
+            self.closed.access(|closed| *closed)
+        }
+    }
+
+    fn new_server(responses: ~[~[ResponsePart]]) -> Server {
+        let requests = MutexArc::new(~[]);
+        let output = MutexArc::new(~"");
+        let closed = MutexArc::new(false);
+        let stage = Requests { requests: requests.clone(), responses: RefCell::new(responses) };
+        let sink = ~Output { output: output.clone(), closed: closed.clone() };
+        let pipeline = PipelineBuilder::new(stage)
+            .filter(HttpServerCodec::new())
+            .build_with_sink(sink as ~PipelineUp<~[u8]>);
+        Server { pipeline: pipeline, requests: requests, output: output, closed: closed }
+    }
+
+    /// A `200 OK` response with a `Content-Length`.
+    fn ok(body: &str) -> ~[ResponsePart] {
+        let mut response = Response::new(200, "OK");
+        response.headers.set("Content-Length", body.len().to_str().as_slice());
+        ~[ResponseHead(response), ResponseBody(body.as_bytes().to_owned()), ResponseEnd]
+    }
+
+    /// A response without a body.
+    fn empty(status: u16, reason: &str) -> ~[ResponsePart] {
+        ~[ResponseHead(Response::new(status, reason)), ResponseEnd]
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let server = new_server(~[ok("a"), ok("b")]);
+        server.pipeline.down(bytes!("GET /a HTTP/1.1\r\n\r\n").to_owned()).unwrap();
+        server.pipeline.down(bytes!("GET /b HTTP/1.1\r\n\r\n").to_owned()).unwrap();
+        assert_eq!(server.requests(), ~[~"GET /a", ~"end", ~"GET /b", ~"end"]);
+        assert_eq!(server.output(), ~"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na\
+                                      HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb");
+        assert!(!server.closed());
+    }
+
+    #[test]
+    fn test_http10_keep_alive() {
+        let server = new_server(~[ok("a"), ok("b")]);
+        server.pipeline.down(
+            bytes!("GET /a HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").to_owned()).unwrap();
+        assert!(!server.closed());
+        server.pipeline.down(bytes!("GET /b HTTP/1.0\r\n\r\n").to_owned()).unwrap();
+        assert_eq!(server.output(),
+                   ~"HTTP/1.1 200 OK\r\nContent-Length: 1\r\nConnection: keep-alive\r\n\r\na\
+                     HTTP/1.1 200 OK\r\nContent-Length: 1\r\nConnection: close\r\n\r\nb");
+        assert!(server.closed());
+    }
+
+    #[test]
+    fn test_pipelining() {
+        let server = new_server(~[ok("a"), ok("b")]);
+        let input = bytes!("GET /a HTTP/1.1\r\n\r\n\
+                            POST /b HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi");
+        // Byte by byte, so heads and bodies are split across reads.
+        for byte in input.iter() {
+            server.pipeline.down(~[*byte]).unwrap();
+        }
+        assert_eq!(server.requests(),
+                   ~[~"GET /a", ~"end", ~"POST /b", ~"body h", ~"body i", ~"end"]);
+        assert_eq!(server.output(), ~"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na\
+                                      HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb");
+    }
+
+    #[test]
+    fn test_responses_without_bodies() {
+        let mut head = Response::new(200, "OK");
+        head.headers.set("Content-Length", "5");
+        let server = new_server(~[~[ResponseHead(head), ResponseEnd],
+                              empty(204, "No Content"),
+                              empty(304, "Not Modified")]);
+        server.pipeline.down(bytes!("HEAD /a HTTP/1.1\r\n\r\n\
+                                     GET /b HTTP/1.1\r\n\r\n\
+                                     GET /c HTTP/1.1\r\n\r\n").to_owned()).unwrap();
+        // Neither a body nor chunked framing follows any of the heads.
+        assert_eq!(server.output(), ~"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n\
+                                      HTTP/1.1 204 No Content\r\n\r\n\
+                                      HTTP/1.1 304 Not Modified\r\n\r\n");
+        assert!(!server.closed());
+    }
+
+    #[test]
+    fn test_chunked_request_with_trailers() {
+        let server = new_server(~[]);
+        server.pipeline.down(bytes!("POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                                     5;name=value\r\nhello\r\n1\r\n!\r\n0\r\n").to_owned())
+            .unwrap();
+        assert_eq!(server.requests(), ~[~"POST /a", ~"body hello", ~"body !"]);
+        server.pipeline.down(bytes!("X-Checksum: 1\r\nX-Other: 2\r\n\r\n\
+                                     GET /b HTTP/1.1\r\n\r\n").to_owned()).unwrap();
+        assert_eq!(server.requests(),
+                   ~[~"POST /a", ~"body hello", ~"body !", ~"end", ~"GET /b", ~"end"]);
+    }
+
+    #[test]
+    fn test_refused_upgrade_resumes() {
+        let server = new_server(~[ok("no"), ok("b")]);
+        server.pipeline.down(bytes!("GET /ws HTTP/1.1\r\nUpgrade: websocket\r\n\
+                                     Connection: Upgrade\r\n\r\n\
+                                     GET /b HTTP/1.1\r\n\r\n").to_owned()).unwrap();
+        // The request after the upgrade is held until the upgrade is refused.
+        assert_eq!(server.requests(), ~[~"GET /ws", ~"end", ~"GET /b", ~"end"]);
+        assert_eq!(server.output(), ~"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nno\
+                                      HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb");
+        assert!(!server.closed());
+    }
+
+    #[test]
+    fn test_request_connection_close() {
+        let server = new_server(~[ok("a"), ok("b")]);
+        server.pipeline.down(bytes!("GET /a HTTP/1.1\r\nConnection: close\r\n\r\n\
+                                     GET /b HTTP/1.1\r\n\r\n").to_owned()).unwrap();
+        assert_eq!(server.requests(), ~[~"GET /a", ~"end"]);
+        assert_eq!(server.output(),
+                   ~"HTTP/1.1 200 OK\r\nContent-Length: 1\r\nConnection: close\r\n\r\na");
+        assert!(server.closed());
+    }
+
+    #[test]
+    fn test_response_connection_close_drops_pipelined() {
+        let mut close = ok("a");
+        match close[0] {
+            ResponseHead(ref mut response) => response.headers.set("Connection", "close"),
+            _ => fail!()
+        }
+        let server = new_server(~[close, ok("b")]);
+        server.pipeline.down(bytes!("GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n").to_owned())
+            .unwrap();
+        server.pipeline.down(bytes!("GET /c HTTP/1.1\r\n\r\n").to_owned()).unwrap();
+        // The requests after the one that closed the connection are dropped
+        // without being sent down.
+        assert_eq!(server.requests(), ~[~"GET /a", ~"end"]);
+        assert_eq!(server.output(),
+                   ~"HTTP/1.1 200 OK\r\nContent-Length: 1\r\nConnection: close\r\n\r\na");
+        assert!(server.closed());
+    }
+
+    #[test]
+    fn test_content_length_fields() {
+        let server = new_server(~[ok("a")]);
+        server.pipeline.down(bytes!("POST /a HTTP/1.1\r\nContent-Length: 2\r\n\
+                                     Content-Length: 2, 2\r\n\r\nhi").to_owned()).unwrap();
+        assert_eq!(server.requests(), ~[~"POST /a", ~"body hi", ~"end"]);
+
+        let server = new_server(~[]);
+        let err = server.pipeline.down(bytes!("POST /a HTTP/1.1\r\nContent-Length: 2\r\n\
+                                               Content-Length: 3\r\n\r\nhi").to_owned())
+            .unwrap_err();
+        assert_eq!(err.kind, io::InvalidInput);
+
+        let server = new_server(~[]);
+        let err = server.pipeline.down(bytes!("POST /a HTTP/1.1\r\n\
+                                               Content-Length: 2, 3\r\n\r\nhi").to_owned())
+            .unwrap_err();
+        assert_eq!(err.kind, io::InvalidInput);
+        assert_eq!(server.requests().len(), 0);
+    }
+
+    #[test]
+    fn test_whitespace_before_colon() {
+        let server = new_server(~[]);
+        let err = server.pipeline.down(bytes!("GET /a HTTP/1.1\r\nHost : x\r\n\r\n").to_owned())
+            .unwrap_err();
+        assert_eq!(err.kind, io::InvalidInput);
+        assert_eq!(server.requests().len(), 0);
+    }
+
+    #[test]
+    fn test_requests_before_error_are_answered() {
+        let server = new_server(~[ok("a")]);
+        let err = server.pipeline.down(bytes!("GET /a HTTP/1.1\r\n\r\n\
+                                               GET /b HTTP/1.1\r\nHost : x\r\n\r\n").to_owned())
+            .unwrap_err();
+        assert_eq!(err.kind, io::InvalidInput);
+        assert_eq!(server.requests(), ~[~"GET /a", ~"end"]);
+        assert_eq!(server.output(), ~"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na");
+    }
+}
@@ -0,0 +1,381 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Frames preceded by their length.
+//!
+//! Each frame starts with a header of `header_offset` bytes that the codec
+//! ignores, followed by the length field itself. The length field holds the
+//! number of bytes following it minus `length_adjustment`, so a protocol whose
+//! length field counts itself as well as the frame body would use an
+//! adjustment of minus the field's width.
+
+use std::cell::RefCell;
+use std::i64;
+use std::io;
+use std::io::{IoResult, IoError};
+use std::vec;
+
+use pipeline::{Filter, PipelineDown, PipelineUp};
+
+static DEFAULT_MAX_FRAME_SIZE: uint = 8 * 1024 * 1024;
+
+#[deriving(Clone, Eq)]
+pub enum Endianness {
+    BigEndian,
+    LittleEndian
+}
+
+/// Reassembles frames from the bytes sent down and sends them down without
+/// their headers. Frames sent up are given a header, with any bytes before the
+/// length field set to zero.
+pub struct LengthDelimitedCodec {
+    priv length_width: uint,
+    priv endianness: Endianness,
+    priv length_adjustment: int,
+    priv header_offset: uint,
+    priv max_frame_size: uint,
+    priv buf: RefCell<~[u8]>
+}
+
+impl LengthDelimitedCodec {
+    /// Frames preceded by a 4 byte big-endian length of up to 8MiB.
+    pub fn new() -> LengthDelimitedCodec {
+        LengthDelimitedCodec {
+            length_width: 4,
+            endianness: BigEndian,
+            length_adjustment: 0,
+            header_offset: 0,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            buf: RefCell::new(~[])
+        }
+    }
+
+    /// The width of the length field in bytes: 1, 2, 4 or 8.
+    pub fn length_width(mut self, width: uint) -> LengthDelimitedCodec {
+        assert!(width == 1 || width == 2 || width == 4 || width == 8);
+        self.length_width = width;
+        self
+    }
+
+    pub fn endianness(mut self, endianness: Endianness) -> LengthDelimitedCodec {
+        self.endianness = endianness;
+        self
+    }
+
+    /// Added to the value of the length field to give the number of bytes
+    /// that follow it.
+    pub fn length_adjustment(mut self, adjustment: int) -> LengthDelimitedCodec {
+        self.length_adjustment = adjustment;
+        self
+    }
+
+    /// The number of bytes before the length field.
+    pub fn header_offset(mut self, offset: uint) -> LengthDelimitedCodec {
+        self.header_offset = offset;
+        self
+    }
+
+    /// The largest frame, not counting its header, that will be accepted or
+    /// sent.
+    pub fn max_frame_size(mut self, size: uint) -> LengthDelimitedCodec {
+        self.max_frame_size = size;
+        self
+    }
+
+    fn header_len(&self) -> uint {
+        self.header_offset + self.length_width
+    }
+
+    fn read_length(&self, field: &[u8]) -> u64 {
+        let mut value = 0u64;
+        for i in range(0, field.len()) {
+            let byte = match self.endianness {
+                BigEndian => field[i],
+                LittleEndian => field[field.len() - 1 - i]
+            };
+            value = (value << 8) | byte as u64;
+        }
+        value
+    }
+
+    fn write_length(&self, value: u64, out: &mut ~[u8]) {
+        for i in range(0, self.length_width) {
+            let shift = match self.endianness {
+                BigEndian => (self.length_width - 1 - i) * 8,
+                LittleEndian => i * 8
+            };
+            out.push((value >> shift) as u8);
+        }
+    }
+
+    /// The size of the frame body described by a length field.
+    fn frame_size(&self, field: &[u8]) -> IoResult<uint> {
+        let value = self.read_length(field);
+        if value > i64::MAX as u64 {
+            return Err(invalid_length());
+        }
+        let size = value as i64 + self.length_adjustment as i64;
+        if size < 0 {
+            Err(invalid_length())
+        } else if size as u64 > self.max_frame_size as u64 {
+            Err(too_large(self.max_frame_size))
+        } else {
+            Ok(size as uint)
+        }
+    }
+
+    /// Add `data` to the buffer and split off any complete frames. On error
+    /// the buffer is discarded, and the frames split off before the error are
+    /// returned along with it.
+    fn decode(&self, data: &[u8]) -> (~[~[u8]], Option<IoError>) {
+        let mut buf = self.buf.borrow_mut();
+        let buf = buf.get();
+        buf.push_all(data);
+
+        let header_len = self.header_len();
+        let mut frames = ~[];
+        let mut start = 0;
+        while buf.len() - start >= header_len {
+            let field = buf.slice(start + self.header_offset, start + header_len);
+            let size = match self.frame_size(field) {
+                Ok(size) => size,
+                Err(err) => {
+                    buf.clear();
+                    return (frames, Some(err));
+                }
+            };
+            if buf.len() - start < header_len + size {
+                break;
+            }
+            frames.push(buf.slice(start + header_len, start + header_len + size).to_owned());
+            start += header_len + size;
+        }
+        *buf = buf.slice_from(start).to_owned();
+        (frames, None)
+    }
+
+    fn encode(&self, frame: &[u8]) -> IoResult<~[u8]> {
+        if frame.len() > self.max_frame_size {
+            return Err(too_large(self.max_frame_size));
+        }
+        let value = frame.len() as i64 - self.length_adjustment as i64;
+        let max_value = if self.length_width == 8 {
+            i64::MAX
+        } else {
+            (1i64 << (self.length_width * 8)) - 1
+        };
+        if value < 0 || value > max_value {
+            return Err(invalid_length());
+        }
+
+        let mut out = vec::with_capacity(self.header_len() + frame.len());
+        out.grow(self.header_offset, &0u8);
+        self.write_length(value as u64, &mut out);
+        out.push_all(frame);
+        Ok(out)
+    }
+}
+
+fn invalid_length() -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "A frame length is out of range.",
+        detail: None
+    }
+}
+
+fn too_large(max_frame_size: uint) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "A frame is larger than the maximum frame size.",
+        detail: Some(format!("maximum frame size: {}", max_frame_size))
+    }
+}
+
+impl Filter<~[u8], ~[u8], ~[u8], ~[u8]> for LengthDelimitedCodec {
+    fn down<U: PipelineUp<~[u8]>, D: PipelineDown<~[u8]>>(
+            &self, data: ~[u8], _: &U, down: &D) -> IoResult<()> {
+        let (frames, err) = self.decode(data);
+        for frame in frames.move_iter() {
+            try!(down.down(frame));
+        }
+        match err {
+            Some(err) => Err(err),
+            None => Ok(())
+        }
+    }
+
+    fn up<U: PipelineUp<~[u8]>, D: PipelineDown<~[u8]>>(
+            &self, data: ~[u8], up: &U, _: &D) -> IoResult<()> {
+        up.up(try!(self.encode(data)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LengthDelimitedCodec, BigEndian, LittleEndian};
+
+    use std::io;
+    use std::io::IoResult;
+
+    use sync::MutexArc;
+
+    use pipeline::{Filter, PipelineBuilder, PipelineDown, PipelineUp};
+
+    /// Records each frame sent down and sends it back up.
+    struct Frames {
+        frames: MutexArc<~[~[u8]]>
+    }
+
+    impl Filter<~[u8], (), (), ~[u8]> for Frames {
+        fn down<U: PipelineUp<~[u8]>, D: PipelineDown<()>>(
+                &self, data: ~[u8], up: &U, _: &D) -> IoResult<()> {
+            self.frames.access(|frames| frames.push(data.clone()));
+            up.up(data)
+        }
+
+        fn up<U: PipelineUp<~[u8]>, D: PipelineDown<()>>(
+                &self, _: (), _: &U, _: &D) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    struct Collect {
+        output: MutexArc<~[u8]>
+    }
+
+    impl PipelineUp<~[u8]> for Collect {
+        fn up(&self, data: ~[u8]) -> IoResult<()> {
+            self.output.access(|output| output.push_all(data));
+            Ok(())
+        }
+    }
+
+    fn frame_pipeline(
+            codec: LengthDelimitedCodec,
+            frames: &MutexArc<~[~[u8]]>,
+            output: &MutexArc<~[u8]>) -> ~PipelineDown<~[u8]> {
+        PipelineBuilder::new(Frames { frames: frames.clone() })
+            .filter(codec)
+            .build_with_sink(~Collect { output: output.clone() } as ~PipelineUp<~[u8]>)
+    }
+
+    #[test]
+    fn test_reassembly_across_partial_reads() {
+        let frames = MutexArc::new(~[]);
+        let output = MutexArc::new(~[]);
+        let pipeline = frame_pipeline(LengthDelimitedCodec::new(), &frames, &output);
+        let input = bytes!(0, 0, 0, 5, "hello", 0, 0, 0, 0, 0, 0, 0, 2, "hi");
+        for (i, byte) in input.iter().enumerate() {
+            pipeline.down(~[*byte]).unwrap();
+            let expected = if i < 8 { 0 } else if i < 12 { 1 } else if i < 18 { 2 } else { 3 };
+            assert_eq!(frames.access(|f| f.len()), expected);
+        }
+        assert_eq!(frames.access(|f| f.clone()),
+                   ~[bytes!("hello").to_owned(), ~[], bytes!("hi").to_owned()]);
+        assert_eq!(output.access(|o| o.clone()), input.to_owned());
+    }
+
+    #[test]
+    fn test_several_frames_in_one_read() {
+        let frames = MutexArc::new(~[]);
+        let output = MutexArc::new(~[]);
+        let pipeline = frame_pipeline(LengthDelimitedCodec::new(), &frames, &output);
+        pipeline.down(bytes!(0, 0, 0, 1, "a", 0, 0, 0, 1, "b", 0, 0).to_owned()).unwrap();
+        assert_eq!(frames.access(|f| f.clone()), ~[~['a' as u8], ~['b' as u8]]);
+        pipeline.down(bytes!(0, 1, "c").to_owned()).unwrap();
+        assert_eq!(frames.access(|f| f.len()), 3);
+    }
+
+    #[test]
+    fn test_widths_and_endianness() {
+        let cases = [
+            (1u, BigEndian, bytes!(3)),
+            (1u, LittleEndian, bytes!(3)),
+            (2u, BigEndian, bytes!(0, 3)),
+            (2u, LittleEndian, bytes!(3, 0)),
+            (4u, BigEndian, bytes!(0, 0, 0, 3)),
+            (4u, LittleEndian, bytes!(3, 0, 0, 0)),
+            (8u, BigEndian, bytes!(0, 0, 0, 0, 0, 0, 0, 3)),
+            (8u, LittleEndian, bytes!(3, 0, 0, 0, 0, 0, 0, 0))
+        ];
+        for &(width, endianness, header) in cases.iter() {
+            let frames = MutexArc::new(~[]);
+            let output = MutexArc::new(~[]);
+            let codec = LengthDelimitedCodec::new().length_width(width).endianness(endianness);
+            let pipeline = frame_pipeline(codec, &frames, &output);
+            let mut input = header.to_owned();
+            input.push_all(bytes!("abc"));
+            pipeline.down(input.clone()).unwrap();
+            assert_eq!(frames.access(|f| f.clone()), ~[bytes!("abc").to_owned()]);
+            assert_eq!(output.access(|o| o.clone()), input);
+        }
+    }
+
+    #[test]
+    fn test_width_limits_encoding() {
+        let codec = LengthDelimitedCodec::new().length_width(1);
+        let mut frame = ~[];
+        frame.grow(255, &0u8);
+        assert_eq!(codec.encode(frame).unwrap().len(), 256);
+        frame.push(0);
+        assert_eq!(codec.encode(frame).unwrap_err().kind, io::InvalidInput);
+    }
+
+    #[test]
+    fn test_negative_length_adjustment() {
+        let frames = MutexArc::new(~[]);
+        let output = MutexArc::new(~[]);
+        // The length field counts itself.
+        let codec = LengthDelimitedCodec::new().length_width(2).length_adjustment(-2);
+        let pipeline = frame_pipeline(codec, &frames, &output);
+        pipeline.down(bytes!(0, 7, "hello").to_owned()).unwrap();
+        assert_eq!(frames.access(|f| f.clone()), ~[bytes!("hello").to_owned()]);
+        assert_eq!(output.access(|o| o.clone()), bytes!(0, 7, "hello").to_owned());
+
+        // A length smaller than the field itself is invalid.
+        let err = pipeline.down(bytes!(0, 1).to_owned()).unwrap_err();
+        assert_eq!(err.kind, io::InvalidInput);
+    }
+
+    #[test]
+    fn test_header_offset() {
+        let frames = MutexArc::new(~[]);
+        let output = MutexArc::new(~[]);
+        let codec = LengthDelimitedCodec::new().header_offset(2);
+        let pipeline = frame_pipeline(codec, &frames, &output);
+        pipeline.down(bytes!(0xaa, 0xbb, 0, 0).to_owned()).unwrap();
+        pipeline.down(bytes!(0, 3, "abc").to_owned()).unwrap();
+        assert_eq!(frames.access(|f| f.clone()), ~[bytes!("abc").to_owned()]);
+        // The bytes before the length field are zero going up.
+        assert_eq!(output.access(|o| o.clone()), bytes!(0, 0, 0, 0, 0, 3, "abc").to_owned());
+    }
+
+    #[test]
+    fn test_oversize_frame_rejected_before_body() {
+        let frames = MutexArc::new(~[]);
+        let output = MutexArc::new(~[]);
+        let codec = LengthDelimitedCodec::new().max_frame_size(4);
+        let pipeline = frame_pipeline(codec, &frames, &output);
+        pipeline.down(bytes!(0, 0, 0, 4, "abcd").to_owned()).unwrap();
+        // Only the header of the oversize frame has arrived.
+        let err = pipeline.down(bytes!(0, 0, 0, 5).to_owned()).unwrap_err();
+        assert_eq!(err.kind, io::InvalidInput);
+        assert_eq!(frames.access(|f| f.len()), 1);
+    }
+
+    #[test]
+    fn test_frames_before_error_are_sent() {
+        let frames = MutexArc::new(~[]);
+        let output = MutexArc::new(~[]);
+        let codec = LengthDelimitedCodec::new().max_frame_size(4);
+        let pipeline = frame_pipeline(codec, &frames, &output);
+        let err = pipeline.down(bytes!(0, 0, 0, 2, "ab", 0, 0, 0, 5).to_owned()).unwrap_err();
+        assert_eq!(err.kind, io::InvalidInput);
+        assert_eq!(frames.access(|f| f.clone()), ~[bytes!("ab").to_owned()]);
+        assert_eq!(output.access(|o| o.clone()), bytes!(0, 0, 0, 2, "ab").to_owned());
+    }
+}
@@ -0,0 +1,327 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Newline delimited text.
+
+use std::cell::RefCell;
+use std::io;
+use std::io::{IoResult, IoError};
+use std::str;
+use std::util;
+
+use codec::find;
+use pipeline::{Filter, PipelineDown, PipelineEvent, PipelineUp, ReadEof};
+
+static DEFAULT_MAX_LENGTH: uint = 8 * 1024;
+
+#[deriving(Clone, Eq)]
+pub enum LineEnding {
+    /// Lines end with `\n`.
+    Lf,
+    /// Lines end with `\r\n`.
+    CrLf
+}
+
+impl LineEnding {
+    fn delimiter(&self) -> &'static [u8] {
+        match *self {
+            Lf => bytes!("\n"),
+            CrLf => bytes!("\r\n")
+        }
+    }
+}
+
+/// Splits the bytes sent down into lines, without their line endings, and
+/// sends lines that are sent up out with a line ending appended. A line sent
+/// up that contains the line ending is an error.
+///
+/// A line longer than the maximum length is an error. Anything left over
+/// when the peer shuts down its side is sent down as a final line.
+pub struct LineCodec {
+    priv ending: LineEnding,
+    priv max_length: uint,
+    priv buf: RefCell<~[u8]>
+}
+
+impl LineCodec {
+    /// `\n` terminated lines of up to 8KiB.
+    pub fn new() -> LineCodec {
+        LineCodec::with_options(Lf, DEFAULT_MAX_LENGTH)
+    }
+
+    /// Lines terminated by `ending` of up to `max_length` bytes, not counting
+    /// the line ending.
+    pub fn with_options(ending: LineEnding, max_length: uint) -> LineCodec {
+        LineCodec {
+            ending: ending,
+            max_length: max_length,
+            buf: RefCell::new(~[])
+        }
+    }
+
+    /// Add `data` to the buffer and split off any complete lines. On error the
+    /// buffer is discarded, and the lines split off before the error are
+    /// returned along with it.
+    fn decode(&self, data: &[u8]) -> (~[~str], Option<IoError>) {
+        let mut buf = self.buf.borrow_mut();
+        let buf = buf.get();
+        buf.push_all(data);
+
+        let delimiter = self.ending.delimiter();
+        let mut lines = ~[];
+        let mut start = 0;
+        loop {
+            let end = match find(buf.slice_from(start), delimiter) {
+                Some(i) => start + i,
+                None => break
+            };
+            let res = if end - start > self.max_length {
+                Err(too_long(self.max_length))
+            } else {
+                to_line(buf.slice(start, end))
+            };
+            match res {
+                Ok(line) => lines.push(line),
+                Err(err) => {
+                    buf.clear();
+                    return (lines, Some(err));
+                }
+            }
+            start = end + delimiter.len();
+        }
+
+        // The remainder may end with all but the last byte of a delimiter.
+        *buf = buf.slice_from(start).to_owned();
+        if buf.len() > self.max_length + delimiter.len() - 1 {
+            buf.clear();
+            return (lines, Some(too_long(self.max_length)));
+        }
+        (lines, None)
+    }
+}
+
+fn to_line(bytes: &[u8]) -> IoResult<~str> {
+    if str::is_utf8(bytes) {
+        Ok(unsafe { str::raw::from_utf8_owned(bytes.to_owned()) })
+    } else {
+        Err(IoError {
+            kind: io::InvalidInput,
+            desc: "A line is not valid UTF-8.",
+            detail: None
+        })
+    }
+}
+
+fn embedded_ending() -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "A line contains a line ending.",
+        detail: None
+    }
+}
+
+fn too_long(max_length: uint) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "A line is longer than the maximum length.",
+        detail: Some(format!("maximum length: {}", max_length))
+    }
+}
+
+impl Filter<~[u8], ~str, ~str, ~[u8]> for LineCodec {
+    fn down<U: PipelineUp<~[u8]>, D: PipelineDown<~str>>(
+            &self, data: ~[u8], _: &U, down: &D) -> IoResult<()> {
+        let (lines, err) = self.decode(data);
+        for line in lines.move_iter() {
+            try!(down.down(line));
+        }
+        match err {
+            Some(err) => Err(err),
+            None => Ok(())
+        }
+    }
+
+    fn up<U: PipelineUp<~[u8]>, D: PipelineDown<~str>>(
+            &self, data: ~str, up: &U, _: &D) -> IoResult<()> {
+        let delimiter = self.ending.delimiter();
+        let mut bytes = data.into_bytes();
+        if find(bytes, delimiter).is_some() {
+            return Err(embedded_ending());
+        }
+        bytes.push_all(delimiter);
+        up.up(bytes)
+    }
+
+    fn down_event<U: PipelineUp<~[u8]>, D: PipelineDown<~str>>(
+            &self, event: PipelineEvent, _: &U, down: &D) -> IoResult<()> {
+        match event {
+            ReadEof => {
+                let rest = util::replace(self.buf.borrow_mut().get(), ~[]);
+                if rest.len() > 0 {
+                    try!(down.down(try!(to_line(rest))));
+                }
+            }
+            _ => {}
+        }
+        down.down_event(event)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LineCodec, Lf, CrLf};
+
+    use std::io;
+    use std::io::IoResult;
+
+    use sync::MutexArc;
+
+    use pipeline::{Filter, PipelineBuilder, PipelineDown, PipelineUp, ReadEof};
+
+    /// Records each line sent down and sends it back up with `|` replaced
+    /// by `\n`.
+    struct Lines {
+        lines: MutexArc<~[~str]>
+    }
+
+    impl Filter<~str, (), (), ~str> for Lines {
+        fn down<U: PipelineUp<~str>, D: PipelineDown<()>>(
+                &self, data: ~str, up: &U, _: &D) -> IoResult<()> {
+            self.lines.access(|lines| lines.push(data.clone()));
+            up.up(data.replace("|", "\n"))
+        }
+
+        fn up<U: PipelineUp<~str>, D: PipelineDown<()>>(
+                &self, _: (), _: &U, _: &D) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    struct Collect {
+        output: MutexArc<~[u8]>
+    }
+
+    impl PipelineUp<~[u8]> for Collect {
+        fn up(&self, data: ~[u8]) -> IoResult<()> {
+            self.output.access(|output| output.push_all(data));
+            Ok(())
+        }
+    }
+
+    fn line_pipeline(
+            codec: LineCodec,
+            lines: &MutexArc<~[~str]>,
+            output: &MutexArc<~[u8]>) -> ~PipelineDown<~[u8]> {
+        PipelineBuilder::new(Lines { lines: lines.clone() })
+            .filter(codec)
+            .build_with_sink(~Collect { output: output.clone() } as ~PipelineUp<~[u8]>)
+    }
+
+    #[test]
+    fn test_line_split_across_chunks() {
+        let lines = MutexArc::new(~[]);
+        let output = MutexArc::new(~[]);
+        let pipeline = line_pipeline(LineCodec::new(), &lines, &output);
+        pipeline.down(bytes!("hel").to_owned()).unwrap();
+        pipeline.down(bytes!("lo\nwor").to_owned()).unwrap();
+        assert_eq!(lines.access(|l| l.clone()), ~[~"hello"]);
+        pipeline.down(bytes!("ld\n\n").to_owned()).unwrap();
+        assert_eq!(lines.access(|l| l.clone()), ~[~"hello", ~"world", ~""]);
+        assert_eq!(output.access(|o| o.clone()), bytes!("hello\nworld\n\n").to_owned());
+    }
+
+    #[test]
+    fn test_crlf_split_across_chunks() {
+        let lines = MutexArc::new(~[]);
+        let output = MutexArc::new(~[]);
+        let pipeline = line_pipeline(LineCodec::with_options(CrLf, 16), &lines, &output);
+        pipeline.down(bytes!("one\r").to_owned()).unwrap();
+        assert_eq!(lines.access(|l| l.len()), 0);
+        pipeline.down(bytes!("\na\nb\r\n").to_owned()).unwrap();
+        assert_eq!(lines.access(|l| l.clone()), ~[~"one", ~"a\nb"]);
+        assert_eq!(output.access(|o| o.clone()), bytes!("one\r\na\nb\r\n").to_owned());
+    }
+
+    #[test]
+    fn test_max_length() {
+        let lines = MutexArc::new(~[]);
+        let output = MutexArc::new(~[]);
+        let pipeline = line_pipeline(LineCodec::with_options(Lf, 4), &lines, &output);
+        pipeline.down(bytes!("abcd\n").to_owned()).unwrap();
+        let err = pipeline.down(bytes!("abcde\n").to_owned()).unwrap_err();
+        assert_eq!(err.kind, io::InvalidInput);
+        let err = pipeline.down(bytes!("abcde").to_owned()).unwrap_err();
+        assert_eq!(err.kind, io::InvalidInput);
+        assert_eq!(lines.access(|l| l.clone()), ~[~"abcd"]);
+    }
+
+    #[test]
+    fn test_max_length_crlf_partial_ending() {
+        let lines = MutexArc::new(~[]);
+        let output = MutexArc::new(~[]);
+        let pipeline = line_pipeline(LineCodec::with_options(CrLf, 4), &lines, &output);
+        // A full length line followed by half of its line ending is fine.
+        pipeline.down(bytes!("abcd\r").to_owned()).unwrap();
+        pipeline.down(bytes!("\n").to_owned()).unwrap();
+        assert_eq!(lines.access(|l| l.clone()), ~[~"abcd"]);
+        let err = pipeline.down(bytes!("abcde\r").to_owned()).unwrap_err();
+        assert_eq!(err.kind, io::InvalidInput);
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let lines = MutexArc::new(~[]);
+        let output = MutexArc::new(~[]);
+        let pipeline = line_pipeline(LineCodec::new(), &lines, &output);
+        let err = pipeline.down(~[0x61, 0xff, 0x0a]).unwrap_err();
+        assert_eq!(err.kind, io::InvalidInput);
+        // The bad line is discarded and decoding carries on.
+        pipeline.down(bytes!("ok\n").to_owned()).unwrap();
+        assert_eq!(lines.access(|l| l.clone()), ~[~"ok"]);
+    }
+
+    #[test]
+    fn test_lines_before_error_are_sent() {
+        let lines = MutexArc::new(~[]);
+        let output = MutexArc::new(~[]);
+        let pipeline = line_pipeline(LineCodec::with_options(Lf, 4), &lines, &output);
+        let err = pipeline.down(~[0x61, 0x0a, 0xff, 0x0a]).unwrap_err();
+        assert_eq!(err.kind, io::InvalidInput);
+        let err = pipeline.down(bytes!("b\nccccc\n").to_owned()).unwrap_err();
+        assert_eq!(err.kind, io::InvalidInput);
+        assert_eq!(lines.access(|l| l.clone()), ~[~"a", ~"b"]);
+        assert_eq!(output.access(|o| o.clone()), bytes!("a\nb\n").to_owned());
+    }
+
+    #[test]
+    fn test_flush_on_read_eof() {
+        let lines = MutexArc::new(~[]);
+        let output = MutexArc::new(~[]);
+        let pipeline = line_pipeline(LineCodec::new(), &lines, &output);
+        pipeline.down(bytes!("one\ntw").to_owned()).unwrap();
+        pipeline.down(bytes!("o").to_owned()).unwrap();
+        pipeline.down_event(ReadEof).unwrap();
+        assert_eq!(lines.access(|l| l.clone()), ~[~"one", ~"two"]);
+        // Nothing is left to send a second time.
+        pipeline.down_event(ReadEof).unwrap();
+        assert_eq!(lines.access(|l| l.len()), 2);
+    }
+
+    #[test]
+    fn test_embedded_line_ending_rejected() {
+        let lines = MutexArc::new(~[]);
+        let output = MutexArc::new(~[]);
+        let pipeline = line_pipeline(LineCodec::new(), &lines, &output);
+        let err = pipeline.down(bytes!("a|b\n").to_owned()).unwrap_err();
+        assert_eq!(err.kind, io::InvalidInput);
+        assert_eq!(output.access(|o| o.len()), 0);
+
+        // In CrLf mode only `\r\n` is a line ending.
+        let pipeline = line_pipeline(LineCodec::with_options(CrLf, 16), &lines, &output);
+        pipeline.down(bytes!("a|b\r\n").to_owned()).unwrap();
+        assert_eq!(output.access(|o| o.clone()), bytes!("a\nb\r\n").to_owned());
+    }
+}
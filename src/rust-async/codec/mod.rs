@@ -0,0 +1,27 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Codecs for common protocols, each implemented as a `pipeline::Filter`.
+//!
+//! A codec sits between the connection, which sends bytes down and expects
+//! bytes to be sent up, and the filters that deal in messages: it decodes the
+//! byte chunks travelling down into messages and encodes the messages
+//! travelling up into bytes. Malformed input is reported as an `InvalidInput`
+//! error, which closes the connection unless a filter above handles it.
+
+pub mod http;
+pub mod length_delimited;
+pub mod line;
+pub mod resp;
+pub mod websocket;
+
+/// The position of the first occurrence of `needle` in `haystack`.
+pub fn find(haystack: &[u8], needle: &[u8]) -> Option<uint> {
+    if needle.len() == 0 {
+        return Some(0);
+    }
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
@@ -0,0 +1,631 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The Redis serialization protocol, RESP2 and RESP3.
+//!
+//! Both versions are decoded; the nulls of RESP2, `$-1` and `*-1`, become
+//! `Null`. Aggregates are assembled as their elements arrive, so a large
+//! array split across many reads is never parsed twice. Values sent up are
+//! encoded as RESP3 unless the codec is set to RESP2, in which case the
+//! RESP3-only types are sent as their nearest RESP2 equivalent. Attributes
+//! and inline commands are not supported.
+
+use std::cell::RefCell;
+use std::from_str::from_str;
+use std::io;
+use std::io::{IoResult, IoError};
+use std::str;
+
+use codec::find;
+use pipeline::{Filter, PipelineDown, PipelineUp};
+
+static DEFAULT_MAX_BULK_LENGTH: uint = 512 * 1024 * 1024;
+static DEFAULT_MAX_DEPTH: uint = 64;
+/// The longest line, other than the contents of a bulk string, that will be
+/// accepted.
+static MAX_LINE_LENGTH: uint = 64 * 1024;
+
+#[deriving(Clone, Eq)]
+pub enum Protocol {
+    Resp2,
+    Resp3
+}
+
+#[deriving(Clone, Eq)]
+pub enum Value {
+    SimpleString(~str),
+    Error(~str),
+    Integer(i64),
+    BulkString(~[u8]),
+    Array(~[Value]),
+    /// Key and value pairs in the order they were received.
+    Map(~[(Value, Value)]),
+    Set(~[Value]),
+    /// Out of band data, such as a pub/sub message.
+    Push(~[Value]),
+    Null,
+    Boolean(bool),
+    Double(f64),
+    /// An integer too large for `Integer`, in decimal.
+    BigNumber(~str),
+    BulkError(~str),
+    /// A bulk string with a three letter format, such as `txt`.
+    Verbatim(~str, ~[u8])
+}
+
+enum Aggregate {
+    ArrayOf,
+    MapOf,
+    SetOf,
+    PushOf
+}
+
+/// An aggregate whose elements are still arriving.
+struct Partial {
+    kind: Aggregate,
+    /// The number of values still to come; twice the number of pairs for a
+    /// map.
+    remaining: u64,
+    items: ~[Value]
+}
+
+impl Partial {
+    fn finish(self) -> Value {
+        let Partial { kind, items, .. } = self;
+        match kind {
+            ArrayOf => Array(items),
+            SetOf => Set(items),
+            PushOf => Push(items),
+            MapOf => {
+                let mut pairs = ~[];
+                let mut items = items.move_iter();
+                loop {
+                    match (items.next(), items.next()) {
+                        (Some(key), Some(value)) => pairs.push((key, value)),
+                        _ => break
+                    }
+                }
+                Map(pairs)
+            }
+        }
+    }
+}
+
+/// What the start of the buffer holds.
+enum Element {
+    Complete(Value),
+    /// The header of an aggregate with this many values.
+    Start(Aggregate, u64)
+}
+
+struct RespState {
+    buf: ~[u8],
+    /// The aggregates being assembled, outermost first.
+    stack: ~[Partial]
+}
+
+/// Decodes the bytes sent down into `Value`s and encodes the `Value`s sent up
+/// into bytes.
+pub struct RespCodec {
+    priv protocol: Protocol,
+    priv max_bulk_length: uint,
+    priv max_depth: uint,
+    priv state: RefCell<RespState>
+}
+
+impl RespCodec {
+    /// Sends RESP3 and accepts bulk strings of up to 512MiB and aggregates
+    /// nested up to 64 deep.
+    pub fn new() -> RespCodec {
+        RespCodec {
+            protocol: Resp3,
+            max_bulk_length: DEFAULT_MAX_BULK_LENGTH,
+            max_depth: DEFAULT_MAX_DEPTH,
+            state: RefCell::new(RespState { buf: ~[], stack: ~[] })
+        }
+    }
+
+    /// The protocol version that values sent up are encoded in.
+    pub fn protocol(mut self, protocol: Protocol) -> RespCodec {
+        self.protocol = protocol;
+        self
+    }
+
+    /// The longest bulk string, bulk error or verbatim string that will be
+    /// accepted.
+    pub fn max_bulk_length(mut self, length: uint) -> RespCodec {
+        self.max_bulk_length = length;
+        self
+    }
+
+    /// How deeply aggregates may be nested. An array of integers has a depth
+    /// of one.
+    pub fn max_depth(mut self, depth: uint) -> RespCodec {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Add `data` to the buffer and decode as many values as possible into
+    /// `values`. On error the buffer and any partly assembled aggregates are
+    /// discarded, while the values decoded before the error are kept.
+    fn decode(&self, data: &[u8], values: &mut ~[Value]) -> IoResult<()> {
+        let mut state = self.state.borrow_mut();
+        let state = state.get();
+        state.buf.push_all(data);
+        match self.decode_values(state, values) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                state.buf.clear();
+                state.stack.clear();
+                Err(err)
+            }
+        }
+    }
+
+    fn decode_values(&self, state: &mut RespState, values: &mut ~[Value]) -> IoResult<()> {
+        let mut start = 0;
+        loop {
+            let (element, len) = match try!(self.parse_element(state.buf.slice_from(start))) {
+                Some(element) => element,
+                None => break
+            };
+            start += len;
+            let mut value = match element {
+                Complete(value) => value,
+                Start(kind, count) => {
+                    if state.stack.len() >= self.max_depth {
+                        return Err(IoError {
+                            kind: io::InvalidInput,
+                            desc: "A value is nested more deeply than the maximum depth.",
+                            detail: Some(format!("maximum depth: {}", self.max_depth))
+                        });
+                    }
+                    let partial = Partial { kind: kind, remaining: count, items: ~[] };
+                    if count > 0 {
+                        state.stack.push(partial);
+                        continue;
+                    }
+                    partial.finish()
+                }
+            };
+
+            // Add the value to the aggregates it completes.
+            loop {
+                let mut partial = match state.stack.pop() {
+                    Some(partial) => partial,
+                    None => {
+                        values.push(value);
+                        break;
+                    }
+                };
+                partial.items.push(value);
+                partial.remaining -= 1;
+                if partial.remaining > 0 {
+                    state.stack.push(partial);
+                    break;
+                }
+                value = partial.finish();
+            }
+        }
+        // While a large value arrives in pieces nothing is consumed, and the
+        // buffer is left alone rather than copied on every read.
+        if start > 0 {
+            state.buf = state.buf.slice_from(start).to_owned();
+        }
+        Ok(())
+    }
+
+    /// Parse the element at the front of `buf`, returning it and its length,
+    /// or `None` if it is not complete yet.
+    fn parse_element(&self, buf: &[u8]) -> IoResult<Option<(Element, uint)>> {
+        let end = match find(buf, bytes!("\r\n")) {
+            Some(end) if end > MAX_LINE_LENGTH => return Err(line_too_long()),
+            Some(end) => end,
+            None if buf.len() > MAX_LINE_LENGTH => return Err(line_too_long()),
+            None => return Ok(None)
+        };
+        if end == 0 {
+            return Err(malformed("A line has no type."));
+        }
+        let line = buf.slice(1, end);
+        let len = end + 2;
+        let element = match buf[0] as char {
+            '+' => Complete(SimpleString(try!(to_str(line)))),
+            '-' => Complete(Error(try!(to_str(line)))),
+            ':' => Complete(Integer(try!(parse_integer(line)))),
+            '_' if line.len() == 0 => Complete(Null),
+            '#' if line == bytes!("t") => Complete(Boolean(true)),
+            '#' if line == bytes!("f") => Complete(Boolean(false)),
+            ',' => Complete(Double(try!(parse_double(line)))),
+            '(' => Complete(BigNumber(try!(parse_big_number(line)))),
+            '$' | '!' | '=' => {
+                let length = match try!(parse_length(line)) {
+                    Some(length) => length,
+                    None => return Ok(Some((Complete(Null), len)))
+                };
+                if length > self.max_bulk_length as u64 {
+                    return Err(IoError {
+                        kind: io::InvalidInput,
+                        desc: "A bulk string is longer than the maximum length.",
+                        detail: Some(format!("maximum length: {}", self.max_bulk_length))
+                    });
+                }
+                let length = length as uint;
+                if buf.len() - len < length + 2 {
+                    return Ok(None);
+                }
+                if buf.slice(len + length, len + length + 2) != bytes!("\r\n") {
+                    return Err(malformed("A bulk string is not followed by CRLF."));
+                }
+                let data = buf.slice(len, len + length).to_owned();
+                let value = match buf[0] as char {
+                    '$' => BulkString(data),
+                    '!' => BulkError(try!(to_str(data))),
+                    _ => try!(parse_verbatim(data))
+                };
+                return Ok(Some((Complete(value), len + length + 2)));
+            }
+            '*' | '%' | '~' | '>' => {
+                let count = match try!(parse_length(line)) {
+                    Some(count) => count,
+                    None => return Ok(Some((Complete(Null), len)))
+                };
+                match buf[0] as char {
+                    '*' => Start(ArrayOf, count),
+                    '~' => Start(SetOf, count),
+                    '>' => Start(PushOf, count),
+                    _ if count > (1u64 << 62) => return Err(malformed("A map is too large.")),
+                    _ => Start(MapOf, count * 2)
+                }
+            }
+            '|' => return Err(malformed("Attributes are not supported.")),
+            _ => return Err(malformed("A value has an unknown or malformed type."))
+        };
+        Ok(Some((element, len)))
+    }
+
+    fn encode(&self, value: &Value, out: &mut ~[u8]) -> IoResult<()> {
+        let resp3 = self.protocol == Resp3;
+        match *value {
+            SimpleString(ref s) => try!(encode_line('+', s.as_slice(), out)),
+            Error(ref s) => try!(encode_line('-', s.as_slice(), out)),
+            Integer(n) => encode_header(':', n.to_str().as_slice(), out),
+            BulkString(ref data) => encode_bulk('$', data.as_slice(), out),
+            Array(ref items) => try!(self.encode_aggregate('*', items.as_slice(), out)),
+            Map(ref pairs) => {
+                // RESP2 has no maps, so the pairs are flattened into an array.
+                let (kind, count) = if resp3 { ('%', pairs.len()) } else { ('*', pairs.len() * 2) };
+                encode_header(kind, count.to_str().as_slice(), out);
+                for &(ref key, ref value) in pairs.iter() {
+                    try!(self.encode(key, out));
+                    try!(self.encode(value, out));
+                }
+            }
+            Set(ref items) => {
+                try!(self.encode_aggregate(if resp3 { '~' } else { '*' }, items.as_slice(), out));
+            }
+            Push(ref items) => {
+                try!(self.encode_aggregate(if resp3 { '>' } else { '*' }, items.as_slice(), out));
+            }
+            Null if resp3 => out.push_all(bytes!("_\r\n")),
+            Null => out.push_all(bytes!("$-1\r\n")),
+            Boolean(b) if resp3 => encode_header('#', if b { "t" } else { "f" }, out),
+            Boolean(b) => encode_header(':', if b { "1" } else { "0" }, out),
+            Double(n) => {
+                let text = format_double(n);
+                if resp3 {
+                    encode_header(',', text.as_slice(), out);
+                } else {
+                    encode_bulk('$', text.as_bytes(), out);
+                }
+            }
+            BigNumber(ref n) if resp3 => try!(encode_line('(', n.as_slice(), out)),
+            BigNumber(ref n) => encode_bulk('$', n.as_bytes(), out),
+            BulkError(ref s) if resp3 => encode_bulk('!', s.as_bytes(), out),
+            BulkError(ref s) => try!(encode_line('-', s.as_slice(), out)),
+            Verbatim(ref format, ref data) => {
+                if format.len() != 3 {
+                    return Err(malformed("A verbatim string format is not three bytes long."));
+                }
+                if resp3 {
+                    let mut payload = format.as_bytes().to_owned();
+                    payload.push(':' as u8);
+                    payload.push_all(data.as_slice());
+                    encode_bulk('=', payload.as_slice(), out);
+                } else {
+                    encode_bulk('$', data.as_slice(), out);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_aggregate(&self, kind: char, items: &[Value], out: &mut ~[u8]) -> IoResult<()> {
+        encode_header(kind, items.len().to_str().as_slice(), out);
+        for item in items.iter() {
+            try!(self.encode(item, out));
+        }
+        Ok(())
+    }
+}
+
+fn encode_header(kind: char, text: &str, out: &mut ~[u8]) {
+    out.push(kind as u8);
+    out.push_all(text.as_bytes());
+    out.push_all(bytes!("\r\n"));
+}
+
+/// Encode a simple string, simple error or big number, which may not contain
+/// CR or LF.
+fn encode_line(kind: char, text: &str, out: &mut ~[u8]) -> IoResult<()> {
+    if text.contains_char('\r') || text.contains_char('\n') {
+        return Err(malformed("A simple string contains CR or LF."));
+    }
+    encode_header(kind, text, out);
+    Ok(())
+}
+
+/// Format a double as RESP3 spells it, which for infinities and NaN differs
+/// from `to_str`.
+fn format_double(n: f64) -> ~str {
+    if n != n {
+        ~"nan"
+    } else if n == 1.0 / 0.0 {
+        ~"inf"
+    } else if n == -1.0 / 0.0 {
+        ~"-inf"
+    } else {
+        n.to_str()
+    }
+}
+
+fn encode_bulk(kind: char, data: &[u8], out: &mut ~[u8]) {
+    encode_header(kind, data.len().to_str().as_slice(), out);
+    out.push_all(data);
+    out.push_all(bytes!("\r\n"));
+}
+
+fn to_str(bytes: &[u8]) -> IoResult<~str> {
+    if !str::is_utf8(bytes) {
+        return Err(malformed("A string is not valid UTF-8."));
+    }
+    Ok(unsafe { str::raw::from_utf8_owned(bytes.to_owned()) })
+}
+
+fn parse_integer(line: &[u8]) -> IoResult<i64> {
+    match from_str::<i64>(try!(to_str(line)).as_slice()) {
+        Some(n) => Ok(n),
+        None => Err(malformed("An integer is malformed or out of range."))
+    }
+}
+
+/// Parse the length of a bulk string or aggregate, which is `None` for the
+/// RESP2 null.
+fn parse_length(line: &[u8]) -> IoResult<Option<u64>> {
+    if line == bytes!("-1") {
+        return Ok(None);
+    }
+    if line.len() == 0 || !line.iter().all(|&b| b >= '0' as u8 && b <= '9' as u8) {
+        return Err(malformed("A length is malformed."));
+    }
+    match from_str::<u64>(try!(to_str(line)).as_slice()) {
+        Some(length) => Ok(Some(length)),
+        None => Err(malformed("A length is out of range."))
+    }
+}
+
+fn parse_double(line: &[u8]) -> IoResult<f64> {
+    let text = try!(to_str(line));
+    let value = match text.as_slice() {
+        "inf" => Some(1.0 / 0.0),
+        "-inf" => Some(-1.0 / 0.0),
+        "nan" => Some(0.0 / 0.0),
+        text => from_str::<f64>(text)
+    };
+    match value {
+        Some(value) => Ok(value),
+        None => Err(malformed("A double is malformed."))
+    }
+}
+
+fn parse_big_number(line: &[u8]) -> IoResult<~str> {
+    let digits = if line.len() > 0 && (line[0] == '-' as u8 || line[0] == '+' as u8) {
+        line.slice_from(1)
+    } else {
+        line
+    };
+    if digits.len() == 0 || !digits.iter().all(|&b| b >= '0' as u8 && b <= '9' as u8) {
+        return Err(malformed("A big number is malformed."));
+    }
+    to_str(line)
+}
+
+fn parse_verbatim(data: ~[u8]) -> IoResult<Value> {
+    if data.len() < 4 || data[3] != ':' as u8 {
+        return Err(malformed("A verbatim string has no format."));
+    }
+    let format = try!(to_str(data.slice_to(3)));
+    Ok(Verbatim(format, data.slice_from(4).to_owned()))
+}
+
+fn malformed(desc: &'static str) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: desc,
+        detail: None
+    }
+}
+
+fn line_too_long() -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "A line is longer than the maximum length.",
+        detail: Some(format!("maximum length: {}", MAX_LINE_LENGTH))
+    }
+}
+
+impl Filter<~[u8], Value, Value, ~[u8]> for RespCodec {
+    fn down<U: PipelineUp<~[u8]>, D: PipelineDown<Value>>(
+            &self, data: ~[u8], _: &U, down: &D) -> IoResult<()> {
+        let mut values = ~[];
+        let res = self.decode(data, &mut values);
+        for value in values.move_iter() {
+            try!(down.down(value));
+        }
+        res
+    }
+
+    fn up<U: PipelineUp<~[u8]>, D: PipelineDown<Value>>(
+            &self, data: Value, up: &U, _: &D) -> IoResult<()> {
+        let mut out = ~[];
+        try!(self.encode(&data, &mut out));
+        up.up(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RespCodec, Resp2, Value};
+    use super::{SimpleString, Integer, BulkString, BulkError, Array, Map, Set, Null, Boolean};
+    use super::{Double, Verbatim};
+
+    use std::io;
+    use std::io::IoResult;
+    use std::str;
+
+    fn error_kind<T>(result: IoResult<T>) -> io::IoErrorKind {
+        match result {
+            Ok(_) => fail!("expected an error"),
+            Err(err) => err.kind
+        }
+    }
+
+    /// Decode `input` in reads of one byte each.
+    fn decode(codec: &RespCodec, input: &[u8]) -> IoResult<~[Value]> {
+        let mut values = ~[];
+        try!(codec.decode(input, &mut values));
+        Ok(values)
+    }
+
+    fn decode_bytewise(codec: &RespCodec, input: &[u8]) -> ~[Value] {
+        let mut values = ~[];
+        for byte in input.iter() {
+            values.push_all_move(decode(codec, &[*byte]).unwrap());
+        }
+        values
+    }
+
+    fn encode(codec: &RespCodec, value: Value) -> ~str {
+        let mut out = ~[];
+        codec.encode(&value, &mut out).unwrap();
+        unsafe { str::raw::from_utf8_owned(out) }
+    }
+
+    #[test]
+    fn test_bulk_string_split() {
+        let input = bytes!("$5\r\nhello\r\n:1\r\n");
+        let expected = ~[BulkString(bytes!("hello").to_owned()), Integer(1)];
+        // Every split, including ones inside the payload and inside its CRLF.
+        for i in range(0, input.len() + 1) {
+            let codec = RespCodec::new();
+            let mut values = decode(&codec, input.slice_to(i)).unwrap();
+            values.push_all_move(decode(&codec, input.slice_from(i)).unwrap());
+            assert!(values == expected);
+        }
+        assert!(decode_bytewise(&RespCodec::new(), input) == expected);
+
+        // The payload may contain CRLF.
+        let values = decode(&RespCodec::new(), bytes!("$4\r\n\r\n\r\n\r\n")).unwrap();
+        assert!(values == ~[BulkString(bytes!("\r\n\r\n").to_owned())]);
+        let result = decode(&RespCodec::new(), bytes!("$2\r\nabc\r\n"));
+        assert_eq!(error_kind(result), io::InvalidInput);
+    }
+
+    #[test]
+    fn test_nested_aggregates() {
+        let input = bytes!("*2\r\n%1\r\n+key\r\n*2\r\n:1\r\n~1\r\n#t\r\n$0\r\n\r\n*0\r\n");
+        let expected = ~[
+            Array(~[
+                Map(~[(SimpleString(~"key"), Array(~[Integer(1), Set(~[Boolean(true)])]))]),
+                BulkString(~[])
+            ]),
+            Array(~[])
+        ];
+        assert!(decode_bytewise(&RespCodec::new(), input) == expected);
+        assert!(decode(&RespCodec::new(), input).unwrap() == expected);
+
+        // What is encoded decodes to the same value.
+        let codec = RespCodec::new();
+        let encoded = encode(&codec, expected[0].clone());
+        assert!(decode(&codec, encoded.as_bytes()).unwrap() == ~[expected[0].clone()]);
+    }
+
+    #[test]
+    fn test_resp2_nulls() {
+        let input = bytes!("$-1\r\n*-1\r\n_\r\n*2\r\n$-1\r\n:5\r\n");
+        let expected = ~[Null, Null, Null, Array(~[Null, Integer(5)])];
+        assert!(decode_bytewise(&RespCodec::new(), input) == expected);
+
+        let resp2 = RespCodec::new().protocol(Resp2);
+        assert_eq!(encode(&resp2, Null), ~"$-1\r\n");
+        assert_eq!(encode(&RespCodec::new(), Null), ~"_\r\n");
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let codec = RespCodec::new().max_depth(1);
+        assert!(decode(&codec, bytes!("*1\r\n:1\r\n")).unwrap() == ~[Array(~[Integer(1)])]);
+        // The error comes as soon as the header that is too deep arrives.
+        assert_eq!(error_kind(decode(&codec, bytes!("*1\r\n*1\r\n"))), io::InvalidInput);
+
+        let codec = RespCodec::new().max_depth(2);
+        let input = bytes!("*1\r\n%1\r\n:1\r\n:2\r\n");
+        let expected = ~[Array(~[Map(~[(Integer(1), Integer(2))])])];
+        assert!(decode(&codec, input).unwrap() == expected);
+        assert_eq!(error_kind(decode(&codec, bytes!("*1\r\n*1\r\n~1\r\n"))), io::InvalidInput);
+        // The partly assembled aggregates are discarded with the error.
+        assert!(decode(&codec, bytes!(":3\r\n")).unwrap() == ~[Integer(3)]);
+    }
+
+    #[test]
+    fn test_values_before_error_are_kept() {
+        let codec = RespCodec::new().max_depth(1);
+        let mut values = ~[];
+        let result = codec.decode(bytes!(":1\r\n*1\r\n:2\r\n*1\r\n*1\r\n:3\r\n"), &mut values);
+        assert_eq!(error_kind(result), io::InvalidInput);
+        assert!(values == ~[Integer(1), Array(~[Integer(2)])]);
+    }
+
+    #[test]
+    fn test_max_bulk_length() {
+        let codec = RespCodec::new().max_bulk_length(4);
+        let values = decode(&codec, bytes!("$4\r\nabcd\r\n!4\r\nfail\r\n")).unwrap();
+        assert!(values == ~[BulkString(bytes!("abcd").to_owned()), BulkError(~"fail")]);
+        // Longer strings are refused from their header alone.
+        assert_eq!(error_kind(decode(&codec, bytes!("$5\r\n"))), io::InvalidInput);
+        assert_eq!(error_kind(decode(&codec, bytes!("=9\r\n"))), io::InvalidInput);
+
+        // The limit counts a verbatim string's format.
+        assert_eq!(error_kind(decode(&codec, bytes!("=7\r\ntxt:abc\r\n"))), io::InvalidInput);
+        let values = decode(&RespCodec::new(), bytes!("=7\r\ntxt:abc\r\n")).unwrap();
+        assert!(values == ~[Verbatim(~"txt", bytes!("abc").to_owned())]);
+    }
+
+    #[test]
+    fn test_encode_double() {
+        let resp3 = RespCodec::new();
+        let resp2 = RespCodec::new().protocol(Resp2);
+        assert_eq!(encode(&resp3, Double(1.5)), ~",1.5\r\n");
+        assert_eq!(encode(&resp3, Double(0.0 / 0.0)), ~",nan\r\n");
+        assert_eq!(encode(&resp3, Double(1.0 / 0.0)), ~",inf\r\n");
+        assert_eq!(encode(&resp3, Double(-1.0 / 0.0)), ~",-inf\r\n");
+        assert_eq!(encode(&resp2, Double(-1.0 / 0.0)), ~"$4\r\n-inf\r\n");
+
+        // Infinities survive a round trip.
+        let values = decode(&resp3, encode(&resp3, Double(1.0 / 0.0)).as_bytes()).unwrap();
+        assert!(values == ~[Double(1.0 / 0.0)]);
+    }
+}
@@ -0,0 +1,1100 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! WebSocket (RFC 6455) messages and the opening handshake.
+//!
+//! `WebSocketCodec` turns frames into whole messages: fragmented messages are
+//! reassembled, pings are answered with pongs and a close frame from the peer
+//! is answered with one of our own. Once both sides have sent a close frame,
+//! `Closed` is sent up.
+//!
+//! A WebSocket connection starts out as HTTP. A server pipeline for an
+//! `Endpoint` puts a `WebSocketUpgrade` stage directly below its
+//! `HttpServerCodec`, which answers the upgrade request and then carries
+//! messages:
+//!
+//! ```ignore
+//! PipelineBuilder::new(ChatFilter::new())
+//!     .filter(WebSocketUpgrade::new(WebSocketCodec::server()))
+//!     .filter(HttpServerCodec::new())
+//!     .build_with_sink(output)
+//! ```
+//!
+//! A `DynamicPipeline` with an `HttpServerCodec` stage can instead switch
+//! over with `upgrade` when a stage below the HTTP stage receives an upgrade
+//! request:
+//!
+//! ```ignore
+//! RequestEnd if websocket::is_upgrade(&request) => {
+//!     let app = ~typed(ChatFilter::new()) as ~DynamicFilter;
+//!     try!(websocket::upgrade(cx, "http", &request, WebSocketCodec::server(), ~"chat", app));
+//! }
+//! ```
+//!
+//! The HTTP stage stays in the pipeline, passing bytes through once it has
+//! sent the `101` response. Events pass through the new stages as well, so
+//! the `Closed` sent up at the end of the close handshake reaches the
+//! pipeline's up sink, whose owner should then close the connection.
+
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::io::{IoResult, IoError};
+use std::rand;
+use std::str;
+use std::vec;
+
+use extra::base64::{FromBase64, ToBase64, STANDARD};
+
+use codec::http::{Request, Response};
+use codec::http::{RequestPart, RequestHead, RequestBody, RequestEnd};
+use codec::http::{ResponsePart, ResponseHead, ResponseBody, ResponseEnd};
+use dynamic_pipeline::{DynamicFilter, StageContext, typed};
+use pipeline::{Closed, Filter, PipelineDown, PipelineUp};
+
+static DEFAULT_MAX_MESSAGE_SIZE: uint = 16 * 1024 * 1024;
+/// The largest payload of a control frame.
+static MAX_CONTROL_SIZE: uint = 125;
+/// Appended to the client's key to compute the server's accept key.
+static ACCEPT_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+static OP_CONTINUATION: u8 = 0x0;
+static OP_TEXT: u8 = 0x1;
+static OP_BINARY: u8 = 0x2;
+static OP_CLOSE: u8 = 0x8;
+static OP_PING: u8 = 0x9;
+static OP_PONG: u8 = 0xA;
+
+pub enum Message {
+    Text(~str),
+    Binary(~[u8]),
+    Ping(~[u8]),
+    Pong(~[u8]),
+    /// A close frame, with its status code and reason if it has one.
+    Close(Option<(u16, ~str)>)
+}
+
+/// Which end of the connection the codec is on. Clients mask the frames they
+/// send; servers don't.
+enum Role {
+    Client,
+    Server
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: ~[u8]
+}
+
+struct SocketState {
+    buf: ~[u8],
+    /// The opcode and payload so far of a fragmented message.
+    message: Option<(u8, ~[u8])>,
+    close_sent: bool,
+    close_received: bool
+}
+
+/// What decoding produced.
+struct Decoded {
+    messages: ~[Message],
+    /// Frames sent in reply, such as pongs.
+    replies: ~[u8],
+    close: bool
+}
+
+/// Decodes the bytes sent down into `Message`s and encodes the `Message`s
+/// sent up into frames.
+pub struct WebSocketCodec {
+    priv role: Role,
+    priv max_message_size: uint,
+    priv fragment_size: Option<uint>,
+    priv state: RefCell<SocketState>
+}
+
+impl WebSocketCodec {
+    /// The server end, accepting messages of up to 16MiB.
+    pub fn server() -> WebSocketCodec {
+        WebSocketCodec::new(Server)
+    }
+
+    /// The client end, accepting messages of up to 16MiB.
+    pub fn client() -> WebSocketCodec {
+        WebSocketCodec::new(Client)
+    }
+
+    fn new(role: Role) -> WebSocketCodec {
+        WebSocketCodec {
+            role: role,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            fragment_size: None,
+            state: RefCell::new(SocketState {
+                buf: ~[],
+                message: None,
+                close_sent: false,
+                close_received: false
+            })
+        }
+    }
+
+    /// The largest message, after reassembly, that will be accepted or sent.
+    pub fn max_message_size(mut self, size: uint) -> WebSocketCodec {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Send messages larger than `size` bytes as several frames of at most
+    /// `size` bytes each. By default each message is sent as a single frame.
+    pub fn fragment_size(mut self, size: uint) -> WebSocketCodec {
+        assert!(size > 0);
+        self.fragment_size = Some(size);
+        self
+    }
+
+    /// Add `data` to the buffer and decode as much as possible. On error the
+    /// rest of the input is discarded.
+    fn decode(&self, data: &[u8]) -> IoResult<Decoded> {
+        let mut state = self.state.borrow_mut();
+        let state = state.get();
+        let mut decoded = Decoded { messages: ~[], replies: ~[], close: false };
+        if state.close_received {
+            return Ok(decoded);
+        }
+        state.buf.push_all(data);
+        match self.decode_frames(state, &mut decoded) {
+            Ok(()) => Ok(decoded),
+            Err(err) => {
+                state.buf.clear();
+                state.close_received = true;
+                Err(err)
+            }
+        }
+    }
+
+    fn decode_frames(&self, state: &mut SocketState, decoded: &mut Decoded) -> IoResult<()> {
+        let mut start = 0;
+        while !state.close_received {
+            let in_message = match state.message {
+                Some((_, ref payload)) => payload.len(),
+                None => 0
+            };
+            let (frame, len) = match try!(self.parse_frame(state.buf.slice_from(start),
+                                                           in_message)) {
+                Some(frame) => frame,
+                None => break
+            };
+            start += len;
+            try!(self.handle_frame(state, frame, decoded));
+        }
+        if state.close_received {
+            state.buf.clear();
+        } else {
+            state.buf = state.buf.slice_from(start).to_owned();
+        }
+        Ok(())
+    }
+
+    /// Split a complete frame off the front of `buf`, returning it and its
+    /// length, or `None` if the frame is not complete yet. `in_message` is
+    /// the size of the fragmented message the frame may continue.
+    fn parse_frame(&self, buf: &[u8], in_message: uint) -> IoResult<Option<(Frame, uint)>> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        if buf[0] & 0x70 != 0 {
+            return Err(protocol_error("A frame has reserved bits set."));
+        }
+        let fin = buf[0] & 0x80 != 0;
+        let opcode = buf[0] & 0x0F;
+        let masked = buf[1] & 0x80 != 0;
+        match (self.role, masked) {
+            (Server, false) => return Err(protocol_error("A frame from a client is not masked.")),
+            (Client, true) => return Err(protocol_error("A frame from a server is masked.")),
+            _ => {}
+        }
+
+        let (len, mut pos) = match buf[1] & 0x7F {
+            126 if buf.len() < 4 => return Ok(None),
+            126 => (read_u64(buf.slice(2, 4)), 4),
+            127 if buf.len() < 10 => return Ok(None),
+            127 => (read_u64(buf.slice(2, 10)), 10),
+            len => (len as u64, 2)
+        };
+        if opcode & 0x8 != 0 {
+            if !fin || len > MAX_CONTROL_SIZE as u64 {
+                return Err(protocol_error("A control frame is fragmented or too long."));
+            }
+        } else if len > (self.max_message_size - in_message) as u64 {
+            return Err(too_large(self.max_message_size));
+        }
+        let len = len as uint;
+
+        let mut key = [0u8, ..4];
+        if masked {
+            if buf.len() < pos + 4 {
+                return Ok(None);
+            }
+            for i in range(0, 4) {
+                key[i] = buf[pos + i];
+            }
+            pos += 4;
+        }
+        if buf.len() - pos < len {
+            return Ok(None);
+        }
+        let mut payload = buf.slice(pos, pos + len).to_owned();
+        if masked {
+            apply_mask(payload.mut_slice_from(0), key);
+        }
+        Ok(Some((Frame { fin: fin, opcode: opcode, payload: payload }, pos + len)))
+    }
+
+    fn handle_frame(&self, state: &mut SocketState, frame: Frame, decoded: &mut Decoded)
+            -> IoResult<()> {
+        let Frame { fin, opcode, payload } = frame;
+        match opcode {
+            OP_CONTINUATION => {
+                let (opcode, mut message) = match state.message.take() {
+                    Some(message) => message,
+                    None => return Err(protocol_error("A continuation frame has no message \
+                        to continue."))
+                };
+                message.push_all(payload);
+                if fin {
+                    decoded.messages.push(try!(to_message(opcode, message)));
+                } else {
+                    state.message = Some((opcode, message));
+                }
+            }
+            OP_TEXT | OP_BINARY => {
+                if state.message.is_some() {
+                    return Err(protocol_error("A message started before the previous one \
+                        was complete."));
+                }
+                if fin {
+                    decoded.messages.push(try!(to_message(opcode, payload)));
+                } else {
+                    state.message = Some((opcode, payload));
+                }
+            }
+            OP_PING => {
+                if !state.close_sent {
+                    self.encode_frame(true, OP_PONG, payload, &mut decoded.replies);
+                }
+                decoded.messages.push(Ping(payload));
+            }
+            OP_PONG => decoded.messages.push(Pong(payload)),
+            OP_CLOSE => {
+                let close = try!(parse_close(payload));
+                state.close_received = true;
+                if !state.close_sent {
+                    // Echo the status code, as the RFC suggests.
+                    let reply = match close {
+                        Some((code, _)) => Some((code, ~"")),
+                        None => None
+                    };
+                    self.encode_frame(true, OP_CLOSE, encode_close(reply), &mut decoded.replies);
+                    state.close_sent = true;
+                }
+                decoded.messages.push(Close(close));
+                decoded.close = true;
+            }
+            _ => return Err(protocol_error("A frame has an unknown opcode."))
+        }
+        Ok(())
+    }
+
+    /// Encode `message`, returning its frames and whether the close
+    /// handshake is now complete.
+    fn encode(&self, message: Message) -> IoResult<(~[u8], bool)> {
+        let mut state = self.state.borrow_mut();
+        let state = state.get();
+        if state.close_sent {
+            return Err(IoError {
+                kind: io::InvalidInput,
+                desc: "A message was sent after the close frame.",
+                detail: None
+            });
+        }
+        let mut out = ~[];
+        match message {
+            Text(text) => try!(self.encode_message(OP_TEXT, text.into_bytes(), &mut out)),
+            Binary(data) => try!(self.encode_message(OP_BINARY, data, &mut out)),
+            Ping(data) => try!(self.encode_control(OP_PING, data, &mut out)),
+            Pong(data) => try!(self.encode_control(OP_PONG, data, &mut out)),
+            Close(close) => {
+                try!(self.encode_control(OP_CLOSE, encode_close(close), &mut out));
+                state.close_sent = true;
+                return Ok((out, state.close_received));
+            }
+        }
+        Ok((out, false))
+    }
+
+    fn encode_message(&self, opcode: u8, payload: &[u8], out: &mut ~[u8]) -> IoResult<()> {
+        if payload.len() > self.max_message_size {
+            return Err(too_large(self.max_message_size));
+        }
+        let size = match self.fragment_size {
+            Some(size) if payload.len() > size => size,
+            _ => {
+                self.encode_frame(true, opcode, payload, out);
+                return Ok(());
+            }
+        };
+        let mut start = 0;
+        let mut opcode = opcode;
+        while start < payload.len() {
+            let end = if payload.len() - start > size { start + size } else { payload.len() };
+            self.encode_frame(end == payload.len(), opcode, payload.slice(start, end), out);
+            opcode = OP_CONTINUATION;
+            start = end;
+        }
+        Ok(())
+    }
+
+    fn encode_control(&self, opcode: u8, payload: &[u8], out: &mut ~[u8]) -> IoResult<()> {
+        if payload.len() > MAX_CONTROL_SIZE {
+            return Err(IoError {
+                kind: io::InvalidInput,
+                desc: "A control frame payload is longer than 125 bytes.",
+                detail: None
+            });
+        }
+        self.encode_frame(true, opcode, payload, out);
+        Ok(())
+    }
+
+    fn encode_frame(&self, fin: bool, opcode: u8, payload: &[u8], out: &mut ~[u8]) {
+        out.push((if fin { 0x80 } else { 0 }) | opcode);
+        let mask_bit = match self.role {
+            Client => 0x80,
+            Server => 0
+        };
+        let len = payload.len();
+        if len < 126 {
+            out.push(mask_bit | len as u8);
+        } else if len <= 0xFFFF {
+            out.push(mask_bit | 126);
+            write_u64(len as u64, 2, out);
+        } else {
+            out.push(mask_bit | 127);
+            write_u64(len as u64, 8, out);
+        }
+        match self.role {
+            Client => {
+                let key: [u8, ..4] = [rand::random(), rand::random(), rand::random(),
+                                      rand::random()];
+                out.push_all(key.as_slice());
+                let start = out.len();
+                out.push_all(payload);
+                apply_mask(out.mut_slice_from(start), key);
+            }
+            Server => out.push_all(payload)
+        }
+    }
+}
+
+fn read_u64(field: &[u8]) -> u64 {
+    let mut value = 0u64;
+    for &b in field.iter() {
+        value = (value << 8) | b as u64;
+    }
+    value
+}
+
+fn write_u64(value: u64, width: uint, out: &mut ~[u8]) {
+    for i in range(0, width) {
+        out.push((value >> ((width - 1 - i) * 8)) as u8);
+    }
+}
+
+fn apply_mask(data: &mut [u8], key: [u8, ..4]) {
+    for i in range(0, data.len()) {
+        data[i] ^= key[i % 4];
+    }
+}
+
+fn to_message(opcode: u8, payload: ~[u8]) -> IoResult<Message> {
+    if opcode == OP_BINARY {
+        return Ok(Binary(payload));
+    }
+    if !str::is_utf8(payload) {
+        return Err(protocol_error("A text message is not valid UTF-8."));
+    }
+    Ok(Text(unsafe { str::raw::from_utf8_owned(payload) }))
+}
+
+fn parse_close(payload: ~[u8]) -> IoResult<Option<(u16, ~str)>> {
+    match payload.len() {
+        0 => Ok(None),
+        1 => Err(protocol_error("A close frame has a truncated status code.")),
+        _ => {
+            let code = read_u64(payload.slice_to(2)) as u16;
+            let reason = payload.slice_from(2);
+            if !str::is_utf8(reason) {
+                return Err(protocol_error("A close reason is not valid UTF-8."));
+            }
+            Ok(Some((code, unsafe { str::raw::from_utf8_owned(reason.to_owned()) })))
+        }
+    }
+}
+
+fn encode_close(close: Option<(u16, ~str)>) -> ~[u8] {
+    match close {
+        Some((code, reason)) => {
+            let mut payload = ~[];
+            write_u64(code as u64, 2, &mut payload);
+            payload.push_all(reason.as_bytes());
+            payload
+        }
+        None => ~[]
+    }
+}
+
+fn protocol_error(desc: &'static str) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: desc,
+        detail: None
+    }
+}
+
+fn too_large(max_message_size: uint) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "A message is larger than the maximum message size.",
+        detail: Some(format!("maximum message size: {}", max_message_size))
+    }
+}
+
+impl Filter<~[u8], Message, Message, ~[u8]> for WebSocketCodec {
+    fn down<U: PipelineUp<~[u8]>, D: PipelineDown<Message>>(
+            &self, data: ~[u8], up: &U, down: &D) -> IoResult<()> {
+        let Decoded { messages, replies, close } = try!(self.decode(data));
+        if replies.len() > 0 {
+            try!(up.up(replies));
+        }
+        for message in messages.move_iter() {
+            try!(down.down(message));
+        }
+        if close {
+            try!(up.up_event(Closed));
+        }
+        Ok(())
+    }
+
+    fn up<U: PipelineUp<~[u8]>, D: PipelineDown<Message>>(
+            &self, data: Message, up: &U, _: &D) -> IoResult<()> {
+        let (bytes, close) = try!(self.encode(data));
+        try!(up.up(bytes));
+        if close {
+            try!(up.up_event(Closed));
+        }
+        Ok(())
+    }
+}
+
+/// Carries the bytes of an upgraded connection through an `HttpServerCodec`:
+/// the `RequestBody` parts sent down are unwrapped, and bytes sent up are
+/// wrapped in `ResponseBody` parts.
+pub struct ServerTunnel;
+
+impl Filter<RequestPart, ~[u8], ~[u8], ResponsePart> for ServerTunnel {
+    fn down<U: PipelineUp<ResponsePart>, D: PipelineDown<~[u8]>>(
+            &self, data: RequestPart, _: &U, down: &D) -> IoResult<()> {
+        match data {
+            RequestBody(data) => down.down(data),
+            _ => Err(protocol_error("An HTTP request was received on an upgraded connection."))
+        }
+    }
+
+    fn up<U: PipelineUp<ResponsePart>, D: PipelineDown<~[u8]>>(
+            &self, data: ~[u8], up: &U, _: &D) -> IoResult<()> {
+        up.up(ResponseBody(data))
+    }
+}
+
+/// Answers a WebSocket upgrade request received through an `HttpServerCodec`
+/// and then carries the messages of the upgraded connection, decoding and
+/// encoding them with `codec`. A request that isn't a valid upgrade is
+/// answered with `400 Bad Request` and the connection is closed.
+pub struct WebSocketUpgrade {
+    priv codec: WebSocketCodec,
+    priv request: RefCell<Option<Request>>,
+    priv upgraded: Cell<bool>
+}
+
+impl WebSocketUpgrade {
+    pub fn new(codec: WebSocketCodec) -> WebSocketUpgrade {
+        WebSocketUpgrade {
+            codec: codec,
+            request: RefCell::new(None),
+            upgraded: Cell::new(false)
+        }
+    }
+}
+
+impl Filter<RequestPart, Message, Message, ResponsePart> for WebSocketUpgrade {
+    fn down<U: PipelineUp<ResponsePart>, D: PipelineDown<Message>>(
+            &self, data: RequestPart, up: &U, down: &D) -> IoResult<()> {
+        match data {
+            RequestHead(request) => {
+                *self.request.borrow_mut().get() = Some(request);
+                Ok(())
+            }
+            // The body of a request that will be refused.
+            RequestBody(_) if !self.upgraded.get() => Ok(()),
+            RequestBody(data) => {
+                let Decoded { messages, replies, close } = try!(self.codec.decode(data));
+                if replies.len() > 0 {
+                    try!(up.up(ResponseBody(replies)));
+                }
+                for message in messages.move_iter() {
+                    try!(down.down(message));
+                }
+                if close {
+                    try!(up.up_event(Closed));
+                }
+                Ok(())
+            }
+            RequestEnd => {
+                let request = self.request.borrow_mut().get().take();
+                let request = match request {
+                    Some(request) => request,
+                    None => return Ok(())
+                };
+                match accept(&request) {
+                    Ok(response) => {
+                        // Anything after the request is decoded as soon as
+                        // the response has been sent.
+                        self.upgraded.set(true);
+                        up.up(ResponseHead(response))
+                    }
+                    Err(_) => {
+                        let mut response = Response::new(400, "Bad Request");
+                        response.headers.set("Content-Length", "0");
+                        response.headers.set("Connection", "close");
+                        try!(up.up(ResponseHead(response)));
+                        up.up(ResponseEnd)
+                    }
+                }
+            }
+        }
+    }
+
+    fn up<U: PipelineUp<ResponsePart>, D: PipelineDown<Message>>(
+            &self, data: Message, up: &U, _: &D) -> IoResult<()> {
+        if !self.upgraded.get() {
+            return Err(protocol_error("A message was sent before the WebSocket handshake."));
+        }
+        let (bytes, close) = try!(self.codec.encode(data));
+        try!(up.up(ResponseBody(bytes)));
+        if close {
+            try!(up.up_event(Closed));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `request` asks to upgrade to WebSocket.
+pub fn is_upgrade(request: &Request) -> bool {
+    request.headers.has_token("Upgrade", "websocket") &&
+        request.headers.has_token("Connection", "upgrade")
+}
+
+/// The `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> ~str {
+    let mut input = key.trim().as_bytes().to_owned();
+    input.push_all(ACCEPT_GUID.as_bytes());
+    sha1(input).to_base64(STANDARD)
+}
+
+/// Check an upgrade request and build the `101 Switching Protocols` response
+/// to it.
+pub fn accept(request: &Request) -> IoResult<Response> {
+    if request.method.as_slice() != "GET" || !is_upgrade(request) {
+        return Err(bad_handshake("A request is not a WebSocket upgrade."));
+    }
+    if request.headers.get("Sec-WebSocket-Version") != Some("13") {
+        return Err(bad_handshake("A WebSocket upgrade asks for an unsupported version."));
+    }
+    let key = match request.headers.get("Sec-WebSocket-Key") {
+        Some(key) => key,
+        None => return Err(bad_handshake("A WebSocket upgrade has no key."))
+    };
+    match key.trim().from_base64() {
+        Ok(ref nonce) if nonce.len() == 16 => {}
+        _ => return Err(bad_handshake("A WebSocket key is malformed."))
+    }
+    let mut response = Response::new(101, "Switching Protocols");
+    response.headers.set("Upgrade", "websocket");
+    response.headers.set("Connection", "Upgrade");
+    response.headers.set("Sec-WebSocket-Accept", accept_key(key).as_slice());
+    Ok(response)
+}
+
+/// An upgrade request for `uri` on `host`, and the key to check the response
+/// against with `check_response`.
+pub fn upgrade_request(uri: &str, host: &str) -> (Request, ~str) {
+    let nonce = vec::from_fn(16, |_| rand::random::<u8>());
+    let key = nonce.to_base64(STANDARD);
+    let mut request = Request::new("GET", uri);
+    request.headers.set("Host", host);
+    request.headers.set("Upgrade", "websocket");
+    request.headers.set("Connection", "Upgrade");
+    request.headers.set("Sec-WebSocket-Key", key.as_slice());
+    request.headers.set("Sec-WebSocket-Version", "13");
+    (request, key)
+}
+
+/// Check the server's response to an upgrade request sent with `key`.
+pub fn check_response(response: &Response, key: &str) -> IoResult<()> {
+    if response.status != 101 || !response.headers.has_token("Upgrade", "websocket") ||
+            !response.headers.has_token("Connection", "upgrade") {
+        return Err(bad_handshake("A server refused a WebSocket upgrade."));
+    }
+    match response.headers.get("Sec-WebSocket-Accept") {
+        Some(accept) if accept.trim() == accept_key(key).as_slice() => Ok(()),
+        _ => Err(bad_handshake("A server sent the wrong WebSocket accept key."))
+    }
+}
+
+/// Answer a WebSocket upgrade request and switch a `DynamicPipeline` over to
+/// WebSocket.
+///
+/// Call this from the last stage of the pipeline, which must be directly
+/// below the HTTP stage called `http`, once it has received the end of
+/// `request`. The `101` response is sent up, then the calling stage is
+/// replaced by a `ServerTunnel`, `codec` and `app`, which is called `name`.
+/// `name` must differ from the calling stage's name.
+///
+/// Events sent up by `codec`, such as `Closed` once the close handshake is
+/// complete, pass through the tunnel and the HTTP stage unchanged.
+pub fn upgrade(
+        cx: &mut StageContext,
+        http: &str,
+        request: &Request,
+        codec: WebSocketCodec,
+        name: ~str,
+        app: ~DynamicFilter) -> IoResult<()> {
+    let current = cx.name().to_owned();
+    if name == current {
+        return Err(IoError {
+            kind: io::InvalidInput,
+            desc: "A WebSocket application stage must not reuse the name of the stage it \
+                replaces.",
+            detail: Some(format!("stage: {}", name))
+        });
+    }
+    let response = try!(accept(request));
+    cx.send_up(ResponseHead(response));
+    cx.remove(current);
+    cx.insert_after(http, ~"websocket-tunnel", ~typed(ServerTunnel) as ~DynamicFilter);
+    cx.insert_after("websocket-tunnel", ~"websocket", ~typed(codec) as ~DynamicFilter);
+    cx.insert_after("websocket", name, app);
+    Ok(())
+}
+
+fn bad_handshake(desc: &'static str) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: desc,
+        detail: None
+    }
+}
+
+/// SHA-1, which the handshake needs and nothing else does.
+fn sha1(data: &[u8]) -> ~[u8] {
+    let mut h = [0x67452301u32, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut msg = data.to_owned();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    write_u64(data.len() as u64 * 8, 8, &mut msg);
+
+    for block in msg.chunks(64) {
+        let mut w = [0u32, ..80];
+        for i in range(0, 16) {
+            w[i] = read_u64(block.slice(i * 4, i * 4 + 4)) as u32;
+        }
+        for i in range(16, 80) {
+            w[i] = rotate(w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16], 1);
+        }
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for i in range(0, 80) {
+            let (f, k) = if i < 20 {
+                ((b & c) | (!b & d), 0x5A827999u32)
+            } else if i < 40 {
+                (b ^ c ^ d, 0x6ED9EBA1)
+            } else if i < 60 {
+                ((b & c) | (b & d) | (c & d), 0x8F1BBCDC)
+            } else {
+                (b ^ c ^ d, 0xCA62C1D6)
+            };
+            let t = rotate(a, 5) + f + e + k + w[i];
+            e = d;
+            d = c;
+            c = rotate(b, 30);
+            b = a;
+            a = t;
+        }
+        h[0] += a;
+        h[1] += b;
+        h[2] += c;
+        h[3] += d;
+        h[4] += e;
+    }
+
+    let mut digest = ~[];
+    for &word in h.iter() {
+        write_u64(word as u64, 4, &mut digest);
+    }
+    digest
+}
+
+fn rotate(x: u32, n: uint) -> u32 {
+    (x << n) | (x >> (32 - n))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Message, Text, Binary, Ping, Pong, Close};
+    use super::{WebSocketCodec, WebSocketUpgrade, accept, accept_key, upgrade};
+
+    use std::any::Any;
+    use std::io;
+    use std::io::IoResult;
+    use std::str;
+
+    use sync::MutexArc;
+
+    use codec::http::{Request, RequestPart, RequestHead, RequestEnd, ResponsePart};
+    use codec::http::server::HttpServerCodec;
+    use dynamic_pipeline::{DynamicFilter, DynamicPipeline, StageContext, StageTypes};
+    use dynamic_pipeline::{downcast, typed};
+    use pipeline::{Closed, Filter, PipelineBuilder, PipelineDown, PipelineEvent, PipelineUp};
+
+    /// The key from the example handshake in RFC 6455.
+    static KEY: &'static str = "dGhlIHNhbXBsZSBub25jZQ==";
+    static ACCEPT: &'static str = "s3pPLMBiTxaQ9kYGzzhZRbK+xo4=";
+
+    /// RFC 6455's example of a masked frame holding "Hello".
+    static MASKED_HELLO: &'static [u8] =
+        &[0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+
+    fn describe(message: &Message) -> ~str {
+        match *message {
+            Text(ref text) => format!("text {}", *text),
+            Binary(ref data) => format!("binary {}", data.len()),
+            Ping(ref data) => format!("ping {}", text(*data)),
+            Pong(ref data) => format!("pong {}", text(*data)),
+            Close(Some((code, ref reason))) => format!("close {} {}", code, *reason),
+            Close(None) => ~"close"
+        }
+    }
+
+    fn text(data: &[u8]) -> ~str {
+        unsafe { str::raw::from_utf8_owned(data.to_owned()) }
+    }
+
+    fn error_kind<T>(result: IoResult<T>) -> io::IoErrorKind {
+        match result {
+            Ok(_) => fail!("expected an error"),
+            Err(err) => err.kind
+        }
+    }
+
+    fn describe_all(messages: &[Message]) -> ~[~str] {
+        messages.iter().map(|m| describe(m)).collect()
+    }
+
+    fn upgrade_request() -> Request {
+        let mut request = Request::new("GET", "/chat");
+        request.headers.set("Host", "server.example.com");
+        request.headers.set("Upgrade", "websocket");
+        request.headers.set("Connection", "Upgrade");
+        request.headers.set("Sec-WebSocket-Key", KEY);
+        request.headers.set("Sec-WebSocket-Version", "13");
+        request
+    }
+
+    static UPGRADE_REQUEST: &'static str =
+        "GET /chat HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\n\
+         Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n";
+
+    static UPGRADE_RESPONSE: &'static str =
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xo4=\r\n\r\n";
+
+    #[test]
+    fn test_accept_key() {
+        assert_eq!(accept_key(KEY), ACCEPT.to_owned());
+        let response = accept(&upgrade_request()).unwrap();
+        assert_eq!(response.status, 101);
+        assert_eq!(response.headers.get("Sec-WebSocket-Accept"), Some(ACCEPT));
+
+        let mut request = upgrade_request();
+        request.headers.set("Sec-WebSocket-Key", "too short");
+        assert_eq!(error_kind(accept(&request)), io::InvalidInput);
+    }
+
+    #[test]
+    fn test_masked_client_frame() {
+        let server = WebSocketCodec::server();
+        let decoded = server.decode(MASKED_HELLO).unwrap();
+        assert_eq!(describe_all(decoded.messages.as_slice()), ~[~"text Hello"]);
+
+        // The client masks what it sends and the server unmasks it.
+        let (frame, _) = WebSocketCodec::client().encode(Text(~"round trip")).unwrap();
+        assert!(frame[1] & 0x80 != 0);
+        let decoded = server.decode(frame.as_slice()).unwrap();
+        assert_eq!(describe_all(decoded.messages.as_slice()), ~[~"text round trip"]);
+
+        // The server doesn't mask, and won't accept unmasked frames.
+        let (frame, _) = server.encode(Binary(~[1, 2, 3])).unwrap();
+        assert_eq!(frame, ~[0x82, 0x03, 1, 2, 3]);
+        assert_eq!(error_kind(server.decode(frame.as_slice())), io::InvalidInput);
+    }
+
+    #[test]
+    fn test_fragmented_text() {
+        let client = WebSocketCodec::client();
+        // RFC 6455's fragmented "Hello", with a ping between the fragments.
+        let input = bytes!(0x01, 0x03, "Hel", 0x89, 0x00, 0x80, 0x02, "lo");
+        let mut messages = ~[];
+        for byte in input.iter() {
+            let decoded = client.decode(&[*byte]).unwrap();
+            messages.push_all_move(describe_all(decoded.messages.as_slice()));
+        }
+        assert_eq!(messages, ~[~"ping ", ~"text Hello"]);
+
+        // A new message can't start before the fragmented one is complete.
+        let client = WebSocketCodec::client();
+        let result = client.decode(bytes!(0x01, 0x01, "a", 0x81, 0x01, "b"));
+        assert_eq!(error_kind(result), io::InvalidInput);
+    }
+
+    #[test]
+    fn test_ping_answered_with_pong() {
+        let server = WebSocketCodec::server();
+        let (ping, _) = WebSocketCodec::client().encode(Ping(bytes!("hi").to_owned())).unwrap();
+        let decoded = server.decode(ping.as_slice()).unwrap();
+        assert_eq!(decoded.replies, bytes!(0x8a, 0x02, "hi").to_owned());
+        assert_eq!(describe_all(decoded.messages.as_slice()), ~[~"ping hi"]);
+    }
+
+    #[test]
+    fn test_close_echo() {
+        let server = WebSocketCodec::server();
+        let client = WebSocketCodec::client();
+        let (close, _) = client.encode(Close(Some((1001, ~"going away")))).unwrap();
+        let decoded = server.decode(close.as_slice()).unwrap();
+        // The status code is echoed without the reason.
+        assert_eq!(decoded.replies, ~[0x88, 0x02, 0x03, 0xe9]);
+        assert!(decoded.close);
+        assert_eq!(describe_all(decoded.messages.as_slice()), ~[~"close 1001 going away"]);
+        assert_eq!(server.encode(Text(~"late")).unwrap_err().kind, io::InvalidInput);
+
+        // Once the server has sent its own close frame, the client's isn't
+        // answered.
+        let server = WebSocketCodec::server();
+        assert_eq!(server.encode(Close(None)).unwrap(), (~[0x88, 0x00], false));
+        let (close, _) = client.encode(Close(None)).unwrap();
+        let decoded = server.decode(close.as_slice()).unwrap();
+        assert_eq!(decoded.replies.len(), 0);
+        assert!(decoded.close);
+    }
+
+    /// Records the messages sent down and sends text messages back up.
+    struct Echo {
+        messages: MutexArc<~[~str]>
+    }
+
+    impl Filter<Message, (), (), Message> for Echo {
+        fn down<U: PipelineUp<Message>, D: PipelineDown<()>>(
+                &self, data: Message, up: &U, _: &D) -> IoResult<()> {
+            self.messages.access(|messages| messages.push(describe(&data)));
+            match data {
+                Text(text) => up.up(Text(text)),
+                _ => Ok(())
+            }
+        }
+
+        fn up<U: PipelineUp<Message>, D: PipelineDown<()>>(
+                &self, _: (), _: &U, _: &D) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    struct Output {
+        output: MutexArc<~[u8]>,
+        closed: MutexArc<bool>
+    }
+
+    impl PipelineUp<~[u8]> for Output {
+        fn up(&self, data: ~[u8]) -> IoResult<()> {
+            self.output.access(|output| output.push_all(data));
+            Ok(())
+        }
+
+        fn up_event(&self, event: PipelineEvent) -> IoResult<()> {
+            match event {
+                Closed => self.closed.access(|closed| *closed = true),
+                _ => {}
+            }
+            Ok(())
+        }
+    }
+
+    fn upgrade_pipeline(
+            messages: &MutexArc<~[~str]>,
+            output: &MutexArc<~[u8]>,
+            closed: &MutexArc<bool>) -> ~PipelineDown<~[u8]> {
+        let sink = ~Output { output: output.clone(), closed: closed.clone() };
+        PipelineBuilder::new(Echo { messages: messages.clone() })
+            .filter(WebSocketUpgrade::new(WebSocketCodec::server()))
+            .filter(HttpServerCodec::new())
+            .build_with_sink(sink as ~PipelineUp<~[u8]>)
+    }
+
+    #[test]
+    fn test_upgrade_stage() {
+        let messages = MutexArc::new(~[]);
+        let output = MutexArc::new(~[]);
+        let closed = MutexArc::new(false);
+        let pipeline = upgrade_pipeline(&messages, &output, &closed);
+
+        // A frame arriving with the request is decoded once the upgrade has
+        // been answered.
+        let mut input = UPGRADE_REQUEST.as_bytes().to_owned();
+        input.push_all(MASKED_HELLO);
+        pipeline.down(input).unwrap();
+        assert_eq!(messages.access(|m| m.clone()), ~[~"text Hello"]);
+        let mut expected = UPGRADE_RESPONSE.as_bytes().to_owned();
+        expected.push_all(bytes!(0x81, 0x05, "Hello"));
+        assert_eq!(output.access(|o| o.clone()), expected);
+
+        // The close handshake reaches the connection's owner as `Closed`.
+        let (close, _) = WebSocketCodec::client().encode(Close(Some((1000, ~"")))).unwrap();
+        pipeline.down(close).unwrap();
+        assert_eq!(messages.access(|m| m.clone()), ~[~"text Hello", ~"close 1000 "]);
+        expected.push_all(bytes!(0x88, 0x02, 0x03, 0xe8));
+        assert_eq!(output.access(|o| o.clone()), expected);
+        assert!(closed.access(|c| *c));
+    }
+
+    #[test]
+    fn test_upgrade_stage_refuses_other_requests() {
+        let messages = MutexArc::new(~[]);
+        let output = MutexArc::new(~[]);
+        let closed = MutexArc::new(false);
+        let pipeline = upgrade_pipeline(&messages, &output, &closed);
+        pipeline.down(bytes!("GET /chat HTTP/1.1\r\n\r\n").to_owned()).unwrap();
+        let expected = bytes!("HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\
+                               Connection: close\r\n\r\n");
+        assert_eq!(output.access(|o| o.clone()), expected.to_owned());
+        assert!(closed.access(|c| *c));
+    }
+
+    /// Upgrades a `DynamicPipeline` with `upgrade`, naming the new
+    /// application stage `name`.
+    struct Upgrader {
+        name: ~str,
+        request: Option<Request>,
+        messages: MutexArc<~[~str]>
+    }
+
+    impl DynamicFilter for Upgrader {
+        fn types(&self) -> StageTypes {
+            StageTypes::of::<RequestPart, (), (), ResponsePart>()
+        }
+
+        fn down(&mut self, data: ~Any, cx: &mut StageContext) -> IoResult<()> {
+            match try!(downcast::<RequestPart>(data)) {
+                RequestHead(request) => self.request = Some(request),
+                RequestEnd => {
+                    let request = self.request.take_unwrap();
+                    let app = ~typed(Echo { messages: self.messages.clone() }) as ~DynamicFilter;
+                    try!(upgrade(cx, "http", &request, WebSocketCodec::server(),
+                                 self.name.clone(), app));
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+
+        fn up(&mut self, _: ~Any, _: &mut StageContext) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    /// The up sink of a `DynamicPipeline`, recording `Closed`.
+    struct DynamicOutput {
+        closed: MutexArc<bool>
+    }
+
+    impl PipelineUp<~Any> for DynamicOutput {
+        fn up(&self, data: ~Any) -> IoResult<()> {
+            try!(downcast::<~[u8]>(data));
+            Ok(())
+        }
+
+        fn up_event(&self, event: PipelineEvent) -> IoResult<()> {
+            match event {
+                Closed => self.closed.access(|closed| *closed = true),
+                _ => {}
+            }
+            Ok(())
+        }
+    }
+
+    fn dynamic_pipeline(name: &str, messages: &MutexArc<~[~str]>) -> DynamicPipeline {
+        let mut pipeline = DynamicPipeline::new::<~[u8], ~[u8]>();
+        pipeline.add_last(~"http", ~typed(HttpServerCodec::new()) as ~DynamicFilter).unwrap();
+        let upgrader = Upgrader {
+            name: name.to_owned(),
+            request: None,
+            messages: messages.clone()
+        };
+        pipeline.add_last(~"upgrader", ~upgrader as ~DynamicFilter).unwrap();
+        pipeline
+    }
+
+    #[test]
+    fn test_dynamic_upgrade() {
+        let messages = MutexArc::new(~[]);
+        let mut pipeline = dynamic_pipeline("chat", &messages);
+        pipeline.down(UPGRADE_REQUEST.as_bytes().to_owned()).unwrap();
+        assert_eq!(pipeline.names(),
+                   ~[~"http", ~"websocket-tunnel", ~"websocket", ~"chat"]);
+        pipeline.down(MASKED_HELLO.to_owned()).unwrap();
+        assert_eq!(messages.access(|m| m.clone()), ~[~"text Hello"]);
+
+        // The application stage can't take the name of the stage it replaces.
+        let mut pipeline = dynamic_pipeline("upgrader", &messages);
+        let err = pipeline.down(UPGRADE_REQUEST.as_bytes().to_owned()).unwrap_err();
+        assert_eq!(err.kind, io::InvalidInput);
+        assert_eq!(pipeline.names(), ~[~"http", ~"upgrader"]);
+    }
+
+    #[test]
+    fn test_dynamic_upgrade_reports_closed() {
+        let messages = MutexArc::new(~[]);
+        let closed = MutexArc::new(false);
+        let mut pipeline = dynamic_pipeline("chat", &messages);
+        pipeline.set_up_sink(~DynamicOutput { closed: closed.clone() } as ~PipelineUp<~Any>);
+        pipeline.down(UPGRADE_REQUEST.as_bytes().to_owned()).unwrap();
+        assert!(!closed.access(|c| *c));
+
+        // The codec's `Closed` passes through the tunnel and the HTTP stage.
+        let (close, _) = WebSocketCodec::client().encode(Close(Some((1000, ~"")))).unwrap();
+        pipeline.down(close).unwrap();
+        assert_eq!(messages.access(|m| m.clone()), ~[~"close 1000 "]);
+        assert!(closed.access(|c| *c));
+    }
+}
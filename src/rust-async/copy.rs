@@ -0,0 +1,528 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Copying bytes from an `AsyncReader` to an `AsyncWriter`.
+//!
+//! A copy is resumable: `poll` moves as many bytes as it can without
+//! blocking and should be called again whenever either end becomes ready.
+//! When the reader reaches end of stream and all data has been written, the
+//! write side of the writer is shut down so that the peer sees end of stream
+//! as well. Writers that have no separate write side to shut down, such as
+//! pipes, are left open and should be dropped once the copy has finished.
+//!
+//! When both ends expose a descriptor through `splice_fd`, data is moved
+//! with splice(2) instead of being copied through userspace.
+
+use std::io;
+use std::io::{IoResult, IoError};
+use std::libc;
+use std::libc::{c_int, size_t};
+use std::ptr;
+use std::vec;
+
+use async::{AsyncReader, AsyncWriter, ShutdownWrite, is_eof, set_nonblocking, would_block};
+use epoll_selector::EpollSelectable;
+use fcntl;
+
+static BUFFER_SIZE: uint = 64 * 1024;
+
+/// A kernel pipe used as the intermediate buffer for splice(2).
+struct SplicePipe {
+    read_fd: c_int,
+    write_fd: c_int,
+    src_fd: c_int,
+    dst_fd: c_int,
+    buffered: uint
+}
+
+impl SplicePipe {
+    fn new(src_fd: c_int, dst_fd: c_int) -> IoResult<SplicePipe> {
+        let mut fds = [0 as c_int, ..2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+            return Err(IoError::last_error());
+        }
+        let pipe = SplicePipe {
+            read_fd: fds[0],
+            write_fd: fds[1],
+            src_fd: src_fd,
+            dst_fd: dst_fd,
+            buffered: 0
+        };
+        try!(set_nonblocking(src_fd, true));
+        try!(set_nonblocking(dst_fd, true));
+        Ok(pipe)
+    }
+
+    fn splice(fd_in: c_int, fd_out: c_int) -> IoResult<uint> {
+        let res = unsafe {
+            fcntl::splice(
+                fd_in,
+                ptr::mut_null(),
+                fd_out,
+                ptr::mut_null(),
+                BUFFER_SIZE as size_t,
+                fcntl::SPLICE_F_MOVE | fcntl::SPLICE_F_NONBLOCK)
+        };
+        if res < 0 {
+            Err(IoError::last_error())
+        } else {
+            Ok(res as uint)
+        }
+    }
+}
+
+impl Drop for SplicePipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read_fd);
+            libc::close(self.write_fd);
+        }
+    }
+}
+
+/// The state of copying in one direction, independent of the reader and
+/// writer themselves.
+struct CopyState {
+    buf: ~[u8],
+    pos: uint,
+    cap: uint,
+    pipe: Option<SplicePipe>,
+    read_done: bool,
+    write_done: bool,
+    amount: u64
+}
+
+impl CopyState {
+    fn buffered() -> CopyState {
+        CopyState {
+            buf: vec::from_elem(BUFFER_SIZE, 0u8),
+            pos: 0,
+            cap: 0,
+            pipe: None,
+            read_done: false,
+            write_done: false,
+            amount: 0
+        }
+    }
+
+    /// Splice if both ends expose a descriptor, otherwise copy through a
+    /// buffer. Falls back to the buffer if no pipe can be created.
+    fn choose<R: AsyncReader, W: AsyncWriter>(reader: &R, writer: &W) -> CopyState {
+        match (reader.splice_fd(), writer.splice_fd()) {
+            (Some(src_fd), Some(dst_fd)) => match CopyState::spliced(src_fd, dst_fd) {
+                Ok(state) => state,
+                Err(_) => CopyState::buffered()
+            },
+            _ => CopyState::buffered()
+        }
+    }
+
+    fn spliced(src_fd: c_int, dst_fd: c_int) -> IoResult<CopyState> {
+        let pipe = try!(SplicePipe::new(src_fd, dst_fd));
+        Ok(CopyState {
+            buf: ~[],
+            pos: 0,
+            cap: 0,
+            pipe: Some(pipe),
+            read_done: false,
+            write_done: false,
+            amount: 0
+        })
+    }
+
+    /// Copy until blocked. Returns true once everything has been copied and
+    /// the writer has been shut down.
+    fn poll<R: AsyncReader, W: AsyncWriter>(
+            &mut self,
+            reader: &mut R,
+            writer: &mut W) -> IoResult<bool> {
+        if self.write_done {
+            return Ok(true);
+        }
+        let drained = if self.pipe.is_some() {
+            try!(self.poll_splice())
+        } else {
+            try!(self.poll_buffered(reader, writer))
+        };
+        if drained && self.read_done {
+            match writer.async_shutdown(ShutdownWrite) {
+                Ok(()) => {}
+                // Pipes and other descriptors that can't be half-closed are
+                // left open; their owner signals end of stream by dropping
+                // them.
+                Err(ref e) if e.kind == io::IoUnavailable => {}
+                Err(e) => return Err(e)
+            }
+            self.write_done = true;
+        }
+        Ok(self.write_done)
+    }
+
+    /// Returns true if no data is left waiting to be written.
+    fn poll_buffered<R: AsyncReader, W: AsyncWriter>(
+            &mut self,
+            reader: &mut R,
+            writer: &mut W) -> IoResult<bool> {
+        loop {
+            while self.pos < self.cap {
+                match writer.async_write(self.buf.slice(self.pos, self.cap)) {
+                    // The writer would otherwise be asked again forever.
+                    Ok(0) => return Err(write_zero()),
+                    Ok(n) => {
+                        self.pos += n;
+                        self.amount += n as u64;
+                    }
+                    Err(ref e) if would_block(e) => return Ok(false),
+                    Err(e) => return Err(e)
+                }
+            }
+            if self.read_done {
+                return Ok(true);
+            }
+            match reader.async_read(self.buf) {
+                Ok(n) => {
+                    self.pos = 0;
+                    self.cap = n;
+                }
+                Err(ref e) if is_eof(e) => self.read_done = true,
+                Err(ref e) if would_block(e) => return Ok(true),
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    fn poll_splice(&mut self) -> IoResult<bool> {
+        let CopyState { ref mut pipe, ref mut read_done, ref mut amount, .. } = *self;
+        let pipe = pipe.get_mut_ref();
+        loop {
+            while pipe.buffered > 0 {
+                match SplicePipe::splice(pipe.read_fd, pipe.dst_fd) {
+                    Ok(0) => return Err(write_zero()),
+                    Ok(n) => {
+                        pipe.buffered -= n;
+                        *amount += n as u64;
+                    }
+                    Err(ref e) if would_block(e) => return Ok(false),
+                    Err(e) => return Err(e)
+                }
+            }
+            if *read_done {
+                return Ok(true);
+            }
+            match SplicePipe::splice(pipe.src_fd, pipe.write_fd) {
+                Ok(0) => *read_done = true,
+                Ok(n) => pipe.buffered += n,
+                Err(ref e) if would_block(e) => return Ok(true),
+                Err(e) => return Err(e)
+            }
+        }
+    }
+}
+
+/// Copies everything from a reader to a writer.
+pub struct Copy<R, W> {
+    priv reader: R,
+    priv writer: W,
+    priv state: CopyState
+}
+
+fn write_zero() -> IoError {
+    IoError {
+        kind: io::OtherIoError,
+        desc: "The writer accepted no bytes.",
+        detail: None
+    }
+}
+
+/// Copy from `reader` to `writer`. If both expose a descriptor through
+/// `splice_fd`, the copy uses splice(2) and both descriptors are put into
+/// non-blocking mode; otherwise it goes through a userspace buffer.
+pub fn async_copy<R: AsyncReader, W: AsyncWriter>(reader: R, writer: W) -> Copy<R, W> {
+    let state = CopyState::choose(&reader, &writer);
+    Copy { reader: reader, writer: writer, state: state }
+}
+
+/// Copy from `reader` to `writer` with splice(2), without copying through
+/// userspace, and report an error if that isn't possible. Both descriptors
+/// are put into non-blocking mode.
+pub fn async_splice<
+        R: AsyncReader + EpollSelectable,
+        W: AsyncWriter + EpollSelectable
+     >(reader: R, writer: W) -> IoResult<Copy<R, W>> {
+    let state = try!(CopyState::spliced(reader.get_fd(), writer.get_fd()));
+    Ok(Copy { reader: reader, writer: writer, state: state })
+}
+
+impl <R: AsyncReader, W: AsyncWriter> Copy<R, W> {
+    /// Copy as much as possible without blocking. Returns true once the
+    /// reader has reached end of stream, all of its data has been written and
+    /// the writer has been shut down.
+    pub fn poll(&mut self) -> IoResult<bool> {
+        self.state.poll(&mut self.reader, &mut self.writer)
+    }
+
+    /// The number of bytes written so far.
+    pub fn bytes_copied(&self) -> u64 {
+        self.state.amount
+    }
+
+    pub fn unwrap(self) -> (R, W) {
+        let Copy { reader, writer, .. } = self;
+        (reader, writer)
+    }
+}
+
+/// Copies in both directions between two streams, as a proxy does.
+pub struct BidirectionalCopy<A, B> {
+    priv a: A,
+    priv b: B,
+    priv a_to_b: CopyState,
+    priv b_to_a: CopyState
+}
+
+pub fn async_copy_bidirectional<
+        A: AsyncReader + AsyncWriter,
+        B: AsyncReader + AsyncWriter
+     >(a: A, b: B) -> BidirectionalCopy<A, B> {
+    let a_to_b = CopyState::choose(&a, &b);
+    let b_to_a = CopyState::choose(&b, &a);
+    BidirectionalCopy { a: a, b: b, a_to_b: a_to_b, b_to_a: b_to_a }
+}
+
+pub fn async_splice_bidirectional<
+        A: AsyncReader + AsyncWriter + EpollSelectable,
+        B: AsyncReader + AsyncWriter + EpollSelectable
+     >(a: A, b: B) -> IoResult<BidirectionalCopy<A, B>> {
+    let a_to_b = try!(CopyState::spliced(a.get_fd(), b.get_fd()));
+    let b_to_a = try!(CopyState::spliced(b.get_fd(), a.get_fd()));
+    Ok(BidirectionalCopy { a: a, b: b, a_to_b: a_to_b, b_to_a: b_to_a })
+}
+
+impl <
+        A: AsyncReader + AsyncWriter,
+        B: AsyncReader + AsyncWriter
+     >
+        BidirectionalCopy<A, B> {
+    /// Copy as much as possible in both directions without blocking.
+    /// Returns true once both directions have finished.
+    pub fn poll(&mut self) -> IoResult<bool> {
+        let forward = try!(self.a_to_b.poll(&mut self.a, &mut self.b));
+        let backward = try!(self.b_to_a.poll(&mut self.b, &mut self.a));
+        Ok(forward && backward)
+    }
+
+    pub fn bytes_a_to_b(&self) -> u64 {
+        self.a_to_b.amount
+    }
+
+    pub fn bytes_b_to_a(&self) -> u64 {
+        self.b_to_a.amount
+    }
+
+    pub fn unwrap(self) -> (A, B) {
+        let BidirectionalCopy { a, b, .. } = self;
+        (a, b)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{async_copy, async_copy_bidirectional, async_splice};
+
+    use std::io;
+    use std::io::IoResult;
+    use std::libc;
+    use std::libc::c_int;
+    use std::vec;
+
+    use native::io::file::FileDesc;
+
+    use async::{AsyncReader, AsyncWriter, Shutdown, ShutdownWrite, is_eof, set_nonblocking};
+    use async::would_block;
+    use unix::UnixStream;
+
+    /// Forwards to a stream without exposing its descriptor, so copies
+    /// through it can't splice.
+    struct Opaque {
+        inner: UnixStream
+    }
+
+    impl AsyncReader for Opaque {
+        fn async_read(&mut self, output: &mut [u8]) -> IoResult<uint> {
+            self.inner.async_read(output)
+        }
+    }
+
+    impl AsyncWriter for Opaque {
+        fn async_write(&mut self, input: &[u8]) -> IoResult<uint> {
+            self.inner.async_write(input)
+        }
+
+        fn async_shutdown(&mut self, how: Shutdown) -> IoResult<()> {
+            self.inner.async_shutdown(how)
+        }
+    }
+
+    /// A writer that accepts nothing.
+    struct Full;
+
+    impl AsyncWriter for Full {
+        fn async_write(&mut self, _input: &[u8]) -> IoResult<uint> {
+            Ok(0)
+        }
+    }
+
+    /// Read everything available into `out`. Returns true at end of stream.
+    fn drain<R: AsyncReader>(reader: &mut R, out: &mut ~[u8]) -> bool {
+        let mut buf = [0u8, ..4096];
+        loop {
+            match reader.async_read(buf) {
+                Ok(n) => out.push_all(buf.slice_to(n)),
+                Err(ref e) if would_block(e) => return false,
+                Err(ref e) if is_eof(e) => return true,
+                Err(e) => fail!("{}", e)
+            }
+        }
+    }
+
+    /// Write `data` to `src` and then shut it down, calling `poll` and
+    /// reading from `dst` in between, until `dst` reaches end of stream.
+    /// Returns what was read from `dst`.
+    fn pump(src: &mut UnixStream, data: &[u8], dst: &mut UnixStream, poll: || -> bool) -> ~[u8] {
+        let mut written = 0;
+        let mut received = ~[];
+        loop {
+            if written < data.len() {
+                match src.async_write(data.slice_from(written)) {
+                    Ok(n) => written += n,
+                    Err(ref e) if would_block(e) => {}
+                    Err(e) => fail!("{}", e)
+                }
+                if written == data.len() {
+                    src.async_shutdown(ShutdownWrite).unwrap();
+                }
+            }
+            let finished = poll();
+            if drain(dst, &mut received) {
+                assert!(finished);
+                return received;
+            }
+        }
+    }
+
+    /// More than fits in a socket buffer, so that both ends of the copy
+    /// block part way through.
+    fn large_data() -> ~[u8] {
+        vec::from_fn(1024 * 1024, |i| (i % 251) as u8)
+    }
+
+    #[test]
+    fn test_copy_resumes_after_would_block() {
+        let (mut src, src_peer) = UnixStream::pair().unwrap();
+        let (dst_peer, mut dst) = UnixStream::pair().unwrap();
+        let data = large_data();
+        let mut copy = async_copy(Opaque { inner: src_peer }, Opaque { inner: dst_peer });
+        assert!(copy.state.pipe.is_none());
+        let received = pump(&mut src, data.as_slice(), &mut dst, || copy.poll().unwrap());
+        assert!(received == data);
+        assert_eq!(copy.bytes_copied(), data.len() as u64);
+    }
+
+    #[test]
+    fn test_splice_resumes_after_would_block() {
+        let (mut src, src_peer) = UnixStream::pair().unwrap();
+        let (dst_peer, mut dst) = UnixStream::pair().unwrap();
+        let data = large_data();
+        let mut copy = async_splice(src_peer, dst_peer).unwrap();
+        let received = pump(&mut src, data.as_slice(), &mut dst, || copy.poll().unwrap());
+        assert!(received == data);
+        assert_eq!(copy.bytes_copied(), data.len() as u64);
+    }
+
+    #[test]
+    fn test_copy_splices_between_descriptors() {
+        let (mut src, src_peer) = UnixStream::pair().unwrap();
+        let (dst_peer, mut dst) = UnixStream::pair().unwrap();
+        let data = large_data();
+        let mut copy = async_copy(src_peer, dst_peer);
+        assert!(copy.state.pipe.is_some());
+        let received = pump(&mut src, data.as_slice(), &mut dst, || copy.poll().unwrap());
+        assert!(received == data);
+        assert_eq!(copy.bytes_copied(), data.len() as u64);
+    }
+
+    #[test]
+    fn test_copy_fails_when_nothing_is_written() {
+        let (mut src, src_peer) = UnixStream::pair().unwrap();
+        src.async_write(bytes!("stuck")).unwrap();
+        let mut copy = async_copy(src_peer, Full);
+        match copy.poll() {
+            Err(ref e) => assert!(e.kind == io::OtherIoError),
+            Ok(_) => fail!("a writer that accepts nothing must fail the copy")
+        }
+    }
+
+    #[test]
+    fn test_copy_empty_stream() {
+        let (mut src, src_peer) = UnixStream::pair().unwrap();
+        let (dst_peer, mut dst) = UnixStream::pair().unwrap();
+        src.async_shutdown(ShutdownWrite).unwrap();
+        let mut copy = async_copy(src_peer, dst_peer);
+        assert!(copy.poll().unwrap());
+        let mut received = ~[];
+        assert!(drain(&mut dst, &mut received));
+        assert_eq!(received.len(), 0);
+    }
+
+    #[test]
+    fn test_copy_to_pipe() {
+        let mut fds = [0 as c_int, ..2];
+        assert!(unsafe { libc::pipe(fds.as_mut_ptr()) } == 0);
+        set_nonblocking(fds[0], true).unwrap();
+        set_nonblocking(fds[1], true).unwrap();
+        let mut pipe_read = FileDesc::new(fds[0], true);
+        let pipe_write = FileDesc::new(fds[1], true);
+
+        let (mut src, src_peer) = UnixStream::pair().unwrap();
+        src.async_write(bytes!("through a pipe")).unwrap();
+        src.async_shutdown(ShutdownWrite).unwrap();
+
+        // A pipe can't shut down its write side, which must not fail the
+        // copy.
+        let mut copy = async_copy(src_peer, pipe_write);
+        assert!(copy.poll().unwrap());
+        drop(copy);
+
+        let mut received = ~[];
+        assert!(drain(&mut pipe_read, &mut received));
+        assert_eq!(received.as_slice(), bytes!("through a pipe"));
+    }
+
+    #[test]
+    fn test_copy_bidirectional() {
+        let (mut a, a_peer) = UnixStream::pair().unwrap();
+        let (b_peer, mut b) = UnixStream::pair().unwrap();
+        a.async_write(bytes!("ping")).unwrap();
+        a.async_shutdown(ShutdownWrite).unwrap();
+        b.async_write(bytes!("pong")).unwrap();
+
+        let mut copy = async_copy_bidirectional(a_peer, b_peer);
+        let mut at_a = ~[];
+        let mut at_b = ~[];
+        assert!(!copy.poll().unwrap());
+        assert!(drain(&mut b, &mut at_b));
+        assert!(!drain(&mut a, &mut at_a));
+
+        // One direction finishing leaves the other open.
+        b.async_shutdown(ShutdownWrite).unwrap();
+        assert!(copy.poll().unwrap());
+        assert!(drain(&mut a, &mut at_a));
+        assert_eq!(at_a.as_slice(), bytes!("pong"));
+        assert_eq!(at_b.as_slice(), bytes!("ping"));
+        assert_eq!(copy.bytes_a_to_b(), 4);
+        assert_eq!(copy.bytes_b_to_a(), 4);
+    }
+}
@@ -0,0 +1,1016 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Pipelines whose stages can be changed while they are running.
+//!
+//! A `PipelineBuilder` fixes its filters at compile time. A `DynamicPipeline`
+//! instead holds a list of named stages that exchange boxed values, so that
+//! a stage can, for example, insert a TLS stage after STARTTLS or replace an
+//! HTTP stage with a WebSocket stage after an upgrade. Every change is type
+//! checked against the neighbouring stages when it is made.
+//!
+//! Data sent down enters at the first stage and data sent up enters at the
+//! last stage, just as with `PipelineBuilder`. Events travel the same way and
+//! are passed on unchanged by stages that don't handle them.
+
+use std::any::{Any, AnyOwnExt};
+use std::cell::RefCell;
+use std::io;
+use std::io::{IoResult, IoError};
+use std::util;
+use std::unstable::intrinsics::TypeId;
+
+use pipeline::{Filter, PipelineDown, PipelineEvent, PipelineUp};
+
+/// The types that a stage exchanges with its neighbours.
+#[deriving(Clone, Eq)]
+pub struct StageTypes {
+    /// The type received from the stage above.
+    down_in: TypeId,
+    /// The type sent to the stage below.
+    down_out: TypeId,
+    /// The type received from the stage below.
+    up_in: TypeId,
+    /// The type sent to the stage above.
+    up_out: TypeId
+}
+
+impl StageTypes {
+    pub fn of<Din: 'static, Dout: 'static, Uin: 'static, Uout: 'static>() -> StageTypes {
+        StageTypes {
+            down_in: TypeId::of::<Din>(),
+            down_out: TypeId::of::<Dout>(),
+            up_in: TypeId::of::<Uin>(),
+            up_out: TypeId::of::<Uout>()
+        }
+    }
+}
+
+/// A stage of a `DynamicPipeline`.
+pub trait DynamicFilter {
+    fn types(&self) -> StageTypes;
+
+    /// Handle a value of type `types().down_in` travelling down.
+    fn down(&mut self, data: ~Any, cx: &mut StageContext) -> IoResult<()>;
+
+    /// Handle a value of type `types().up_in` travelling up.
+    fn up(&mut self, data: ~Any, cx: &mut StageContext) -> IoResult<()>;
+
+    /// Handle an event travelling down. The default passes it on.
+    fn down_event(&mut self, event: PipelineEvent, cx: &mut StageContext) -> IoResult<()> {
+        cx.send_down_event(event);
+        Ok(())
+    }
+
+    /// Handle an event travelling up. The default passes it on.
+    fn up_event(&mut self, event: PipelineEvent, cx: &mut StageContext) -> IoResult<()> {
+        cx.send_up_event(event);
+        Ok(())
+    }
+}
+
+/// Recover the concrete value from a boxed value handed to a stage.
+pub fn downcast<T: 'static>(data: ~Any) -> IoResult<T> {
+    match data.move::<T>() {
+        Ok(value) => Ok(*value),
+        Err(_) => Err(IoError {
+            kind: io::InvalidInput,
+            desc: "A pipeline stage received a value of an unexpected type.",
+            detail: None
+        })
+    }
+}
+
+/// Something travelling through the pipeline.
+enum Item {
+    Data(~Any),
+    Event(PipelineEvent)
+}
+
+enum Change {
+    InsertBefore(~str, ~str, ~DynamicFilter),
+    InsertAfter(~str, ~str, ~DynamicFilter),
+    Remove(~str),
+    Replace(~str, ~DynamicFilter)
+}
+
+/// Passed to a stage while it handles a value or an event. Whatever it sends
+/// is delivered to the neighbouring stages, and changes to the pipeline are
+/// applied, after the stage returns.
+pub struct StageContext {
+    priv name: ~str,
+    priv down: ~[Item],
+    priv up: ~[Item],
+    priv changes: ~[Change]
+}
+
+impl StageContext {
+    /// The name of the stage being called.
+    pub fn name<'a>(&'a self) -> &'a str {
+        self.name.as_slice()
+    }
+
+    pub fn send_down<T: 'static>(&mut self, data: T) {
+        self.down.push(Data(~data as ~Any));
+    }
+
+    pub fn send_up<T: 'static>(&mut self, data: T) {
+        self.up.push(Data(~data as ~Any));
+    }
+
+    pub fn send_down_event(&mut self, event: PipelineEvent) {
+        self.down.push(Event(event));
+    }
+
+    pub fn send_up_event(&mut self, event: PipelineEvent) {
+        self.up.push(Event(event));
+    }
+
+    /// Insert a new stage directly above the stage called `name`.
+    pub fn insert_before(&mut self, name: &str, new_name: ~str, filter: ~DynamicFilter) {
+        self.changes.push(InsertBefore(name.to_owned(), new_name, filter));
+    }
+
+    /// Insert a new stage directly below the stage called `name`.
+    pub fn insert_after(&mut self, name: &str, new_name: ~str, filter: ~DynamicFilter) {
+        self.changes.push(InsertAfter(name.to_owned(), new_name, filter));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.changes.push(Remove(name.to_owned()));
+    }
+
+    /// Replace the filter of the stage called `name`, keeping its name.
+    pub fn replace(&mut self, name: &str, filter: ~DynamicFilter) {
+        self.changes.push(Replace(name.to_owned(), filter));
+    }
+}
+
+struct Stage {
+    name: ~str,
+    types: StageTypes,
+    filter: ~DynamicFilter
+}
+
+enum Direction {
+    Down,
+    Up
+}
+
+/// A value or event waiting to be delivered. The target is an index into the
+/// stages; -1 is the sink above the first stage and `stages.len()` the sink
+/// below the last.
+struct Delivery {
+    target: int,
+    direction: Direction,
+    item: Item
+}
+
+pub struct DynamicPipeline {
+    priv stages: ~[Stage],
+    priv top_down: TypeId,
+    priv top_up: TypeId,
+    priv up_sink: Option<~PipelineUp<~Any>>,
+    priv down_sink: Option<~PipelineDown<~Any>>,
+    priv queue: ~[Delivery]
+}
+
+fn type_mismatch(name: &str) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "The types of adjacent pipeline stages do not match.",
+        detail: Some(format!("stage: {}", name))
+    }
+}
+
+fn no_such_stage(name: &str) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "There is no pipeline stage with that name.",
+        detail: Some(format!("stage: {}", name))
+    }
+}
+
+impl DynamicPipeline {
+    /// Create an empty pipeline that accepts `Din` sent down and produces
+    /// `Uout` sent up out of its first stage.
+    pub fn new<Din: 'static, Uout: 'static>() -> DynamicPipeline {
+        DynamicPipeline {
+            stages: ~[],
+            top_down: TypeId::of::<Din>(),
+            top_up: TypeId::of::<Uout>(),
+            up_sink: None,
+            down_sink: None,
+            queue: ~[]
+        }
+    }
+
+    /// Receive values sent up out of the first stage. Without a sink they
+    /// are discarded.
+    pub fn set_up_sink(&mut self, sink: ~PipelineUp<~Any>) {
+        self.up_sink = Some(sink);
+    }
+
+    /// Receive values sent down out of the last stage. Without a sink they
+    /// are discarded.
+    pub fn set_down_sink(&mut self, sink: ~PipelineDown<~Any>) {
+        self.down_sink = Some(sink);
+    }
+
+    /// The names of the stages, from first to last.
+    pub fn names(&self) -> ~[~str] {
+        self.stages.iter().map(|s| s.name.clone()).collect()
+    }
+
+    fn position(&self, name: &str) -> IoResult<uint> {
+        match self.stages.iter().position(|s| s.name.as_slice() == name) {
+            Some(i) => Ok(i),
+            None => Err(no_such_stage(name))
+        }
+    }
+
+    /// Check that a stage with `types` fits between the stage at `above`
+    /// (the top of the pipeline if `above` is -1) and the stage at `below`
+    /// (which may be past the end).
+    fn check_fit(&self, above: int, below: uint, types: &StageTypes, name: &str) -> IoResult<()> {
+        let (above_down, above_up) = if above < 0 {
+            (self.top_down, self.top_up)
+        } else {
+            let s = &self.stages[above as uint].types;
+            (s.down_out, s.up_in)
+        };
+        if types.down_in != above_down || types.up_out != above_up {
+            return Err(type_mismatch(name));
+        }
+        if below < self.stages.len() {
+            let s = &self.stages[below].types;
+            if types.down_out != s.down_in || types.up_in != s.up_out {
+                return Err(type_mismatch(name));
+            }
+        }
+        Ok(())
+    }
+
+    fn insert_at(&mut self, index: uint, name: ~str, filter: ~DynamicFilter) -> IoResult<()> {
+        if self.stages.iter().any(|s| s.name == name) {
+            return Err(IoError {
+                kind: io::InvalidInput,
+                desc: "A pipeline stage with that name already exists.",
+                detail: Some(format!("stage: {}", name))
+            });
+        }
+        let types = filter.types();
+        try!(self.check_fit(index as int - 1, index, &types, name.as_slice()));
+        self.stages.insert(index, Stage { name: name, types: types, filter: filter });
+        for d in self.queue.mut_iter() {
+            if d.target >= index as int {
+                d.target += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a stage below all of the existing ones.
+    pub fn add_last(&mut self, name: ~str, filter: ~DynamicFilter) -> IoResult<()> {
+        let len = self.stages.len();
+        self.insert_at(len, name, filter)
+    }
+
+    pub fn insert_before(
+            &mut self,
+            name: &str,
+            new_name: ~str,
+            filter: ~DynamicFilter) -> IoResult<()> {
+        let i = try!(self.position(name));
+        self.insert_at(i, new_name, filter)
+    }
+
+    pub fn insert_after(
+            &mut self,
+            name: &str,
+            new_name: ~str,
+            filter: ~DynamicFilter) -> IoResult<()> {
+        let i = try!(self.position(name));
+        self.insert_at(i + 1, new_name, filter)
+    }
+
+    /// Remove a stage, returning its filter. The stages above and below it
+    /// must fit together.
+    pub fn remove(&mut self, name: &str) -> IoResult<~DynamicFilter> {
+        let i = try!(self.position(name));
+        if i + 1 < self.stages.len() {
+            let below = self.stages[i + 1].types.clone();
+            try!(self.check_fit(i as int - 1, i + 2, &below, self.stages[i + 1].name.as_slice()));
+        }
+        let stage = self.stages.remove(i);
+        for d in self.queue.mut_iter() {
+            if d.target > i as int {
+                d.target -= 1;
+            } else if d.target == i as int {
+                // The stage below slides into this position, which is where
+                // data going down should go next; data going up skips to
+                // the stage above.
+                match d.direction {
+                    Down => {}
+                    Up => d.target -= 1
+                }
+            }
+        }
+        Ok(stage.filter)
+    }
+
+    /// Replace the filter of a stage, returning the old one.
+    pub fn replace(&mut self, name: &str, filter: ~DynamicFilter) -> IoResult<~DynamicFilter> {
+        let i = try!(self.position(name));
+        let types = filter.types();
+        try!(self.check_fit(i as int - 1, i + 1, &types, name));
+        self.stages[i].types = types;
+        Ok(util::replace(&mut self.stages[i].filter, filter))
+    }
+
+    fn apply(&mut self, change: Change) -> IoResult<()> {
+        match change {
+            InsertBefore(name, new_name, filter) => {
+                self.insert_before(name.as_slice(), new_name, filter)
+            }
+            InsertAfter(name, new_name, filter) => {
+                self.insert_after(name.as_slice(), new_name, filter)
+            }
+            Remove(name) => self.remove(name.as_slice()).map(|_| ()),
+            Replace(name, filter) => self.replace(name.as_slice(), filter).map(|_| ())
+        }
+    }
+
+    /// Send a value into the first stage.
+    pub fn down<T: 'static>(&mut self, data: T) -> IoResult<()> {
+        if TypeId::of::<T>() != self.top_down {
+            return Err(type_mismatch("<top>"));
+        }
+        self.queue.push(Delivery { target: 0, direction: Down, item: Data(~data as ~Any) });
+        self.process()
+    }
+
+    /// Send an event into the first stage.
+    pub fn down_event(&mut self, event: PipelineEvent) -> IoResult<()> {
+        self.queue.push(Delivery { target: 0, direction: Down, item: Event(event) });
+        self.process()
+    }
+
+    /// Send a value up into the last stage.
+    pub fn up<T: 'static>(&mut self, data: T) -> IoResult<()> {
+        let target = self.stages.len() as int - 1;
+        if target >= 0 && TypeId::of::<T>() != self.stages[target as uint].types.up_in {
+            return Err(type_mismatch("<bottom>"));
+        }
+        self.queue.push(Delivery { target: target, direction: Up, item: Data(~data as ~Any) });
+        self.process()
+    }
+
+    /// Send an event up into the last stage.
+    pub fn up_event(&mut self, event: PipelineEvent) -> IoResult<()> {
+        let target = self.stages.len() as int - 1;
+        self.queue.push(Delivery { target: target, direction: Up, item: Event(event) });
+        self.process()
+    }
+
+    /// Deliver queued values until the queue is empty. After an error the
+    /// rest of the queue is discarded, so that it isn't delivered along with
+    /// the next value.
+    fn process(&mut self) -> IoResult<()> {
+        let res = self.process_queue();
+        if res.is_err() {
+            self.queue = ~[];
+        }
+        res
+    }
+
+    fn process_queue(&mut self) -> IoResult<()> {
+        loop {
+            let Delivery { target, direction, item } = match self.queue.shift() {
+                Some(d) => d,
+                None => return Ok(())
+            };
+
+            if target < 0 {
+                match self.up_sink {
+                    Some(ref sink) => try!(match item {
+                        Data(data) => sink.up(data),
+                        Event(event) => sink.up_event(event)
+                    }),
+                    None => {}
+                }
+                continue;
+            }
+            if target as uint >= self.stages.len() {
+                match self.down_sink {
+                    Some(ref sink) => try!(match item {
+                        Data(data) => sink.down(data),
+                        Event(event) => sink.down_event(event)
+                    }),
+                    None => {}
+                }
+                continue;
+            }
+
+            let mut cx = StageContext {
+                name: self.stages[target as uint].name.clone(),
+                down: ~[],
+                up: ~[],
+                changes: ~[]
+            };
+            {
+                let filter = &mut self.stages[target as uint].filter;
+                try!(match (direction, item) {
+                    (Down, Data(data)) => filter.down(data, &mut cx),
+                    (Down, Event(event)) => filter.down_event(event, &mut cx),
+                    (Up, Data(data)) => filter.up(data, &mut cx),
+                    (Up, Event(event)) => filter.up_event(event, &mut cx)
+                });
+            }
+
+            let StageContext { name, down, up, changes } = cx;
+            for change in changes.move_iter() {
+                try!(self.apply(change));
+            }
+
+            // The stage may have moved or been removed by its own changes.
+            let (above, below) = match self.position(name.as_slice()) {
+                Ok(i) => (i as int - 1, i as int + 1),
+                Err(_) => (target - 1, target)
+            };
+            for item in down.move_iter() {
+                self.queue.push(Delivery { target: below, direction: Down, item: item });
+            }
+            for item in up.move_iter() {
+                self.queue.push(Delivery { target: above, direction: Up, item: item });
+            }
+        }
+    }
+}
+
+struct Outputs {
+    down: RefCell<~[Item]>,
+    up: RefCell<~[Item]>
+}
+
+struct DownCollector<'a, T> {
+    outputs: &'a Outputs
+}
+
+impl <'a, T: 'static> PipelineDown<T> for DownCollector<'a, T> {
+    fn down(&self, data: T) -> IoResult<()> {
+        self.outputs.down.borrow_mut().get().push(Data(~data as ~Any));
+        Ok(())
+    }
+
+    fn down_event(&self, event: PipelineEvent) -> IoResult<()> {
+        self.outputs.down.borrow_mut().get().push(Event(event));
+        Ok(())
+    }
+}
+
+struct UpCollector<'a, T> {
+    outputs: &'a Outputs
+}
+
+impl <'a, T: 'static> PipelineUp<T> for UpCollector<'a, T> {
+    fn up(&self, data: T) -> IoResult<()> {
+        self.outputs.up.borrow_mut().get().push(Data(~data as ~Any));
+        Ok(())
+    }
+
+    fn up_event(&self, event: PipelineEvent) -> IoResult<()> {
+        self.outputs.up.borrow_mut().get().push(Event(event));
+        Ok(())
+    }
+}
+
+/// Adapts a statically typed `Filter` into a `DynamicFilter`.
+pub struct Typed<F, Din, Dout, Uin, Uout> {
+    priv filter: F
+}
+
+pub fn typed<
+        Din: 'static, Dout: 'static, Uin: 'static, Uout: 'static,
+        F: Filter<Din, Dout, Uin, Uout>
+     >(filter: F) -> Typed<F, Din, Dout, Uin, Uout> {
+    Typed { filter: filter }
+}
+
+fn forward(outputs: Outputs, cx: &mut StageContext) {
+    let Outputs { down, up } = outputs;
+    cx.down.push_all_move(down.unwrap());
+    cx.up.push_all_move(up.unwrap());
+}
+
+impl <
+        Din: 'static, Dout: 'static, Uin: 'static, Uout: 'static,
+        F: Filter<Din, Dout, Uin, Uout>
+     >
+        DynamicFilter for Typed<F, Din, Dout, Uin, Uout> {
+    fn types(&self) -> StageTypes {
+        StageTypes::of::<Din, Dout, Uin, Uout>()
+    }
+
+    fn down(&mut self, data: ~Any, cx: &mut StageContext) -> IoResult<()> {
+        let data: Din = try!(downcast(data));
+        let outputs = Outputs { down: RefCell::new(~[]), up: RefCell::new(~[]) };
+        {
+            let up: UpCollector<Uout> = UpCollector { outputs: &outputs };
+            let down: DownCollector<Dout> = DownCollector { outputs: &outputs };
+            let res = match self.filter.down(data, &up, &down) {
+                Err(err) => self.filter.error(err, &up, &down),
+                Ok(()) => Ok(())
+            };
+            try!(res);
+        }
+        forward(outputs, cx);
+        Ok(())
+    }
+
+    fn up(&mut self, data: ~Any, cx: &mut StageContext) -> IoResult<()> {
+        let data: Uin = try!(downcast(data));
+        let outputs = Outputs { down: RefCell::new(~[]), up: RefCell::new(~[]) };
+        {
+            let up: UpCollector<Uout> = UpCollector { outputs: &outputs };
+            let down: DownCollector<Dout> = DownCollector { outputs: &outputs };
+            let res = match self.filter.up(data, &up, &down) {
+                Err(err) => self.filter.error(err, &up, &down),
+                Ok(()) => Ok(())
+            };
+            try!(res);
+        }
+        forward(outputs, cx);
+        Ok(())
+    }
+
+    fn down_event(&mut self, event: PipelineEvent, cx: &mut StageContext) -> IoResult<()> {
+        let outputs = Outputs { down: RefCell::new(~[]), up: RefCell::new(~[]) };
+        {
+            let up: UpCollector<Uout> = UpCollector { outputs: &outputs };
+            let down: DownCollector<Dout> = DownCollector { outputs: &outputs };
+            let res = match self.filter.down_event(event, &up, &down) {
+                Err(err) => self.filter.error(err, &up, &down),
+                Ok(()) => Ok(())
+            };
+            try!(res);
+        }
+        forward(outputs, cx);
+        Ok(())
+    }
+
+    fn up_event(&mut self, event: PipelineEvent, cx: &mut StageContext) -> IoResult<()> {
+        let outputs = Outputs { down: RefCell::new(~[]), up: RefCell::new(~[]) };
+        {
+            let up: UpCollector<Uout> = UpCollector { outputs: &outputs };
+            let down: DownCollector<Dout> = DownCollector { outputs: &outputs };
+            let res = match self.filter.up_event(event, &up, &down) {
+                Err(err) => self.filter.error(err, &up, &down),
+                Ok(()) => Ok(())
+            };
+            try!(res);
+        }
+        forward(outputs, cx);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DynamicFilter, DynamicPipeline, StageContext, StageTypes, downcast, typed};
+
+    use std::any::Any;
+    use std::io;
+    use std::io::IoResult;
+    use std::uint;
+    use std::util;
+
+    use sync::MutexArc;
+
+    use pipeline::{Closed, Filter, Flush, PipelineDown, PipelineEvent, PipelineUp};
+
+    struct AddOne;
+
+    impl Filter<uint, uint, uint, uint> for AddOne {
+        fn down<U: PipelineUp<uint>, D: PipelineDown<uint>>(
+                &self, data: uint, _: &U, down: &D) -> IoResult<()> {
+            down.down(data + 1)
+        }
+
+        fn up<U: PipelineUp<uint>, D: PipelineDown<uint>>(
+                &self, data: uint, up: &U, _: &D) -> IoResult<()> {
+            up.up(data + 1)
+        }
+    }
+
+    struct Double;
+
+    impl Filter<uint, uint, uint, uint> for Double {
+        fn down<U: PipelineUp<uint>, D: PipelineDown<uint>>(
+                &self, data: uint, _: &U, down: &D) -> IoResult<()> {
+            down.down(data * 2)
+        }
+
+        fn up<U: PipelineUp<uint>, D: PipelineDown<uint>>(
+                &self, data: uint, up: &U, _: &D) -> IoResult<()> {
+            up.up(data * 2)
+        }
+    }
+
+    /// Turns numbers into strings going down and strings into their lengths
+    /// going up.
+    struct Stringify;
+
+    impl Filter<uint, ~str, ~str, uint> for Stringify {
+        fn down<U: PipelineUp<uint>, D: PipelineDown<~str>>(
+                &self, data: uint, _: &U, down: &D) -> IoResult<()> {
+            down.down(data.to_str())
+        }
+
+        fn up<U: PipelineUp<uint>, D: PipelineDown<~str>>(
+                &self, data: ~str, up: &U, _: &D) -> IoResult<()> {
+            up.up(data.len())
+        }
+    }
+
+    /// Sends zero down in place of a flush.
+    struct FlushAsZero;
+
+    impl Filter<uint, uint, uint, uint> for FlushAsZero {
+        fn down<U: PipelineUp<uint>, D: PipelineDown<uint>>(
+                &self, data: uint, _: &U, down: &D) -> IoResult<()> {
+            down.down(data)
+        }
+
+        fn up<U: PipelineUp<uint>, D: PipelineDown<uint>>(
+                &self, data: uint, up: &U, _: &D) -> IoResult<()> {
+            up.up(data)
+        }
+
+        fn down_event<U: PipelineUp<uint>, D: PipelineDown<uint>>(
+                &self, event: PipelineEvent, _: &U, down: &D) -> IoResult<()> {
+            match event {
+                Flush => down.down(0),
+                event => down.down_event(event)
+            }
+        }
+    }
+
+    fn add_one() -> ~DynamicFilter {
+        ~typed::<uint, uint, uint, uint, AddOne>(AddOne) as ~DynamicFilter
+    }
+
+    fn double() -> ~DynamicFilter {
+        ~typed::<uint, uint, uint, uint, Double>(Double) as ~DynamicFilter
+    }
+
+    fn stringify() -> ~DynamicFilter {
+        ~typed::<uint, ~str, ~str, uint, Stringify>(Stringify) as ~DynamicFilter
+    }
+
+    fn uint_types() -> StageTypes {
+        StageTypes::of::<uint, uint, uint, uint>()
+    }
+
+    /// Sends every value down twice, the second time plus 100.
+    struct Split;
+
+    impl DynamicFilter for Split {
+        fn types(&self) -> StageTypes {
+            uint_types()
+        }
+
+        fn down(&mut self, data: ~Any, cx: &mut StageContext) -> IoResult<()> {
+            let n: uint = try!(downcast(data));
+            cx.send_down(n);
+            cx.send_down(n + 100);
+            Ok(())
+        }
+
+        fn up(&mut self, data: ~Any, cx: &mut StageContext) -> IoResult<()> {
+            let n: uint = try!(downcast(data));
+            cx.send_up(n);
+            Ok(())
+        }
+    }
+
+    /// Passes values on, inserting an `AddOne` stage above itself the first
+    /// time it sees one.
+    struct InsertOnce {
+        inserted: bool
+    }
+
+    impl DynamicFilter for InsertOnce {
+        fn types(&self) -> StageTypes {
+            uint_types()
+        }
+
+        fn down(&mut self, data: ~Any, cx: &mut StageContext) -> IoResult<()> {
+            let n: uint = try!(downcast(data));
+            if !self.inserted {
+                self.inserted = true;
+                let name = cx.name().to_owned();
+                cx.insert_before(name, ~"inserted", add_one());
+            }
+            cx.send_down(n);
+            Ok(())
+        }
+
+        fn up(&mut self, data: ~Any, cx: &mut StageContext) -> IoResult<()> {
+            let n: uint = try!(downcast(data));
+            cx.send_up(n);
+            Ok(())
+        }
+    }
+
+    /// Passes the first value on in either direction and then removes
+    /// itself.
+    struct RemoveSelf;
+
+    impl RemoveSelf {
+        fn pass(&mut self, data: ~Any, cx: &mut StageContext, down: bool) -> IoResult<()> {
+            let n: uint = try!(downcast(data));
+            if down {
+                cx.send_down(n);
+            } else {
+                cx.send_up(n);
+            }
+            let name = cx.name().to_owned();
+            cx.remove(name);
+            Ok(())
+        }
+    }
+
+    impl DynamicFilter for RemoveSelf {
+        fn types(&self) -> StageTypes {
+            uint_types()
+        }
+
+        fn down(&mut self, data: ~Any, cx: &mut StageContext) -> IoResult<()> {
+            self.pass(data, cx, true)
+        }
+
+        fn up(&mut self, data: ~Any, cx: &mut StageContext) -> IoResult<()> {
+            self.pass(data, cx, false)
+        }
+    }
+
+    /// Fails on zero and passes everything else on.
+    struct FailOnZero;
+
+    impl DynamicFilter for FailOnZero {
+        fn types(&self) -> StageTypes {
+            uint_types()
+        }
+
+        fn down(&mut self, data: ~Any, cx: &mut StageContext) -> IoResult<()> {
+            let n: uint = try!(downcast(data));
+            if n == 0 {
+                return Err(io::standard_error(io::InvalidInput));
+            }
+            cx.send_down(n);
+            Ok(())
+        }
+
+        fn up(&mut self, data: ~Any, cx: &mut StageContext) -> IoResult<()> {
+            let n: uint = try!(downcast(data));
+            cx.send_up(n);
+            Ok(())
+        }
+    }
+
+    struct Collect {
+        values: MutexArc<~[uint]>
+    }
+
+    impl Collect {
+        fn push(&self, data: ~Any) -> IoResult<()> {
+            let n: uint = try!(downcast(data));
+            self.values.access(|values| values.push(n));
+            Ok(())
+        }
+    }
+
+    /// Records a flush as `uint::MAX` and `Closed` as `uint::MAX - 1`.
+    fn record_event(values: &MutexArc<~[uint]>, event: PipelineEvent) {
+        match event {
+            Flush => values.access(|values| values.push(uint::MAX)),
+            Closed => values.access(|values| values.push(uint::MAX - 1)),
+            _ => {}
+        }
+    }
+
+    impl PipelineDown<~Any> for Collect {
+        fn down(&self, data: ~Any) -> IoResult<()> {
+            self.push(data)
+        }
+
+        fn down_event(&self, event: PipelineEvent) -> IoResult<()> {
+            record_event(&self.values, event);
+            Ok(())
+        }
+    }
+
+    impl PipelineUp<~Any> for Collect {
+        fn up(&self, data: ~Any) -> IoResult<()> {
+            self.push(data)
+        }
+
+        fn up_event(&self, event: PipelineEvent) -> IoResult<()> {
+            record_event(&self.values, event);
+            Ok(())
+        }
+    }
+
+    /// A pipeline of `uint` stages whose sinks record what reaches them.
+    fn collecting_pipeline() -> (DynamicPipeline, MutexArc<~[uint]>, MutexArc<~[uint]>) {
+        let mut pipeline = DynamicPipeline::new::<uint, uint>();
+        let down = MutexArc::new(~[]);
+        let up = MutexArc::new(~[]);
+        pipeline.set_down_sink(~Collect { values: down.clone() } as ~PipelineDown<~Any>);
+        pipeline.set_up_sink(~Collect { values: up.clone() } as ~PipelineUp<~Any>);
+        (pipeline, down, up)
+    }
+
+    fn taken(values: &MutexArc<~[uint]>) -> ~[uint] {
+        values.access(|values| util::replace(values, ~[]))
+    }
+
+    fn error_kind<T>(result: IoResult<T>) -> io::IoErrorKind {
+        match result {
+            Ok(_) => fail!("expected an error"),
+            Err(err) => err.kind
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (mut pipeline, down, up) = collecting_pipeline();
+        pipeline.add_last(~"first", add_one()).unwrap();
+        pipeline.add_last(~"second", double()).unwrap();
+        pipeline.down(1u).unwrap();
+        pipeline.up(1u).unwrap();
+        assert_eq!(taken(&down), ~[4]);
+        assert_eq!(taken(&up), ~[3]);
+    }
+
+    #[test]
+    fn test_insert_and_remove() {
+        let (mut pipeline, down, _) = collecting_pipeline();
+        pipeline.add_last(~"a", add_one()).unwrap();
+        pipeline.add_last(~"b", double()).unwrap();
+        pipeline.insert_before("b", ~"c", add_one()).unwrap();
+        pipeline.insert_after("b", ~"d", add_one()).unwrap();
+        assert_eq!(pipeline.names(), ~[~"a", ~"c", ~"b", ~"d"]);
+        pipeline.down(0u).unwrap();
+        assert_eq!(taken(&down), ~[5]);
+
+        assert!(pipeline.remove("c").unwrap().types() == uint_types());
+        assert_eq!(pipeline.names(), ~[~"a", ~"b", ~"d"]);
+        pipeline.down(0u).unwrap();
+        assert_eq!(taken(&down), ~[3]);
+
+        assert_eq!(error_kind(pipeline.remove("c")), io::InvalidInput);
+        assert_eq!(error_kind(pipeline.add_last(~"a", add_one())), io::InvalidInput);
+        assert_eq!(error_kind(pipeline.insert_before("x", ~"e", add_one())), io::InvalidInput);
+        assert_eq!(pipeline.names(), ~[~"a", ~"b", ~"d"]);
+    }
+
+    #[test]
+    fn test_replace() {
+        let (mut pipeline, down, _) = collecting_pipeline();
+        pipeline.add_last(~"a", add_one()).unwrap();
+        pipeline.down(3u).unwrap();
+        let old = pipeline.replace("a", double()).unwrap();
+        assert!(old.types() == uint_types());
+        pipeline.down(3u).unwrap();
+        assert_eq!(taken(&down), ~[4, 6]);
+        assert_eq!(pipeline.names(), ~[~"a"]);
+    }
+
+    #[test]
+    fn test_type_checks() {
+        let (mut pipeline, _, up) = collecting_pipeline();
+        pipeline.add_last(~"number", add_one()).unwrap();
+        pipeline.add_last(~"string", stringify()).unwrap();
+
+        // A `uint` stage can't follow one that sends strings down.
+        assert_eq!(error_kind(pipeline.add_last(~"after", add_one())), io::InvalidInput);
+        // Nor can the string stage be swapped for one that doesn't.
+        assert_eq!(error_kind(pipeline.replace("number", stringify())), io::InvalidInput);
+        // Values of the wrong type are refused at either end.
+        assert_eq!(error_kind(pipeline.down(~"text")), io::InvalidInput);
+        assert_eq!(error_kind(pipeline.up(1u)), io::InvalidInput);
+        assert_eq!(pipeline.names(), ~[~"number", ~"string"]);
+
+        pipeline.up(~"four").unwrap();
+        assert_eq!(taken(&up), ~[5]);
+    }
+
+    #[test]
+    fn test_remove_checks_neighbours() {
+        let (mut pipeline, _, _) = collecting_pipeline();
+        let unstringify = ~typed::<~str, uint, uint, ~str, Unstringify>(Unstringify);
+        pipeline.add_last(~"string", stringify()).unwrap();
+        pipeline.add_last(~"bytes", unstringify as ~DynamicFilter).unwrap();
+        pipeline.add_last(~"number", add_one()).unwrap();
+        // Without the middle stage, a string would be sent into a `uint`
+        // stage.
+        assert_eq!(error_kind(pipeline.remove("bytes")), io::InvalidInput);
+        assert_eq!(pipeline.names(), ~[~"string", ~"bytes", ~"number"]);
+    }
+
+    /// The reverse of `Stringify`.
+    struct Unstringify;
+
+    impl Filter<~str, uint, uint, ~str> for Unstringify {
+        fn down<U: PipelineUp<~str>, D: PipelineDown<uint>>(
+                &self, data: ~str, _: &U, down: &D) -> IoResult<()> {
+            down.down(data.len())
+        }
+
+        fn up<U: PipelineUp<~str>, D: PipelineDown<uint>>(
+                &self, data: uint, up: &U, _: &D) -> IoResult<()> {
+            up.up(data.to_str())
+        }
+    }
+
+    #[test]
+    fn test_queued_deliveries_follow_inserted_stage() {
+        let (mut pipeline, down, _) = collecting_pipeline();
+        pipeline.add_last(~"split", ~Split as ~DynamicFilter).unwrap();
+        pipeline.add_last(~"insert", ~InsertOnce { inserted: false } as ~DynamicFilter).unwrap();
+
+        // The second value was already queued for "insert" when the new
+        // stage went in above it, so it goes to "insert" rather than to the
+        // new stage.
+        pipeline.down(1u).unwrap();
+        assert_eq!(taken(&down), ~[1, 101]);
+        assert_eq!(pipeline.names(), ~[~"split", ~"inserted", ~"insert"]);
+
+        pipeline.down(1u).unwrap();
+        assert_eq!(taken(&down), ~[2, 102]);
+    }
+
+    #[test]
+    fn test_stage_removing_itself() {
+        let (mut pipeline, down, up) = collecting_pipeline();
+        pipeline.add_last(~"a", add_one()).unwrap();
+        pipeline.add_last(~"once", ~RemoveSelf as ~DynamicFilter).unwrap();
+        pipeline.add_last(~"b", double()).unwrap();
+
+        // What the stage sent before removing itself still reaches its
+        // neighbour.
+        pipeline.down(1u).unwrap();
+        assert_eq!(taken(&down), ~[4]);
+        assert_eq!(pipeline.names(), ~[~"a", ~"b"]);
+
+        pipeline.add_last(~"once", ~RemoveSelf as ~DynamicFilter).unwrap();
+        pipeline.add_last(~"c", add_one()).unwrap();
+        pipeline.up(1u).unwrap();
+        assert_eq!(taken(&up), ~[5]);
+        assert_eq!(pipeline.names(), ~[~"a", ~"b", ~"c"]);
+    }
+
+    #[test]
+    fn test_error_discards_queue() {
+        let (mut pipeline, down, _) = collecting_pipeline();
+        pipeline.add_last(~"split", ~Split as ~DynamicFilter).unwrap();
+        pipeline.add_last(~"fail", ~FailOnZero as ~DynamicFilter).unwrap();
+
+        // The 100 queued behind the failing 0 is dropped with it.
+        assert_eq!(error_kind(pipeline.down(0u)), io::InvalidInput);
+        assert_eq!(taken(&down), ~[]);
+
+        pipeline.down(1u).unwrap();
+        assert_eq!(taken(&down), ~[1, 101]);
+    }
+
+    #[test]
+    fn test_events_pass_through_stages() {
+        let (mut pipeline, down, up) = collecting_pipeline();
+        pipeline.add_last(~"typed", add_one()).unwrap();
+        pipeline.add_last(~"dynamic", ~Split as ~DynamicFilter).unwrap();
+        pipeline.down_event(Closed).unwrap();
+        pipeline.up_event(Flush).unwrap();
+        assert_eq!(taken(&down), ~[uint::MAX - 1]);
+        assert_eq!(taken(&up), ~[uint::MAX]);
+    }
+
+    #[test]
+    fn test_typed_filter_handles_events() {
+        let (mut pipeline, down, up) = collecting_pipeline();
+        let flush_as_zero = ~typed::<uint, uint, uint, uint, FlushAsZero>(FlushAsZero);
+        pipeline.add_last(~"flush", flush_as_zero as ~DynamicFilter).unwrap();
+        pipeline.add_last(~"add", add_one()).unwrap();
+        pipeline.down_event(Flush).unwrap();
+        pipeline.down_event(Closed).unwrap();
+        // Only the direction the filter overrides is affected.
+        pipeline.up_event(Flush).unwrap();
+        assert_eq!(taken(&down), ~[1, uint::MAX - 1]);
+        assert_eq!(taken(&up), ~[uint::MAX]);
+    }
+}
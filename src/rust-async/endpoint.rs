@@ -0,0 +1,405 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Attaches a byte pipeline to a registered stream.
+//!
+//! An `Endpoint` is both ends of the pipeline at once: when the stream is
+//! readable it reads everything available and sends it down the pipeline, and
+//! everything the pipeline sends up out of its first filter is collected by an
+//! `EndpointSink` and written to the stream, buffering whatever the stream
+//! will not take yet.
+//!
+//! The pipeline is sent `Opened` when the endpoint is created, and anything
+//! it sends up in response is written straight away. It is sent `ReadEof`
+//! when the peer shuts down its side, after which the endpoint stops reading
+//! but stays open until everything sent up has been written. It is sent
+//! `WritabilityChanged` when unwritten output crosses the endpoint's
+//! watermarks, and `Closed` when the endpoint is closed. A filter may send
+//! `PauseRead` up to stop the endpoint reading from the stream, typically
+//! when it sees the stream become unwritable, and `ResumeRead` once it
+//! becomes writable again. Sending `Closed` up makes `ready` return a
+//! `Closed` error, so that the owner closes the endpoint, once everything
+//! sent up before it has been written.
+
+use std::io;
+use std::io::IoResult;
+use std::util;
+use std::vec;
+
+use sync::MutexArc;
+
+use async::{AsyncReader, AsyncWriter, is_eof, would_block};
+use epoll_selector::{EpollSelectable, EpollSelectionHandle};
+use pipeline::{PipelineDown, PipelineUp, PipelineEvent, Watermarks};
+use pipeline::{Opened, ReadEof, Closed, WritabilityChanged, PauseRead, ResumeRead};
+use select;
+use select::{ReadyMode, SelectorHandle};
+
+static READ_BUFFER_SIZE: uint = 64 * 1024;
+
+/// What a pipeline has sent up since the endpoint last looked.
+struct Output {
+    buf: ~[u8],
+    read_paused: bool,
+    close_requested: bool
+}
+
+/// The sink at the top of an endpoint's pipeline. Collects the bytes sent up
+/// out of the pipeline so that the endpoint can write them to the stream.
+pub struct EndpointSink {
+    priv output: MutexArc<Output>
+}
+
+impl PipelineUp<~[u8]> for EndpointSink {
+    fn up(&self, data: ~[u8]) -> IoResult<()> {
+        self.output.access(|output| output.buf.push_all(data));
+        Ok(())
+    }
+
+    fn up_event(&self, event: PipelineEvent) -> IoResult<()> {
+        match event {
+            PauseRead => self.output.access(|output| output.read_paused = true),
+            ResumeRead => self.output.access(|output| output.read_paused = false),
+            Closed => self.output.access(|output| output.close_requested = true),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+pub struct Endpoint<N, S> {
+    priv handle: EpollSelectionHandle<N, S>,
+    priv pipeline: ~PipelineDown<~[u8]>,
+    priv output: MutexArc<Output>,
+    /// Reused for every read from the stream.
+    priv read_buf: ~[u8],
+    /// Output not yet taken by the stream, from `written` onwards.
+    priv pending: ~[u8],
+    priv written: uint,
+    priv watermarks: Watermarks,
+    priv reading: bool,
+    priv read_eof: bool,
+    priv want_write: bool
+}
+
+impl <
+        N: Send + Freeze,
+        S: AsyncReader + AsyncWriter + EpollSelectable
+     >
+        Endpoint<N, S> {
+    /// Attach the pipeline returned by `build` to the stream in `handle`,
+    /// which must be registered for reading. `build` is given the sink that
+    /// the pipeline must send its output up to.
+    pub fn new(
+            handle: EpollSelectionHandle<N, S>,
+            watermarks: Watermarks,
+            build: |~PipelineUp<~[u8]>| -> ~PipelineDown<~[u8]>) -> IoResult<Endpoint<N, S>> {
+        let output = MutexArc::new(Output { buf: ~[], read_paused: false, close_requested: false });
+        let sink = ~EndpointSink { output: output.clone() } as ~PipelineUp<~[u8]>;
+        let pipeline = build(sink);
+        try!(pipeline.down_event(Opened));
+        let mut endpoint = Endpoint {
+            handle: handle,
+            pipeline: pipeline,
+            output: output,
+            read_buf: vec::from_elem(READ_BUFFER_SIZE, 0u8),
+            pending: ~[],
+            written: 0,
+            watermarks: watermarks,
+            reading: true,
+            read_eof: false,
+            want_write: false
+        };
+        try!(endpoint.write());
+        Ok(endpoint)
+    }
+
+    pub fn get_ref<'a>(&'a self) -> &'a S {
+        self.handle.get_ref()
+    }
+
+    pub fn get_mut_ref<'a>(&'a mut self) -> &'a mut S {
+        self.handle.get_mut_ref()
+    }
+
+    /// The number of bytes sent up by the pipeline that the stream has not
+    /// taken yet.
+    pub fn pending(&self) -> uint {
+        self.pending.len() - self.written
+    }
+
+    /// Handle readiness. An error means that the endpoint should be closed.
+    /// End of stream from the peer is only reported, as an `EndOfFile` error,
+    /// once everything sent up has been written.
+    pub fn ready(&mut self, mode: ReadyMode) -> IoResult<()> {
+        if self.reading && mode.satisfies(select::SelectRead) {
+            match self.read() {
+                Ok(()) => {}
+                Err(e) => {
+                    // Try to write out whatever the pipeline sent up before
+                    // the error before giving up.
+                    let _ = self.write();
+                    return Err(e)
+                }
+            }
+        }
+        self.write()
+    }
+
+    /// Write `data` to the stream as if the pipeline had sent it up.
+    pub fn send(&mut self, data: &[u8]) -> IoResult<()> {
+        self.output.access(|output| output.buf.push_all(data));
+        self.write()
+    }
+
+    /// Send `event` down the pipeline, then write whatever the pipeline sends
+    /// up in response. This is how the owner of a client connection usually
+    /// hands requests to the filter at the bottom of the pipeline, wrapped in
+    /// a `UserEvent`.
+    pub fn send_event(&mut self, event: PipelineEvent) -> IoResult<()> {
+        try!(self.pipeline.down_event(event));
+        self.write()
+    }
+
+    /// Send `Closed` down the pipeline and return the stream.
+    pub fn close(self) -> S {
+        let Endpoint { handle, pipeline, .. } = self;
+        let _ = pipeline.down_event(Closed);
+        handle.unwrap()
+    }
+
+    fn read(&mut self) -> IoResult<()> {
+        loop {
+            // Stop as soon as a filter asks for reading to be paused.
+            if self.output.access(|output| output.read_paused) {
+                return Ok(());
+            }
+            match self.handle.get_mut_ref().async_read(self.read_buf) {
+                // An error from the pipeline closes the endpoint.
+                Ok(n) => try!(self.pipeline.down(self.read_buf.slice_to(n).to_owned())),
+                Err(ref e) if would_block(e) => return Ok(()),
+                Err(ref e) if is_eof(e) => {
+                    self.read_eof = true;
+                    return self.pipeline.down_event(ReadEof);
+                }
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    fn write(&mut self) -> IoResult<()> {
+        let output = self.output.access(|output| util::replace(&mut output.buf, ~[]));
+        self.pending.push_all(output);
+        try!(self.signal_writability(self.watermarks.add(output.len())));
+
+        let start = self.written;
+        while self.written < self.pending.len() {
+            match self.handle.get_mut_ref().async_write(self.pending.slice_from(self.written)) {
+                Ok(n) => self.written += n,
+                Err(ref e) if would_block(e) => break,
+                Err(e) => return Err(e)
+            }
+        }
+        let written = self.written - start;
+        self.compact();
+        try!(self.signal_writability(self.watermarks.remove(written)));
+
+        try!(self.update_interest());
+        if !self.want_write {
+            if self.output.access(|output| output.close_requested) {
+                return Err(io::standard_error(io::Closed));
+            }
+            if self.read_eof {
+                return Err(io::standard_error(io::EndOfFile));
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the written part of `pending` once it is all written or makes up
+    /// at least half of it, so that each byte is moved a bounded number of
+    /// times however slowly the stream takes it.
+    fn compact(&mut self) {
+        if self.written == self.pending.len() {
+            self.pending.truncate(0);
+            self.written = 0;
+        } else if self.written >= self.pending.len() / 2 {
+            self.pending = self.pending.slice_from(self.written).to_owned();
+            self.written = 0;
+        }
+    }
+
+    fn signal_writability(&mut self, change: Option<bool>) -> IoResult<()> {
+        match change {
+            Some(writable) => self.pipeline.down_event(WritabilityChanged(writable)),
+            None => Ok(())
+        }
+    }
+
+    /// Only ask for readability while reading is not paused and the peer
+    /// has not shut down its side, and for writability while there is
+    /// something left to write, including anything sent up in response to a
+    /// writability change.
+    fn update_interest(&mut self) -> IoResult<()> {
+        let (paused, unsent) =
+            self.output.access(|output| (output.read_paused, output.buf.len() > 0));
+        let reading = !paused && !self.read_eof;
+        let want_write = unsent || self.pending() > 0;
+        if reading != self.reading || want_write != self.want_write {
+            let mode = match (reading, want_write) {
+                (true, true) => select::SelectBoth,
+                (true, false) => select::SelectRead,
+                (false, true) => select::SelectWrite,
+                (false, false) => select::SelectIgnore
+            };
+            try!(self.handle.modify(mode, true));
+            self.reading = reading;
+            self.want_write = want_write;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Endpoint;
+
+    use std::io;
+    use std::io::IoResult;
+    use std::vec;
+
+    use async::{AsyncReader, AsyncWriter, ShutdownWrite, is_eof, would_block};
+    use epoll_selector::EpollSelector;
+    use pipeline::{Filter, PipelineBuilder, PipelineDown, PipelineEvent, PipelineUp, Watermarks};
+    use pipeline::{Opened, ReadEof};
+    use select;
+    use select::{NullNotifier, Selector};
+    use unix::UnixStream;
+
+    /// Greets the peer when opened, echoes what it is sent and answers end
+    /// of stream with `farewell`.
+    struct Greeter {
+        farewell: ~[u8]
+    }
+
+    impl Filter<~[u8], (), (), ~[u8]> for Greeter {
+        fn down<U: PipelineUp<~[u8]>, D: PipelineDown<()>>(
+                &self, data: ~[u8], up: &U, _: &D) -> IoResult<()> {
+            up.up(data)
+        }
+
+        fn up<U: PipelineUp<~[u8]>, D: PipelineDown<()>>(
+                &self, _: (), _: &U, _: &D) -> IoResult<()> {
+            Ok(())
+        }
+
+        fn down_event<U: PipelineUp<~[u8]>, D: PipelineDown<()>>(
+                &self, event: PipelineEvent, up: &U, _: &D) -> IoResult<()> {
+            match event {
+                Opened => up.up(bytes!("hello\n").to_owned()),
+                ReadEof => up.up(self.farewell.clone()),
+                _ => Ok(())
+            }
+        }
+    }
+
+    fn drain(stream: &mut UnixStream, out: &mut ~[u8]) {
+        let mut buf = [0u8, ..64 * 1024];
+        loop {
+            match stream.async_read(buf) {
+                Ok(n) => out.push_all(buf.slice_to(n)),
+                Err(ref e) if would_block(e) || is_eof(e) => return,
+                Err(e) => fail!("{}", e)
+            }
+        }
+    }
+
+    fn open_endpoint(
+            selector: &EpollSelector<NullNotifier>,
+            stream: UnixStream,
+            farewell: ~[u8]) -> Endpoint<NullNotifier, UnixStream> {
+        let handle = selector.register(stream, 1, select::SelectRead, true).unwrap();
+        let watermarks = Watermarks::new(1024, 4096);
+        Endpoint::new(handle, watermarks, |sink| {
+            PipelineBuilder::new(Greeter { farewell: farewell.clone() }).build_with_sink(sink)
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_opened_output_is_written() {
+        let selector = EpollSelector::new(NullNotifier).unwrap();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let endpoint = open_endpoint(&selector, server, ~[]);
+        let mut received = ~[];
+        drain(&mut client, &mut received);
+        assert_eq!(received.as_slice(), bytes!("hello\n"));
+        assert_eq!(endpoint.pending(), 0);
+    }
+
+    #[test]
+    fn test_half_close_waits_for_output() {
+        let selector = EpollSelector::new(NullNotifier).unwrap();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let farewell = vec::from_elem(4 * 1024 * 1024, 'x' as u8);
+        let mut endpoint = open_endpoint(&selector, server, farewell.clone());
+
+        client.async_write(bytes!("ping")).unwrap();
+        client.async_shutdown(ShutdownWrite).unwrap();
+
+        // The farewell doesn't fit in the socket, so the endpoint stays open
+        // after end of stream until it has all been written.
+        endpoint.ready(select::ReadyRead).unwrap();
+        assert!(endpoint.pending() > 0);
+
+        let mut received = ~[];
+        loop {
+            drain(&mut client, &mut received);
+            match endpoint.ready(select::ReadyWrite) {
+                Ok(()) => assert!(endpoint.pending() > 0),
+                Err(e) => {
+                    assert_eq!(e.kind, io::EndOfFile);
+                    break;
+                }
+            }
+        }
+        drain(&mut client, &mut received);
+        assert_eq!(endpoint.pending(), 0);
+        assert_eq!(received.len(), 6 + 4 + farewell.len());
+        assert_eq!(received.slice_to(10), bytes!("hello\nping"));
+    }
+
+    #[test]
+    fn test_eof_closes_once_drained() {
+        let selector = EpollSelector::new(NullNotifier).unwrap();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut endpoint = open_endpoint(&selector, server, bytes!("bye\n").to_owned());
+        client.async_shutdown(ShutdownWrite).unwrap();
+        assert_eq!(endpoint.ready(select::ReadyRead).unwrap_err().kind, io::EndOfFile);
+        let mut received = ~[];
+        drain(&mut client, &mut received);
+        assert_eq!(received.as_slice(), bytes!("hello\nbye\n"));
+    }
+
+    #[test]
+    fn test_partial_writes_keep_order() {
+        let selector = EpollSelector::new(NullNotifier).unwrap();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let farewell = vec::from_fn(4 * 1024 * 1024, |i| (i % 251) as u8);
+        let mut endpoint = open_endpoint(&selector, server, farewell.clone());
+        client.async_shutdown(ShutdownWrite).unwrap();
+
+        // The stream takes the farewell a socket buffer at a time.
+        let mut received = ~[];
+        let mut res = endpoint.ready(select::ReadyRead);
+        while res.is_ok() {
+            drain(&mut client, &mut received);
+            res = endpoint.ready(select::ReadyWrite);
+        }
+        drain(&mut client, &mut received);
+        assert_eq!(received.slice_to(6), bytes!("hello\n"));
+        assert!(received.slice_from(6) == farewell.as_slice());
+    }
+}
@@ -12,10 +12,13 @@ use native;
 use select;
 use select::{SelectMode, ReadyMode, Selector, SelectEvent, SelectorHandle, SelectNotifier};
 
+use std::cell::RefCell;
 use std::libc;
 use std::libc::c_int;
 use std::io;
 use std::io::{IoResult, IoError};
+use std::os;
+use std::vec;
 
 use sync::{Arc, MutexArc};
 
@@ -42,9 +45,9 @@ struct Epoll<N> {
 
 fn events_flags(mode: SelectMode, rearm: bool) -> u32 {
     let events = match mode {
-        select::SelectRead => epoll::EPOLLIN,
+        select::SelectRead => epoll::EPOLLIN | epoll::EPOLLRDHUP,
         select::SelectWrite => epoll::EPOLLOUT,
-        select::SelectBoth => epoll::EPOLLIN | epoll::EPOLLOUT,
+        select::SelectBoth => epoll::EPOLLIN | epoll::EPOLLRDHUP | epoll::EPOLLOUT,
         select::SelectIgnore => 0
     };
     if !rearm {
@@ -117,7 +120,9 @@ impl <S: EpollSelectable, N> Epoll<N> {
 }
 
 pub struct EpollSelector<N> {
-    priv epoll: Arc<Epoll<N>>
+    priv epoll: Arc<Epoll<N>>,
+    /// Receives the kernel's events in `select`, grown as needed.
+    priv epoll_events: RefCell<~[epoll_event]>
 }
 
 impl <N: SelectNotifier + Send + Freeze> EpollSelector<N> {
@@ -147,50 +152,88 @@ impl <N: SelectNotifier + Send + Freeze> EpollSelector<N> {
             epoll_fd: epoll_fd,
             notifier: notifier
         };
-        let epoll_selector = EpollSelector { epoll: Arc::new(epoll) };
+        let epoll_selector = EpollSelector {
+            epoll: Arc::new(epoll),
+            epoll_events: RefCell::new(~[])
+        };
         Ok(epoll_selector)
     }
 
-    pub fn run(&self) {
-        let mut events = [epoll_event { events: 0, data: 0}, ..8];
-        let mut notify_events = [SelectEvent { mode: select::ReadyRead, data: 0 }, ..8];
+    /// Wait for at most `timeout_ms` milliseconds (or forever, if negative)
+    /// for registered selectables to become ready. The ready events are
+    /// written into `events` and their number is returned. An interrupted
+    /// wait returns `Ok(0)`. `events` must not be empty.
+    pub fn select(&self, events: &mut [SelectEvent], timeout_ms: int) -> IoResult<uint> {
+        if events.len() == 0 {
+            return Err(IoError {
+                kind: io::InvalidInput,
+                desc: "There is no room for any events.",
+                detail: None
+            });
+        }
+        let mut epoll_events = self.epoll_events.borrow_mut();
+        let epoll_events = epoll_events.get();
+        if epoll_events.len() < events.len() {
+            let more = events.len() - epoll_events.len();
+            epoll_events.push_all_move(vec::from_fn(more, |_| epoll_event { events: 0, data: 0 }));
+        }
 
         fn is_set(event: u32, flag: u32) -> bool { event & flag != 0 }
 
-        let epoll = self.epoll.get();
+        let res = unsafe {
+            epoll::epoll_wait(
+                self.epoll.get().epoll_fd,
+                epoll_events.as_ptr(),
+                events.len() as c_int,
+                timeout_ms as c_int)
+        };
+
+        if res < 0 {
+            return match os::errno() as c_int {
+                libc::EINTR => Ok(0),
+                n => fail!(format!("Unexpected error code {} - this is probably a bug.", n))
+            };
+        }
+
+        for i in range(0, res as uint) {
+            events[i].data = epoll_events[i].data;
+            events[i].read_closed = is_set(epoll_events[i].events, epoll::EPOLLRDHUP);
+
+            events[i].mode =
+                if is_set(epoll_events[i].events, epoll::EPOLLERR) ||
+                        is_set(epoll_events[i].events, epoll::EPOLLHUP) {
+                    select::ReadyBoth
+                } else if events[i].read_closed &&
+                        is_set(epoll_events[i].events, epoll::EPOLLOUT) {
+                    select::ReadyBoth
+                } else if is_set(epoll_events[i].events, epoll::EPOLLIN) &&
+                        is_set(epoll_events[i].events, epoll::EPOLLOUT) {
+                    select::ReadyBoth
+                } else if events[i].read_closed ||
+                        is_set(epoll_events[i].events, epoll::EPOLLIN) {
+                    select::ReadyRead
+                } else if is_set(epoll_events[i].events, epoll::EPOLLOUT) {
+                    select::ReadyWrite
+                } else {
+                    unreachable!()
+                };
+        }
+
+        Ok(res as uint)
+    }
+
+    pub fn run(&self) {
+        let mut notify_events =
+            [SelectEvent { mode: select::ReadyRead, data: 0, read_closed: false }, ..8];
 
         loop {
-            let res = unsafe {
-                epoll::epoll_wait(epoll.epoll_fd, events.as_ptr(), events.len() as c_int, -1)
+            let n = match self.select(notify_events, -1) {
+                Ok(0) => continue,
+                Ok(n) => n,
+                Err(e) => fail!(format!("Unexpected error {} - this is probably a bug.", e))
             };
 
-            match res {
-                libc::EINTR => continue,
-                n if n < 0 =>
-                    fail!(format!("Unexpected error code {} - this is probably a bug.", n)),
-                _ => {}
-            }
-
-            for i in range(0, res as uint) {
-                notify_events[i].data = events[i].data;
-
-                notify_events[i].mode =
-                    if is_set(events[i].events, epoll::EPOLLERR) ||
-                            is_set(events[i].events, epoll::EPOLLHUP) {
-                        select::ReadyBoth
-                    } else if is_set(events[i].events, epoll::EPOLLIN) &&
-                            is_set(events[i].events, epoll::EPOLLOUT) {
-                        select::ReadyBoth
-                    } else if is_set(events[i].events, epoll::EPOLLIN) {
-                        select::ReadyRead
-                    } else if is_set(events[i].events, epoll::EPOLLOUT) {
-                        select::ReadyWrite
-                    } else {
-                        unreachable!()
-                    };
-            }
-
-            epoll.notifier.notify(notify_events.slice_to(res as uint));
+            self.epoll.get().notifier.notify(notify_events.slice_to(n));
         }
     }
 
@@ -216,13 +259,21 @@ impl <N: Send + Freeze, S: EpollSelectable> Selector<S, EpollSelectionHandle<N,
     }
 }
 
-struct EpollSelectionHandle<N, S> {
-    epoll: Arc<Epoll<N>>,
-    selectable: Option<S>,
-    data: u64
+pub struct EpollSelectionHandle<N, S> {
+    priv epoll: Arc<Epoll<N>>,
+    priv selectable: Option<S>,
+    priv data: u64
 }
 
 impl <N: Send + Freeze, S: EpollSelectable> EpollSelectionHandle<N, S> {
+    pub fn get_ref<'a>(&'a self) -> &'a S {
+        self.selectable.get_ref()
+    }
+
+    pub fn get_mut_ref<'a>(&'a mut self) -> &'a mut S {
+        self.selectable.get_mut_ref()
+    }
+
     pub fn unwrap(mut self) -> S {
         let selectable = self.selectable.take_unwrap();
         // ignore potential error - is there anything we could do with it?
@@ -246,3 +297,44 @@ impl <N: Send + Freeze, S: EpollSelectable> Drop for EpollSelectionHandle<N, S>
         let _ = self.epoll.get().remove(&self.selectable.take_unwrap());
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::EpollSelector;
+
+    use std::io;
+
+    use async::AsyncWriter;
+    use select;
+    use select::{NullNotifier, SelectEvent, Selector};
+    use unix::UnixStream;
+
+    #[test]
+    fn test_select_without_room_for_events() {
+        let selector = EpollSelector::new(NullNotifier).unwrap();
+        let mut events: [SelectEvent, ..0] = [];
+        assert_eq!(selector.select(events, 0).unwrap_err().kind, io::InvalidInput);
+    }
+
+    #[test]
+    fn test_select_reuses_buffer() {
+        let selector = EpollSelector::new(NullNotifier).unwrap();
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let (c, mut d) = UnixStream::pair().unwrap();
+        let _a = selector.register(a, 1, select::SelectRead, true).unwrap();
+        let _c = selector.register(c, 2, select::SelectRead, true).unwrap();
+        b.async_write(bytes!("x")).unwrap();
+        d.async_write(bytes!("x")).unwrap();
+
+        // Fewer events than are ready, then more.
+        let mut one = [SelectEvent { mode: select::ReadyWrite, data: 0, read_closed: false }];
+        assert_eq!(selector.select(one, 0).unwrap(), 1);
+        assert!(one[0].mode == select::ReadyRead);
+        let mut four =
+            [SelectEvent { mode: select::ReadyWrite, data: 0, read_closed: false }, ..4];
+        assert_eq!(selector.select(four, 0).unwrap(), 2);
+        let mut tokens = ~[four[0].data, four[1].data];
+        tokens.sort();
+        assert_eq!(tokens, ~[1, 2]);
+    }
+}
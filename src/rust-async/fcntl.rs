@@ -0,0 +1,27 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::libc::{c_int, c_uint, size_t, ssize_t};
+
+pub static F_GETFL: c_int = 3;
+pub static F_SETFL: c_int = 4;
+
+pub static O_NONBLOCK: c_int = 0x800;
+
+pub static SPLICE_F_MOVE: c_uint = 1;
+pub static SPLICE_F_NONBLOCK: c_uint = 2;
+
+extern {
+    pub fn fcntl(fd: c_int, cmd: c_int, arg: c_int) -> c_int;
+
+    pub fn splice(
+        fd_in: c_int,
+        off_in: *mut i64,
+        fd_out: c_int,
+        off_out: *mut i64,
+        len: size_t,
+        flags: c_uint) -> ssize_t;
+}
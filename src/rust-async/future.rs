@@ -0,0 +1,447 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Composable asynchronous operations.
+//!
+//! A `Future` is polled by an `Executor` each time the selector reports
+//! events or a timer expires. The leaves of a future are readiness of a
+//! registered selectable (`readable`, `writable`) and timers (`sleep`); more
+//! complex operations are built from those with `map`, `and_then`, `join`,
+//! `select` and `timeout`. For example, a connect, write, read sequence on a
+//! selectable registered with `data` of 1 might be written as:
+//!
+//! ```ignore
+//! let op = future::writable(1)
+//!     .and_then(proc(_) { /* write request */ future::readable(1) })
+//!     .map(proc(_) { /* read response */ })
+//!     .timeout(5000);
+//! executor.block_on(op);
+//! ```
+
+use std::io;
+use std::io::{IoResult, IoError};
+use std::util;
+
+use extra::time;
+
+use epoll_selector::EpollSelector;
+use select;
+use select::{SelectEvent, SelectMode, NullNotifier};
+
+/// The result of polling a future that did not fail.
+pub enum Poll<T> {
+    Complete(T),
+    Pending
+}
+
+/// State made available to futures while they are being polled.
+pub struct Context {
+    priv now: u64,
+    priv ready: ~[SelectEvent],
+    priv deadline: Option<u64>
+}
+
+impl Context {
+    /// The time, in nanoseconds, at which the current round of polling
+    /// started.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Returns true if the selectable registered with `data` was reported
+    /// ready for `mode` in the most recent round of events.
+    pub fn is_ready(&self, data: u64, mode: SelectMode) -> bool {
+        self.ready.iter().any(|e| e.data == data && e.mode.satisfies(mode))
+    }
+
+    /// Ask the executor to poll again no later than `deadline` (in
+    /// nanoseconds, as returned by `now`).
+    pub fn wake_at(&mut self, deadline: u64) {
+        self.deadline = match self.deadline {
+            Some(d) if d <= deadline => Some(d),
+            _ => Some(deadline)
+        };
+    }
+}
+
+pub trait Future<T> {
+    /// Make as much progress as possible without blocking.
+    fn poll(&mut self, cx: &mut Context) -> IoResult<Poll<T>>;
+
+    /// Transform the result of this future once it completes.
+    fn map<U>(self, f: proc(T) -> U) -> Map<Self, T, U> {
+        Map { future: self, f: Some(f) }
+    }
+
+    /// Once this future completes, use its result to start another.
+    fn and_then<U, B: Future<U>>(self, f: proc(T) -> B) -> AndThen<Self, T, B> {
+        AndThen { state: First(self, f) }
+    }
+
+    /// Run this future and `other` concurrently, completing when both have.
+    fn join<U, B: Future<U>>(self, other: B) -> Join<Self, B, T, U> {
+        Join { a: self, b: other, ra: None, rb: None }
+    }
+
+    /// Run this future and `other` concurrently, completing with the result
+    /// of whichever finishes first.
+    fn select<B: Future<T>>(self, other: B) -> Select<Self, B> {
+        Select { a: self, b: other }
+    }
+
+    /// Fail this future if it has not completed within `ms` milliseconds.
+    fn timeout(self, ms: u64) -> Timeout<Self> {
+        Timeout { future: self, timer: sleep(ms) }
+    }
+}
+
+pub struct Map<A, T, U> {
+    priv future: A,
+    priv f: Option<proc(T) -> U>
+}
+
+impl <T, U, A: Future<T>> Future<U> for Map<A, T, U> {
+    fn poll(&mut self, cx: &mut Context) -> IoResult<Poll<U>> {
+        match try!(self.future.poll(cx)) {
+            Complete(v) => {
+                let f = self.f.take().expect("Map polled after completion");
+                Ok(Complete(f(v)))
+            }
+            Pending => Ok(Pending)
+        }
+    }
+}
+
+enum AndThenState<A, T, B> {
+    First(A, proc(T) -> B),
+    Second(B),
+    Finished
+}
+
+pub struct AndThen<A, T, B> {
+    priv state: AndThenState<A, T, B>
+}
+
+impl <T, U, A: Future<T>, B: Future<U>> Future<U> for AndThen<A, T, B> {
+    fn poll(&mut self, cx: &mut Context) -> IoResult<Poll<U>> {
+        loop {
+            let next = match self.state {
+                First(ref mut a, _) => match try!(a.poll(cx)) {
+                    Complete(v) => v,
+                    Pending => return Ok(Pending)
+                },
+                Second(ref mut b) => return b.poll(cx),
+                Finished => fail!("AndThen polled after completion")
+            };
+            let state = util::replace(&mut self.state, Finished);
+            match state {
+                First(_, f) => self.state = Second(f(next)),
+                _ => unreachable!()
+            }
+        }
+    }
+}
+
+pub struct Join<A, B, T, U> {
+    priv a: A,
+    priv b: B,
+    priv ra: Option<T>,
+    priv rb: Option<U>
+}
+
+impl <T, U, A: Future<T>, B: Future<U>> Future<(T, U)> for Join<A, B, T, U> {
+    fn poll(&mut self, cx: &mut Context) -> IoResult<Poll<(T, U)>> {
+        if self.ra.is_none() {
+            match try!(self.a.poll(cx)) {
+                Complete(v) => self.ra = Some(v),
+                Pending => {}
+            }
+        }
+        if self.rb.is_none() {
+            match try!(self.b.poll(cx)) {
+                Complete(v) => self.rb = Some(v),
+                Pending => {}
+            }
+        }
+        if self.ra.is_some() && self.rb.is_some() {
+            Ok(Complete((self.ra.take_unwrap(), self.rb.take_unwrap())))
+        } else {
+            Ok(Pending)
+        }
+    }
+}
+
+pub struct Select<A, B> {
+    priv a: A,
+    priv b: B
+}
+
+impl <T, A: Future<T>, B: Future<T>> Future<T> for Select<A, B> {
+    fn poll(&mut self, cx: &mut Context) -> IoResult<Poll<T>> {
+        match try!(self.a.poll(cx)) {
+            Complete(v) => return Ok(Complete(v)),
+            Pending => {}
+        }
+        self.b.poll(cx)
+    }
+}
+
+pub struct Timeout<A> {
+    priv future: A,
+    priv timer: Sleep
+}
+
+impl <T, A: Future<T>> Future<T> for Timeout<A> {
+    fn poll(&mut self, cx: &mut Context) -> IoResult<Poll<T>> {
+        match try!(self.future.poll(cx)) {
+            Complete(v) => return Ok(Complete(v)),
+            Pending => {}
+        }
+        match try!(self.timer.poll(cx)) {
+            Complete(()) => Err(IoError {
+                kind: io::OtherIoError,
+                desc: "The operation timed out.",
+                detail: None
+            }),
+            Pending => Ok(Pending)
+        }
+    }
+}
+
+/// A future that completes once the selectable registered with `data` is
+/// reported ready for `mode`.
+pub struct Readiness {
+    priv data: u64,
+    priv mode: SelectMode
+}
+
+impl Future<()> for Readiness {
+    fn poll(&mut self, cx: &mut Context) -> IoResult<Poll<()>> {
+        if cx.is_ready(self.data, self.mode) {
+            Ok(Complete(()))
+        } else {
+            Ok(Pending)
+        }
+    }
+}
+
+pub fn readable(data: u64) -> Readiness {
+    Readiness { data: data, mode: select::SelectRead }
+}
+
+pub fn writable(data: u64) -> Readiness {
+    Readiness { data: data, mode: select::SelectWrite }
+}
+
+/// A future that completes after a number of milliseconds, measured from the
+/// first time it is polled.
+pub struct Sleep {
+    priv ms: u64,
+    priv deadline: Option<u64>
+}
+
+impl Future<()> for Sleep {
+    fn poll(&mut self, cx: &mut Context) -> IoResult<Poll<()>> {
+        let deadline = match self.deadline {
+            Some(d) => d,
+            None => {
+                let d = cx.now() + self.ms * 1000000;
+                self.deadline = Some(d);
+                d
+            }
+        };
+        if cx.now() >= deadline {
+            Ok(Complete(()))
+        } else {
+            cx.wake_at(deadline);
+            Ok(Pending)
+        }
+    }
+}
+
+pub fn sleep(ms: u64) -> Sleep {
+    Sleep { ms: ms, deadline: None }
+}
+
+/// A future that is already complete.
+pub struct Done<T> {
+    priv value: Option<T>
+}
+
+impl <T> Future<T> for Done<T> {
+    fn poll(&mut self, _: &mut Context) -> IoResult<Poll<T>> {
+        Ok(Complete(self.value.take().expect("Done polled after completion")))
+    }
+}
+
+pub fn done<T>(value: T) -> Done<T> {
+    Done { value: Some(value) }
+}
+
+static MAX_EVENTS: uint = 64;
+
+/// Drives futures to completion using its own selector. Selectables that
+/// the futures wait on must be registered with `selector()`, using level
+/// triggered (rearming) registrations.
+pub struct Executor {
+    priv selector: EpollSelector<NullNotifier>
+}
+
+impl Executor {
+    pub fn new() -> IoResult<Executor> {
+        let selector = try!(EpollSelector::new(NullNotifier));
+        Ok(Executor { selector: selector })
+    }
+
+    pub fn selector<'a>(&'a self) -> &'a EpollSelector<NullNotifier> {
+        &self.selector
+    }
+
+    /// Poll `future` until it completes or fails. A future that is waiting
+    /// on neither a selectable nor a timer will block forever.
+    pub fn block_on<T, F: Future<T>>(&self, future: F) -> IoResult<T> {
+        let mut future = future;
+        let mut events =
+            [SelectEvent { mode: select::ReadyRead, data: 0, read_closed: false }, ..MAX_EVENTS];
+        let mut cx = Context { now: time::precise_time_ns(), ready: ~[], deadline: None };
+
+        loop {
+            cx.deadline = None;
+            match try!(future.poll(&mut cx)) {
+                Complete(v) => return Ok(v),
+                Pending => {}
+            }
+
+            let timeout_ms = match cx.deadline {
+                Some(d) if d <= cx.now => 0,
+                Some(d) => ((d - cx.now + 999999) / 1000000) as int,
+                None => -1
+            };
+            let n = try!(self.selector.select(events, timeout_ms));
+
+            cx.ready = events.slice_to(n).to_owned();
+            cx.now = time::precise_time_ns();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Context, Executor, Future, Poll, Complete, Pending};
+    use super::{done, readable, sleep, writable};
+
+    use std::io::IoResult;
+
+    use async::AsyncWriter;
+    use select;
+    use select::{SelectEvent, Selector};
+    use unix::UnixStream;
+
+    static MS: u64 = 1000000;
+
+    fn context(now: u64, ready: &[u64]) -> Context {
+        let ready = ready.iter().map(|data| {
+            SelectEvent { mode: select::ReadyBoth, data: *data, read_closed: false }
+        }).collect();
+        Context { now: now, ready: ready, deadline: None }
+    }
+
+    fn complete<T>(res: IoResult<Poll<T>>) -> T {
+        match res {
+            Ok(Complete(v)) => v,
+            Ok(Pending) => fail!("the future is still pending"),
+            Err(e) => fail!("the future failed: {}", e)
+        }
+    }
+
+    fn assert_pending<T>(res: IoResult<Poll<T>>) {
+        match res {
+            Ok(Pending) => {}
+            Ok(Complete(_)) => fail!("the future completed"),
+            Err(e) => fail!("the future failed: {}", e)
+        }
+    }
+
+    #[test]
+    fn test_map_and_then() {
+        let mut op = done(2u)
+            .map(proc(v) { v * 3 })
+            .and_then(proc(v) { done(v + 1) });
+        assert_eq!(complete(op.poll(&mut context(0, []))), 7);
+    }
+
+    #[test]
+    fn test_and_then_waits_for_second() {
+        let mut op = readable(1).and_then(proc(_) { writable(2) });
+        assert_pending(op.poll(&mut context(0, [])));
+        assert_pending(op.poll(&mut context(0, [1])));
+        complete(op.poll(&mut context(0, [2])));
+    }
+
+    #[test]
+    fn test_join_keeps_first_result() {
+        let mut op = readable(1).map(proc(_) { 1u }).join(readable(2).map(proc(_) { 2u }));
+        assert_pending(op.poll(&mut context(0, [1])));
+        // The first future is not polled again once it has completed.
+        assert_eq!(complete(op.poll(&mut context(0, [2]))), (1, 2));
+    }
+
+    #[test]
+    fn test_select_either() {
+        let mut op = readable(1).map(proc(_) { 1u }).select(readable(2).map(proc(_) { 2u }));
+        assert_pending(op.poll(&mut context(0, [])));
+        assert_eq!(complete(op.poll(&mut context(0, [2]))), 2);
+    }
+
+    #[test]
+    fn test_sleep_sets_deadline() {
+        let mut op = sleep(5);
+        let mut cx = context(10 * MS, []);
+        assert_pending(op.poll(&mut cx));
+        assert_eq!(cx.deadline, Some(15 * MS));
+        // The deadline is measured from the first poll.
+        assert_pending(op.poll(&mut context(14 * MS, [])));
+        complete(op.poll(&mut context(15 * MS, [])));
+    }
+
+    #[test]
+    fn test_wake_at_keeps_earliest() {
+        let mut cx = context(0, []);
+        cx.wake_at(20);
+        cx.wake_at(10);
+        cx.wake_at(30);
+        assert_eq!(cx.deadline, Some(10));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut op = readable(1).timeout(10);
+        assert_pending(op.poll(&mut context(0, [])));
+        assert!(op.poll(&mut context(10 * MS, [])).is_err());
+
+        let mut op = readable(1).timeout(10);
+        assert_pending(op.poll(&mut context(0, [])));
+        complete(op.poll(&mut context(5 * MS, [1])));
+    }
+
+    #[test]
+    fn test_executor_readiness() {
+        let executor = Executor::new().unwrap();
+        let (mut a, b) = UnixStream::pair().unwrap();
+        let _handle = executor.selector().register(b, 1, select::SelectRead, true).unwrap();
+        a.async_write(bytes!("ping")).unwrap();
+        executor.block_on(readable(1).timeout(5000)).unwrap();
+    }
+
+    #[test]
+    fn test_executor_timeout() {
+        let executor = Executor::new().unwrap();
+        let (_a, b) = UnixStream::pair().unwrap();
+        let _handle = executor.selector().register(b, 1, select::SelectRead, true).unwrap();
+        assert!(executor.block_on(readable(1).timeout(10)).is_err());
+        executor.block_on(sleep(10)).unwrap();
+    }
+}
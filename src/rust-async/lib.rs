@@ -23,10 +23,26 @@ use epoll_selector::EpollSelector;
 use native::io::net::TcpListener;
 
 pub mod async;
+pub mod bootstrap;
+pub mod buffered;
+pub mod codec;
+pub mod copy;
+pub mod dynamic_pipeline;
+pub mod endpoint;
 pub mod epoll;
 pub mod epoll_selector;
+pub mod fcntl;
+pub mod future;
+pub mod net;
+pub mod ops;
 pub mod pipeline;
+pub mod reactor;
 pub mod select;
+pub mod sendfile;
+pub mod socket;
+pub mod sockopt;
+pub mod uio;
+pub mod unix;
 
 struct MyNotifier;
 
@@ -0,0 +1,683 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Non-blocking sockets created and owned by this library.
+
+use std::cast;
+use std::io;
+use std::io::{Acceptor, IoResult, IoError};
+use std::io::net::ip::{SocketAddr, Ipv4Addr, Ipv6Addr};
+use std::libc;
+use std::libc::{c_int, c_uint, c_void, size_t, socklen_t, ssize_t};
+use std::mem;
+use std::os;
+use std::ptr;
+use std::vec;
+
+use async::{AsyncAcceptor, AsyncDatagram, AsyncListener, AsyncReader, AsyncWriter, Shutdown};
+use async::{empty_msghdr, io_result, read_iovecs, read_result, shutdown_socket, total_len};
+use async::write_iovecs;
+use epoll_selector::EpollSelectable;
+use socket;
+use socket::{msghdr, mmsghdr};
+use uio;
+use uio::iovec;
+
+fn htons(u: u16) -> u16 {
+    mem::to_be16(u as i16) as u16
+}
+
+fn ntohs(u: u16) -> u16 {
+    mem::from_be16(u as i16) as u16
+}
+
+/// Convert an address into the representation used by the socket calls.
+pub fn addr_to_sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, socklen_t) {
+    unsafe {
+        let mut storage: libc::sockaddr_storage = mem::init();
+        let len = match addr.ip {
+            Ipv4Addr(a, b, c, d) => {
+                let storage_in: *mut libc::sockaddr_in = cast::transmute(&mut storage);
+                let ip = (a as u32 << 24) | (b as u32 << 16) | (c as u32 << 8) | (d as u32 << 0);
+                (*storage_in).sin_family = libc::AF_INET as libc::sa_family_t;
+                (*storage_in).sin_port = htons(addr.port);
+                (*storage_in).sin_addr = libc::in_addr { s_addr: mem::from_be32(ip as i32) as u32 };
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            Ipv6Addr(a, b, c, d, e, f, g, h) => {
+                let storage_in6: *mut libc::sockaddr_in6 = cast::transmute(&mut storage);
+                (*storage_in6).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                (*storage_in6).sin6_port = htons(addr.port);
+                (*storage_in6).sin6_addr = libc::in6_addr {
+                    s6_addr: [htons(a), htons(b), htons(c), htons(d),
+                              htons(e), htons(f), htons(g), htons(h)]
+                };
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as socklen_t)
+    }
+}
+
+/// Convert an address filled in by a socket call back into a `SocketAddr`.
+pub fn sockaddr_to_addr(storage: &libc::sockaddr_storage, len: socklen_t) -> IoResult<SocketAddr> {
+    let len = len as uint;
+    match storage.ss_family as c_int {
+        libc::AF_INET if len >= mem::size_of::<libc::sockaddr_in>() => {
+            let storage_in: &libc::sockaddr_in = unsafe { cast::transmute(storage) };
+            let ip = mem::to_be32(storage_in.sin_addr.s_addr as i32) as u32;
+            Ok(SocketAddr {
+                ip: Ipv4Addr((ip >> 24) as u8, (ip >> 16) as u8, (ip >> 8) as u8, ip as u8),
+                port: ntohs(storage_in.sin_port)
+            })
+        }
+        libc::AF_INET6 if len >= mem::size_of::<libc::sockaddr_in6>() => {
+            let storage_in6: &libc::sockaddr_in6 = unsafe { cast::transmute(storage) };
+            let s = storage_in6.sin6_addr.s6_addr;
+            Ok(SocketAddr {
+                ip: Ipv6Addr(ntohs(s[0]), ntohs(s[1]), ntohs(s[2]), ntohs(s[3]),
+                             ntohs(s[4]), ntohs(s[5]), ntohs(s[6]), ntohs(s[7])),
+                port: ntohs(storage_in6.sin6_port)
+            })
+        }
+        _ => Err(IoError {
+            kind: io::InvalidInput,
+            desc: "The socket address is not an IPv4 or IPv6 address.",
+            detail: None
+        })
+    }
+}
+
+fn check(res: c_int) -> IoResult<()> {
+    if res < 0 {
+        Err(IoError::last_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// An owned, non-blocking socket file descriptor, which is closed when
+/// dropped.
+pub struct Socket {
+    priv fd: c_int
+}
+
+impl Socket {
+    /// Create a non-blocking, close-on-exec socket.
+    pub fn new(domain: c_int, ty: c_int) -> IoResult<Socket> {
+        let fd = unsafe {
+            libc::socket(domain, ty | socket::SOCK_NONBLOCK | socket::SOCK_CLOEXEC, 0)
+        };
+        if fd < 0 {
+            return Err(IoError::last_error());
+        }
+        Ok(Socket { fd: fd })
+    }
+
+    /// Take ownership of an existing socket file descriptor.
+    pub fn from_fd(fd: c_int) -> Socket {
+        Socket { fd: fd }
+    }
+
+    pub fn fd(&self) -> c_int {
+        self.fd
+    }
+
+    pub fn bind(&self, addr: SocketAddr) -> IoResult<()> {
+        let (storage, len) = addr_to_sockaddr(addr);
+        check(unsafe {
+            libc::bind(self.fd, &storage as *libc::sockaddr_storage as *libc::sockaddr, len)
+        })
+    }
+
+    /// Start connecting to `addr`. The connection is usually still in
+    /// progress when this returns; it has completed once the socket is
+    /// writable, at which point `take_error` reports whether it succeeded.
+    pub fn connect(&self, addr: SocketAddr) -> IoResult<()> {
+        let (storage, len) = addr_to_sockaddr(addr);
+        let res = unsafe {
+            libc::connect(self.fd, &storage as *libc::sockaddr_storage as *libc::sockaddr, len)
+        };
+        if res < 0 && os::errno() as c_int != libc::EINPROGRESS {
+            return Err(IoError::last_error());
+        }
+        Ok(())
+    }
+
+    /// Returns and clears the pending error on the socket (SO_ERROR).
+    pub fn take_error(&self) -> IoResult<Option<IoError>> {
+        let err: c_int = try!(self.getsockopt(socket::SOL_SOCKET, socket::SO_ERROR));
+        if err == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(IoError::from_errno(err as uint, true)))
+        }
+    }
+
+    pub fn peer_addr(&self) -> IoResult<SocketAddr> {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::init() };
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as socklen_t;
+        try!(check(unsafe {
+            libc::getpeername(
+                self.fd,
+                &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+                &mut len)
+        }));
+        sockaddr_to_addr(&storage, len)
+    }
+
+    pub fn local_addr(&self) -> IoResult<SocketAddr> {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::init() };
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as socklen_t;
+        try!(check(unsafe {
+            libc::getsockname(
+                self.fd,
+                &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+                &mut len)
+        }));
+        sockaddr_to_addr(&storage, len)
+    }
+
+    pub fn listen(&self, backlog: int) -> IoResult<()> {
+        check(unsafe { libc::listen(self.fd, backlog as c_int) })
+    }
+
+    /// Accept a pending connection. The new socket is also non-blocking.
+    pub fn accept(&self) -> IoResult<Socket> {
+        let fd = unsafe {
+            socket::accept4(
+                self.fd,
+                ptr::mut_null(),
+                ptr::mut_null(),
+                socket::SOCK_NONBLOCK | socket::SOCK_CLOEXEC)
+        };
+        if fd < 0 {
+            return Err(IoError::last_error());
+        }
+        Ok(Socket { fd: fd })
+    }
+
+    pub fn setsockopt<T>(&self, level: c_int, name: c_int, value: T) -> IoResult<()> {
+        check(unsafe {
+            libc::setsockopt(
+                self.fd,
+                level,
+                name,
+                &value as *T as *c_void,
+                mem::size_of::<T>() as socklen_t)
+        })
+    }
+
+    pub fn getsockopt<T>(&self, level: c_int, name: c_int) -> IoResult<T> {
+        let mut value: T = unsafe { mem::init() };
+        let mut len = mem::size_of::<T>() as socklen_t;
+        try!(check(unsafe {
+            libc::getsockopt(self.fd, level, name, &mut value as *mut T as *mut c_void, &mut len)
+        }));
+        Ok(value)
+    }
+}
+
+impl AsyncReader for Socket {
+    fn async_read(&mut self, output: &mut [u8]) -> IoResult<uint> {
+        read_result(unsafe {
+            libc::recv(self.fd, output.as_mut_ptr() as *mut c_void, output.len() as size_t, 0)
+        }, output.len())
+    }
+
+    fn async_read_vectored(&mut self, outputs: &mut [&mut [u8]]) -> IoResult<uint> {
+        let len = total_len(outputs);
+        let iovs = read_iovecs(outputs);
+        read_result(unsafe { uio::readv(self.fd, iovs.as_ptr(), iovs.len() as c_int) }, len)
+    }
+}
+
+impl AsyncWriter for Socket {
+    fn async_write(&mut self, input: &[u8]) -> IoResult<uint> {
+        io_result(unsafe {
+            libc::send(
+                self.fd,
+                input.as_ptr() as *mut c_void,
+                input.len() as size_t,
+                socket::MSG_NOSIGNAL)
+        })
+    }
+
+    fn async_write_vectored(&mut self, inputs: &[&[u8]]) -> IoResult<uint> {
+        let mut iovs = write_iovecs(inputs);
+        let msg = empty_msghdr(iovs.as_mut_slice());
+        io_result(unsafe { socket::sendmsg(self.fd, &msg, socket::MSG_NOSIGNAL) })
+    }
+
+    fn async_shutdown(&mut self, how: Shutdown) -> IoResult<()> {
+        shutdown_socket(self.fd, how)
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
+
+impl EpollSelectable for Socket {
+    fn get_fd(&self) -> c_int {
+        self.fd
+    }
+}
+
+fn domain(addr: SocketAddr) -> c_int {
+    match addr.ip {
+        Ipv4Addr(..) => libc::AF_INET,
+        Ipv6Addr(..) => libc::AF_INET6
+    }
+}
+
+static LISTEN_BACKLOG: int = 128;
+
+/// A TCP socket bound to an address but not yet listening.
+pub struct TcpListener {
+    priv sock: Socket
+}
+
+impl TcpListener {
+    /// Bind to `addr`. SO_REUSEADDR is enabled, as is usual for servers.
+    pub fn bind(addr: SocketAddr) -> IoResult<TcpListener> {
+        TcpListener::bind_with(addr, |_| Ok(()))
+    }
+
+    /// Bind to `addr`, calling `configure` on the socket first. Options that
+    /// only take effect before binding, such as `set_reuse_port`, must be set
+    /// this way.
+    pub fn bind_with(
+            addr: SocketAddr,
+            configure: |&Socket| -> IoResult<()>) -> IoResult<TcpListener> {
+        let sock = try!(Socket::new(domain(addr), libc::SOCK_STREAM));
+        try!(sock.setsockopt(socket::SOL_SOCKET, socket::SO_REUSEADDR, 1 as c_int));
+        try!(configure(&sock));
+        try!(sock.bind(addr));
+        Ok(TcpListener { sock: sock })
+    }
+
+    pub fn socket<'a>(&'a self) -> &'a Socket {
+        &self.sock
+    }
+
+    pub fn socket_name(&self) -> IoResult<SocketAddr> {
+        self.sock.local_addr()
+    }
+}
+
+impl AsyncListener<TcpStream, TcpAcceptor> for TcpListener {
+    fn listen(self) -> IoResult<TcpAcceptor> {
+        try!(self.sock.listen(LISTEN_BACKLOG));
+        Ok(TcpAcceptor { sock: self.sock })
+    }
+}
+
+/// A listening TCP socket.
+pub struct TcpAcceptor {
+    priv sock: Socket
+}
+
+impl TcpAcceptor {
+    pub fn socket<'a>(&'a self) -> &'a Socket {
+        &self.sock
+    }
+
+    pub fn socket_name(&self) -> IoResult<SocketAddr> {
+        self.sock.local_addr()
+    }
+}
+
+impl AsyncAcceptor<TcpStream> for TcpAcceptor {
+    fn accept(&mut self) -> IoResult<TcpStream> {
+        let sock = try!(self.sock.accept());
+        Ok(TcpStream { sock: sock })
+    }
+}
+
+impl Acceptor<TcpStream> for TcpAcceptor {
+    fn accept(&mut self) -> IoResult<TcpStream> {
+        let sock = try!(self.sock.accept());
+        Ok(TcpStream { sock: sock })
+    }
+}
+
+impl EpollSelectable for TcpAcceptor {
+    fn get_fd(&self) -> c_int {
+        self.sock.fd()
+    }
+}
+
+/// A non-blocking TCP connection.
+pub struct TcpStream {
+    priv sock: Socket
+}
+
+impl TcpStream {
+    /// Start connecting to `addr`. See `Socket::connect`; once the stream is
+    /// writable, call `finish_connect` to learn whether the connection was
+    /// established.
+    pub fn connect(addr: SocketAddr) -> IoResult<TcpStream> {
+        let sock = try!(Socket::new(domain(addr), libc::SOCK_STREAM));
+        try!(sock.connect(addr));
+        Ok(TcpStream { sock: sock })
+    }
+
+    /// Check the outcome of a connection started with `connect`.
+    pub fn finish_connect(&self) -> IoResult<()> {
+        match try!(self.sock.take_error()) {
+            Some(err) => Err(err),
+            None => Ok(())
+        }
+    }
+
+    pub fn socket<'a>(&'a self) -> &'a Socket {
+        &self.sock
+    }
+
+    pub fn peer_name(&self) -> IoResult<SocketAddr> {
+        self.sock.peer_addr()
+    }
+
+    pub fn socket_name(&self) -> IoResult<SocketAddr> {
+        self.sock.local_addr()
+    }
+}
+
+impl AsyncReader for TcpStream {
+    fn async_read(&mut self, output: &mut [u8]) -> IoResult<uint> {
+        self.sock.async_read(output)
+    }
+
+    fn async_read_vectored(&mut self, outputs: &mut [&mut [u8]]) -> IoResult<uint> {
+        self.sock.async_read_vectored(outputs)
+    }
+
+    fn splice_fd(&self) -> Option<c_int> {
+        Some(self.sock.fd())
+    }
+}
+
+impl AsyncWriter for TcpStream {
+    fn async_write(&mut self, input: &[u8]) -> IoResult<uint> {
+        self.sock.async_write(input)
+    }
+
+    fn async_write_vectored(&mut self, inputs: &[&[u8]]) -> IoResult<uint> {
+        self.sock.async_write_vectored(inputs)
+    }
+
+    fn async_shutdown(&mut self, how: Shutdown) -> IoResult<()> {
+        self.sock.async_shutdown(how)
+    }
+
+    fn splice_fd(&self) -> Option<c_int> {
+        Some(self.sock.fd())
+    }
+}
+
+impl EpollSelectable for TcpStream {
+    fn get_fd(&self) -> c_int {
+        self.sock.fd()
+    }
+}
+
+/// A non-blocking UDP socket.
+pub struct UdpSocket {
+    priv sock: Socket
+}
+
+impl UdpSocket {
+    pub fn bind(addr: SocketAddr) -> IoResult<UdpSocket> {
+        let sock = try!(Socket::new(domain(addr), libc::SOCK_DGRAM));
+        try!(sock.bind(addr));
+        Ok(UdpSocket { sock: sock })
+    }
+
+    pub fn socket<'a>(&'a self) -> &'a Socket {
+        &self.sock
+    }
+
+    pub fn socket_name(&self) -> IoResult<SocketAddr> {
+        self.sock.local_addr()
+    }
+
+    pub fn fd(&self) -> c_int {
+        self.sock.fd()
+    }
+}
+
+impl EpollSelectable for UdpSocket {
+    fn get_fd(&self) -> c_int {
+        self.sock.fd()
+    }
+}
+
+fn empty_mmsghdr(name: *mut libc::sockaddr_storage, iov: *mut iovec) -> mmsghdr {
+    mmsghdr {
+        msg_hdr: msghdr {
+            msg_name: name as *mut c_void,
+            msg_namelen: mem::size_of::<libc::sockaddr_storage>() as socklen_t,
+            msg_iov: iov,
+            msg_iovlen: 1,
+            msg_control: ptr::mut_null(),
+            msg_controllen: 0,
+            msg_flags: 0
+        },
+        msg_len: 0
+    }
+}
+
+impl AsyncDatagram for UdpSocket {
+    fn async_send_to(&mut self, buf: &[u8], dst: SocketAddr) -> IoResult<uint> {
+        let (storage, len) = addr_to_sockaddr(dst);
+        let res = unsafe {
+            libc::sendto(
+                self.sock.fd(),
+                buf.as_ptr() as *c_void,
+                buf.len() as size_t,
+                socket::MSG_DONTWAIT,
+                &storage as *libc::sockaddr_storage as *libc::sockaddr,
+                len)
+        };
+        io_result(res)
+    }
+
+    fn async_recv_from(&mut self, buf: &mut [u8]) -> IoResult<(uint, SocketAddr)> {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::init() };
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as socklen_t;
+        let res = unsafe {
+            libc::recvfrom(
+                self.sock.fd(),
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len() as size_t,
+                socket::MSG_DONTWAIT,
+                &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+                &mut len)
+        };
+        if res < 0 {
+            return Err(IoError::last_error());
+        }
+        let addr = try!(sockaddr_to_addr(&storage, len));
+        Ok((res as uint, addr))
+    }
+
+    fn async_send_many(&mut self, msgs: &[(&[u8], SocketAddr)]) -> IoResult<uint> {
+        if msgs.len() == 0 {
+            return Ok(0);
+        }
+        // All of the buffers are allocated up front so that the pointers
+        // stored in the headers stay valid.
+        let mut storages: ~[(libc::sockaddr_storage, socklen_t)] =
+            msgs.iter().map(|&(_, addr)| addr_to_sockaddr(addr)).collect();
+        let bufs: ~[&[u8]] = msgs.iter().map(|&(buf, _)| buf).collect();
+        let mut iovs = write_iovecs(bufs);
+        let mut hdrs = vec::with_capacity(msgs.len());
+        for i in range(0, msgs.len()) {
+            let (ref mut storage, len) = storages[i];
+            let mut hdr = empty_mmsghdr(storage, &mut iovs[i]);
+            hdr.msg_hdr.msg_namelen = len;
+            hdrs.push(hdr);
+        }
+        let res = unsafe {
+            socket::sendmmsg(
+                self.sock.fd(),
+                hdrs.as_mut_ptr(),
+                hdrs.len() as c_uint,
+                socket::MSG_DONTWAIT)
+        };
+        io_result(res as ssize_t)
+    }
+
+    fn async_recv_many(&mut self, bufs: &mut [&mut [u8]]) -> IoResult<~[(uint, SocketAddr)]> {
+        if bufs.len() == 0 {
+            return Ok(~[]);
+        }
+        let mut storages: ~[libc::sockaddr_storage] =
+            vec::from_fn(bufs.len(), |_| unsafe { mem::init() });
+        let mut iovs = read_iovecs(bufs);
+        let mut hdrs = vec::with_capacity(bufs.len());
+        for i in range(0, bufs.len()) {
+            hdrs.push(empty_mmsghdr(&mut storages[i], &mut iovs[i]));
+        }
+        let res = unsafe {
+            socket::recvmmsg(
+                self.sock.fd(),
+                hdrs.as_mut_ptr(),
+                hdrs.len() as c_uint,
+                socket::MSG_DONTWAIT,
+                ptr::null())
+        };
+        if res < 0 {
+            return Err(IoError::last_error());
+        }
+        let mut results = vec::with_capacity(res as uint);
+        for i in range(0, res as uint) {
+            let addr = try!(sockaddr_to_addr(&storages[i], hdrs[i].msg_hdr.msg_namelen));
+            results.push((hdrs[i].msg_len as uint, addr));
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TcpListener, TcpStream, UdpSocket};
+
+    use std::from_str::from_str;
+    use std::io::IoResult;
+    use std::io::net::ip::SocketAddr;
+
+    use async::{AsyncAcceptor, AsyncDatagram, AsyncListener, AsyncReader, AsyncWriter};
+    use async::{ShutdownWrite, is_eof, would_block};
+
+    fn localhost() -> SocketAddr {
+        from_str("127.0.0.1:0").unwrap()
+    }
+
+    /// Retry `op` until it stops reporting would-block.
+    fn retry<T>(op: || -> IoResult<T>) -> IoResult<T> {
+        loop {
+            match op() {
+                Err(ref e) if would_block(e) => {}
+                res => return res
+            }
+        }
+    }
+
+    fn assert_would_block<T>(res: IoResult<T>) {
+        match res {
+            Err(ref e) if would_block(e) => {}
+            _ => fail!("expected a would-block error")
+        }
+    }
+
+    fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind(localhost()).unwrap();
+        let addr = listener.socket_name().unwrap();
+        let mut acceptor = listener.listen().unwrap();
+        let client = TcpStream::connect(addr).unwrap();
+        let server = retry(|| acceptor.accept()).unwrap();
+        client.finish_connect().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_accept_would_block() {
+        let listener = TcpListener::bind(localhost()).unwrap();
+        let mut acceptor = listener.listen().unwrap();
+        assert_would_block(acceptor.accept());
+    }
+
+    #[test]
+    fn test_vectored_round_trip() {
+        let (mut client, mut server) = connected_pair();
+        let n = client.async_write_vectored([bytes!("hello "), bytes!("world")]).unwrap();
+        assert_eq!(n, 11);
+
+        let mut first = [0u8, ..4];
+        let mut second = [0u8, ..16];
+        let n = {
+            let outputs: &mut [&mut [u8]] = [first.mut_slice_from(0), second.mut_slice_from(0)];
+            retry(|| server.async_read_vectored(outputs)).unwrap()
+        };
+        // Both buffers arrive in one segment on loopback.
+        assert_eq!(n, 11);
+        assert_eq!(first.as_slice(), bytes!("hell"));
+        assert_eq!(second.slice_to(7), bytes!("o world"));
+    }
+
+    #[test]
+    fn test_short_write_then_would_block() {
+        let (mut client, mut server) = connected_pair();
+        let chunk = [7u8, ..64 * 1024];
+        let mut sent = 0u;
+        loop {
+            match client.async_write(chunk) {
+                Ok(n) => sent += n,
+                Err(ref e) if would_block(e) => break,
+                Err(e) => fail!("{}", e)
+            }
+        }
+        client.async_shutdown(ShutdownWrite).unwrap();
+
+        let mut buf = [0u8, ..64 * 1024];
+        let mut received = 0u;
+        loop {
+            match retry(|| server.async_read(buf)) {
+                Ok(n) => received += n,
+                Err(ref e) if is_eof(e) => break,
+                Err(e) => fail!("{}", e)
+            }
+        }
+        assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn test_udp_send_and_recv_many() {
+        let mut a = UdpSocket::bind(localhost()).unwrap();
+        let mut b = UdpSocket::bind(localhost()).unwrap();
+        let a_addr = a.socket_name().unwrap();
+        let b_addr = b.socket_name().unwrap();
+
+        let msgs = [(bytes!("one"), b_addr), (bytes!("three"), b_addr)];
+        assert_eq!(a.async_send_many(msgs).unwrap(), 2);
+
+        let mut first = [0u8, ..8];
+        let mut second = [0u8, ..8];
+        let results = {
+            let bufs: &mut [&mut [u8]] = [first.mut_slice_from(0), second.mut_slice_from(0)];
+            retry(|| b.async_recv_many(bufs)).unwrap()
+        };
+        assert_eq!(results, ~[(3, a_addr), (5, a_addr)]);
+        assert_eq!(first.slice_to(3), bytes!("one"));
+        assert_eq!(second.slice_to(5), bytes!("three"));
+
+        assert_would_block(b.async_recv_from(first));
+    }
+}
@@ -0,0 +1,349 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Resumable operations built on `AsyncReader` and `AsyncWriter`.
+//!
+//! Each operation keeps track of its own progress. Call `poll` whenever the
+//! stream is ready; it returns `Pending` if the stream would block before the
+//! operation finished, and `Complete` with the result once it has.
+
+use std::io;
+use std::io::{IoResult, IoError};
+use std::util;
+use std::vec;
+
+use async::{AsyncReader, AsyncWriter, is_eof, would_block};
+use buffered::AsyncBufReader;
+use future::{Poll, Complete, Pending};
+
+fn unexpected_eof() -> IoError {
+    IoError {
+        kind: io::EndOfFile,
+        desc: "The stream ended before the operation completed.",
+        detail: None
+    }
+}
+
+fn limit_exceeded() -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "The data exceeded the maximum allowed length.",
+        detail: None
+    }
+}
+
+/// Reads exactly a given number of bytes.
+pub struct ReadExact {
+    priv buf: ~[u8],
+    priv filled: uint
+}
+
+impl ReadExact {
+    pub fn new(len: uint) -> ReadExact {
+        ReadExact { buf: vec::from_elem(len, 0u8), filled: 0 }
+    }
+
+    /// The number of bytes read so far.
+    pub fn filled(&self) -> uint {
+        self.filled
+    }
+
+    pub fn poll<R: AsyncReader>(&mut self, reader: &mut R) -> IoResult<Poll<~[u8]>> {
+        while self.filled < self.buf.len() {
+            match reader.async_read(self.buf.mut_slice_from(self.filled)) {
+                Ok(n) => self.filled += n,
+                Err(ref e) if would_block(e) => return Ok(Pending),
+                Err(ref e) if is_eof(e) => return Err(unexpected_eof()),
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(Complete(util::replace(&mut self.buf, ~[])))
+    }
+}
+
+/// Writes the whole of a buffer.
+pub struct WriteAll {
+    priv buf: ~[u8],
+    priv written: uint
+}
+
+impl WriteAll {
+    pub fn new(buf: ~[u8]) -> WriteAll {
+        WriteAll { buf: buf, written: 0 }
+    }
+
+    /// The number of bytes still to be written.
+    pub fn remaining(&self) -> uint {
+        self.buf.len() - self.written
+    }
+
+    pub fn poll<W: AsyncWriter>(&mut self, writer: &mut W) -> IoResult<Poll<()>> {
+        while self.written < self.buf.len() {
+            match writer.async_write(self.buf.slice_from(self.written)) {
+                Ok(n) => self.written += n,
+                Err(ref e) if would_block(e) => return Ok(Pending),
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(Complete(()))
+    }
+}
+
+/// Reads up to and including a delimiter byte.
+///
+/// This reads from an `AsyncBufReader` so that bytes after the delimiter are
+/// left in its buffer for the next operation.
+pub struct ReadUntil {
+    priv delim: u8,
+    priv limit: uint,
+    priv buf: ~[u8]
+}
+
+impl ReadUntil {
+    /// Read until `delim`, failing if more than `limit` bytes arrive before
+    /// it.
+    pub fn new(delim: u8, limit: uint) -> ReadUntil {
+        ReadUntil { delim: delim, limit: limit, buf: ~[] }
+    }
+
+    pub fn poll<R: AsyncReader>(
+            &mut self,
+            reader: &mut AsyncBufReader<R>) -> IoResult<Poll<~[u8]>> {
+        loop {
+            let (used, done) = {
+                let available = match reader.fill_buf() {
+                    Ok(available) => available,
+                    Err(ref e) if would_block(e) => return Ok(Pending),
+                    Err(ref e) if is_eof(e) => return Err(unexpected_eof()),
+                    Err(e) => return Err(e)
+                };
+                match available.iter().position(|b| *b == self.delim) {
+                    Some(i) => {
+                        self.buf.push_all(available.slice_to(i + 1));
+                        (i + 1, true)
+                    }
+                    None => {
+                        self.buf.push_all(available);
+                        (available.len(), false)
+                    }
+                }
+            };
+            reader.consume(used);
+            if self.buf.len() > self.limit {
+                return Err(limit_exceeded());
+            }
+            if done {
+                return Ok(Complete(util::replace(&mut self.buf, ~[])));
+            }
+        }
+    }
+}
+
+/// Reads until end of stream.
+pub struct ReadToEnd {
+    priv limit: uint,
+    priv buf: ~[u8],
+    priv chunk: ~[u8]
+}
+
+static CHUNK_SIZE: uint = 8 * 1024;
+
+impl ReadToEnd {
+    /// Read until end of stream, failing if more than `limit` bytes arrive.
+    pub fn new(limit: uint) -> ReadToEnd {
+        ReadToEnd {
+            limit: limit,
+            buf: ~[],
+            // One byte more than the limit, so that exceeding it is noticed,
+            // without overflowing when there is effectively no limit.
+            chunk: vec::from_elem(if limit < CHUNK_SIZE { limit + 1 } else { CHUNK_SIZE }, 0u8)
+        }
+    }
+
+    pub fn poll<R: AsyncReader>(&mut self, reader: &mut R) -> IoResult<Poll<~[u8]>> {
+        loop {
+            match reader.async_read(self.chunk) {
+                Ok(n) => {
+                    if self.buf.len() + n > self.limit {
+                        return Err(limit_exceeded());
+                    }
+                    self.buf.push_all(self.chunk.slice_to(n));
+                }
+                Err(ref e) if would_block(e) => return Ok(Pending),
+                Err(ref e) if is_eof(e) => {
+                    return Ok(Complete(util::replace(&mut self.buf, ~[])));
+                }
+                Err(e) => return Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ReadExact, ReadToEnd, ReadUntil, WriteAll};
+
+    use std::cmp;
+    use std::io;
+    use std::io::IoResult;
+    use std::uint;
+    use std::vec::bytes;
+
+    use async::{AsyncReader, AsyncWriter};
+    use buffered::AsyncBufReader;
+    use future::{Poll, Complete, Pending};
+
+    /// Returns each chunk in turn from `async_read`, splitting chunks that
+    /// don't fit. An empty chunk is reported as would-block, and running out
+    /// of chunks as end of stream.
+    struct Script {
+        chunks: ~[~[u8]]
+    }
+
+    fn script(chunks: &[&[u8]]) -> Script {
+        Script { chunks: chunks.iter().map(|c| c.to_owned()).collect() }
+    }
+
+    impl AsyncReader for Script {
+        fn async_read(&mut self, output: &mut [u8]) -> IoResult<uint> {
+            if self.chunks.len() == 0 {
+                return Err(io::standard_error(io::EndOfFile));
+            }
+            if self.chunks[0].len() == 0 {
+                self.chunks.shift();
+                return Err(io::standard_error(io::ResourceUnavailable));
+            }
+            let n = cmp::min(output.len(), self.chunks[0].len());
+            bytes::copy_memory(output, self.chunks[0].slice_to(n));
+            let rest = self.chunks[0].slice_from(n).to_owned();
+            if rest.len() == 0 {
+                self.chunks.shift();
+            } else {
+                self.chunks[0] = rest;
+            }
+            Ok(n)
+        }
+    }
+
+    /// Accepts at most `max_write` bytes per call, reporting would-block on
+    /// every other call.
+    struct Trickle {
+        max_write: uint,
+        block: bool,
+        written: ~[u8]
+    }
+
+    impl AsyncWriter for Trickle {
+        fn async_write(&mut self, input: &[u8]) -> IoResult<uint> {
+            self.block = !self.block;
+            if !self.block {
+                return Err(io::standard_error(io::ResourceUnavailable));
+            }
+            let n = cmp::min(self.max_write, input.len());
+            self.written.push_all(input.slice_to(n));
+            Ok(n)
+        }
+    }
+
+    fn complete<T>(res: IoResult<Poll<T>>) -> T {
+        match res {
+            Ok(Complete(v)) => v,
+            Ok(Pending) => fail!("the operation is still pending"),
+            Err(e) => fail!("the operation failed: {}", e)
+        }
+    }
+
+    fn assert_pending<T>(res: IoResult<Poll<T>>) {
+        match res {
+            Ok(Pending) => {}
+            Ok(Complete(_)) => fail!("the operation completed"),
+            Err(e) => fail!("the operation failed: {}", e)
+        }
+    }
+
+    fn error_kind<T>(res: IoResult<Poll<T>>) -> io::IoErrorKind {
+        match res {
+            Err(e) => e.kind,
+            Ok(_) => fail!("the operation did not fail")
+        }
+    }
+
+    #[test]
+    fn test_read_exact_resumes() {
+        let mut reader = script([bytes!("ab"), &[], bytes!("cdef")]);
+        let mut op = ReadExact::new(4);
+        assert_pending(op.poll(&mut reader));
+        assert_eq!(op.filled(), 2);
+        assert_eq!(complete(op.poll(&mut reader)), bytes!("abcd").to_owned());
+    }
+
+    #[test]
+    fn test_read_exact_eof() {
+        let mut reader = script([bytes!("ab")]);
+        let mut op = ReadExact::new(4);
+        assert_eq!(error_kind(op.poll(&mut reader)), io::EndOfFile);
+    }
+
+    #[test]
+    fn test_write_all_short_writes() {
+        let mut writer = Trickle { max_write: 3, block: false, written: ~[] };
+        let mut op = WriteAll::new(bytes!("abcdefgh").to_owned());
+        let mut polls = 0;
+        loop {
+            polls += 1;
+            match op.poll(&mut writer) {
+                Ok(Complete(())) => break,
+                Ok(Pending) => assert!(op.remaining() > 0),
+                Err(e) => fail!("{}", e)
+            }
+        }
+        assert_eq!(polls, 3);
+        assert_eq!(op.remaining(), 0);
+        assert_eq!(writer.written.as_slice(), bytes!("abcdefgh"));
+    }
+
+    #[test]
+    fn test_read_until_leaves_rest_buffered() {
+        let reader = script([bytes!("one\ntw"), &[], bytes!("o\nthree")]);
+        let mut reader = AsyncBufReader::with_capacity(16, reader);
+        let mut op = ReadUntil::new('\n' as u8, 16);
+        assert_eq!(complete(op.poll(&mut reader)), bytes!("one\n").to_owned());
+        assert_pending(op.poll(&mut reader));
+        assert_eq!(complete(op.poll(&mut reader)), bytes!("two\n").to_owned());
+        assert_eq!(reader.buffer(), bytes!("three"));
+        assert_eq!(error_kind(op.poll(&mut reader)), io::EndOfFile);
+    }
+
+    #[test]
+    fn test_read_until_limit() {
+        let reader = script([bytes!("abcdef\n")]);
+        let mut reader = AsyncBufReader::with_capacity(16, reader);
+        let mut op = ReadUntil::new('\n' as u8, 4);
+        assert_eq!(error_kind(op.poll(&mut reader)), io::InvalidInput);
+    }
+
+    #[test]
+    fn test_read_to_end() {
+        let mut reader = script([bytes!("abc"), &[], bytes!("def")]);
+        let mut op = ReadToEnd::new(6);
+        assert_pending(op.poll(&mut reader));
+        assert_eq!(complete(op.poll(&mut reader)), bytes!("abcdef").to_owned());
+    }
+
+    #[test]
+    fn test_read_to_end_limit() {
+        let mut reader = script([bytes!("abc"), bytes!("def")]);
+        let mut op = ReadToEnd::new(5);
+        assert_eq!(error_kind(op.poll(&mut reader)), io::InvalidInput);
+    }
+
+    #[test]
+    fn test_read_to_end_unlimited() {
+        let mut reader = script([bytes!("abc"), bytes!("def")]);
+        let mut op = ReadToEnd::new(uint::MAX);
+        assert_eq!(complete(op.poll(&mut reader)), bytes!("abcdef").to_owned());
+    }
+}
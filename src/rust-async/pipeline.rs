@@ -4,79 +4,428 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::io::{IoResult, IoError};
+
+/// Events that travel through a pipeline alongside data. The connection owner
+/// sends `Opened`, `ReadEof`, `WritabilityChanged` and `Closed` down; filters
+/// usually send `Flush`, `PauseRead`, `ResumeRead` and `Closed` up. `UserEvent` may
+/// travel in either direction.
+pub enum PipelineEvent {
+    /// The connection has been established.
+    Opened,
+    /// The peer has shut down its side; no more data will be sent down.
+    ReadEof,
+    /// Sent down, the connection has been closed and anything sent up will be
+    /// discarded. Sent up, asks for the connection to be closed once
+    /// everything sent up before it has been written.
+    Closed,
+    /// Anything held back so far should be written out.
+    Flush,
+    /// `false` once output has backed up behind the connection, `true` once it
+    /// has drained again.
+    WritabilityChanged(bool),
+    /// Stop reading from the connection until `ResumeRead` is sent up.
+    PauseRead,
+    /// Start reading from the connection again.
+    ResumeRead,
+    /// An application defined event.
+    UserEvent(~Any)
+}
+
+/// A stage of a pipeline.
+///
+/// Errors travel back along the call chain: an error returned from `down` or
+/// `up` is first offered to the same filter's `error` method, and whatever
+/// that returns is passed back to the stage that sent the data, and so on
+/// until it reaches the owner of the pipeline, which should close the
+/// connection. A filter also sees errors returned to it by its neighbours
+/// and may translate them or handle them before returning.
+///
+/// Events are passed on unchanged unless a filter overrides `down_event` or
+/// `up_event`.
 pub trait Filter<Din, Dout, Uin, Uout> {
-    fn down<U: PipelineUp<Uout>, D: PipelineDown<Dout>>(&self, data: Din, up: &U, down: &D);
+    fn down<U: PipelineUp<Uout>, D: PipelineDown<Dout>>(
+            &self, data: Din, up: &U, down: &D) -> IoResult<()>;
+
+    fn up<U: PipelineUp<Uout>, D: PipelineDown<Dout>>(
+            &self, data: Uin, up: &U, down: &D) -> IoResult<()>;
+
+    /// Called with an error returned from this filter's `down` or `up`.
+    /// Returning `Ok` swallows the error; returning `Err` passes it (or a
+    /// different error) on. The default passes it on unchanged.
+    fn error<U: PipelineUp<Uout>, D: PipelineDown<Dout>>(
+            &self, err: IoError, up: &U, down: &D) -> IoResult<()> {
+        let _ = (up, down);
+        Err(err)
+    }
+
+    fn down_event<U: PipelineUp<Uout>, D: PipelineDown<Dout>>(
+            &self, event: PipelineEvent, up: &U, down: &D) -> IoResult<()> {
+        let _ = up;
+        down.down_event(event)
+    }
+
+    fn up_event<U: PipelineUp<Uout>, D: PipelineDown<Dout>>(
+            &self, event: PipelineEvent, up: &U, down: &D) -> IoResult<()> {
+        let _ = down;
+        up.up_event(event)
+    }
+}
+
+/// Tracks how much a stage is holding back against a low and a high
+/// watermark. Once the amount exceeds the high watermark the stage should be
+/// considered unwritable until it falls to the low watermark again, which
+/// keeps a stage from flapping around a single threshold.
+pub struct Watermarks {
+    priv low: uint,
+    priv high: uint,
+    priv buffered: Cell<uint>,
+    priv writable: Cell<bool>
+}
 
-    fn up<U: PipelineUp<Uout>, D: PipelineDown<Dout>>(&self, data: Uin, up: &U, down: &D);
+impl Watermarks {
+    pub fn new(low: uint, high: uint) -> Watermarks {
+        assert!(low <= high);
+        Watermarks {
+            low: low,
+            high: high,
+            buffered: Cell::new(0),
+            writable: Cell::new(true)
+        }
+    }
+
+    pub fn low(&self) -> uint {
+        self.low
+    }
+
+    pub fn high(&self) -> uint {
+        self.high
+    }
+
+    /// The amount currently held back.
+    pub fn buffered(&self) -> uint {
+        self.buffered.get()
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.writable.get()
+    }
+
+    /// Record that `amount` more is held back. Returns `Some(false)` if this
+    /// made the stage unwritable.
+    pub fn add(&self, amount: uint) -> Option<bool> {
+        self.buffered.set(self.buffered.get() + amount);
+        self.update()
+    }
+
+    /// Record that `amount` has been passed on. Returns `Some(true)` if this
+    /// made the stage writable again.
+    pub fn remove(&self, amount: uint) -> Option<bool> {
+        assert!(amount <= self.buffered.get());
+        self.buffered.set(self.buffered.get() - amount);
+        self.update()
+    }
+
+    fn update(&self) -> Option<bool> {
+        let buffered = self.buffered.get();
+        if self.writable.get() && buffered > self.high {
+            self.writable.set(false);
+            Some(false)
+        } else if !self.writable.get() && buffered <= self.low {
+            self.writable.set(true);
+            Some(true)
+        } else {
+            None
+        }
+    }
+}
+
+/// Something held back by `FlowControl`.
+enum Held<T> {
+    HeldData(T),
+    HeldEvent(PipelineEvent)
+}
+
+/// A stage with its own watermarks, for use anywhere in a pipeline.
+///
+/// While the stages between it and the connection are writable, data and
+/// events sent up pass straight through. Once it is sent
+/// `WritabilityChanged(false)` from below it holds them back instead, in
+/// order, until it is sent `WritabilityChanged(true)`. The stages above it
+/// are not told about the connection's writability; they are sent
+/// `WritabilityChanged` according to how much this stage is holding back
+/// against its own watermarks, as measured by `size`. `PauseRead` and
+/// `ResumeRead` are never held back.
+pub struct FlowControl<T> {
+    priv watermarks: Watermarks,
+    priv size: fn(&T) -> uint,
+    priv held: RefCell<~[Held<T>]>,
+    priv writable: Cell<bool>
+}
+
+impl <T> FlowControl<T> {
+    pub fn new(low: uint, high: uint, size: fn(&T) -> uint) -> FlowControl<T> {
+        FlowControl {
+            watermarks: Watermarks::new(low, high),
+            size: size,
+            held: RefCell::new(~[]),
+            writable: Cell::new(true)
+        }
+    }
+
+    /// This stage's watermarks, and how much it is holding back.
+    pub fn watermarks<'a>(&'a self) -> &'a Watermarks {
+        &self.watermarks
+    }
+
+    /// The number of data items and events being held back.
+    pub fn held(&self) -> uint {
+        self.held.borrow().get().len()
+    }
+
+    /// Pass on whatever is held back for as long as the stages below stay
+    /// writable. Sending data up may change that, so the flag is checked
+    /// before each item and the queue is not borrowed while sending.
+    fn release<U: PipelineUp<T>, D: PipelineDown<T>>(&self, up: &U, down: &D) -> IoResult<()> {
+        while self.writable.get() {
+            let next = self.held.borrow_mut().get().shift();
+            match next {
+                Some(HeldData(data)) => {
+                    let amount = (self.size)(&data);
+                    try!(up.up(data));
+                    match self.watermarks.remove(amount) {
+                        Some(writable) => try!(down.down_event(WritabilityChanged(writable))),
+                        None => {}
+                    }
+                }
+                Some(HeldEvent(event)) => try!(up.up_event(event)),
+                None => break
+            }
+        }
+        Ok(())
+    }
+
+    fn passes_through(&self) -> bool {
+        self.writable.get() && self.held.borrow().get().len() == 0
+    }
+}
+
+impl <T> Filter<T, T, T, T> for FlowControl<T> {
+    fn down<U: PipelineUp<T>, D: PipelineDown<T>>(
+            &self, data: T, _: &U, down: &D) -> IoResult<()> {
+        down.down(data)
+    }
+
+    fn up<U: PipelineUp<T>, D: PipelineDown<T>>(
+            &self, data: T, up: &U, down: &D) -> IoResult<()> {
+        if self.passes_through() {
+            return up.up(data);
+        }
+        let amount = (self.size)(&data);
+        self.held.borrow_mut().get().push(HeldData(data));
+        match self.watermarks.add(amount) {
+            Some(writable) => down.down_event(WritabilityChanged(writable)),
+            None => Ok(())
+        }
+    }
+
+    fn down_event<U: PipelineUp<T>, D: PipelineDown<T>>(
+            &self, event: PipelineEvent, up: &U, down: &D) -> IoResult<()> {
+        match event {
+            WritabilityChanged(writable) => {
+                self.writable.set(writable);
+                self.release(up, down)
+            }
+            event => down.down_event(event)
+        }
+    }
+
+    fn up_event<U: PipelineUp<T>, D: PipelineDown<T>>(
+            &self, event: PipelineEvent, up: &U, _: &D) -> IoResult<()> {
+        match event {
+            PauseRead | ResumeRead => up.up_event(event),
+            event => {
+                if self.passes_through() {
+                    up.up_event(event)
+                } else {
+                    self.held.borrow_mut().get().push(HeldEvent(event));
+                    Ok(())
+                }
+            }
+        }
+    }
 }
 
 pub trait PipelineDown<Din> {
-    fn down(&self, data: Din);
+    fn down(&self, data: Din) -> IoResult<()>;
+
+    /// Send an event down. Ignored unless overridden.
+    fn down_event(&self, event: PipelineEvent) -> IoResult<()> {
+        let _ = event;
+        Ok(())
+    }
 }
 
 impl <Din> PipelineDown<Din> for ~PipelineDown<Din> {
-    fn down(&self, data: Din) {
-        self.down(data);
+    fn down(&self, data: Din) -> IoResult<()> {
+        self.down(data)
+    }
+
+    fn down_event(&self, event: PipelineEvent) -> IoResult<()> {
+        self.down_event(event)
     }
 }
 
 pub trait PipelineUp<Uin> {
-    fn up(&self, data: Uin);
+    fn up(&self, data: Uin) -> IoResult<()>;
+
+    /// Send an event up. Ignored unless overridden.
+    fn up_event(&self, event: PipelineEvent) -> IoResult<()> {
+        let _ = event;
+        Ok(())
+    }
 }
 
 impl <Uin> PipelineUp<Uin> for ~PipelineUp<Uin> {
-    fn up(&self, data: Uin) {
-        self.up(data);
+    fn up(&self, data: Uin) -> IoResult<()> {
+        self.up(data)
     }
+
+    fn up_event(&self, event: PipelineEvent) -> IoResult<()> {
+        self.up_event(event)
+    }
+}
+
+// A stage owns its filter and, by value, every stage below it. Stages do not
+// hold references to the stages above them. Instead, whenever data is sent
+// into a stage, the caller lends it a link to the stage above for the
+// duration of the call. As a result the whole pipeline is a single tree of
+// owned values which can be moved and dropped freely.
+
+/// The part of a pipeline from some stage down to the bottom.
+trait Chain<Din, Uout> {
+    /// Send data into the top of this chain. Anything the top stage sends up
+    /// is passed to `up`.
+    fn chain_down<U: PipelineUp<Uout>>(&self, data: Din, up: &U) -> IoResult<()>;
+
+    fn chain_event<U: PipelineUp<Uout>>(&self, event: PipelineEvent, up: &U) -> IoResult<()>;
 }
 
-struct PipelineStage<U, F, D> {
-    up: U,
+struct PipelineStage<F, N> {
     filter: F,
-    down: D
+    next: N
 }
 
 impl <
         Din, Dout, Uin, Uout,
         F: Filter<Din, Dout, Uin, Uout>,
-        U: PipelineUp<Uout>,
-        D: PipelineDown<Dout>
+        N: Chain<Dout, Uin>
      >
-        PipelineDown<Din>
-        for PipelineStage<U, F, D> {
-    fn down(&self, data: Din) {
-        self.filter.down(data, &self.up, &self.down);
+        Chain<Din, Uout>
+        for PipelineStage<F, N> {
+    fn chain_down<U: PipelineUp<Uout>>(&self, data: Din, up: &U) -> IoResult<()> {
+        let link = StageLink { stage: self, up: up };
+        match self.filter.down(data, up, &link) {
+            Err(err) => self.filter.error(err, up, &link),
+            Ok(()) => Ok(())
+        }
+    }
+
+    fn chain_event<U: PipelineUp<Uout>>(&self, event: PipelineEvent, up: &U) -> IoResult<()> {
+        let link = StageLink { stage: self, up: up };
+        match self.filter.down_event(event, up, &link) {
+            Err(err) => self.filter.error(err, up, &link),
+            Ok(()) => Ok(())
+        }
     }
 }
 
-struct PipelineStageRef<S> {
-    stage: *S
+/// A borrowed link to a stage, together with the link to the stage above
+/// it. It is handed to the stage's filter as its `down`, and to the next
+/// stage as its `up`.
+struct StageLink<'a, F, N, U> {
+    stage: &'a PipelineStage<F, N>,
+    up: &'a U
 }
 
 impl <
-        Din, Dout, Uin, Uout,
+        'a, Din, Dout, Uin, Uout,
+        F: Filter<Din, Dout, Uin, Uout>,
+        N: Chain<Dout, Uin>,
+        U: PipelineUp<Uout>
+     >
+        PipelineDown<Dout>
+        for StageLink<'a, F, N, U> {
+    fn down(&self, data: Dout) -> IoResult<()> {
+        self.stage.next.chain_down(data, self)
+    }
+
+    fn down_event(&self, event: PipelineEvent) -> IoResult<()> {
+        self.stage.next.chain_event(event, self)
+    }
+}
+
+impl <
+        'a, Din, Dout, Uin, Uout,
         F: Filter<Din, Dout, Uin, Uout>,
-        U: PipelineUp<Uout>,
-        D: PipelineDown<Dout>
+        N: Chain<Dout, Uin>,
+        U: PipelineUp<Uout>
      >
         PipelineUp<Uin>
-        for PipelineStageRef<PipelineStage<U, F, D>> {
-    fn up(&self, data: Uin) {
-        let s = unsafe { self.stage.to_option().unwrap() };
-        s.filter.up(data, &s.up, &s.down);
+        for StageLink<'a, F, N, U> {
+    fn up(&self, data: Uin) -> IoResult<()> {
+        match self.stage.filter.up(data, self.up, self) {
+            Err(err) => self.stage.filter.error(err, self.up, self),
+            Ok(()) => Ok(())
+        }
+    }
+
+    fn up_event(&self, event: PipelineEvent) -> IoResult<()> {
+        match self.stage.filter.up_event(event, self.up, self) {
+            Err(err) => self.stage.filter.error(err, self.up, self),
+            Ok(()) => Ok(())
+        }
     }
 }
 
 struct AnyUp<Uin>;
 
 impl <Uin> PipelineUp<Uin> for AnyUp<Uin> {
-    fn up(&self, _: Uin) {}
+    fn up(&self, _: Uin) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+/// The bottom of every pipeline. Anything sent down into it is discarded.
+struct PipelineTerm;
+
+impl <Din, Uout> Chain<Din, Uout> for PipelineTerm {
+    fn chain_down<U: PipelineUp<Uout>>(&self, _: Din, _: &U) -> IoResult<()> {
+        Ok(())
+    }
+
+    fn chain_event<U: PipelineUp<Uout>>(&self, _: PipelineEvent, _: &U) -> IoResult<()> {
+        Ok(())
+    }
 }
 
-struct AnyDown<Din>;
+/// A built pipeline: the chain of stages and the sink for data sent up out
+/// of the first stage.
+struct Pipeline<C, Uout> {
+    chain: C,
+    sink: ~PipelineUp<Uout>
+}
 
-impl <Din> PipelineDown<Din> for AnyDown<Din> {
-    fn down(&self, _: Din) {}
+impl <Din, Uout, C: Chain<Din, Uout>> PipelineDown<Din> for Pipeline<C, Uout> {
+    fn down(&self, data: Din) -> IoResult<()> {
+        self.chain.chain_down(data, &self.sink)
+    }
+
+    fn down_event(&self, event: PipelineEvent) -> IoResult<()> {
+        self.chain.chain_event(event, &self.sink)
+    }
 }
 
 pub struct PipelineBuilder<F, N> {
@@ -84,8 +433,6 @@ pub struct PipelineBuilder<F, N> {
     priv next: N
 }
 
-struct PipelineTerm;
-
 impl <Din, Dout, Uin, Uout, F: Filter<Din, Dout, Uin, Uout>> PipelineBuilder<F, PipelineTerm> {
     pub fn new(filter: F) -> PipelineBuilder<F, PipelineTerm> {
         PipelineBuilder {
@@ -113,79 +460,394 @@ impl <
 impl <
         Din, Dout, Uin, Uout: Send,
         F: Filter<Din, Dout, Uin, Uout> + Send,
-        D: PipelineDown<Dout> + PipelineSetup<Uin> + Send,
-        N: BuildStage<Dout, D>
+        C: Chain<Dout, Uin> + Send,
+        N: BuildStage<C>
      >
         PipelineBuilder<F, N> {
     pub fn build(self) -> ~PipelineDown<Din> {
-        let mut ps = ~self.build_stage();
-
-        let me = ~PipelineStageRef {
-            stage: &*ps
-        } as ~PipelineUp<Uin>;
-
-        ps.down.setup(me);
+        let any_up: ~AnyUp<Uout> = ~AnyUp;
+        self.build_with_sink(any_up as ~PipelineUp<Uout>)
+    }
 
-        ps as ~PipelineDown<Din>
+    /// Build the pipeline, delivering anything that the first filter sends
+    /// up to `sink` instead of discarding it.
+    pub fn build_with_sink(self, sink: ~PipelineUp<Uout>) -> ~PipelineDown<Din> {
+        let pipeline = Pipeline {
+            chain: self.build_stage(),
+            sink: sink
+        };
+        ~pipeline as ~PipelineDown<Din>
     }
 }
 
-
-trait BuildStage<Din, S: PipelineDown<Din>> {
+trait BuildStage<S> {
     fn build_stage(self) -> S;
 }
 
 impl <
-        Din, Dout, Uin, Uout: Send,
-        F: Filter<Din, Dout, Uin, Uout> + Send,
-        D: PipelineDown<Dout> + PipelineSetup<Uin> + Send,
-        N: BuildStage<Dout, D>
+        Din, Dout, Uin, Uout,
+        F: Filter<Din, Dout, Uin, Uout>,
+        C: Chain<Dout, Uin>,
+        N: BuildStage<C>
      >
-        BuildStage<Din, PipelineStage<~PipelineUp<Uout>, F, D>>
+        BuildStage<PipelineStage<F, C>>
         for PipelineBuilder<F, N> {
-    fn build_stage(self) -> PipelineStage<~PipelineUp<Uout>, F, D> {
+    fn build_stage(self) -> PipelineStage<F, C> {
         let PipelineBuilder {filter, next} = self;
-
-        let any_up: ~AnyUp<Uout> = ~AnyUp;
-        let temp_up = any_up as ~PipelineUp<Uout>;
-
-        let ps = PipelineStage {
-            up: temp_up,
+        PipelineStage {
             filter: filter,
-            down: next.build_stage()
-        };
-
-        ps
+            next: next.build_stage()
+        }
     }
 }
 
-impl <Din> BuildStage<Din, AnyDown<Din>> for PipelineTerm {
-    fn build_stage(self) -> AnyDown<Din> {
-        AnyDown
+impl BuildStage<PipelineTerm> for PipelineTerm {
+    fn build_stage(self) -> PipelineTerm {
+        PipelineTerm
     }
 }
 
-trait PipelineSetup<Uout> {
-    fn setup(&mut self, up: ~PipelineUp<Uout>);
-}
+#[cfg(test)]
+mod test {
+    use super::{Filter, FlowControl, PipelineBuilder, PipelineDown, PipelineUp};
+    use super::{PipelineEvent, ReadEof, Flush, WritabilityChanged, Watermarks};
 
-impl <
-        Din, Dout, Uin, Uout: Send,
-        F: Filter<Din, Dout, Uin, Uout> + Send,
-        D: PipelineDown<Dout> + PipelineSetup<Uin> + Send
-     >
-        PipelineSetup<Uout>
-        for PipelineStage<~PipelineUp<Uout>, F, D> {
-    fn setup(&mut self, up: ~PipelineUp<Uout>) {
-        self.up = up;
-        let me = ~PipelineStageRef {
-            stage: &*self
-        } as ~PipelineUp<Uin>;
-        self.down.setup(me);
+    use std::io;
+    use std::io::{IoResult, IoError};
+    use std::uint;
+
+    use sync::MutexArc;
+
+    /// Adds one to everything passing through it in either direction and
+    /// counts how many times it has been dropped.
+    struct AddOne {
+        drops: MutexArc<uint>
+    }
+
+    impl Filter<uint, uint, uint, uint> for AddOne {
+        fn down<U: PipelineUp<uint>, D: PipelineDown<uint>>(
+                &self, data: uint, _: &U, down: &D) -> IoResult<()> {
+            down.down(data + 1)
+        }
+
+        fn up<U: PipelineUp<uint>, D: PipelineDown<uint>>(
+                &self, data: uint, up: &U, _: &D) -> IoResult<()> {
+            up.up(data + 1)
+        }
+    }
+
+    impl Drop for AddOne {
+        fn drop(&mut self) {
+            self.drops.access(|n| *n += 1);
+        }
+    }
+
+    /// Sends everything it receives back up.
+    struct Echo;
+
+    impl Filter<uint, (), (), uint> for Echo {
+        fn down<U: PipelineUp<uint>, D: PipelineDown<()>>(
+                &self, data: uint, up: &U, _: &D) -> IoResult<()> {
+            up.up(data)
+        }
+
+        fn up<U: PipelineUp<uint>, D: PipelineDown<()>>(
+                &self, _: (), _: &U, _: &D) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    struct Collect {
+        values: MutexArc<~[uint]>
+    }
+
+    impl PipelineUp<uint> for Collect {
+        fn up(&self, data: uint) -> IoResult<()> {
+            self.values.access(|values| values.push(data));
+            Ok(())
+        }
+
+        /// Records a flush as `uint::MAX`.
+        fn up_event(&self, event: PipelineEvent) -> IoResult<()> {
+            match event {
+                Flush => self.values.access(|values| values.push(uint::MAX)),
+                _ => {}
+            }
+            Ok(())
+        }
+    }
+
+    fn deep_pipeline(drops: &MutexArc<uint>, values: &MutexArc<~[uint]>) -> ~PipelineDown<uint> {
+        let f = || AddOne { drops: drops.clone() };
+        PipelineBuilder::new(Echo)
+            .filter(f()).filter(f()).filter(f()).filter(f())
+            .filter(f()).filter(f()).filter(f()).filter(f())
+            .filter(f()).filter(f()).filter(f()).filter(f())
+            .filter(f()).filter(f()).filter(f()).filter(f())
+            .build_with_sink(~Collect { values: values.clone() } as ~PipelineUp<uint>)
+    }
+
+    #[test]
+    fn test_deep_round_trip() {
+        let drops = MutexArc::new(0u);
+        let values = MutexArc::new(~[]);
+        let pipeline = deep_pipeline(&drops, &values);
+        pipeline.down(0).unwrap();
+        pipeline.down(100).unwrap();
+        assert_eq!(values.access(|v| v.clone()), ~[32, 132]);
+    }
+
+    #[test]
+    fn test_use_after_move() {
+        let drops = MutexArc::new(0u);
+        let values = MutexArc::new(~[]);
+        let pipeline = deep_pipeline(&drops, &values);
+        // Moving the pipeline must not leave any stage pointing at its old
+        // location.
+        let mut moved = ~[];
+        moved.push(pipeline);
+        let pipeline = moved.pop().unwrap();
+        pipeline.down(1).unwrap();
+        assert_eq!(values.access(|v| v.clone()), ~[33]);
+    }
+
+    #[test]
+    fn test_drop_releases_every_stage() {
+        let drops = MutexArc::new(0u);
+        let values = MutexArc::new(~[]);
+        {
+            let pipeline = deep_pipeline(&drops, &values);
+            pipeline.down(0).unwrap();
+            assert_eq!(drops.access(|n| *n), 0);
+        }
+        assert_eq!(drops.access(|n| *n), 16);
+    }
+
+    #[test]
+    fn test_unused_pipeline_is_dropped() {
+        let drops = MutexArc::new(0u);
+        let values = MutexArc::new(~[]);
+        {
+            let _pipeline = deep_pipeline(&drops, &values);
+        }
+        assert_eq!(drops.access(|n| *n), 16);
+        assert_eq!(values.access(|v| v.len()), 0);
+    }
+
+    /// Rejects odd numbers going down.
+    struct RejectOdd;
+
+    impl Filter<uint, uint, uint, uint> for RejectOdd {
+        fn down<U: PipelineUp<uint>, D: PipelineDown<uint>>(
+                &self, data: uint, _: &U, down: &D) -> IoResult<()> {
+            if data % 2 == 1 {
+                return Err(io::standard_error(io::InvalidInput));
+            }
+            down.down(data)
+        }
+
+        fn up<U: PipelineUp<uint>, D: PipelineDown<uint>>(
+                &self, data: uint, up: &U, _: &D) -> IoResult<()> {
+            up.up(data)
+        }
+    }
+
+    /// Replaces any error from below with an `EndOfFile` error.
+    struct Translate;
+
+    impl Filter<uint, uint, uint, uint> for Translate {
+        fn down<U: PipelineUp<uint>, D: PipelineDown<uint>>(
+                &self, data: uint, _: &U, down: &D) -> IoResult<()> {
+            down.down(data)
+        }
+
+        fn up<U: PipelineUp<uint>, D: PipelineDown<uint>>(
+                &self, data: uint, up: &U, _: &D) -> IoResult<()> {
+            up.up(data)
+        }
+
+        fn error<U: PipelineUp<uint>, D: PipelineDown<uint>>(
+                &self, _: IoError, _: &U, _: &D) -> IoResult<()> {
+            Err(io::standard_error(io::EndOfFile))
+        }
+    }
+
+    /// Answers any error from below with a value of 0 sent up.
+    struct Recover;
+
+    impl Filter<uint, uint, uint, uint> for Recover {
+        fn down<U: PipelineUp<uint>, D: PipelineDown<uint>>(
+                &self, data: uint, _: &U, down: &D) -> IoResult<()> {
+            down.down(data)
+        }
+
+        fn up<U: PipelineUp<uint>, D: PipelineDown<uint>>(
+                &self, data: uint, up: &U, _: &D) -> IoResult<()> {
+            up.up(data)
+        }
+
+        fn error<U: PipelineUp<uint>, D: PipelineDown<uint>>(
+                &self, _: IoError, up: &U, _: &D) -> IoResult<()> {
+            up.up(0)
+        }
+    }
+
+    #[test]
+    fn test_error_reaches_owner() {
+        let values = MutexArc::new(~[]);
+        let pipeline = PipelineBuilder::new(Echo)
+            .filter(RejectOdd)
+            .build_with_sink(~Collect { values: values.clone() } as ~PipelineUp<uint>);
+        assert!(pipeline.down(2).is_ok());
+        assert_eq!(pipeline.down(3).unwrap_err().kind, io::InvalidInput);
+        assert_eq!(values.access(|v| v.clone()), ~[2]);
+    }
+
+    #[test]
+    fn test_error_translated() {
+        let values = MutexArc::new(~[]);
+        let pipeline = PipelineBuilder::new(Echo)
+            .filter(RejectOdd)
+            .filter(Translate)
+            .build_with_sink(~Collect { values: values.clone() } as ~PipelineUp<uint>);
+        assert_eq!(pipeline.down(3).unwrap_err().kind, io::EndOfFile);
+    }
+
+    #[test]
+    fn test_error_swallowed() {
+        let values = MutexArc::new(~[]);
+        let pipeline = PipelineBuilder::new(Echo)
+            .filter(RejectOdd)
+            .filter(Recover)
+            .build_with_sink(~Collect { values: values.clone() } as ~PipelineUp<uint>);
+        assert!(pipeline.down(3).is_ok());
+        assert_eq!(values.access(|v| v.clone()), ~[0]);
+    }
+
+    /// Holds back the sum of everything sent down and sends it up, followed
+    /// by a flush, at end of stream.
+    struct Sum {
+        total: MutexArc<uint>
+    }
+
+    impl Filter<uint, (), (), uint> for Sum {
+        fn down<U: PipelineUp<uint>, D: PipelineDown<()>>(
+                &self, data: uint, _: &U, _: &D) -> IoResult<()> {
+            self.total.access(|total| *total += data);
+            Ok(())
+        }
+
+        fn up<U: PipelineUp<uint>, D: PipelineDown<()>>(
+                &self, _: (), _: &U, _: &D) -> IoResult<()> {
+            Ok(())
+        }
+
+        fn down_event<U: PipelineUp<uint>, D: PipelineDown<()>>(
+                &self, event: PipelineEvent, up: &U, _: &D) -> IoResult<()> {
+            match event {
+                ReadEof => {
+                    try!(up.up(self.total.access(|total| *total)));
+                    up.up_event(Flush)
+                }
+                _ => Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn test_events_pass_through() {
+        let drops = MutexArc::new(0u);
+        let values = MutexArc::new(~[]);
+        let pipeline = PipelineBuilder::new(Sum { total: MutexArc::new(0u) })
+            .filter(AddOne { drops: drops.clone() })
+            .filter(AddOne { drops: drops.clone() })
+            .build_with_sink(~Collect { values: values.clone() } as ~PipelineUp<uint>);
+        pipeline.down(1).unwrap();
+        pipeline.down(2).unwrap();
+        assert_eq!(values.access(|v| v.clone()), ~[]);
+        pipeline.down_event(ReadEof).unwrap();
+        assert_eq!(values.access(|v| v.clone()), ~[9, uint::MAX]);
+    }
+
+    #[test]
+    fn test_watermarks() {
+        let marks = Watermarks::new(2, 4);
+        assert_eq!(marks.add(4), None);
+        assert_eq!(marks.add(1), Some(false));
+        assert_eq!(marks.add(10), None);
+        assert_eq!(marks.remove(12), None);
+        assert!(!marks.is_writable());
+        assert_eq!(marks.remove(1), Some(true));
+        assert_eq!(marks.buffered(), 2);
+    }
+
+    /// Sends data back up, and a flush for 0. Records the writability it is
+    /// told about.
+    struct Producer {
+        writability: MutexArc<~[bool]>
+    }
+
+    impl Filter<uint, (), (), uint> for Producer {
+        fn down<U: PipelineUp<uint>, D: PipelineDown<()>>(
+                &self, data: uint, up: &U, _: &D) -> IoResult<()> {
+            if data == 0 {
+                up.up_event(Flush)
+            } else {
+                up.up(data)
+            }
+        }
+
+        fn up<U: PipelineUp<uint>, D: PipelineDown<()>>(
+                &self, _: (), _: &U, _: &D) -> IoResult<()> {
+            Ok(())
+        }
+
+        fn down_event<U: PipelineUp<uint>, D: PipelineDown<()>>(
+                &self, event: PipelineEvent, _: &U, _: &D) -> IoResult<()> {
+            match event {
+                WritabilityChanged(writable) => {
+                    self.writability.access(|w| w.push(writable));
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+    }
+
+    fn one(_: &uint) -> uint {
+        1
     }
-}
 
-impl <X> PipelineSetup<X> for AnyDown<X> {
-    fn setup(&mut self, _: ~PipelineUp<X>) {
+    #[test]
+    fn test_flow_control() {
+        let writability = MutexArc::new(~[]);
+        let values = MutexArc::new(~[]);
+        let pipeline = PipelineBuilder::new(Producer { writability: writability.clone() })
+            .filter(FlowControl::new(1, 3, one))
+            .build_with_sink(~Collect { values: values.clone() } as ~PipelineUp<uint>);
+
+        pipeline.down(1).unwrap();
+        assert_eq!(values.access(|v| v.clone()), ~[1]);
+
+        // The connection's writability is not passed on; the stage holds data
+        // back and reports its own.
+        pipeline.down_event(WritabilityChanged(false)).unwrap();
+        assert_eq!(writability.access(|w| w.clone()), ~[]);
+        pipeline.down(2).unwrap();
+        pipeline.down(0).unwrap();
+        pipeline.down(3).unwrap();
+        pipeline.down(4).unwrap();
+        assert_eq!(writability.access(|w| w.clone()), ~[]);
+        pipeline.down(5).unwrap();
+        assert_eq!(writability.access(|w| w.clone()), ~[false]);
+        assert_eq!(values.access(|v| v.clone()), ~[1]);
+
+        // Everything is released in order, including the flush.
+        pipeline.down_event(WritabilityChanged(true)).unwrap();
+        assert_eq!(values.access(|v| v.clone()), ~[1, 2, uint::MAX, 3, 4, 5]);
+        assert_eq!(writability.access(|w| w.clone()), ~[false, true]);
+
+        pipeline.down(6).unwrap();
+        assert_eq!(values.access(|v| v.clone()), ~[1, 2, uint::MAX, 3, 4, 5, 6]);
     }
 }
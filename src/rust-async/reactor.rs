@@ -0,0 +1,301 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An event loop that connects a selector, asynchronous IO and pipelines.
+//!
+//! The `Reactor` accepts connections from a listener, registers them with
+//! its own `EpollSelector` and, whenever a connection is readable, reads all
+//! available bytes and sends them down a pipeline created for that
+//! connection. Whatever the pipeline sends up out of its first filter is
+//! written back to the connection. Each connection is an `Endpoint`, which
+//! describes the events its pipeline is sent.
+
+use std::hashmap::HashMap;
+use std::io;
+use std::io::IoResult;
+
+use async;
+use async::{AsyncAcceptor, AsyncReader, AsyncWriter};
+use endpoint::Endpoint;
+use epoll_selector::{EpollSelectable, EpollSelector, EpollSelectionHandle};
+use pipeline::{PipelineDown, PipelineUp, Watermarks};
+use select;
+use select::{NullNotifier, ReadyMode, SelectEvent, Selector, SelectorHandle};
+
+static LISTENER_TOKEN: u64 = 0;
+static MAX_EVENTS: uint = 64;
+static DEFAULT_LOW_WATERMARK: uint = 32 * 1024;
+static DEFAULT_HIGH_WATERMARK: uint = 64 * 1024;
+
+/// Creates a new pipeline for each accepted connection.
+pub trait PipelineFactory {
+    /// Create a pipeline. Everything the pipeline sends up to `output` will be
+    /// written to the connection.
+    fn new_pipeline(&self, output: ~PipelineUp<~[u8]>) -> ~PipelineDown<~[u8]>;
+}
+
+pub struct Reactor<A, S, F> {
+    priv selector: EpollSelector<NullNotifier>,
+    priv listener: Option<EpollSelectionHandle<NullNotifier, A>>,
+    /// Whether accepting has been paused after an error.
+    priv accept_paused: bool,
+    priv factory: F,
+    priv connections: HashMap<u64, Endpoint<NullNotifier, S>>,
+    priv next_token: u64,
+    priv low_watermark: uint,
+    priv high_watermark: uint
+}
+
+impl <
+        A: AsyncAcceptor<S> + EpollSelectable + Send,
+        S: AsyncReader + AsyncWriter + EpollSelectable + Send,
+        F: PipelineFactory
+     >
+        Reactor<A, S, F> {
+    pub fn new(factory: F) -> IoResult<Reactor<A, S, F>> {
+        let selector = try!(EpollSelector::new(NullNotifier));
+        Ok(Reactor {
+            selector: selector,
+            listener: None,
+            accept_paused: false,
+            factory: factory,
+            connections: HashMap::new(),
+            next_token: LISTENER_TOKEN + 1,
+            low_watermark: DEFAULT_LOW_WATERMARK,
+            high_watermark: DEFAULT_HIGH_WATERMARK
+        })
+    }
+
+    /// Set the number of unwritten bytes above which a connection is reported
+    /// unwritable, and the number at or below which it is reported writable
+    /// again. Applies to connections added afterwards.
+    pub fn set_watermarks(&mut self, low: uint, high: uint) {
+        assert!(low <= high);
+        self.low_watermark = low;
+        self.high_watermark = high;
+    }
+
+    /// Accept connections from `acceptor`, which must already be listening.
+    /// The acceptor is put into non-blocking mode.
+    pub fn listen(&mut self, acceptor: A) -> IoResult<()> {
+        try!(async::set_nonblocking(acceptor.get_fd(), true));
+        let handle = try!(
+            self.selector.register(acceptor, LISTENER_TOKEN, select::SelectRead, true));
+        self.listener = Some(handle);
+        self.accept_paused = false;
+        Ok(())
+    }
+
+    /// Start accepting connections again after an error paused it.
+    pub fn resume_listening(&mut self) -> IoResult<()> {
+        if !self.accept_paused {
+            return Ok(());
+        }
+        match self.listener {
+            Some(ref mut listener) => try!(listener.modify(select::SelectRead, true)),
+            None => {}
+        }
+        self.accept_paused = false;
+        Ok(())
+    }
+
+    /// Start serving a connection that was established elsewhere.
+    pub fn add_connection(&mut self, stream: S) -> IoResult<u64> {
+        let token = self.next_token;
+        self.next_token += 1;
+
+        let handle = try!(self.selector.register(stream, token, select::SelectRead, true));
+        let watermarks = Watermarks::new(self.low_watermark, self.high_watermark);
+        let factory = &self.factory;
+        let endpoint = try!(Endpoint::new(handle, watermarks, |sink| factory.new_pipeline(sink)));
+        self.connections.insert(token, endpoint);
+        Ok(token)
+    }
+
+    /// Stop accepting connections and return the acceptor, if any. Open
+    /// connections are still served.
+    pub fn stop_listening(&mut self) -> Option<A> {
+        self.accept_paused = false;
+        self.listener.take().map(|handle| handle.unwrap())
+    }
+
+    /// The number of open connections.
+    pub fn connection_count(&self) -> uint {
+        self.connections.len()
+    }
+
+    /// Close every open connection.
+    pub fn close_all(&mut self) {
+        let tokens: ~[u64] = self.connections.keys().map(|token| *token).collect();
+        for token in tokens.iter() {
+            self.remove_connection(*token);
+        }
+    }
+
+    /// Process events until accepting a connection fails.
+    pub fn run(&mut self) -> IoResult<()> {
+        loop {
+            try!(self.run_once(-1));
+        }
+    }
+
+    /// Wait up to `timeout_ms` milliseconds for events and process them.
+    ///
+    /// If accepting a connection fails other than because the backlog is
+    /// empty, for example because the process has run out of file
+    /// descriptors, accepting is paused and the error is returned once the
+    /// other events have been processed. Accepting resumes when a connection
+    /// closes or `resume_listening` is called.
+    pub fn run_once(&mut self, timeout_ms: int) -> IoResult<()> {
+        let mut events =
+            [SelectEvent { mode: select::ReadyRead, data: 0, read_closed: false }, ..MAX_EVENTS];
+        let n = try!(self.selector.select(events, timeout_ms));
+        let mut result = Ok(());
+        for event in events.slice_to(n).iter() {
+            if event.data == LISTENER_TOKEN {
+                result = self.accept();
+            } else {
+                self.dispatch(event.data, event.mode);
+            }
+        }
+        result
+    }
+
+    fn accept(&mut self) -> IoResult<()> {
+        loop {
+            let stream = match self.listener {
+                Some(ref mut listener) => listener.get_mut_ref().accept(),
+                None => return Ok(())
+            };
+            match stream {
+                Ok(stream) => {
+                    // A connection that can't be registered is simply dropped.
+                    let _ = self.add_connection(stream);
+                }
+                // The backlog is empty.
+                Err(ref err) if async::would_block(err) => return Ok(()),
+                // The peer gave up before it was accepted.
+                Err(ref err) if err.kind == io::ConnectionAborted => {}
+                Err(err) => {
+                    // The listener stays readable, so accepting is paused
+                    // rather than retried on every event.
+                    match self.listener {
+                        Some(ref mut listener) => {
+                            try!(listener.modify(select::SelectIgnore, true));
+                        }
+                        None => {}
+                    }
+                    self.accept_paused = true;
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    fn dispatch(&mut self, token: u64, mode: ReadyMode) {
+        let keep = match self.connections.find_mut(&token) {
+            Some(conn) => conn.ready(mode).is_ok(),
+            None => return
+        };
+        if !keep {
+            self.remove_connection(token);
+        }
+    }
+
+    /// Close a connection. Closing frees a descriptor, so accepting resumes
+    /// if it was paused.
+    fn remove_connection(&mut self, token: u64) {
+        match self.connections.pop(&token) {
+            Some(conn) => {
+                conn.close();
+            }
+            None => return
+        }
+        // If resuming fails, accepting stays paused until the next attempt.
+        let _ = self.resume_listening();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PipelineFactory, Reactor};
+
+    use std::from_str::from_str;
+    use std::io::IoResult;
+    use std::io::net::ip::SocketAddr;
+
+    use async::{AsyncReader, AsyncWriter, would_block};
+    use net::{TcpAcceptor, TcpListener, TcpStream};
+    use pipeline::{PipelineDown, PipelineUp};
+
+    /// Writes everything it is sent back to the connection.
+    struct Echo {
+        output: ~PipelineUp<~[u8]>
+    }
+
+    impl PipelineDown<~[u8]> for Echo {
+        fn down(&self, data: ~[u8]) -> IoResult<()> {
+            self.output.up(data)
+        }
+    }
+
+    struct EchoFactory;
+
+    impl PipelineFactory for EchoFactory {
+        fn new_pipeline(&self, output: ~PipelineUp<~[u8]>) -> ~PipelineDown<~[u8]> {
+            ~Echo { output: output } as ~PipelineDown<~[u8]>
+        }
+    }
+
+    type EchoReactor = Reactor<TcpAcceptor, TcpStream, EchoFactory>;
+
+    fn listening_reactor() -> (EchoReactor, SocketAddr) {
+        let listener = TcpListener::bind(from_str("127.0.0.1:0").unwrap()).unwrap();
+        let addr = listener.socket_name().unwrap();
+        let mut reactor = Reactor::new(EchoFactory).unwrap();
+        reactor.listen(listener.listen().unwrap()).unwrap();
+        (reactor, addr)
+    }
+
+    #[test]
+    fn test_echo_over_loopback() {
+        let (mut reactor, addr) = listening_reactor();
+        let mut client = TcpStream::connect(addr).unwrap();
+        while reactor.connection_count() == 0 {
+            reactor.run_once(10).unwrap();
+        }
+        client.finish_connect().unwrap();
+        client.async_write(bytes!("hello")).unwrap();
+
+        let mut echoed = ~[];
+        let mut buf = [0u8, ..16];
+        while echoed.len() < 5 {
+            reactor.run_once(10).unwrap();
+            match client.async_read(buf) {
+                Ok(n) => echoed.push_all(buf.slice_to(n)),
+                Err(ref e) if would_block(e) => {}
+                Err(e) => fail!("read failed: {}", e)
+            }
+        }
+        assert_eq!(echoed.as_slice(), bytes!("hello"));
+    }
+
+    #[test]
+    fn test_closed_connection_is_removed() {
+        let (mut reactor, addr) = listening_reactor();
+        let client = TcpStream::connect(addr).unwrap();
+        while reactor.connection_count() == 0 {
+            reactor.run_once(10).unwrap();
+        }
+        drop(client);
+        while reactor.connection_count() == 1 {
+            reactor.run_once(10).unwrap();
+        }
+        // Nothing was paused, so resuming is a no-op.
+        reactor.resume_listening().unwrap();
+        assert!(reactor.stop_listening().is_some());
+    }
+}
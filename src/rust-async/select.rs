@@ -6,6 +6,7 @@
 
 use std::io::IoResult;
 
+#[deriving(Clone, Eq)]
 pub enum SelectMode {
     SelectRead,
     SelectWrite,
@@ -13,6 +14,7 @@ pub enum SelectMode {
     SelectIgnore
 }
 
+#[deriving(Clone, Eq)]
 pub enum ReadyMode {
     ReadyRead,
     ReadyWrite,
@@ -28,11 +30,36 @@ pub trait Selector<S, H: SelectorHandle> {
     fn register(&self, selectable: S, data: u64, mode: SelectMode, rearm: bool) -> IoResult<H>;
 }
 
+#[deriving(Clone)]
 pub struct SelectEvent {
     mode: ReadyMode,
-    data: u64
+    data: u64,
+    /// The peer has shut down its side of the connection. Once buffered data
+    /// has been read, reads will report end of stream.
+    read_closed: bool
 }
 
 pub trait SelectNotifier {
     fn notify(&self, events: &[SelectEvent]);
 }
+
+impl ReadyMode {
+    /// Returns true if this readiness satisfies interest in `mode`.
+    pub fn satisfies(&self, mode: SelectMode) -> bool {
+        match (*self, mode) {
+            (_, SelectIgnore) => false,
+            (ReadyBoth, _) => true,
+            (ReadyRead, SelectRead) | (ReadyRead, SelectBoth) => true,
+            (ReadyWrite, SelectWrite) | (ReadyWrite, SelectBoth) => true,
+            _ => false
+        }
+    }
+}
+
+/// A notifier that ignores all events. Useful when events are retrieved with
+/// `EpollSelector::select` rather than delivered through `run`.
+pub struct NullNotifier;
+
+impl SelectNotifier for NullNotifier {
+    fn notify(&self, _: &[SelectEvent]) {}
+}
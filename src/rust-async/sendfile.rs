@@ -0,0 +1,248 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Zero-copy transfer of file contents to a socket with sendfile(2).
+
+use std::cmp;
+use std::io;
+use std::io::{IoResult, IoError};
+use std::libc::{c_int, size_t, ssize_t};
+
+use native::io::file::FileDesc;
+
+use async::{set_nonblocking, would_block};
+use epoll_selector::{EpollSelectable, EpollSelectionHandle};
+use select;
+use select::{SelectMode, SelectorHandle};
+
+// Linux limits a single sendfile call to a little under 2GB.
+static MAX_CHUNK: u64 = 0x7ffff000;
+
+extern {
+    fn sendfile(out_fd: c_int, in_fd: c_int, offset: *mut i64, count: size_t) -> ssize_t;
+}
+
+/// Sends a range of a file to a socket.
+///
+/// Each call to `poll` transfers as much as the socket will accept and then
+/// returns. The offset and remaining length are tracked between calls, so
+/// after a would-block the operation simply resumes when the socket is
+/// writable again.
+pub struct AsyncSendFile {
+    priv file: FileDesc,
+    priv offset: u64,
+    priv remaining: u64,
+    priv prepared: bool,
+    priv blocked: bool
+}
+
+impl AsyncSendFile {
+    /// Send `len` bytes of `file`, starting at `offset`.
+    pub fn new(file: FileDesc, offset: u64, len: u64) -> AsyncSendFile {
+        AsyncSendFile {
+            file: file,
+            offset: offset,
+            remaining: len,
+            prepared: false,
+            blocked: false
+        }
+    }
+
+    /// The offset in the file of the next byte to be sent.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The number of bytes left to send.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Send as much as possible to `socket` without blocking. Returns true
+    /// once the whole range has been sent. The socket is switched into
+    /// non-blocking mode on the first call.
+    pub fn poll<S: EpollSelectable>(&mut self, socket: &S) -> IoResult<bool> {
+        if !self.prepared {
+            try!(set_nonblocking(socket.get_fd(), true));
+            self.prepared = true;
+        }
+        while self.remaining > 0 {
+            let mut offset = self.offset as i64;
+            let count = cmp::min(self.remaining, MAX_CHUNK);
+            let res = unsafe {
+                sendfile(socket.get_fd(), self.file.fd(), &mut offset, count as size_t)
+            };
+            if res < 0 {
+                let err = IoError::last_error();
+                if would_block(&err) {
+                    return Ok(false);
+                }
+                return Err(err);
+            }
+            if res == 0 {
+                return Err(IoError {
+                    kind: io::EndOfFile,
+                    desc: "The file ended before the requested range was sent.",
+                    detail: None
+                });
+            }
+            self.offset += res as u64;
+            self.remaining -= res as u64;
+        }
+        Ok(true)
+    }
+
+    /// Like `poll`, but also keeps the selector registration in step. `mode`
+    /// is the interest the owner has registered the handle with. While the
+    /// transfer is blocked the handle also waits for writability, and once
+    /// it completes the handle goes back to `mode`. A transfer that never
+    /// blocks leaves the registration alone.
+    pub fn poll_handle<N: Send + Freeze, S: EpollSelectable>(
+            &mut self,
+            handle: &mut EpollSelectionHandle<N, S>,
+            mode: SelectMode) -> IoResult<bool> {
+        let done = try!(self.poll(handle.get_ref()));
+        if !done && !self.blocked {
+            let blocked_mode = match mode {
+                select::SelectRead | select::SelectBoth => select::SelectBoth,
+                select::SelectWrite | select::SelectIgnore => select::SelectWrite
+            };
+            try!(handle.modify(blocked_mode, true));
+            self.blocked = true;
+        } else if done && self.blocked {
+            try!(handle.modify(mode, true));
+            self.blocked = false;
+        }
+        Ok(done)
+    }
+
+    /// Returns the file being sent.
+    pub fn unwrap(self) -> FileDesc {
+        self.file
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AsyncSendFile;
+
+    use std::io;
+    use std::libc;
+    use std::libc::{c_void, size_t};
+    use std::os;
+    use std::vec;
+
+    use native::io::file::FileDesc;
+
+    use async::{AsyncReader, AsyncWriter, would_block};
+    use epoll_selector::EpollSelector;
+    use select;
+    use select::{NullNotifier, SelectEvent, Selector};
+    use unix::UnixStream;
+
+    /// Create an unlinked temporary file holding `data`.
+    fn temp_file(name: &str, data: &[u8]) -> FileDesc {
+        let path = os::tmpdir().join(format!("rust-async-{}-{}", name, unsafe { libc::getpid() }));
+        let fd = path.with_c_str(|p| unsafe {
+            let fd = libc::open(p, libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC, 0o600);
+            libc::unlink(p);
+            fd
+        });
+        assert!(fd >= 0);
+        let res = unsafe { libc::write(fd, data.as_ptr() as *c_void, data.len() as size_t) };
+        assert_eq!(res as uint, data.len());
+        FileDesc::new(fd, true)
+    }
+
+    fn drain<R: AsyncReader>(reader: &mut R, out: &mut ~[u8]) {
+        let mut buf = [0u8, ..64 * 1024];
+        loop {
+            match reader.async_read(buf) {
+                Ok(n) => out.push_all(buf.slice_to(n)),
+                Err(ref e) if would_block(e) => return,
+                Err(e) => fail!("{}", e)
+            }
+        }
+    }
+
+    fn large_data() -> ~[u8] {
+        vec::from_fn(4 * 1024 * 1024, |i| (i % 251) as u8)
+    }
+
+    #[test]
+    fn test_send_resumes_after_would_block() {
+        let data = large_data();
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let file = temp_file("sendfile-all", data.as_slice());
+        let mut send = AsyncSendFile::new(file, 0, data.len() as u64);
+        let mut received = ~[];
+        let mut blocked = false;
+        while !send.poll(&a).unwrap() {
+            blocked = true;
+            drain(&mut b, &mut received);
+        }
+        drain(&mut b, &mut received);
+        assert!(blocked);
+        assert_eq!(send.remaining(), 0);
+        assert_eq!(send.offset(), data.len() as u64);
+        assert!(received == data);
+    }
+
+    #[test]
+    fn test_send_range() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let file = temp_file("sendfile-range", bytes!("0123456789"));
+        let mut send = AsyncSendFile::new(file, 2, 5);
+        assert!(send.poll(&a).unwrap());
+        let mut received = ~[];
+        drain(&mut b, &mut received);
+        assert_eq!(received.as_slice(), bytes!("23456"));
+        assert_eq!(send.offset(), 7);
+    }
+
+    #[test]
+    fn test_file_too_short() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        let file = temp_file("sendfile-short", bytes!("0123456789"));
+        let mut send = AsyncSendFile::new(file, 4, 10);
+        assert_eq!(send.poll(&a).unwrap_err().kind, io::EndOfFile);
+        assert_eq!(send.remaining(), 4);
+    }
+
+    #[test]
+    fn test_poll_handle_restores_mode() {
+        let data = large_data();
+        let selector = EpollSelector::new(NullNotifier).unwrap();
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let mut handle = selector.register(a, 1, select::SelectRead, true).unwrap();
+        let mut events =
+            [SelectEvent { mode: select::ReadyRead, data: 0, read_closed: false }, ..4];
+
+        let file = temp_file("sendfile-mode", data.as_slice());
+        let mut send = AsyncSendFile::new(file, 0, data.len() as u64);
+        assert!(!send.poll_handle(&mut handle, select::SelectRead).unwrap());
+
+        // While blocked the owner's interest in reads is kept.
+        b.async_write(bytes!("x")).unwrap();
+        assert_eq!(selector.select(events, 0).unwrap(), 1);
+        assert!(events[0].mode == select::ReadyRead);
+
+        let mut received = ~[];
+        loop {
+            drain(&mut b, &mut received);
+            if send.poll_handle(&mut handle, select::SelectRead).unwrap() {
+                break;
+            }
+        }
+
+        // Once finished the handle is only interested in reads again, even
+        // though the socket is writable.
+        assert_eq!(selector.select(events, 0).unwrap(), 1);
+        assert!(events[0].mode == select::ReadyRead);
+        drain(&mut b, &mut received);
+        assert!(received == data);
+    }
+}
@@ -0,0 +1,100 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::libc::{c_char, c_int, c_uint, c_void, size_t, ssize_t, socklen_t};
+use std::libc::{gid_t, pid_t, sa_family_t, uid_t};
+
+use uio::iovec;
+
+pub static MSG_PEEK: c_int = 0x2;
+pub static MSG_DONTWAIT: c_int = 0x40;
+pub static MSG_NOSIGNAL: c_int = 0x4000;
+
+pub static SOCK_NONBLOCK: c_int = 0x800;
+pub static SOCK_CLOEXEC: c_int = 0x80000;
+
+pub static AF_UNIX: c_int = 1;
+
+pub static SOL_SOCKET: c_int = 1;
+pub static SO_REUSEADDR: c_int = 2;
+pub static SO_ERROR: c_int = 4;
+pub static SO_BROADCAST: c_int = 6;
+pub static SO_SNDBUF: c_int = 7;
+pub static SO_RCVBUF: c_int = 8;
+pub static SO_KEEPALIVE: c_int = 9;
+pub static SO_REUSEPORT: c_int = 15;
+pub static SO_PASSCRED: c_int = 16;
+pub static SO_PEERCRED: c_int = 17;
+
+pub static IPPROTO_TCP: c_int = 6;
+pub static TCP_NODELAY: c_int = 1;
+pub static TCP_KEEPIDLE: c_int = 4;
+pub static TCP_KEEPINTVL: c_int = 5;
+pub static TCP_KEEPCNT: c_int = 6;
+pub static TCP_FASTOPEN: c_int = 23;
+
+pub static SCM_RIGHTS: c_int = 1;
+pub static SCM_CREDENTIALS: c_int = 2;
+
+pub static MSG_CTRUNC: c_int = 0x8;
+pub static MSG_CMSG_CLOEXEC: c_int = 0x40000000;
+
+pub static SHUT_RD: c_int = 0;
+pub static SHUT_WR: c_int = 1;
+pub static SHUT_RDWR: c_int = 2;
+
+pub struct msghdr {
+    msg_name: *mut c_void,
+    msg_namelen: socklen_t,
+    msg_iov: *mut iovec,
+    msg_iovlen: size_t,
+    msg_control: *mut c_void,
+    msg_controllen: size_t,
+    msg_flags: c_int
+}
+
+pub struct cmsghdr {
+    cmsg_len: size_t,
+    cmsg_level: c_int,
+    cmsg_type: c_int
+}
+
+pub struct ucred {
+    pid: pid_t,
+    uid: uid_t,
+    gid: gid_t
+}
+
+pub struct sockaddr_un {
+    sun_family: sa_family_t,
+    sun_path: [c_char, ..108]
+}
+
+pub struct mmsghdr {
+    msg_hdr: msghdr,
+    msg_len: c_uint
+}
+
+extern {
+    pub fn accept4(
+        sockfd: c_int,
+        addr: *mut c_void,
+        addrlen: *mut socklen_t,
+        flags: c_int) -> c_int;
+
+    pub fn sendmsg(sockfd: c_int, msg: *msghdr, flags: c_int) -> ssize_t;
+
+    pub fn recvmsg(sockfd: c_int, msg: *mut msghdr, flags: c_int) -> ssize_t;
+
+    pub fn sendmmsg(sockfd: c_int, msgvec: *mut mmsghdr, vlen: c_uint, flags: c_int) -> c_int;
+
+    pub fn recvmmsg(
+        sockfd: c_int,
+        msgvec: *mut mmsghdr,
+        vlen: c_uint,
+        flags: c_int,
+        timeout: *c_void) -> c_int;
+}
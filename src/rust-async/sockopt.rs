@@ -0,0 +1,235 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Typed socket options for the sockets in `net`.
+
+use std::io::IoResult;
+use std::io::net::ip::SocketAddr;
+use std::libc::c_int;
+
+use async::AsyncListener;
+use net::{Socket, TcpAcceptor, TcpListener, TcpStream, UdpSocket};
+use socket;
+
+/// TCP keepalive probe parameters, in seconds and probe counts.
+#[deriving(Clone, Eq)]
+pub struct Keepalive {
+    idle: uint,
+    interval: uint,
+    count: uint
+}
+
+/// Options common to all sockets.
+pub trait SocketOptions {
+    fn as_socket<'a>(&'a self) -> &'a Socket;
+
+    fn set_reuse_address(&self, enabled: bool) -> IoResult<()> {
+        self.as_socket().setsockopt(socket::SOL_SOCKET, socket::SO_REUSEADDR, enabled as c_int)
+    }
+
+    /// Allow several sockets to bind the same address and port. This must be
+    /// set before binding; see `TcpListener::bind_with`.
+    fn set_reuse_port(&self, enabled: bool) -> IoResult<()> {
+        self.as_socket().setsockopt(socket::SOL_SOCKET, socket::SO_REUSEPORT, enabled as c_int)
+    }
+
+    fn set_send_buffer_size(&self, size: uint) -> IoResult<()> {
+        self.as_socket().setsockopt(socket::SOL_SOCKET, socket::SO_SNDBUF, size as c_int)
+    }
+
+    fn send_buffer_size(&self) -> IoResult<uint> {
+        let size: c_int =
+            try!(self.as_socket().getsockopt(socket::SOL_SOCKET, socket::SO_SNDBUF));
+        Ok(size as uint)
+    }
+
+    fn set_recv_buffer_size(&self, size: uint) -> IoResult<()> {
+        self.as_socket().setsockopt(socket::SOL_SOCKET, socket::SO_RCVBUF, size as c_int)
+    }
+
+    fn recv_buffer_size(&self) -> IoResult<uint> {
+        let size: c_int =
+            try!(self.as_socket().getsockopt(socket::SOL_SOCKET, socket::SO_RCVBUF));
+        Ok(size as uint)
+    }
+}
+
+/// Options specific to TCP sockets.
+pub trait TcpOptions: SocketOptions {
+    /// Disable Nagle's algorithm.
+    fn set_nodelay(&self, enabled: bool) -> IoResult<()> {
+        self.as_socket().setsockopt(socket::IPPROTO_TCP, socket::TCP_NODELAY, enabled as c_int)
+    }
+
+    fn nodelay(&self) -> IoResult<bool> {
+        let enabled: c_int =
+            try!(self.as_socket().getsockopt(socket::IPPROTO_TCP, socket::TCP_NODELAY));
+        Ok(enabled != 0)
+    }
+
+    /// Enable keepalive probes with the given parameters, or disable them
+    /// with `None`.
+    fn set_keepalive(&self, keepalive: Option<Keepalive>) -> IoResult<()> {
+        let sock = self.as_socket();
+        match keepalive {
+            Some(k) => {
+                let tcp = socket::IPPROTO_TCP;
+                try!(sock.setsockopt(socket::SOL_SOCKET, socket::SO_KEEPALIVE, 1 as c_int));
+                try!(sock.setsockopt(tcp, socket::TCP_KEEPIDLE, k.idle as c_int));
+                try!(sock.setsockopt(tcp, socket::TCP_KEEPINTVL, k.interval as c_int));
+                sock.setsockopt(tcp, socket::TCP_KEEPCNT, k.count as c_int)
+            }
+            None => sock.setsockopt(socket::SOL_SOCKET, socket::SO_KEEPALIVE, 0 as c_int)
+        }
+    }
+
+    /// Accept data in the SYN of incoming connections, queueing up to
+    /// `queue_len` such connections. Only meaningful on listening sockets.
+    fn set_fastopen(&self, queue_len: uint) -> IoResult<()> {
+        self.as_socket().setsockopt(socket::IPPROTO_TCP, socket::TCP_FASTOPEN, queue_len as c_int)
+    }
+}
+
+/// Options specific to UDP sockets.
+pub trait UdpOptions: SocketOptions {
+    fn set_broadcast(&self, enabled: bool) -> IoResult<()> {
+        self.as_socket().setsockopt(socket::SOL_SOCKET, socket::SO_BROADCAST, enabled as c_int)
+    }
+}
+
+impl SocketOptions for Socket {
+    fn as_socket<'a>(&'a self) -> &'a Socket {
+        self
+    }
+}
+
+impl SocketOptions for TcpListener {
+    fn as_socket<'a>(&'a self) -> &'a Socket {
+        self.socket()
+    }
+}
+
+impl TcpOptions for TcpListener {}
+
+impl SocketOptions for TcpAcceptor {
+    fn as_socket<'a>(&'a self) -> &'a Socket {
+        self.socket()
+    }
+}
+
+impl TcpOptions for TcpAcceptor {}
+
+impl SocketOptions for TcpStream {
+    fn as_socket<'a>(&'a self) -> &'a Socket {
+        self.socket()
+    }
+}
+
+impl TcpOptions for TcpStream {}
+
+impl SocketOptions for UdpSocket {
+    fn as_socket<'a>(&'a self) -> &'a Socket {
+        self.socket()
+    }
+}
+
+impl UdpOptions for UdpSocket {}
+
+/// Create `count` listening sockets bound to the same address with
+/// SO_REUSEPORT. The kernel balances incoming connections between them, so
+/// each can be registered with a different selector, typically one per
+/// thread. If the port in `addr` is 0, all of the sockets share the port
+/// chosen for the first one.
+pub fn reuse_port_group(addr: SocketAddr, count: uint) -> IoResult<~[TcpAcceptor]> {
+    let mut addr = addr;
+    let mut acceptors = ~[];
+    for _ in range(0, count) {
+        let listener = try!(TcpListener::bind_with(addr, |sock| sock.set_reuse_port(true)));
+        let acceptor = try!(listener.listen());
+        addr = try!(acceptor.socket_name());
+        acceptors.push(acceptor);
+    }
+    Ok(acceptors)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Keepalive, SocketOptions, TcpOptions, UdpOptions, reuse_port_group};
+
+    use std::from_str::from_str;
+    use std::io::net::ip::SocketAddr;
+    use std::libc::c_int;
+
+    use net::{TcpListener, UdpSocket};
+    use socket;
+
+    fn localhost() -> SocketAddr {
+        from_str("127.0.0.1:0").unwrap()
+    }
+
+    #[test]
+    fn test_nodelay() {
+        let listener = TcpListener::bind(localhost()).unwrap();
+        listener.set_nodelay(true).unwrap();
+        assert!(listener.nodelay().unwrap());
+        listener.set_nodelay(false).unwrap();
+        assert!(!listener.nodelay().unwrap());
+    }
+
+    #[test]
+    fn test_buffer_sizes() {
+        let sock = UdpSocket::bind(localhost()).unwrap();
+        // Linux doubles the requested size to allow for bookkeeping.
+        sock.set_send_buffer_size(64 * 1024).unwrap();
+        assert!(sock.send_buffer_size().unwrap() >= 64 * 1024);
+        sock.set_recv_buffer_size(64 * 1024).unwrap();
+        assert!(sock.recv_buffer_size().unwrap() >= 64 * 1024);
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let listener = TcpListener::bind(localhost()).unwrap();
+        let sock = listener.socket();
+        listener.set_keepalive(Some(Keepalive { idle: 30, interval: 5, count: 3 })).unwrap();
+        let enabled: c_int = sock.getsockopt(socket::SOL_SOCKET, socket::SO_KEEPALIVE).unwrap();
+        let idle: c_int = sock.getsockopt(socket::IPPROTO_TCP, socket::TCP_KEEPIDLE).unwrap();
+        let interval: c_int = sock.getsockopt(socket::IPPROTO_TCP, socket::TCP_KEEPINTVL).unwrap();
+        let count: c_int = sock.getsockopt(socket::IPPROTO_TCP, socket::TCP_KEEPCNT).unwrap();
+        assert_eq!((enabled != 0, idle, interval, count), (true, 30, 5, 3));
+
+        listener.set_keepalive(None).unwrap();
+        let enabled: c_int = sock.getsockopt(socket::SOL_SOCKET, socket::SO_KEEPALIVE).unwrap();
+        assert_eq!(enabled, 0);
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let sock = UdpSocket::bind(localhost()).unwrap();
+        sock.set_broadcast(true).unwrap();
+        let enabled: c_int =
+            sock.socket().getsockopt(socket::SOL_SOCKET, socket::SO_BROADCAST).unwrap();
+        assert!(enabled != 0);
+    }
+
+    #[test]
+    fn test_reuse_port_group_shares_port() {
+        let acceptors = reuse_port_group(localhost(), 3).unwrap();
+        assert_eq!(acceptors.len(), 3);
+        let addr = acceptors[0].socket_name().unwrap();
+        assert!(addr.port != 0);
+        for acceptor in acceptors.iter() {
+            assert_eq!(acceptor.socket_name().unwrap(), addr);
+        }
+    }
+
+    #[test]
+    fn test_reuse_port_required() {
+        let acceptors = reuse_port_group(localhost(), 1).unwrap();
+        let addr = acceptors[0].socket_name().unwrap();
+        // Binding the same port without SO_REUSEPORT fails.
+        assert!(TcpListener::bind(addr).is_err());
+    }
+}
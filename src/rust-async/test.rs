@@ -5,6 +5,27 @@
 // except according to those terms.
 
 extern crate extra;
+extern crate native;
+extern crate sync;
 
 pub mod async;
-pub mod pipeline;
\ No newline at end of file
+pub mod bootstrap;
+pub mod buffered;
+pub mod codec;
+pub mod copy;
+pub mod dynamic_pipeline;
+pub mod endpoint;
+pub mod epoll;
+pub mod epoll_selector;
+pub mod fcntl;
+pub mod future;
+pub mod net;
+pub mod ops;
+pub mod pipeline;
+pub mod reactor;
+pub mod select;
+pub mod sendfile;
+pub mod socket;
+pub mod sockopt;
+pub mod uio;
+pub mod unix;
@@ -0,0 +1,18 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::libc::{c_int, c_void, size_t, ssize_t};
+
+pub struct iovec {
+    iov_base: *mut c_void,
+    iov_len: size_t
+}
+
+extern {
+    pub fn readv(fd: c_int, iov: *iovec, iovcnt: c_int) -> ssize_t;
+
+    pub fn writev(fd: c_int, iov: *iovec, iovcnt: c_int) -> ssize_t;
+}
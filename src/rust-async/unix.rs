@@ -0,0 +1,453 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Non-blocking Unix domain stream sockets, including passing file
+//! descriptors and credentials between processes.
+
+use std::cast;
+use std::io;
+use std::io::{Acceptor, IoResult, IoError};
+use std::libc;
+use std::libc::{c_int, c_void, size_t, socklen_t};
+use std::mem;
+use std::ptr;
+use std::vec;
+
+use native::io::file::FileDesc;
+
+use async::{AsyncAcceptor, AsyncListener, AsyncReader, AsyncWriter, Shutdown};
+use async::{empty_msghdr, io_result, read_iovecs, write_iovecs};
+use epoll_selector::EpollSelectable;
+use net::Socket;
+use socket;
+use socket::{cmsghdr, sockaddr_un, ucred};
+
+static LISTEN_BACKLOG: int = 128;
+
+// The most file descriptors accepted in a single message.
+static MAX_FDS: uint = 32;
+
+/// The identity of a process on the other end of a Unix socket.
+#[deriving(Clone, Eq)]
+pub struct Credentials {
+    pid: libc::pid_t,
+    uid: libc::uid_t,
+    gid: libc::gid_t
+}
+
+/// Ancillary data received alongside a message.
+pub struct Ancillary {
+    /// File descriptors sent with SCM_RIGHTS. They are owned by the receiver
+    /// and closed when dropped.
+    fds: ~[FileDesc],
+    /// Credentials sent with SCM_CREDENTIALS. These are only delivered once
+    /// `set_pass_credentials` has been enabled.
+    credentials: Option<Credentials>,
+    /// The control data was too large for the buffer and some of it was
+    /// discarded.
+    truncated: bool
+}
+
+fn path_to_sockaddr(path: &Path) -> IoResult<(sockaddr_un, socklen_t)> {
+    let bytes = path.as_vec();
+    let mut addr: sockaddr_un = unsafe { mem::init() };
+    // Leave room for the terminating nul.
+    if bytes.len() >= addr.sun_path.len() {
+        return Err(IoError {
+            kind: io::InvalidInput,
+            desc: "The path is too long for a Unix socket address.",
+            detail: None
+        });
+    }
+    addr.sun_family = socket::AF_UNIX as libc::sa_family_t;
+    for (i, b) in bytes.iter().enumerate() {
+        addr.sun_path[i] = *b as libc::c_char;
+    }
+    addr.sun_path[bytes.len()] = 0;
+    let len = mem::size_of::<libc::sa_family_t>() + bytes.len() + 1;
+    Ok((addr, len as socklen_t))
+}
+
+fn check(res: c_int) -> IoResult<()> {
+    if res < 0 {
+        Err(IoError::last_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// A Unix socket bound to a path but not yet listening.
+pub struct UnixListener {
+    priv sock: Socket
+}
+
+impl UnixListener {
+    /// Bind to `path`. The path must not already exist; it is not removed
+    /// when the listener is dropped.
+    pub fn bind(path: &Path) -> IoResult<UnixListener> {
+        let sock = try!(Socket::new(socket::AF_UNIX, libc::SOCK_STREAM));
+        let (addr, len) = try!(path_to_sockaddr(path));
+        try!(check(unsafe {
+            libc::bind(sock.fd(), &addr as *sockaddr_un as *libc::sockaddr, len)
+        }));
+        Ok(UnixListener { sock: sock })
+    }
+}
+
+impl AsyncListener<UnixStream, UnixAcceptor> for UnixListener {
+    fn listen(self) -> IoResult<UnixAcceptor> {
+        try!(self.sock.listen(LISTEN_BACKLOG));
+        Ok(UnixAcceptor { sock: self.sock })
+    }
+}
+
+/// A listening Unix socket.
+pub struct UnixAcceptor {
+    priv sock: Socket
+}
+
+impl AsyncAcceptor<UnixStream> for UnixAcceptor {
+    fn accept(&mut self) -> IoResult<UnixStream> {
+        let sock = try!(self.sock.accept());
+        Ok(UnixStream { sock: sock })
+    }
+}
+
+impl Acceptor<UnixStream> for UnixAcceptor {
+    fn accept(&mut self) -> IoResult<UnixStream> {
+        let sock = try!(self.sock.accept());
+        Ok(UnixStream { sock: sock })
+    }
+}
+
+impl EpollSelectable for UnixAcceptor {
+    fn get_fd(&self) -> c_int {
+        self.sock.fd()
+    }
+}
+
+/// A connected Unix stream socket.
+pub struct UnixStream {
+    priv sock: Socket
+}
+
+fn cmsg_align(len: uint) -> uint {
+    let align = mem::size_of::<size_t>();
+    (len + align - 1) & !(align - 1)
+}
+
+fn cmsg_space(len: uint) -> uint {
+    cmsg_align(mem::size_of::<cmsghdr>()) + cmsg_align(len)
+}
+
+fn cmsg_len(len: uint) -> uint {
+    cmsg_align(mem::size_of::<cmsghdr>()) + len
+}
+
+/// Build a control buffer holding a single control message.
+fn control_message<T>(level: c_int, ty: c_int, data: &[T]) -> ~[u8] {
+    let data_len = data.len() * mem::size_of::<T>();
+    let mut buf = vec::from_elem(cmsg_space(data_len), 0u8);
+    unsafe {
+        let hdr: *mut cmsghdr = cast::transmute(buf.as_mut_ptr());
+        (*hdr).cmsg_len = cmsg_len(data_len) as size_t;
+        (*hdr).cmsg_level = level;
+        (*hdr).cmsg_type = ty;
+        let dst = buf.as_mut_ptr().offset(cmsg_align(mem::size_of::<cmsghdr>()) as int);
+        ptr::copy_nonoverlapping_memory(dst, data.as_ptr() as *u8, data_len);
+    }
+    buf
+}
+
+impl UnixStream {
+    /// Connect to the listener bound to `path`.
+    pub fn connect(path: &Path) -> IoResult<UnixStream> {
+        let sock = try!(Socket::new(socket::AF_UNIX, libc::SOCK_STREAM));
+        let (addr, len) = try!(path_to_sockaddr(path));
+        try!(check(unsafe {
+            libc::connect(sock.fd(), &addr as *sockaddr_un as *libc::sockaddr, len)
+        }));
+        Ok(UnixStream { sock: sock })
+    }
+
+    /// Create a pair of connected sockets.
+    pub fn pair() -> IoResult<(UnixStream, UnixStream)> {
+        let mut fds = [0 as c_int, ..2];
+        try!(check(unsafe {
+            libc::socketpair(
+                socket::AF_UNIX,
+                libc::SOCK_STREAM | socket::SOCK_NONBLOCK | socket::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr())
+        }));
+        Ok((UnixStream { sock: Socket::from_fd(fds[0]) },
+            UnixStream { sock: Socket::from_fd(fds[1]) }))
+    }
+
+    /// The credentials of the process that created the peer socket.
+    pub fn peer_credentials(&self) -> IoResult<Credentials> {
+        let cred: ucred = try!(self.sock.getsockopt(socket::SOL_SOCKET, socket::SO_PEERCRED));
+        Ok(Credentials { pid: cred.pid, uid: cred.uid, gid: cred.gid })
+    }
+
+    /// Enable or disable receiving SCM_CREDENTIALS with each message.
+    pub fn set_pass_credentials(&self, enabled: bool) -> IoResult<()> {
+        self.sock.setsockopt(socket::SOL_SOCKET, socket::SO_PASSCRED, enabled as c_int)
+    }
+
+    /// Send `data` along with copies of the file descriptors in `fds`. At
+    /// least one byte of data must be sent for the descriptors to arrive.
+    pub fn send_with_fds(&mut self, data: &[u8], fds: &[c_int]) -> IoResult<uint> {
+        let control = control_message(socket::SOL_SOCKET, socket::SCM_RIGHTS, fds);
+        self.send_with_control(data, control.as_slice())
+    }
+
+    /// Send `data` along with the credentials of this process. The kernel
+    /// verifies them, so the receiver can trust them.
+    pub fn send_with_credentials(&mut self, data: &[u8]) -> IoResult<uint> {
+        let cred = unsafe {
+            ucred { pid: libc::getpid(), uid: libc::getuid(), gid: libc::getgid() }
+        };
+        let control = control_message(socket::SOL_SOCKET, socket::SCM_CREDENTIALS, [cred]);
+        self.send_with_control(data, control.as_slice())
+    }
+
+    fn send_with_control(&mut self, data: &[u8], control: &[u8]) -> IoResult<uint> {
+        let mut iovs = write_iovecs([data]);
+        let mut msg = empty_msghdr(iovs.as_mut_slice());
+        msg.msg_control = control.as_ptr() as *mut c_void;
+        msg.msg_controllen = control.len() as size_t;
+        io_result(unsafe { socket::sendmsg(self.sock.fd(), &msg, socket::MSG_NOSIGNAL) })
+    }
+
+    /// Receive data along with any file descriptors and credentials that
+    /// were sent with it.
+    pub fn recv_with_ancillary(&mut self, buf: &mut [u8]) -> IoResult<(uint, Ancillary)> {
+        let mut control = vec::from_elem(
+            cmsg_space(MAX_FDS * mem::size_of::<c_int>()) + cmsg_space(mem::size_of::<ucred>()),
+            0u8);
+        let buf_len = buf.len();
+        let mut iovs = read_iovecs(&mut [buf]);
+        let mut msg = empty_msghdr(iovs.as_mut_slice());
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = control.len() as size_t;
+        let res = try!(io_result(unsafe {
+            socket::recvmsg(self.sock.fd(), &mut msg, socket::MSG_CMSG_CLOEXEC)
+        }));
+
+        let mut ancillary = Ancillary {
+            fds: ~[],
+            credentials: None,
+            truncated: msg.msg_flags & socket::MSG_CTRUNC != 0
+        };
+
+        let header_len = cmsg_align(mem::size_of::<cmsghdr>());
+        let control_len = msg.msg_controllen as uint;
+        let mut offset = 0;
+        while offset + header_len <= control_len {
+            unsafe {
+                let hdr: &cmsghdr = cast::transmute(control.as_ptr().offset(offset as int));
+                let len = hdr.cmsg_len as uint;
+                if len < header_len || offset + len > control_len {
+                    break;
+                }
+                let data = control.as_ptr().offset((offset + header_len) as int);
+                let data_len = len - header_len;
+                if hdr.cmsg_level == socket::SOL_SOCKET && hdr.cmsg_type == socket::SCM_RIGHTS {
+                    let fds: *c_int = cast::transmute(data);
+                    for i in range(0, data_len / mem::size_of::<c_int>()) {
+                        ancillary.fds.push(FileDesc::new(*fds.offset(i as int), true));
+                    }
+                } else if hdr.cmsg_level == socket::SOL_SOCKET &&
+                        hdr.cmsg_type == socket::SCM_CREDENTIALS &&
+                        data_len >= mem::size_of::<ucred>() {
+                    let cred: &ucred = cast::transmute(data);
+                    ancillary.credentials =
+                        Some(Credentials { pid: cred.pid, uid: cred.uid, gid: cred.gid });
+                }
+                offset += cmsg_align(len);
+            }
+        }
+
+        if res == 0 && buf_len > 0 {
+            return Err(io::standard_error(io::EndOfFile));
+        }
+        Ok((res, ancillary))
+    }
+}
+
+impl AsyncReader for UnixStream {
+    fn async_read(&mut self, output: &mut [u8]) -> IoResult<uint> {
+        self.sock.async_read(output)
+    }
+
+    fn async_read_vectored(&mut self, outputs: &mut [&mut [u8]]) -> IoResult<uint> {
+        self.sock.async_read_vectored(outputs)
+    }
+
+    fn splice_fd(&self) -> Option<c_int> {
+        Some(self.sock.fd())
+    }
+}
+
+impl AsyncWriter for UnixStream {
+    fn async_write(&mut self, input: &[u8]) -> IoResult<uint> {
+        self.sock.async_write(input)
+    }
+
+    fn async_write_vectored(&mut self, inputs: &[&[u8]]) -> IoResult<uint> {
+        self.sock.async_write_vectored(inputs)
+    }
+
+    fn async_shutdown(&mut self, how: Shutdown) -> IoResult<()> {
+        self.sock.async_shutdown(how)
+    }
+
+    fn splice_fd(&self) -> Option<c_int> {
+        Some(self.sock.fd())
+    }
+}
+
+impl EpollSelectable for UnixStream {
+    fn get_fd(&self) -> c_int {
+        self.sock.fd()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Credentials, MAX_FDS, UnixListener, UnixStream};
+
+    use std::io;
+    use std::io::IoResult;
+    use std::libc;
+    use std::libc::c_int;
+    use std::os;
+    use std::vec;
+
+    use native::io::file::FileDesc;
+
+    use async::{AsyncAcceptor, AsyncListener, AsyncReader, AsyncWriter};
+    use async::{set_nonblocking, would_block};
+
+    fn retry<T>(op: || -> IoResult<T>) -> IoResult<T> {
+        loop {
+            match op() {
+                Err(ref e) if would_block(e) => {}
+                res => return res
+            }
+        }
+    }
+
+    fn pipe() -> (FileDesc, FileDesc) {
+        let mut fds = [0 as c_int, ..2];
+        assert!(unsafe { libc::pipe(fds.as_mut_ptr()) } == 0);
+        set_nonblocking(fds[0], true).unwrap();
+        set_nonblocking(fds[1], true).unwrap();
+        (FileDesc::new(fds[0], true), FileDesc::new(fds[1], true))
+    }
+
+    fn own_credentials() -> Credentials {
+        unsafe { Credentials { pid: libc::getpid(), uid: libc::getuid(), gid: libc::getgid() } }
+    }
+
+    #[test]
+    fn test_pair_round_trip() {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+        let mut buf = [0u8, ..16];
+        assert!(would_block(&b.async_read(buf).unwrap_err()));
+        assert_eq!(a.async_write(bytes!("hello")).unwrap(), 5);
+        assert_eq!(b.async_read(buf).unwrap(), 5);
+        assert_eq!(buf.slice_to(5), bytes!("hello"));
+        drop(a);
+        assert_eq!(b.async_read(buf).unwrap_err().kind, io::EndOfFile);
+    }
+
+    #[test]
+    fn test_listen_and_connect() {
+        let path = os::tmpdir().join(format!("rust-async-unix-{}", unsafe { libc::getpid() }));
+        let mut acceptor = UnixListener::bind(&path).unwrap().listen().unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        let mut server = retry(|| acceptor.accept()).unwrap();
+        path.with_c_str(|p| unsafe { libc::unlink(p) });
+
+        client.async_write(bytes!("ping")).unwrap();
+        let mut buf = [0u8, ..4];
+        assert_eq!(retry(|| server.async_read(buf)).unwrap(), 4);
+        assert_eq!(buf.as_slice(), bytes!("ping"));
+        assert!(server.peer_credentials().unwrap() == own_credentials());
+    }
+
+    #[test]
+    fn test_path_too_long() {
+        let path = Path::new(vec::from_elem(200, 'a' as u8));
+        match UnixListener::bind(&path) {
+            Err(ref e) if e.kind == io::InvalidInput => {}
+            _ => fail!("expected the path to be rejected")
+        }
+    }
+
+    #[test]
+    fn test_pass_fd() {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+        let (mut pipe_read, pipe_write) = pipe();
+        assert_eq!(a.send_with_fds(bytes!("x"), [pipe_write.fd()]).unwrap(), 1);
+        // The receiver gets its own copy of the descriptor.
+        drop(pipe_write);
+
+        let mut buf = [0u8, ..4];
+        let (n, ancillary) = b.recv_with_ancillary(buf).unwrap();
+        assert_eq!(n, 1);
+        assert!(!ancillary.truncated);
+        assert!(ancillary.credentials.is_none());
+        assert_eq!(ancillary.fds.len(), 1);
+
+        let mut fds = ancillary.fds;
+        let mut received = fds.pop().unwrap();
+        assert_eq!(received.async_write(bytes!("via fd")).unwrap(), 6);
+        let mut out = [0u8, ..6];
+        assert_eq!(pipe_read.async_read(out).unwrap(), 6);
+        assert_eq!(out.as_slice(), bytes!("via fd"));
+    }
+
+    #[test]
+    fn test_too_many_fds_truncated() {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+        let (pipe_read, _pipe_write) = pipe();
+        let fds = vec::from_elem(MAX_FDS + 1, pipe_read.fd());
+        a.send_with_fds(bytes!("x"), fds).unwrap();
+
+        let mut buf = [0u8, ..4];
+        let (n, ancillary) = b.recv_with_ancillary(buf).unwrap();
+        assert_eq!(n, 1);
+        assert!(ancillary.truncated);
+        assert!(ancillary.fds.len() <= MAX_FDS);
+    }
+
+    #[test]
+    fn test_pass_credentials() {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+        b.set_pass_credentials(true).unwrap();
+        a.send_with_credentials(bytes!("c")).unwrap();
+
+        let mut buf = [0u8, ..4];
+        let (n, ancillary) = b.recv_with_ancillary(buf).unwrap();
+        assert_eq!(n, 1);
+        assert!(ancillary.credentials == Some(own_credentials()));
+        assert_eq!(ancillary.fds.len(), 0);
+    }
+
+    #[test]
+    fn test_recv_with_ancillary_eof() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        drop(a);
+        let mut buf = [0u8, ..4];
+        match b.recv_with_ancillary(buf) {
+            Err(ref e) if e.kind == io::EndOfFile => {}
+            _ => fail!("expected end of file")
+        }
+    }
+}